@@ -1,61 +1,908 @@
-use secp256k1::key::{PublicKey, SecretKey};
-use secp256k1::{Secp256k1, constants};
-
-use rand::os::{OsRng};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
 
-use crypto::ripemd160::Ripemd160;
-use crypto::sha2::Sha256;
+use crypto::aes::{self, KeySize};
 use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::{Mac, MacResult};
+use crypto::pbkdf2::pbkdf2;
+use crypto::scrypt::{scrypt, ScryptParams};
+use crypto::sha2::{Sha256, Sha512};
+use crypto::symmetriccipher::SynchronousStreamCipher;
+use rand::{OsRng, Rng};
+use rustc_serialize::hex::{FromHex, ToHex};
+use rustc_serialize::json::{self, Json};
+use secp256k1::Secp256k1;
+use secp256k1::key::{PublicKey, SecretKey};
+
+use bitcoin::network::constants::Network;
+use bitcoin::util::base58::{FromBase58, ToBase58};
+use bitcoin::util::bip32::{ChildNumber, ExtendedPrivKey, ExtendedPubKey};
 
-use rust_base58::ToBase58;
+use bech32;
+use rpc::object;
+use store::write_atomically;
+use util::{hash160, p2pkh_script, p2sh_script, witness_script};
+use wire::u32_to_le;
 
-use util;
+/// The BIP39 English word list, one word per line in sorted order
+const WORDLIST: &'static str = include_str!("bip39_english.txt");
+const KEYSTORE_VERSION: u64 = 1;
+/// scrypt cost parameters for the keystore's encryption key: log2(N), r, p
+const SCRYPT_PARAMS: (u8, u32, u32) = (15, 8, 1);
 
-pub fn new_pri_key(secp: &Secp256k1) -> SecretKey {
-    let mut rng: OsRng = OsRng::new().unwrap();
-    SecretKey::new(&secp, &mut rng)
+pub fn new_pri_key(secp: &Secp256k1) -> Result<SecretKey, String> {
+    let mut rng = match OsRng::new() {
+        Ok(rng) => rng,
+        Err(e) => return Err(format!("Opening the OS random number generator: {:?}", e)),
+    };
+    Ok(SecretKey::new(secp, &mut rng))
 }
 
 pub fn pub_from_pri(secp: &Secp256k1, pri_key: &SecretKey) -> PublicKey {
     PublicKey::from_secret_key(secp, pri_key).unwrap()
 }
 
-pub fn addr_from_pub(secp: &Secp256k1, pub_key: &PublicKey, for_testnet: bool)
-                     -> String {
-    let mut data: [u8; constants::PUBLIC_KEY_SIZE] = [0; constants::PUBLIC_KEY_SIZE];
-    let pub_key_arrvec = pub_key.serialize_vec(secp, false);
-    let iter = pub_key_arrvec.iter();
-    
-    for (i, byte) in iter.enumerate() {
-        data[i] = byte.clone();
+/// P2PKH address of a public key, compressed or not
+pub fn addr_from_pub(secp: &Secp256k1, pub_key: &PublicKey, compressed: bool,
+                     network: Network) -> String {
+    let pub_key = pub_key.serialize_vec(secp, compressed);
+    address_from_script(network, &p2pkh_script(&hash160(&pub_key[..]))).unwrap()
+}
+
+/// The kinds of address a wallet derives, each under its own BIP44 style
+/// purpose
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AddressType {
+    /// BIP44
+    P2pkh,
+    /// BIP49
+    P2shP2wpkh,
+    /// BIP84
+    P2wpkh,
+    /// BIP86, key path spends only
+    P2tr,
+}
+
+pub const ADDRESS_TYPES: [AddressType; 4] =
+    [AddressType::P2pkh, AddressType::P2shP2wpkh, AddressType::P2wpkh, AddressType::P2tr];
+
+impl AddressType {
+    /// Parse Core's names for address types
+    pub fn from_name(name: &str) -> Option<AddressType> {
+        match name {
+            "legacy" => Some(AddressType::P2pkh),
+            "p2sh-segwit" => Some(AddressType::P2shP2wpkh),
+            "bech32" => Some(AddressType::P2wpkh),
+            "bech32m" => Some(AddressType::P2tr),
+            _ => None,
+        }
+    }
+
+    /// The type of a standard output script. P2SH scripts are taken to wrap
+    /// P2WPKH, the only kind a keystore hands out.
+    pub fn of_script(script: &[u8]) -> Option<AddressType> {
+        match (script.len(), script.first()) {
+            (25, Some(&0x76)) => Some(AddressType::P2pkh),
+            (23, Some(&0xa9)) => Some(AddressType::P2shP2wpkh),
+            (22, Some(&0x00)) => Some(AddressType::P2wpkh),
+            (34, Some(&0x51)) => Some(AddressType::P2tr),
+            _ => None,
+        }
+    }
+
+    /// Output descriptor of this type over a key expression
+    pub fn descriptor(&self, key: &str) -> String {
+        match *self {
+            AddressType::P2pkh => format!("pkh({})", key),
+            AddressType::P2shP2wpkh => format!("sh(wpkh({}))", key),
+            AddressType::P2wpkh => format!("wpkh({})", key),
+            AddressType::P2tr => format!("tr({})", key),
+        }
+    }
+
+    pub fn purpose(&self) -> u32 {
+        match *self {
+            AddressType::P2pkh => 44,
+            AddressType::P2shP2wpkh => 49,
+            AddressType::P2wpkh => 84,
+            AddressType::P2tr => 86,
+        }
+    }
+
+    /// The output script paying to a key, which is always serialized
+    /// compressed
+    pub fn script_pubkey(&self, secp: &Secp256k1, key: &PublicKey) -> Vec<u8> {
+        let key_hash = hash160(&key.serialize_vec(secp, true)[..]);
+        match *self {
+            AddressType::P2pkh => p2pkh_script(&key_hash),
+            AddressType::P2shP2wpkh => {
+                p2sh_script(&hash160(&witness_script(0, &key_hash)[..]))
+            },
+            AddressType::P2wpkh => witness_script(0, &key_hash),
+            AddressType::P2tr => witness_script(1, &taproot_output_key(secp, key)),
+        }
     }
-    
+}
+
+/// SHA256 of `data` tagged as in BIP340
+pub fn tagged_hash(tag: &str, data: &[u8]) -> [u8; 32] {
+    let mut tag_hash = [0u8; 32];
+    let mut sha256 = Sha256::new();
+    sha256.input(tag.as_bytes());
+    sha256.result(&mut tag_hash);
+
+    let mut hash = [0u8; 32];
     let mut sha256 = Sha256::new();
-    sha256.input(&data[..]);
-    sha256.result(&mut data);
-    
-    let mut ripemd160 = Ripemd160::new();
-    ripemd160.input(&data[..]);
-    ripemd160.result(&mut data);
-    
-    encode_prefix(&mut data, for_testnet);
-    
-    let checksum = util::checksum(&data[..]);
-
-    data[21] = checksum[0];
-    data[22] = checksum[1];
-    data[23] = checksum[2];
-    data[24] = checksum[3];
-            
-    data[0..25].to_base58()
-}
-
-fn encode_prefix(data: &mut [u8; constants::PUBLIC_KEY_SIZE], for_testnet: bool) {
-    let mut temp: u8 = 0;
-    let mut temp2: u8 = if for_testnet { 0x6F } else { 0x00 };
-    for i in 0..20 {
-        temp = data[i];
-        data[i] = temp2;
-        temp2 = temp;
+    sha256.input(&tag_hash);
+    sha256.input(&tag_hash);
+    sha256.input(data);
+    sha256.result(&mut hash);
+    hash
+}
+
+/// The x-only key of a BIP86 output: the internal key, taken with an even
+/// y, tweaked by the TapTweak hash of its x coordinate
+pub fn taproot_output_key(secp: &Secp256k1, key: &PublicKey) -> [u8; 32] {
+    let serialized = key.serialize_vec(secp, true);
+    let mut even = [2u8; 33];
+    even[1..].copy_from_slice(&serialized[1..33]);
+    let mut output_key = PublicKey::from_slice(secp, &even).unwrap();
+    // a tweak outside the curve order is as unlikely as a hash collision
+    let tweak = SecretKey::from_slice(secp, &tagged_hash("TapTweak", &even[1..])).unwrap();
+    output_key.add_exp_assign(secp, &tweak).unwrap();
+
+    let mut x = [0u8; 32];
+    x.copy_from_slice(&output_key.serialize_vec(secp, true)[1..33]);
+    x
+}
+
+/// The address of a standard output script
+pub fn address_from_script(network: Network, script: &[u8]) -> Option<String> {
+    let (p2pkh_version, p2sh_version, hrp) = match network {
+        Network::Bitcoin => (0x00, 0x05, "bc"),
+        Network::Testnet => (0x6f, 0xc4, "tb"),
+    };
+    let base58 = |version: u8, hash: &[u8]| {
+        let mut data = vec![version];
+        data.extend_from_slice(hash);
+        data.to_base58check()
+    };
+    let len = script.len();
+    if len == 25 && script[..3] == [0x76, 0xa9, 0x14] && script[23..] == [0x88, 0xac] {
+        Some(base58(p2pkh_version, &script[3..23]))
+    } else if len == 23 && script[..2] == [0xa9, 0x14] && script[22] == 0x87 {
+        Some(base58(p2sh_version, &script[2..22]))
+    } else if len >= 4 && len <= 42 && script[1] as usize == len - 2 &&
+        (script[0] == 0 || (script[0] >= 0x51 && script[0] <= 0x60)) {
+        let version = if script[0] == 0 { 0 } else { script[0] - 0x50 };
+        Some(bech32::encode(hrp, version, &script[2..]))
+    } else {
+        None
+    }
+}
+
+fn coin_type(network: Network) -> u32 {
+    match network {
+        Network::Bitcoin => 0,
+        Network::Testnet => 1,
+    }
+}
+
+/// m/purpose'/coin_type'/account'
+pub fn account_path(address_type: AddressType, network: Network, account: u32)
+                    -> Vec<ChildNumber> {
+    vec![ChildNumber::Hardened(address_type.purpose()),
+         ChildNumber::Hardened(coin_type(network)),
+         ChildNumber::Hardened(account)]
+}
+
+/// Parse a derivation path such as `m/84'/0'/0'/0/1`, marking hardened
+/// steps with `'` or `h`
+pub fn parse_path(path: &str) -> Result<Vec<ChildNumber>, String> {
+    let mut steps = path.split('/');
+    if steps.next() != Some("m") {
+        return Err(format!("Derivation path {} must start with m", path));
+    }
+    steps.map(|step| {
+        let hardened = step.ends_with('\'') || step.ends_with('h');
+        let index = if hardened { &step[..step.len() - 1] } else { step };
+        match index.parse::<u32>() {
+            Ok(index) if index < 1 << 31 => Ok(if hardened {
+                ChildNumber::Hardened(index)
+            } else {
+                ChildNumber::Normal(index)
+            }),
+            _ => Err(format!("Invalid step {} in derivation path {}", step, path)),
+        }
+    }).collect()
+}
+
+pub fn derive_priv(secp: &Secp256k1, key: &ExtendedPrivKey, path: &[ChildNumber])
+                   -> Result<ExtendedPrivKey, String> {
+    let mut key = key.clone();
+    for &child in path.iter() {
+        key = match key.ckd_priv(secp, child) {
+            Ok(key) => key,
+            Err(e) => return Err(format!("Deriving {:?}: {:?}", child, e)),
+        };
+    }
+    Ok(key)
+}
+
+pub fn random_bytes(len: usize) -> Result<Vec<u8>, String> {
+    let mut rng = match OsRng::new() {
+        Ok(rng) => rng,
+        Err(e) => return Err(format!("Opening the OS random number generator: {:?}", e)),
+    };
+    let mut bytes = vec![0u8; len];
+    rng.fill_bytes(&mut bytes);
+    Ok(bytes)
+}
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    let mut sha256 = Sha256::new();
+    sha256.input(data);
+    sha256.result(&mut hash);
+    hash
+}
+
+/// A new BIP39 mnemonic of 12, 15, 18, 21 or 24 words
+pub fn new_mnemonic(words: usize) -> Result<String, String> {
+    if words % 3 != 0 || words < 12 || words > 24 {
+        return Err("A mnemonic has 12, 15, 18, 21 or 24 words".to_string());
+    }
+    mnemonic_from_entropy(&try!(random_bytes(words / 3 * 4))[..])
+}
+
+pub fn mnemonic_from_entropy(entropy: &[u8]) -> Result<String, String> {
+    if entropy.len() % 4 != 0 || entropy.len() < 16 || entropy.len() > 32 {
+        return Err("Mnemonic entropy must be 16 to 32 bytes, in steps of 4".to_string());
+    }
+    let words = WORDLIST.lines().collect::<Vec<&str>>();
+    // the entropy followed by one checksum bit per 32 bits of entropy
+    let mut bits = bits_of(entropy);
+    bits.extend(bits_of(&sha256(entropy)).into_iter().take(entropy.len() / 4));
+    Ok(bits.chunks(11)
+       .map(|chunk| words[chunk.iter().fold(0, |index, &bit| index << 1 | bit as usize)])
+       .collect::<Vec<&str>>()
+       .join(" "))
+}
+
+/// Check a mnemonic's words and checksum, returning its entropy
+pub fn mnemonic_to_entropy(mnemonic: &str) -> Result<Vec<u8>, String> {
+    let words = WORDLIST.lines().collect::<Vec<&str>>();
+    let mut bits: Vec<bool> = vec![];
+    let mut count = 0;
+    for word in mnemonic.split_whitespace() {
+        match words.binary_search(&word) {
+            Ok(index) => bits.extend((0..11).rev().map(|i| (index >> i) & 1 == 1)),
+            Err(_) => return Err(format!("{} is not a BIP39 word", word)),
+        }
+        count += 1;
+    }
+    if count % 3 != 0 || count < 12 || count > 24 {
+        return Err("A mnemonic has 12, 15, 18, 21 or 24 words".to_string());
+    }
+    let checksum_len = count / 3;
+    let entropy = bits[..bits.len() - checksum_len].chunks(8)
+        .map(|chunk| chunk.iter().fold(0, |byte, &bit| byte << 1 | bit as u8))
+        .collect::<Vec<u8>>();
+    let checksum = bits_of(&sha256(&entropy[..])).into_iter()
+        .take(checksum_len)
+        .collect::<Vec<bool>>();
+    if checksum[..] != bits[bits.len() - checksum_len..] {
+        return Err("Mnemonic checksum doesn't match".to_string());
+    }
+    Ok(entropy)
+}
+
+fn bits_of(data: &[u8]) -> Vec<bool> {
+    data.iter()
+        .flat_map(|&byte| (0..8).rev().map(move |i| (byte >> i) & 1 == 1))
+        .collect()
+}
+
+/// The BIP39 seed of a mnemonic. Both are taken as already NFKD normalized,
+/// which English mnemonics and ASCII passphrases are.
+pub fn mnemonic_to_seed(mnemonic: &str, passphrase: &str) -> [u8; 64] {
+    let mnemonic = mnemonic.split_whitespace().collect::<Vec<&str>>().join(" ");
+    let mut mac = Hmac::new(Sha512::new(), mnemonic.as_bytes());
+    let mut seed = [0u8; 64];
+    pbkdf2(&mut mac, format!("mnemonic{}", passphrase).as_bytes(), 2048, &mut seed);
+    seed
+}
+
+/// Wallet import format of a secret key
+pub fn to_wif(network: Network, key: &SecretKey, compressed: bool) -> String {
+    let mut data = vec![match network {
+        Network::Bitcoin => 0x80,
+        Network::Testnet => 0xef,
+    }];
+    data.extend_from_slice(&key[..]);
+    if compressed {
+        data.push(1);
+    }
+    data.to_base58check()
+}
+
+/// Network, key and whether its public key is compressed
+pub fn from_wif(secp: &Secp256k1, wif: &str) -> Result<(Network, SecretKey, bool), String> {
+    let data: Vec<u8> = match FromBase58::from_base58check(wif) {
+        Ok(data) => data,
+        Err(e) => return Err(format!("Invalid WIF key: {:?}", e)),
+    };
+    let network = match data.first() {
+        Some(&0x80) => Network::Bitcoin,
+        Some(&0xef) => Network::Testnet,
+        _ => return Err("Invalid WIF key: unknown version".to_string()),
+    };
+    let compressed = match data.len() {
+        33 => false,
+        34 if data[33] == 1 => true,
+        _ => return Err("Invalid WIF key: wrong length".to_string()),
+    };
+    match SecretKey::from_slice(secp, &data[1..33]) {
+        Ok(key) => Ok((network, key, compressed)),
+        Err(e) => Err(format!("Invalid WIF key: {:?}", e)),
+    }
+}
+
+/// An HD wallet's master key and imported keys, kept encrypted on disk.
+/// Only the receive and change addresses handed out so far are considered
+/// ours.
+pub struct Keystore {
+    pub network: Network,
+    master: ExtendedPrivKey,
+    mnemonic: Option<String>,
+    /// Imported keys, with whether their public keys are compressed
+    imported: Vec<(SecretKey, bool)>,
+    /// Next unused index per purpose and chain, 0 for receive and 1 for
+    /// change
+    next_index: HashMap<(u32, u32), u32>,
+    path: String,
+    salt: Vec<u8>,
+    /// scrypt cost parameters the keys were derived with
+    scrypt_params: (u8, u32, u32),
+    /// Encryption key and MAC key derived from the password
+    keys: Vec<u8>,
+    secp: Secp256k1,
+}
+
+impl Keystore {
+    /// Create a keystore at `path` from a mnemonic, or from a new 24 word
+    /// mnemonic if none is given
+    pub fn create(path: &str, password: &str, mnemonic: Option<&str>, passphrase: &str,
+                  network: Network) -> Result<Keystore, String> {
+        let mnemonic = match mnemonic {
+            Some(mnemonic) => {
+                try!(mnemonic_to_entropy(mnemonic));
+                mnemonic.to_string()
+            },
+            None => try!(new_mnemonic(24)),
+        };
+        let secp = Secp256k1::new();
+        let seed = mnemonic_to_seed(&mnemonic, passphrase);
+        let master = match ExtendedPrivKey::new_master(&secp, network, &seed) {
+            Ok(master) => master,
+            Err(e) => return Err(format!("Deriving master key: {:?}", e)),
+        };
+        let salt = try!(random_bytes(32));
+        let keystore = Keystore {
+            network: network,
+            master: master,
+            mnemonic: Some(mnemonic),
+            imported: vec![],
+            next_index: HashMap::new(),
+            path: path.to_string(),
+            keys: derive_keys(password, &salt[..], SCRYPT_PARAMS),
+            salt: salt,
+            scrypt_params: SCRYPT_PARAMS,
+            secp: secp,
+        };
+        try!(keystore.save());
+        Ok(keystore)
+    }
+
+    pub fn open(path: &str, password: &str) -> Result<Keystore, String> {
+        let mut data = String::new();
+        if let Err(e) = File::open(path).and_then(|mut file| file.read_to_string(&mut data)) {
+            return Err(format!("Reading keystore {}: {:?}", path, e));
+        }
+        let json = match Json::from_str(&data) {
+            Ok(json) => json,
+            Err(e) => return Err(format!("Parsing keystore {}: {:?}", path, e)),
+        };
+        if json.find("version").and_then(|version| version.as_u64()) != Some(KEYSTORE_VERSION) {
+            return Err(format!("Unsupported keystore version in {}", path));
+        }
+        let hex_field = |key: &str| match json.find(key).and_then(|value| value.as_string()) {
+            Some(hex) => hex.from_hex().map_err(|_| format!("{} in {} is not hex", key, path)),
+            None => Err(format!("Keystore {} has no {}", path, key)),
+        };
+        let salt = try!(hex_field("salt"));
+        let iv = try!(hex_field("iv"));
+        let ciphertext = try!(hex_field("ciphertext"));
+        let mac = try!(hex_field("mac"));
+        let scrypt_params = match json.find("scrypt").and_then(|params| params.as_array()) {
+            Some(params) if params.len() == 3 => {
+                match (params[0].as_u64(), params[1].as_u64(), params[2].as_u64()) {
+                    (Some(log_n), Some(r), Some(p)) => (log_n, r, p),
+                    _ => return Err(format!("Keystore {} has bad scrypt parameters", path)),
+                }
+            },
+            _ => return Err(format!("Keystore {} has no scrypt parameters", path)),
+        };
+        let scrypt_params = match scrypt_params {
+            // bounded so that a tampered file can't make scrypt panic or
+            // take forever before the MAC is checked
+            (log_n, r, p) if log_n >= 1 && log_n <= 20 && r >= 1 && r <= 16 && p >= 1 &&
+                             p <= 16 && log_n < r * 16 => (log_n as u8, r as u32, p as u32),
+            _ => return Err(format!("Keystore {} has bad scrypt parameters", path)),
+        };
+        if iv.len() != 16 {
+            return Err(format!("Keystore {} has a bad IV", path));
+        }
+
+        let keys = derive_keys(password, &salt[..], scrypt_params);
+        let authenticated = mac_data(&salt[..], &iv[..], scrypt_params, &ciphertext[..]);
+        if authenticate(&keys[32..], &authenticated[..]) != MacResult::new(&mac[..]) {
+            return Err("Wrong keystore password, or the keystore was tampered with"
+                       .to_string());
+        }
+        let plaintext = String::from_utf8(apply_cipher(&keys[..32], &iv[..], &ciphertext[..]));
+        let contents = match plaintext.ok().and_then(|text| Json::from_str(&text).ok()) {
+            Some(contents) => contents,
+            None => return Err(format!("Keystore {} is corrupt", path)),
+        };
+
+        let secp = Secp256k1::new();
+        let master: ExtendedPrivKey = match contents.find("xprv")
+            .and_then(|xprv| xprv.as_string())
+            .and_then(|xprv| FromBase58::from_base58check(xprv).ok()) {
+                Some(master) => master,
+                None => return Err(format!("Keystore {} has no valid master key", path)),
+            };
+        let mut imported = vec![];
+        if let Some(keys) = contents.find("imported").and_then(|keys| keys.as_array()) {
+            for wif in keys.iter().filter_map(|key| key.as_string()) {
+                let (_, key, compressed) = try!(from_wif(&secp, wif));
+                imported.push((key, compressed));
+            }
+        }
+        let mut next_index = HashMap::new();
+        if let Some(indexes) = contents.find("next_index").and_then(|indexes| indexes.as_object()) {
+            for (key, index) in indexes.iter() {
+                let mut parts = key.split('/').map(|part| part.parse::<u32>());
+                match (parts.next(), parts.next(), index.as_u64()) {
+                    (Some(Ok(purpose)), Some(Ok(chain)), Some(index)) => {
+                        next_index.insert((purpose, chain), index as u32);
+                    },
+                    _ => return Err(format!("Keystore {} has a bad index {}", path, key)),
+                }
+            }
+        }
+
+        Ok(Keystore {
+            network: master.network,
+            master: master,
+            mnemonic: contents.find("mnemonic")
+                .and_then(|mnemonic| mnemonic.as_string())
+                .map(|mnemonic| mnemonic.to_string()),
+            imported: imported,
+            next_index: next_index,
+            path: path.to_string(),
+            salt: salt,
+            scrypt_params: scrypt_params,
+            keys: keys,
+            secp: secp,
+        })
+    }
+
+    /// Encrypt the keystore to its file, under a new IV
+    pub fn save(&self) -> Result<(), String> {
+        let mut next_index = json::Object::new();
+        for (&(purpose, chain), &index) in self.next_index.iter() {
+            next_index.insert(format!("{}/{}", purpose, chain), Json::U64(index as u64));
+        }
+        let contents = object(vec![
+            ("xprv", Json::String(self.master.to_base58check())),
+            ("mnemonic", self.mnemonic.clone().map_or(Json::Null, Json::String)),
+            ("imported", Json::Array(self.imported.iter()
+                                     .map(|&(ref key, compressed)| {
+                                         Json::String(to_wif(self.network, key, compressed))
+                                     })
+                                     .collect())),
+            ("next_index", Json::Object(next_index)),
+        ]);
+        let iv = try!(random_bytes(16));
+        let ciphertext = apply_cipher(&self.keys[..32], &iv[..],
+                                      json::encode(&contents).unwrap().as_bytes());
+        let (log_n, r, p) = self.scrypt_params;
+        let authenticated = mac_data(&self.salt[..], &iv[..], self.scrypt_params,
+                                     &ciphertext[..]);
+        let file = object(vec![
+            ("version", Json::U64(KEYSTORE_VERSION)),
+            ("scrypt", Json::Array(vec![Json::U64(log_n as u64), Json::U64(r as u64),
+                                        Json::U64(p as u64)])),
+            ("salt", Json::String(self.salt.to_hex())),
+            ("iv", Json::String(iv.to_hex())),
+            ("ciphertext", Json::String(ciphertext.to_hex())),
+            ("mac", Json::String(authenticate(&self.keys[32..], &authenticated[..]).code()
+                                 .to_hex())),
+        ]);
+        write_atomically(&self.path, json::encode(&file).unwrap().as_bytes())
+    }
+
+    pub fn mnemonic(&self) -> Option<&str> {
+        self.mnemonic.as_ref().map(|mnemonic| mnemonic.as_str())
+    }
+
+    /// Account xpub to give a watch-only wallet
+    pub fn account_xpub(&self, address_type: AddressType, account: u32)
+                        -> Result<ExtendedPubKey, String> {
+        let path = account_path(address_type, self.network, account);
+        let key = try!(derive_priv(&self.secp, &self.master, &path[..]));
+        Ok(ExtendedPubKey::from_private(&self.secp, &key))
+    }
+
+    /// Descriptors of the first account's receive and change addresses and
+    /// of the imported compressed keys, for a watch-only wallet
+    pub fn descriptors(&self) -> Result<Vec<String>, String> {
+        let mut descriptors = vec![];
+        for address_type in ADDRESS_TYPES.iter() {
+            descriptors.push(try!(self.account_descriptor(*address_type)));
+        }
+        for &(ref key, compressed) in self.imported.iter().filter(|&&(_, compressed)| compressed) {
+            let public_key = pub_from_pri(&self.secp, key).serialize_vec(&self.secp, compressed);
+            for address_type in ADDRESS_TYPES.iter() {
+                descriptors.push(address_type.descriptor(&public_key.to_hex()));
+            }
+        }
+        Ok(descriptors)
+    }
+
+    /// Descriptor of the first account's addresses of a type
+    pub fn account_descriptor(&self, address_type: AddressType) -> Result<String, String> {
+        let xpub = try!(self.account_xpub(address_type, 0));
+        Ok(address_type.descriptor(&format!("{}/<0;1>/*", xpub.to_base58check())))
+    }
+
+    /// Number of addresses handed out on a chain of the first account
+    pub fn handed_out(&self, address_type: AddressType, change: bool) -> u32 {
+        let chain = if change { 1 } else { 0 };
+        *self.next_index.get(&(address_type.purpose(), chain)).unwrap_or(&0)
+    }
+
+    fn derive_key(&self, address_type: AddressType, chain: u32, index: u32)
+                  -> Result<SecretKey, String> {
+        let mut path = account_path(address_type, self.network, 0);
+        path.push(ChildNumber::Normal(chain));
+        path.push(ChildNumber::Normal(index));
+        Ok(try!(derive_priv(&self.secp, &self.master, &path[..])).secret_key)
+    }
+
+    /// Hand out the next receive or change address of the first account
+    pub fn new_address(&mut self, address_type: AddressType, change: bool)
+                       -> Result<String, String> {
+        let chain = if change { 1 } else { 0 };
+        let index = *self.next_index.get(&(address_type.purpose(), chain)).unwrap_or(&0);
+        let key = try!(self.derive_key(address_type, chain, index));
+        let script = address_type.script_pubkey(&self.secp, &pub_from_pri(&self.secp, &key));
+        self.next_index.insert((address_type.purpose(), chain), index + 1);
+        try!(self.save());
+        Ok(address_from_script(self.network, &script[..]).unwrap())
+    }
+
+    pub fn import_wif(&mut self, wif: &str) -> Result<(), String> {
+        let (network, key, compressed) = try!(from_wif(&self.secp, wif));
+        if network != self.network {
+            return Err("Key is for another network".to_string());
+        }
+        if !self.imported.iter().any(|&(ref imported, _)| *imported == key) {
+            self.imported.push((key, compressed));
+            try!(self.save());
+        }
+        Ok(())
+    }
+
+    /// The key able to spend an output script, along with whether its
+    /// public key is compressed
+    pub fn key_for_script(&self, script: &[u8]) -> Option<(SecretKey, bool)> {
+        for &(ref key, compressed) in self.imported.iter() {
+            let public_key = pub_from_pri(&self.secp, key);
+            if !compressed {
+                let uncompressed = public_key.serialize_vec(&self.secp, false);
+                if script == &p2pkh_script(&hash160(&uncompressed[..]))[..] {
+                    return Some((key.clone(), false));
+                }
+            } else if ADDRESS_TYPES.iter()
+                .any(|address_type| script == &address_type.script_pubkey(&self.secp, &public_key)[..]) {
+                return Some((key.clone(), true));
+            }
+        }
+        for &address_type in ADDRESS_TYPES.iter() {
+            for chain in 0..2 {
+                let next = *self.next_index.get(&(address_type.purpose(), chain)).unwrap_or(&0);
+                for index in 0..next {
+                    let key = match self.derive_key(address_type, chain, index) {
+                        Ok(key) => key,
+                        Err(_) => continue,
+                    };
+                    let public_key = pub_from_pri(&self.secp, &key);
+                    if script == &address_type.script_pubkey(&self.secp, &public_key)[..] {
+                        return Some((key, true));
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+fn derive_keys(password: &str, salt: &[u8], scrypt_params: (u8, u32, u32)) -> Vec<u8> {
+    let (log_n, r, p) = scrypt_params;
+    let mut keys = vec![0u8; 64];
+    scrypt(password.as_bytes(), salt, &ScryptParams::new(log_n, r, p), &mut keys);
+    keys
+}
+
+/// AES-256-CTR, which encrypts and decrypts alike
+fn apply_cipher(key: &[u8], iv: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut cipher = aes::ctr(KeySize::KeySize256, key, iv);
+    let mut output = vec![0u8; data.len()];
+    cipher.process(data, &mut output);
+    output
+}
+
+/// Everything in the keystore file the MAC covers: the version, scrypt
+/// parameters, salt and IV, then the ciphertext. The salt and IV are
+/// preceded by their lengths, so that no two headers encode alike.
+fn mac_data(salt: &[u8], iv: &[u8], scrypt_params: (u8, u32, u32), ciphertext: &[u8])
+            -> Vec<u8> {
+    let (log_n, r, p) = scrypt_params;
+    let mut data = vec![];
+    data.extend_from_slice(&u32_to_le(KEYSTORE_VERSION as u32));
+    data.push(log_n);
+    data.extend_from_slice(&u32_to_le(r));
+    data.extend_from_slice(&u32_to_le(p));
+    for part in [salt, iv].iter() {
+        data.extend_from_slice(&u32_to_le(part.len() as u32));
+        data.extend_from_slice(part);
+    }
+    data.extend_from_slice(ciphertext);
+    data
+}
+
+fn authenticate(key: &[u8], data: &[u8]) -> MacResult {
+    let mut hmac = Hmac::new(Sha256::new(), key);
+    hmac.input(data);
+    hmac.result()
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::hex::{FromHex, ToHex};
+    use secp256k1::Secp256k1;
+    use secp256k1::key::SecretKey;
+
+    use bitcoin::network::constants::Network;
+    use bitcoin::util::base58::ToBase58;
+    use bitcoin::util::bip32::{ExtendedPrivKey, ExtendedPubKey};
+
+    use util::script_from_address;
+    use super::{address_from_script, derive_priv, from_wif, mnemonic_from_entropy,
+                mnemonic_to_entropy, mnemonic_to_seed, parse_path, to_wif};
+
+    /// BIP32 test vector 1 as (path, xprv, xpub)
+    const BIP32_VECTOR_1: [(&'static str, &'static str, &'static str); 6] = [
+        ("m",
+         "xprv9s21ZrQH143K3QTDL4LXw2F7HEK3wJUD2nW2nRk4stbPy6cq3jPPqjiChkVvvNKmPGJxWUtg6LnF5kej\
+          MRNNU3TGtRBeJgk33yuGBxrMPHi",
+         "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqs\
+          efD265TMg7usUDFdp6W1EGMcet8"),
+        ("m/0'",
+         "xprv9uHRZZhk6KAJC1avXpDAp4MDc3sQKNxDiPvvkX8Br5ngLNv1TxvUxt4cV1rGL5hj6KCesnDYUhd7oWgT\
+          11eZG7XnxHrnYeSvkzY7d2bhkJ7",
+         "xpub68Gmy5EdvgibQVfPdqkBBCHxA5htiqg55crXYuXoQRKfDBFA1WEjWgP6LHhwBZeNK1VTsfTFUHCdrfp1\
+          bgwQ9xv5ski8PX9rL2dZXvgGDnw"),
+        ("m/0'/1",
+         "xprv9wTYmMFdV23N2TdNG573QoEsfRrWKQgWeibmLntzniatZvR9BmLnvSxqu53Kw1UmYPxLgboyZQaXwTCg\
+          8MSY3H2EU4pWcQDnRnrVA1xe8fs",
+         "xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq\
+          527Hqck2AxYysAA7xmALppuCkwQ"),
+        ("m/0'/1/2'",
+         "xprv9z4pot5VBttmtdRTWfWQmoH1taj2axGVzFqSb8C9xaxKymcFzXBDptWmT7FwuEzG3ryjH4ktypQSAewR\
+          iNMjANTtpgP4mLTj34bhnZX7UiM",
+         "xpub6D4BDPcP2GT577Vvch3R8wDkScZWzQzMMUm3PWbmWvVJrZwQY4VUNgqFJPMM3No2dFDFGTsxxpG5uJh7\
+          n7epu4trkrX7x7DogT5Uv6fcLW5"),
+        ("m/0'/1/2'/2",
+         "xprvA2JDeKCSNNZky6uBCviVfJSKyQ1mDYahRjijr5idH2WwLsEd4Hsb2Tyh8RfQMuPh7f7RtyzTtdrbdqqs\
+          unu5Mm3wDvUAKRHSC34sJ7in334",
+         "xpub6FHa3pjLCk84BayeJxFW2SP4XRrFd1JYnxeLeU8EqN3vDfZmbqBqaGJAyiLjTAwm6ZLRQUMv1ZACTj37\
+          sR62cfN7fe5JnJ7dh8zL4fiyLHV"),
+        ("m/0'/1/2'/2/1000000000",
+         "xprvA41z7zogVVwxVSgdKUHDy1SKmdb533PjDz7J6N6mV6uS3ze1ai8FHa8kmHScGpWmj4WggLyQjgPie1rF\
+          SruoUihUZREPSL39UNdE3BBDu76",
+         "xpub6H1LXWLaKsWFhvm6RVpEL9P4KfRZSW7abD2ttkWP3SSQvnyA8FSVqNTEcYFgJS2UaFcxupHiYkro49S8\
+          yGasTvXEYBVPamhGW6cFJodrTHy"),
+    ];
+
+    #[test]
+    fn bip32_vector_1() {
+        let secp = Secp256k1::new();
+        let seed = "000102030405060708090a0b0c0d0e0f".from_hex().unwrap();
+        let master = ExtendedPrivKey::new_master(&secp, Network::Bitcoin, &seed).unwrap();
+        for &(path, xprv, xpub) in BIP32_VECTOR_1.iter() {
+            let key = derive_priv(&secp, &master, &parse_path(path).unwrap()[..]).unwrap();
+            assert_eq!(key.to_base58check(), xprv, "{}", path);
+            let public = ExtendedPubKey::from_private(&secp, &key);
+            assert_eq!(public.to_base58check(), xpub, "{}", path);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_paths() {
+        assert!(parse_path("0/1").is_err());
+        assert!(parse_path("m/x").is_err());
+        assert!(parse_path("m/2147483648").is_err());
+        assert_eq!(parse_path("m/0h/1").unwrap(), parse_path("m/0'/1").unwrap());
+    }
+
+    /// BIP39 vectors as (entropy, mnemonic, seed with the passphrase
+    /// `TREZOR`)
+    const BIP39_VECTORS: [(&'static str, &'static str, &'static str); 4] = [
+        ("00000000000000000000000000000000",
+         "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon \
+          about",
+         "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c9\
+          2f2cf141630c7a3c4ab7c81b2f001698e7463b04"),
+        ("7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f",
+         "legal winner thank year wave sausage worth useful legal winner thank yellow",
+         "2e8905819b8723fe2c1d161860e5ee1830318dbf49a83bd451cfb8440c28bd6fa457fe1296106559a3c80937\
+          a1c1069be3a3a5bd381ee6260e8d9739fce1f607"),
+        ("0000000000000000000000000000000000000000000000000000000000000000",
+         "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon \
+          abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon \
+          abandon art",
+         "bda85446c68413707090a52022edd26a1c9462295029f2e60cd7c4f2bbd3097170af7a4d73245cafa9c3cca8\
+          d561a7c3de6f5d4a10be8ed2a5e608d68f92fcc8"),
+        ("ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+         "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo \
+          zoo vote",
+         "dd48c104698c30cfe2b6142103248622fb7bb0ff692eebb00089b32d22484e1613912f0a5b694407be899ffd\
+          31ed3992c456cdf60f5d4564b8ba3f05a69890ad"),
+    ];
+
+    #[test]
+    fn bip39_vectors() {
+        for &(entropy, mnemonic, seed) in BIP39_VECTORS.iter() {
+            let entropy = entropy.from_hex().unwrap();
+            assert_eq!(mnemonic_from_entropy(&entropy).unwrap(), mnemonic);
+            assert_eq!(mnemonic_to_entropy(mnemonic).unwrap(), entropy);
+            assert_eq!(mnemonic_to_seed(mnemonic, "TREZOR").to_hex(), seed);
+        }
+    }
+
+    #[test]
+    fn abandon_about_master_key() {
+        let secp = Secp256k1::new();
+        let seed = mnemonic_to_seed("abandon abandon abandon abandon abandon abandon abandon \
+                                     abandon abandon abandon abandon about", "");
+        assert_eq!(seed.to_hex(),
+                   "5eb00bbddcf069084889a8ab9155568165f5c453ccb85e70811aaed6f6da5fc19a5ac40b389c\
+                    d370d086206dec8aa6c43daea6690f20ad3d8d48b2d2ce9e38e4");
+        let master = ExtendedPrivKey::new_master(&secp, Network::Bitcoin, &seed).unwrap();
+        assert_eq!(master.to_base58check(),
+                   "xprv9s21ZrQH143K3GJpoapnV8SFfukcVBSfeCficPSGfubmSFDxo1kuHnLisriDvSnRRuL2Qrg5\
+                    ggqHKNVpxR86QEC8w35uxmGoggxtQTPvfUu");
+    }
+
+    #[test]
+    fn rejects_bad_mnemonics() {
+        // the checksum of all zero entropy makes the last word "about"
+        assert!(mnemonic_to_entropy("abandon abandon abandon abandon abandon abandon abandon \
+                                     abandon abandon abandon abandon abandon").is_err());
+        assert!(mnemonic_to_entropy("abandon abandon abandon abandon abandon abandon abandon \
+                                     abandon abandon abandon abandon abandonn").is_err());
+        assert!(mnemonic_to_entropy("abandon abandon abandon abandon abandon abandon abandon \
+                                     abandon abandon abandon about").is_err());
+        assert!(mnemonic_from_entropy(&[0; 15]).is_err());
+    }
+
+    #[test]
+    fn wif_vectors() {
+        let secp = Secp256k1::new();
+        let bytes = "0c28fca386c7a227600b2fe50b7cae11ec86d3bf1fbe471be89827e19d72aa1d"
+            .from_hex()
+            .unwrap();
+        let key = SecretKey::from_slice(&secp, &bytes).unwrap();
+        let vectors = [
+            (Network::Bitcoin, false, "5HueCGU8rMjxEXxiPuD5BDku4MkFqeZyd4dZ1jvhTVqvbTLvyTJ"),
+            (Network::Bitcoin, true, "KwdMAjGmerYanjeui5SHS7JkmpZvVipYvB2LJGU1ZxJwYvP98617"),
+            (Network::Testnet, false, "91gGn1HgSap6CbU12F6z3pJri26xzp7Ay1VW6NHCoEayNXwRpu2"),
+            (Network::Testnet, true, "cMzLdeGd5vEqxB8B6VFQoRopQ3sLAAvEzDAoQgvX54xwofSWj1fx"),
+        ];
+        for &(network, compressed, wif) in vectors.iter() {
+            assert_eq!(to_wif(network, &key, compressed), wif);
+            let (decoded_network, decoded, decoded_compressed) = from_wif(&secp, wif).unwrap();
+            assert_eq!(decoded_network, network);
+            assert_eq!(decoded[..], key[..]);
+            assert_eq!(decoded_compressed, compressed);
+        }
+        // a flipped character breaks the checksum
+        assert!(from_wif(&secp, "5HueCGU8rMjxEXxiPuD5BDku4MkFqeZyd4dZ1jvhTVqvbTLvyTj").is_err());
+    }
+
+    /// Valid segwit addresses of BIP173 and BIP350, with the output
+    /// scripts they pay to
+    const VALID_SEGWIT_ADDRESSES: [(&'static str, &'static str); 8] = [
+        ("BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4",
+         "0014751e76e8199196d454941c45d1b3a323f1433bd6"),
+        ("tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7",
+         "00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262"),
+        ("bc1pw508d6qejxtdg4y5r3zarvary0c5xw7kw508d6qejxtdg4y5r3zarvary0c5xw7kt5nd6y",
+         "5128751e76e8199196d454941c45d1b3a323f1433bd6751e76e8199196d454941c45d1b3a323f1433bd6"),
+        ("BC1SW50QGDZ25J", "6002751e"),
+        ("bc1zw508d6qejxtdg4y5r3zarvaryvaxxpcs", "5210751e76e8199196d454941c45d1b3a323"),
+        ("tb1qqqqqp399et2xygdj5xreqhjjvcmzhxw4aywxecjdzew6hylgvsesrxh6hy",
+         "0020000000c4a5cad46221b2a187905e5266362b99d5e91c6ce24d165dab93e86433"),
+        ("tb1pqqqqp399et2xygdj5xreqhjjvcmzhxw4aywxecjdzew6hylgvsesf3hn0c",
+         "5120000000c4a5cad46221b2a187905e5266362b99d5e91c6ce24d165dab93e86433"),
+        ("bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0",
+         "512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"),
+    ];
+
+    /// Invalid segwit addresses of BIP173 and BIP350
+    const INVALID_SEGWIT_ADDRESSES: [&'static str; 23] = [
+        // BIP173
+        "tc1qw508d6qejxtdg4y5r3zarvary0c5xw7kg3g4ty",
+        "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t5",
+        "BC13W508D6QEJXTDG4Y5R3ZARVARY0C5XW7KN40WF2",
+        "bc1rw5uspcuh",
+        "bc10w508d6qejxtdg4y5r3zarvary0c5xw7kw508d6qejxtdg4y5r3zarvary0c5xw7kw5rljs90",
+        "BC1QR508D6QEJXTDG4Y5R3ZARVARYV98GJ9P",
+        "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sL5k7",
+        "bc1zw508d6qejxtdg4y5r3zarvaryvqyzf3du",
+        "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3pjxtptv",
+        "bc1gmk9yu",
+        // BIP350
+        "tc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vq5zuyut",
+        "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqh2y7hd",
+        "tb1z0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqglt7rf",
+        "BC1S0XLXVLHEMJA6C4DQV22UAPCTQUPFHLXM9H8Z3K2E72Q4K9HCZ7VQ54WELL",
+        "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kemeawh",
+        "tb1q0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vq24jc47",
+        "bc1p38j9r5y49hruaue7wxjce0updqjuyyx0kh56v8s25huc6995vvpql3jow4",
+        "BC130XLXVLHEMJA6C4DQV22UAPCTQUPFHLXM9H8Z3K2E72Q4K9HCZ7VQ7ZWS8R",
+        "bc1pw5dgrnzv",
+        "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7v8n0nx0muaewav253zgeav",
+        "tb1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vq47Zagq",
+        "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7v07qwwzcrf",
+        "tb1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vpggkg4j",
+    ];
+
+    #[test]
+    fn valid_segwit_addresses() {
+        for &(address, script) in VALID_SEGWIT_ADDRESSES.iter() {
+            let decoded = script_from_address(address).unwrap();
+            assert_eq!(decoded.to_hex(), script, "{}", address);
+            let network = if address.to_lowercase().starts_with("bc") {
+                Network::Bitcoin
+            } else {
+                Network::Testnet
+            };
+            assert_eq!(address_from_script(network, &decoded[..]).unwrap(),
+                       address.to_lowercase());
+        }
+    }
+
+    #[test]
+    fn invalid_segwit_addresses() {
+        for address in INVALID_SEGWIT_ADDRESSES.iter() {
+            assert!(script_from_address(address).is_err(), "{}", address);
+        }
     }
 }
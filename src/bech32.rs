@@ -0,0 +1,124 @@
+const CHARSET: &'static [u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+/// Checksum constants of bech32 (BIP173), used for witness version 0, and
+/// of bech32m (BIP350), used for later versions
+const BECH32_CONST: u32 = 1;
+const BECH32M_CONST: u32 = 0x2bc830a3;
+
+fn polymod(values: &[u8]) -> u32 {
+    const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut chk: u32 = 1;
+    for &value in values.iter() {
+        let top = chk >> 25;
+        chk = (chk & 0x1ffffff) << 5 ^ value as u32;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= *generator;
+            }
+        }
+    }
+    chk
+}
+
+fn expand_hrp(hrp: &str) -> Vec<u8> {
+    let mut values = hrp.bytes().map(|c| c >> 5).collect::<Vec<u8>>();
+    values.push(0);
+    values.extend(hrp.bytes().map(|c| c & 31));
+    values
+}
+
+/// Regroup bits, e.g. bytes into 5 bit groups
+fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Option<Vec<u8>> {
+    let mut acc: u32 = 0;
+    let mut bits: u32 = 0;
+    let max: u32 = (1 << to) - 1;
+    let mut result = vec![];
+    for &value in data.iter() {
+        if (value as u32) >> from != 0 {
+            return None;
+        }
+        acc = (acc << from) | value as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            result.push(((acc >> bits) & max) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 {
+            result.push(((acc << (to - bits)) & max) as u8);
+        }
+    } else if bits >= from || ((acc << (to - bits)) & max) != 0 {
+        return None;
+    }
+    Some(result)
+}
+
+fn checksum_const(version: u8) -> u32 {
+    if version == 0 { BECH32_CONST } else { BECH32M_CONST }
+}
+
+/// Encode a witness program as an address with human readable part `hrp`,
+/// such as `bc` or `tb`
+pub fn encode(hrp: &str, version: u8, program: &[u8]) -> String {
+    let mut data = vec![version];
+    data.extend(convert_bits(program, 8, 5, true).unwrap());
+
+    let mut values = expand_hrp(hrp);
+    values.extend_from_slice(&data[..]);
+    values.extend_from_slice(&[0; 6]);
+    let checksum = polymod(&values[..]) ^ checksum_const(version);
+    for i in 0..6 {
+        data.push(((checksum >> (5 * (5 - i))) & 31) as u8);
+    }
+
+    let mut address = hrp.to_string();
+    address.push('1');
+    for &value in data.iter() {
+        address.push(CHARSET[value as usize] as char);
+    }
+    address
+}
+
+/// Witness version and program of a segwit address with human readable
+/// part `hrp`
+pub fn decode(hrp: &str, address: &str) -> Result<(u8, Vec<u8>), String> {
+    if address.len() > 90 || (address.to_lowercase() != address &&
+                              address.to_uppercase() != address) {
+        return Err(format!("Invalid segwit address {}", address));
+    }
+    let address = address.to_lowercase();
+    let separator = match address.rfind('1') {
+        Some(i) => i,
+        None => return Err(format!("Invalid segwit address {}", address)),
+    };
+    if &address[..separator] != hrp || address.len() < separator + 7 {
+        return Err(format!("Invalid segwit address {}", address));
+    }
+    let mut data: Vec<u8> = vec![];
+    for c in address[separator + 1..].bytes() {
+        match CHARSET.iter().position(|&x| x == c) {
+            Some(value) => data.push(value as u8),
+            None => return Err(format!("Invalid character in {}", address)),
+        }
+    }
+
+    let mut values = expand_hrp(hrp);
+    values.extend_from_slice(&data[..]);
+    let data = &data[..data.len() - 6];
+    let version = match data.first() {
+        Some(&version) if version <= 16 => version,
+        _ => return Err(format!("Invalid witness version in {}", address)),
+    };
+    if polymod(&values[..]) != checksum_const(version) {
+        return Err(format!("Bad checksum in {}", address));
+    }
+    let program = match convert_bits(&data[1..], 5, 8, false) {
+        Some(program) => program,
+        None => return Err(format!("Invalid witness program in {}", address)),
+    };
+    if program.len() < 2 || program.len() > 40 ||
+        (version == 0 && program.len() != 20 && program.len() != 32) {
+        return Err(format!("Invalid witness program length in {}", address));
+    }
+    Ok((version, program))
+}
@@ -1,39 +1,102 @@
 use std::thread;
 use std::fs::{File, OpenOptions};
 use std::io::{BufReader, BufWriter, Write};
-use std::sync::mpsc::{Sender, Receiver, channel};
+use std::path::Path;
+use std::sync::mpsc::{Sender, Receiver, RecvTimeoutError, channel};
 use std::sync::{Arc, Mutex};
-use std::collections::{HashMap, VecDeque};
-use std::mem::size_of;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
 
-use bitcoin::network::encodable::{ConsensusEncodable, ConsensusDecodable};
-use bitcoin::network::serialize::{RawEncoder, RawDecoder, BitcoinHash};
+use rustc_serialize::json;
+
+use bitcoin::network::encodable::ConsensusDecodable;
+use bitcoin::network::serialize::{RawDecoder, BitcoinHash, serialize};
 use bitcoin::network::message::NetworkMessage;
-use bitcoin::network::message_blockdata::{GetHeadersMessage, Inventory, InvType};
-use bitcoin::network::constants::Network;
+use bitcoin::network::message_blockdata::GetHeadersMessage;
 use bitcoin::network::address::Address;
 use bitcoin::blockdata::blockchain::Blockchain;
 use bitcoin::blockdata::block::{Block, BlockHeader};
-use bitcoin::blockdata::transaction::{Transaction};
 use bitcoin::util::address::Address as Secp256k1Address;
 use bitcoin::util::hash::Sha256dHash;
 use bitcoin::util::Error;
 
-use postgres::{Connection, SslMode};
-
-use peerd::Peerd;
-use util::{ThreadResponse, ipv4_to_ipv4addr, string_of_address, addr_from_output};
+use address::Keystore;
+use broadcast::{Broadcaster, REBROADCAST_INTERVAL};
+use compact::{SendCmpct, CompactBlock, BlockTxn, BlockTxnRequest, PartialBlock,
+              COMPACT_VERSION, HIGH_BANDWIDTH_PEERS, BLOCK_TXN_TIMEOUT};
+use config::Config;
+use fees::FeeEstimator;
+use filter::FilterIndex;
+use log;
+use mempool::Mempool;
+use metrics::{self, PEER_CONNECTIONS, ADDRESS_BOOK_SIZE, HEADERS_HEIGHT, BLOCKS_HEIGHT,
+              BLOCK_ARRIVAL, DB_BLOCK_WRITE, DB_ROWS_INSERTED, DB_ROWS_REMOVED, REORGS,
+              BLOCKS_DISCONNECTED, CHANNEL_BACKLOG};
+use notify::{Event, Notifier};
+use peerd::{Peerd, PeerInfo};
+use rest::{self, RestCall};
+use rpc::{self, RpcCall, best_chain_hash_at, confirmations};
+use storage::{self, Storage, Schema, BlockRecord};
+use store::{HeaderStore, BlockStore, write_atomically, load_legacy_blockchain,
+            best_chain_headers};
+use validation::{check_block, check_header};
+use verify::{InputStatus, RetainedOutputs, TxIndex, verify_block};
+use wallet::Wallet;
+use watch::{Alert, Confirming, WatchList, Webhook};
+use wire::{PeerMessage, InvVector, MSG_TX, MSG_BLOCK, MSG_FILTERED_BLOCK, MSG_CMPCT_BLOCK,
+           MSG_WITNESS_TX, MSG_WITNESS_BLOCK, NODE_COMPACT_FILTERS};
+use witness::{WitnessBlock, WitnessTx};
+use util::{ThreadResponse, ipv4_to_ipv4addr, string_of_address, unix_time};
 
 pub const MAX_CNXS: usize = 50;
 pub const MAX_BLCKS: usize = 200;
+/// Misbehavior score at which a peer is disconnected and banned
+pub const BAN_SCORE: u32 = 100;
+/// Seconds after which an announced block that never arrived is left out
+/// of the block arrival metric
+const ANNOUNCEMENT_EXPIRY: u64 = 3600;
+/// Seconds to wait for a missing retained block before asking another peer
+const MISSING_BLOCK_TIMEOUT: u64 = 60;
 
 pub struct Bitcoind {
     new_addresses: Arc<Mutex<Vec<Address>>>,
-    active_connections: Arc<Mutex<HashMap<String, Sender<NetworkMessage>>>>,
+    active_connections: Arc<Mutex<HashMap<String, Sender<PeerMessage>>>>,
+    banned: Arc<Mutex<HashSet<String>>>,
+    peer_info: Arc<Mutex<HashMap<String, PeerInfo>>>,
+    misbehavior: HashMap<String, u32>,
     blockchain: Blockchain,
-    db_cnx: String,
+    header_store: HeaderStore,
+    block_store: BlockStore,
+    storage: Box<Storage + Send>,
     db_state: VecDeque<Sha256dHash>,
-    path_to_chain: String,
+    /// Retained blocks whose full data we lost and must get from peers,
+    /// with the peer last asked and when
+    missing_blocks: HashMap<Sha256dHash, Option<(String, u64)>>,
+    /// Peers that can send compact blocks with witness data
+    compact_peers: HashSet<String>,
+    /// Peers asked to push new blocks to us as compact blocks, longest
+    /// serving first
+    high_bandwidth: VecDeque<String>,
+    /// Compact blocks waiting on transactions from the peer that sent them,
+    /// with the peer and when they were asked
+    partial_blocks: HashMap<Sha256dHash, (String, u64, PartialBlock)>,
+    /// When blocks we're waiting for were first announced, for the block
+    /// arrival metric
+    block_announced: HashMap<Sha256dHash, Instant>,
+    tx_index: TxIndex,
+    filter_index: FilterIndex,
+    mempool: Mempool,
+    fee_estimator: FeeEstimator,
+    notifier: Option<Notifier>,
+    /// Best tip as of the last block events sent out
+    announced_tip: Sha256dHash,
+    watch_list: WatchList,
+    webhook: Option<Webhook>,
+    confirming: Confirming,
+    keystore: Option<Keystore>,
+    wallet: Wallet,
+    broadcaster: Broadcaster,
+    config: Config,
 }
 
 pub enum State {
@@ -42,35 +105,145 @@ pub enum State {
 }
 
 impl Bitcoind {
-    pub fn new(ip: &str, port: u16, path_to_chain: String, db_cnx: String)
-               -> Bitcoind {
+    pub fn new(config: Config) -> Result<Bitcoind, String> {
+        try!(log::init(config.log.clone()));
+        if config.verify_scripts && !cfg!(feature = "libbitcoinconsensus") {
+            return Err("verify_scripts needs a build with the libbitcoinconsensus feature"
+                       .to_string());
+        }
+        if config.notify_channel.is_some() && !storage::is_postgres(&config.db_cnx) {
+            return Err("notify_channel needs a Postgres db_cnx".to_string());
+        }
 
         let address = Address {
             services: 1,
-            address: ipv4_to_ipv4addr(ip).to_ipv6_mapped().segments(),
-            port: port,
+            address: ipv4_to_ipv4addr(&config.peer_ip).to_ipv6_mapped().segments(),
+            port: config.peer_port,
+        };
+
+        let db_state = load_db_state(&config.path_to_chain).iter()
+            .map(|&hash| hash.clone())
+            .collect::<VecDeque<Sha256dHash>>();
+        let (header_store, block_store, mut blockchain) =
+            try!(open_chain(&config, &db_state));
+        let mut schema = Schema::new(&config.table_prefix, config.dependent_tables.clone());
+        schema.archive = config.archive;
+        let mut storage = try!(storage::open(&config.db_cnx, schema));
+        try!(storage.prepare(config.mirror_mempool));
+        // the retention window is settled before anything is rebuilt from it
+        let (db_state, mut repairs) =
+            try!(reconcile_db_state(&mut *storage, db_state, &blockchain, &config.path_to_chain));
+
+        // put back the transactions of blocks still in the retention window
+        let mut tx_index = TxIndex::new();
+        for hash in db_state.iter() {
+            if let Some(block) = block_store.get(hash) {
+                let block = block.block();
+                tx_index.add_block(&block);
+                if let Err(e) = blockchain.put_txdata(block) {
+                    warn!("sync", block = hash.be_hex_string();
+                          "Could not restore txdata: {:?}", e);
+                }
+            }
+        }
+        let missing_blocks = db_state.iter()
+            .filter(|&&hash| blockchain.get_block(hash).map_or(true, |node| !node.has_txdata))
+            .map(|&hash| (hash, None))
+            .collect::<HashMap<Sha256dHash, Option<(String, u64)>>>();
+        if !missing_blocks.is_empty() {
+            repairs.push(format!("{} retained block(s) missing from the block store, \
+                                  fetching them from peers", missing_blocks.len()));
+        }
+        if repairs.is_empty() {
+            info!("db", "Consistency check passed");
+        }
+        for repair in repairs.iter() {
+            warn!("db", "Consistency check: {}", repair);
+        }
+        let mempool = Mempool::new(config.mempool_size);
+
+        // compute the filters of retained blocks saved before we had them
+        let mut filter_index = try!(FilterIndex::open(
+            &beside_path(&config.path_to_chain, "filters"),
+            &beside_path(&config.path_to_chain, "filter_headers.dat"),
+            &blockchain, config.network));
+        for hash in db_state.iter() {
+            if filter_index.has_filter(hash) {
+                continue;
+            }
+            if let Some(block) = block_store.get(hash) {
+                let source = RetainedOutputs {
+                    index: &tx_index,
+                    blockchain: &blockchain,
+                    mempool: &mempool,
+                    fallback: config.utxo_source.as_ref(),
+                };
+                try!(filter_index.add_block(&block, &source));
+            }
+        }
+        try!(filter_index.extend(&blockchain));
+        let announced_tip = blockchain.best_tip_hash();
+        let watch_list = match config.watch_list_path {
+            Some(ref path) => try!(WatchList::load(path)),
+            None => WatchList::new(),
+        };
+        let webhook = config.webhook_url.clone().map(Webhook::start);
+        let keystore = match config.keystore_path {
+            Some(ref path) => Some(try!(open_keystore(&config, path))),
+            None => None,
         };
+        let mut wallet = match config.wallet_path {
+            Some(ref path) => try!(Wallet::load(path)),
+            None => Wallet::new(),
+        };
+        if let Some(ref keystore) = keystore {
+            for descriptor in try!(keystore.descriptors()).iter() {
+                try!(wallet.add_descriptor(descriptor));
+            }
+        }
+        wallet.rescan(&blockchain, &block_store, &db_state);
         
-        Bitcoind {
+        Ok(Bitcoind {
             new_addresses: Arc::new(Mutex::new(vec![address])),
             active_connections: Arc::new(Mutex::new(HashMap::new())),
-            blockchain: load_blockchain(&path_to_chain),
-            db_cnx: db_cnx,
-            db_state: load_db_state(&path_to_chain).iter()
-                .map(|&hash| hash.clone())
-                .collect::<VecDeque<Sha256dHash>>(),
-            path_to_chain: path_to_chain,
-        }
+            banned: Arc::new(Mutex::new(HashSet::new())),
+            peer_info: Arc::new(Mutex::new(HashMap::new())),
+            misbehavior: HashMap::new(),
+            blockchain: blockchain,
+            header_store: header_store,
+            block_store: block_store,
+            storage: storage,
+            db_state: db_state,
+            missing_blocks: missing_blocks,
+            compact_peers: HashSet::new(),
+            high_bandwidth: VecDeque::new(),
+            partial_blocks: HashMap::new(),
+            block_announced: HashMap::new(),
+            tx_index: tx_index,
+            filter_index: filter_index,
+            mempool: mempool,
+            fee_estimator: FeeEstimator::load(
+                &beside_path(&config.path_to_chain, "fee_estimates.json")),
+            notifier: None,
+            announced_tip: announced_tip,
+            watch_list: watch_list,
+            webhook: webhook,
+            confirming: Confirming::new(config.webhook_confirmations),
+            keystore: keystore,
+            wallet: wallet,
+            broadcaster: try!(Broadcaster::load(
+                &beside_path(&config.path_to_chain, "broadcasts.json"))),
+            config: config,
+        })
     }
 
-    fn start_connection_manager(&mut self)
-                                -> Result<Receiver<ThreadResponse>, String> {
-        
-        let (sm_sender, sm_receiver): (Sender<ThreadResponse>,
-                                       Receiver<ThreadResponse>) = channel();
+    fn start_connection_manager(&mut self, sm_sender: Sender<ThreadResponse>)
+                                -> Result<(), String> {
         
         let new_addresses = self.new_addresses.clone();
         let active_connections = self.active_connections.clone();
+        let banned = self.banned.clone();
+        let peer_info = self.peer_info.clone();
         thread::spawn(move || {
             loop {
                 // access shared structures needed to initiate new connections
@@ -83,18 +256,22 @@ impl Bitcoind {
                         new_addrs_vec.push(new_addr);
                         break;
                     }
+                    metrics::set(ADDRESS_BOOK_SIZE, &[], new_addrs_vec.len() as f64);
                     // initiate connection to new_addr
                     let ip_address = string_of_address(&new_addr);
                     
-                    if act_cnxs_map.contains_key(&ip_address) == false {
+                    if act_cnxs_map.contains_key(&ip_address) == false &&
+                        banned.lock().unwrap().contains(&ip_address) == false {
                         let mut peerd = Peerd::new(ip_address.clone(), new_addr.port);
                         let (cnx_sender, cnx_receiver) = channel();
                         
                         if let Ok(peer_chan) = peerd.listen(cnx_receiver) {
                             act_cnxs_map.insert(ip_address, cnx_sender);
+                            metrics::set(PEER_CONNECTIONS, &[], act_cnxs_map.len() as f64);
                             
                             let cm_addresses_arc = new_addresses.clone();
                             let cm_active_cnxs_arc = active_connections.clone();
+                            let cm_peer_info_arc = peer_info.clone();
                             let sm_sender_clone = sm_sender.clone();
                             thread::spawn(move || {
                                 loop {
@@ -105,34 +282,50 @@ impl Bitcoind {
                                             while let Some((_, new_addr)) = addresses.pop() {
                                                 new_addresses.push(new_addr);
                                             }
+                                            metrics::set(ADDRESS_BOOK_SIZE, &[],
+                                                         new_addresses.len() as f64);
                                         },
-                                        Ok(ThreadResponse::Headers(ip, headers)) => {
-                                            sm_sender_clone.send(ThreadResponse::Headers(ip, headers));
-                                        },
-                                        Ok(ThreadResponse::Inv(ip, inventory)) => {
-                                            sm_sender_clone.send(ThreadResponse::Inv(ip, inventory));
-                                        },
-                                        Ok(ThreadResponse::Block(block)) => {
-                                            sm_sender_clone.send(ThreadResponse::Block(block));
-                                        },
-                                        Ok(ThreadResponse::Tx(transaction)) => {
-                                            sm_sender_clone.send(ThreadResponse::Tx(transaction));
+                                        Ok(ThreadResponse::Connected(ip, info)) => {
+                                            cm_peer_info_arc.lock().unwrap().insert(ip, info);
                                         },
                                         Ok(ThreadResponse::CloseThread((err, tx))) => {
-                                            tx.send(());
-                                            println!("{:?}", err);
-                                            
+                                            let _ = tx.send(());
                                             let mut active_cnxs =
                                                 cm_active_cnxs_arc.lock().unwrap();
                                             active_cnxs.remove(&peerd.config.peer_addr);
-                                            
-                                            println!("Active connections: {}",
-                                                     active_cnxs.len());
-                                            
+                                            cm_peer_info_arc.lock().unwrap()
+                                                .remove(&peerd.config.peer_addr);
+                                            metrics::set(PEER_CONNECTIONS, &[],
+                                                         active_cnxs.len() as f64);
+                                            metrics::remove(CHANNEL_BACKLOG, &[
+                                                ("channel", "peer"),
+                                                ("peer", &peerd.config.peer_addr[..])]);
+                                            info!("net", peer = peerd.config.peer_addr,
+                                                  connections = active_cnxs.len();
+                                                  "Disconnected: {:?}", err);
                                             break;
                                         },
-                                        Err(e) => {
-                                            println!("{:?}", e);
+                                        Ok(response) => {
+                                            // everything else is for the daemon
+                                            metrics::add(CHANNEL_BACKLOG,
+                                                         &[("channel", "daemon")], 1.0);
+                                            if sm_sender_clone.send(response).is_err() {
+                                                metrics::add(CHANNEL_BACKLOG,
+                                                             &[("channel", "daemon")], -1.0);
+                                                error!("net", peer = peerd.config.peer_addr;
+                                                       "Daemon is gone, dropping peer");
+                                                break;
+                                            }
+                                        },
+                                        Err(_) => {
+                                            // the peer's thread is gone, e.g. after a ban
+                                            debug!("net", peer = peerd.config.peer_addr;
+                                                   "Peer thread stopped");
+                                            cm_peer_info_arc.lock().unwrap()
+                                                .remove(&peerd.config.peer_addr);
+                                            metrics::remove(CHANNEL_BACKLOG, &[
+                                                ("channel", "peer"),
+                                                ("peer", &peerd.config.peer_addr[..])]);
                                             break;
                                         }
                                     }
@@ -143,12 +336,34 @@ impl Bitcoind {
                 }
             }
         });
-        Ok(sm_receiver)
+        Ok(())
     }
     
     pub fn listen(mut self) -> Result<(), String> {
         
-        let sm_receiver = try!(self.start_connection_manager());
+        self.update_height_metrics();
+        let (sm_sender, sm_receiver): (Sender<ThreadResponse>,
+                                       Receiver<ThreadResponse>) = channel();
+        if let Some(ref address) = self.config.rpc_address {
+            try!(rpc::start(address, self.config.rpc_auth.clone(), sm_sender.clone()));
+            if self.config.rpc_auth.is_none() && self.config.keystore_path.is_some() {
+                warn!("api", "Wallet methods are disabled over JSON-RPC until rpc_auth is set");
+            }
+        }
+        if let Some(ref address) = self.config.rest_address {
+            try!(rest::start(address, self.config.rpc_auth.clone(), sm_sender.clone()));
+            if self.config.rpc_auth.is_none() {
+                warn!("api", "Wallet and broadcast REST routes are disabled until rpc_auth is set");
+            }
+        }
+        if let Some(ref address) = self.config.metrics_address {
+            try!(metrics::start(address));
+        }
+        if self.config.websocket_address.is_some() || self.config.raw_publish_address.is_some() {
+            self.notifier = Some(try!(Notifier::start(self.config.websocket_address.as_ref(),
+                                                      self.config.raw_publish_address.as_ref())));
+        }
+        try!(self.start_connection_manager(sm_sender));
         
         let mut state_queue: VecDeque<State> = VecDeque::new();
         state_queue.push_back(State::Sync);
@@ -166,31 +381,47 @@ impl Bitcoind {
 
                     let mut done = false;
                     while !done {
-                        println!("Headers sync from {:x}",
-                                 self.blockchain.best_tip_hash());
+                        info!("sync", tip = self.blockchain.best_tip_hash().be_hex_string();
+                              "Syncing headers");
                         let locator_hashes = self.blockchain.locator_hashes();
                         let msg = NetworkMessage::GetHeaders(GetHeadersMessage::new(
                             locator_hashes, Default::default()));
                         {
                             let active_cnx_map = self.active_connections.lock().unwrap();
-                            for sender in active_cnx_map.values() {
-                                sender.send(msg.clone());
+                            for (ip, sender) in active_cnx_map.iter() {
+                                send_peer(ip, sender, PeerMessage::Network(msg.clone()));
                             }
                         }
                         
-                        let mut new_headers = false;
-                        while !new_headers {
-                            match sm_receiver.recv() {
+                        let mut new_headers: Vec<BlockHeader> = vec![];
+                        while new_headers.is_empty() {
+                            let received = sm_receiver.recv();
+                            if received.is_ok() {
+                                metrics::add(CHANNEL_BACKLOG, &[("channel", "daemon")], -1.0);
+                            }
+                            match received {
                                 Ok(ThreadResponse::Headers(ip, headers)) => {
                                     let mut no_headers = true;
+                                    let now = unix_time() as u32;
                                     for lone_header in headers.iter() {
                                         no_headers = false;
+                                        if let Err(e) = check_header(&self.blockchain,
+                                                                     self.config.network,
+                                                                     &lone_header.header,
+                                                                     now) {
+                                            // drop the rest of this peer's chain
+                                            warn!("sync", peer = ip;
+                                                  "Rejecting headers: {}", e);
+                                            self.penalize(&ip, BAN_SCORE);
+                                            break;
+                                        }
                                         match self.blockchain.add_header(
                                             lone_header.header) {
                                             Err(Error::DuplicateHash) => (),
-                                            Err(e) => println!("{:?}", e),
+                                            Err(e) => warn!("sync", peer = ip;
+                                                            "Could not add header: {:?}", e),
                                             Ok(()) => {
-                                                new_headers = true;
+                                                new_headers.push(lone_header.header);
                                             },
                                         }
                                     }
@@ -199,56 +430,146 @@ impl Bitcoind {
                                         break;
                                     }
                                 },
-                                Err(e) => println!("Error syncing: {:?}", e),
-                                _ => (),
+                                Ok(ThreadResponse::SendCmpct(ip, send_cmpct)) => {
+                                    self.note_compact_support(&ip, send_cmpct);
+                                },
+                                Ok(ThreadResponse::Misbehaved(ip, reason)) => {
+                                    warn!("net", peer = ip; "Misbehaved: {}", reason);
+                                    self.penalize(&ip, BAN_SCORE);
+                                },
+                                Ok(ThreadResponse::Rpc(call)) => self.answer_rpc(call),
+                                Ok(ThreadResponse::Rest(call)) => self.answer_rest(call),
+                                Err(_) => return Err("Peer connections are gone".to_string()),
+                                Ok(response) =>
+                                    trace!("sync", "Ignoring {} while syncing headers",
+                                           response.name()),
                             }
                         }
-                        if new_headers {
-                            try!(self.save_blockchain())
-                        }
+                        try!(self.header_store.append(&new_headers[..]));
+                        self.update_height_metrics();
                     }
-                    println!("SYNCED!");
+                    info!("sync", height = self.best_height(); "Headers synced");
+                    // synced headers aren't announced one by one
+                    self.announced_tip = self.blockchain.best_tip_hash();
+                    self.request_missing_blocks();
                     state_queue.push_back(State::Listen);
                 },
                 Some(State::Listen) => {
                     loop {
-                        match sm_receiver.recv() {
+                        self.rebroadcast();
+                        self.expire_partial_blocks();
+                        self.request_missing_blocks();
+                        self.sync_filters();
+                        let received =
+                            sm_receiver.recv_timeout(Duration::from_secs(REBROADCAST_INTERVAL));
+                        if received.is_ok() {
+                            metrics::add(CHANNEL_BACKLOG, &[("channel", "daemon")], -1.0);
+                        }
+                        match received {
                             Ok(ThreadResponse::Inv(ip, inventory)) => {
-                                let mut inv_to_get: Vec<Inventory> = vec![];
+                                let mut inv_to_get: Vec<InvVector> = vec![];
+                                let now = unix_time();
                                 for inv in inventory {
-                                    if inv.inv_type == InvType::Block {
-                                        inv_to_get.push(inv);
+                                    if inv.inv_type == MSG_TX {
+                                        self.broadcaster.announced_by(&ip, &inv.hash);
+                                    }
+                                    if inv.inv_type == MSG_BLOCK {
+                                        if !self.block_wanted(&inv.hash, now) {
+                                            continue;
+                                        }
+                                        self.block_announced.entry(inv.hash)
+                                            .or_insert(Instant::now());
+                                        let inv_type = if self.compact_peers.contains(&ip) {
+                                            MSG_CMPCT_BLOCK
+                                        } else {
+                                            MSG_WITNESS_BLOCK
+                                        };
+                                        inv_to_get.push(InvVector::new(inv_type, inv.hash));
+                                    } else if inv.inv_type == MSG_TX &&
+                                        self.mempool.want(&inv.hash, now) {
+                                        inv_to_get.push(InvVector::new(MSG_WITNESS_TX,
+                                                                       inv.hash));
                                     }
                                 }
                                 if !inv_to_get.is_empty() {
                                     let active_cnx_map = self.active_connections.lock().unwrap();
                                     if let Some(sender) = active_cnx_map.get(&ip) {
-                                        sender.send(NetworkMessage::GetData(inv_to_get));
+                                        send_peer(&ip, sender, PeerMessage::GetData(inv_to_get));
                                     }
                                 }
                             },
-                            Ok(ThreadResponse::Block(block)) => {
-                                let block_clone = block.clone();
-                                match self.blockchain.add_block(block) {
-                                    Ok(()) => {
-                                        try!(self.update_db(block_clone));
-                                    },
-                                    Err(e) => {
-                                        match e {
-                                            Error::PrevHashNotFound => 
-                                                println!("Prev hash not found when adding block!"),
-                                            Error::DuplicateHash => (),
-                                            
-                                            _ => println!("UNExpected error adding block!"),
-                                        }
+                            Ok(ThreadResponse::Block(ip, block)) => {
+                                try!(self.receive_block(&ip, block, false));
+                            },
+                            Ok(ThreadResponse::SendCmpct(ip, send_cmpct)) => {
+                                self.note_compact_support(&ip, send_cmpct);
+                            },
+                            Ok(ThreadResponse::CmpctBlock(ip, block)) => {
+                                try!(self.receive_compact_block(&ip, block));
+                            },
+                            Ok(ThreadResponse::BlockTxn(ip, txn)) => {
+                                try!(self.receive_block_txn(&ip, txn));
+                            },
+                            Ok(ThreadResponse::Tx(tx)) => {
+                                try!(self.accept_tx(tx));
+                            },
+                            Ok(ThreadResponse::GetData(ip, inventory)) => {
+                                self.serve_getdata(&ip, inventory);
+                            },
+                            Ok(ThreadResponse::Misbehaved(ip, reason)) => {
+                                warn!("net", peer = ip; "Misbehaved: {}", reason);
+                                self.penalize(&ip, BAN_SCORE);
+                            },
+                            Ok(ThreadResponse::GetCFilters(ip, request)) => {
+                                match self.filter_index.cfilters(&self.blockchain, &request) {
+                                    Ok(cfilters) => for cfilter in cfilters {
+                                        self.send_to(&ip, PeerMessage::CFilter(cfilter));
                                     },
+                                    Err(e) => debug!("sync", peer = ip;
+                                                     "Not serving filters: {}", e),
+                                }
+                            },
+                            Ok(ThreadResponse::GetCFHeaders(ip, request)) => {
+                                match self.filter_index.cfheaders(&self.blockchain, &request) {
+                                    Ok(cfheaders) =>
+                                        self.send_to(&ip, PeerMessage::CFHeaders(cfheaders)),
+                                    Err(e) => debug!("sync", peer = ip;
+                                                     "Not serving filter headers: {}", e),
+                                }
+                            },
+                            Ok(ThreadResponse::GetCFCheckpt(ip, request)) => {
+                                match self.filter_index.cfcheckpt(&self.blockchain, &request) {
+                                    Ok(cfcheckpt) =>
+                                        self.send_to(&ip, PeerMessage::CFCheckpt(cfcheckpt)),
+                                    Err(e) => debug!("sync", peer = ip;
+                                                     "Not serving filter checkpoints: {}", e),
                                 }
-                                println!("Block received");
                             },
+                            Ok(ThreadResponse::CFilter(ip, cfilter)) => {
+                                if let Err(e) = self.filter_index.add_filter(cfilter) {
+                                    warn!("sync", peer = ip; "Rejecting filter: {}", e);
+                                    self.penalize(&ip, BAN_SCORE);
+                                }
+                            },
+                            Ok(ThreadResponse::CFHeaders(ip, cfheaders)) => {
+                                if let Err(e) = self.filter_index.add_headers(&ip,
+                                                                              &self.blockchain,
+                                                                              cfheaders) {
+                                    warn!("sync", peer = ip; "Rejecting filter headers: {}", e);
+                                    self.penalize(&ip, BAN_SCORE);
+                                }
+                            },
+                            Err(RecvTimeoutError::Timeout) => (),
+                            Err(RecvTimeoutError::Disconnected) =>
+                                return Err("Peer connections are gone".to_string()),
+                            Ok(ThreadResponse::Rpc(call)) => self.answer_rpc(call),
+                            Ok(ThreadResponse::Rest(call)) => self.answer_rest(call),
                             Ok(ThreadResponse::Headers(ip, headers)) => {
-                                println!("More headers received");
+                                debug!("sync", peer = ip, count = headers.len();
+                                       "Ignoring headers outside of headers sync");
                             },
-                            _ => println!("Received some other message"),
+                            Ok(response) =>
+                                trace!("sync", "Ignoring {}", response.name()),
                         }
                     }
                 },
@@ -260,256 +581,720 @@ impl Bitcoind {
         Ok(())
     }
 
-    fn remove_old_blocks(&mut self, conn: &Connection) -> Result<(), String> {
-        fn remove_old_block(conn: &Connection, old_block_hash_string: &String)
-                            -> Result<(), String> {
-            
-            // remove comments
-            match conn.execute(
-                "DELETE FROM talk_comment WHERE block_hash_id = $1",
-                &[&old_block_hash_string]) {
-                Ok(n) => println!("Successfully removed comment data: {}", n),
-                Err(e) => return Err(format!("Removing comment data: {:?}", e)),
+    /// Ask peers for the retained blocks we lost, asking another peer for
+    /// those the last one didn't send in time
+    fn request_missing_blocks(&mut self) {
+        let now = unix_time();
+        let due = self.missing_blocks.iter()
+            .filter(|&(_, asked)| asked.as_ref()
+                    .map_or(true, |&(_, time)| time + MISSING_BLOCK_TIMEOUT <= now))
+            .map(|(hash, asked)| (*hash, asked.as_ref().map(|&(ref ip, _)| ip.clone())))
+            .collect::<Vec<(Sha256dHash, Option<String>)>>();
+        if due.is_empty() {
+            return;
+        }
+        let active_cnx_map = self.active_connections.lock().unwrap();
+        let mut requests: HashMap<String, Vec<InvVector>> = HashMap::new();
+        for (hash, staller) in due {
+            let peer = active_cnx_map.keys()
+                .find(|&ip| Some(ip) != staller.as_ref())
+                .or_else(|| active_cnx_map.keys().next());
+            if let Some(ip) = peer {
+                if let Some(ref staller) = staller {
+                    debug!("sync", peer = ip, staller = staller, block = hash.be_hex_string();
+                           "Missing block not sent in time, asking again");
+                }
+                requests.entry(ip.clone()).or_insert(vec![])
+                    .push(InvVector::new(MSG_WITNESS_BLOCK, hash));
+                self.missing_blocks.insert(hash, Some((ip.clone(), now)));
             }
-
-            // set 'prev_block_hash_id' to NULL for old block's successor
-            match conn.execute(
-                "UPDATE talk_block SET prev_block_hash_id = NULL WHERE block_hash IN \
-                 (SELECT block_hash FROM talk_block WHERE prev_block_hash_id = $1)",
-                &[&old_block_hash_string]) {
-                Ok(n) => println!("Successfully set 'prev_block_hash_id' to NULL \
-                                   for block successor in database"),
-                Err(e) => return Err(format!("Setting prev_block_hash_id: {:?}", e)),
+        }
+        for (ip, inv) in requests {
+            if let Some(sender) = active_cnx_map.get(&ip) {
+                info!("sync", peer = ip, count = inv.len(); "Asking for missing retained blocks");
+                send_peer(&ip, sender, PeerMessage::GetData(inv));
             }
+        }
+    }
 
-            // set news txins referencing doomed txouts to NULL
-            match conn.execute(
-                "UPDATE talk_txin SET output_id = NULL WHERE output_id IN (SELECT \
-                 output FROM talk_txout WHERE tx_id IN (SELECT tx_hash FROM \
-                 talk_transaction WHERE block_hash_id = $1))",
-                &[&old_block_hash_string]) {
-                Ok(n) => println!("Successfully nulled references to doomed txouts"),
-                Err(e) => return Err(format!("Nulling doomed references: {:?}", e)),
+    /// Put back the data of a retained block that was missing on startup.
+    /// The block is already in the database, so only local state changes.
+    fn restore_block(&mut self, block: WitnessBlock) -> Result<(), String> {
+        let block_hash = block.bitcoin_hash();
+        self.missing_blocks.remove(&block_hash);
+        if let Err(e) = self.blockchain.put_txdata(block.block()) {
+            // e.g. the block is no longer on any chain we know of
+            warn!("sync", block = block_hash.be_hex_string(); "Could not restore block: {:?}", e);
+            return Ok(());
+        }
+        self.tx_index.add_block(&block.block());
+        try!(self.block_store.put(&block));
+        info!("sync", block = block_hash.be_hex_string(), missing = self.missing_blocks.len();
+              "Restored block");
+        Ok(())
+    }
+
+    /// Validate a block from a peer and add it to the chain. A
+    /// `reconstructed` block was put together from a compact block, so if
+    /// it's invalid a short ID most likely matched the wrong mempool
+    /// transaction, and rather than blaming the peer the block is fetched
+    /// in full.
+    fn receive_block(&mut self, ip: &String, block: WitnessBlock, reconstructed: bool)
+                     -> Result<(), String> {
+        let block_hash = block.bitcoin_hash();
+        let height = self.blockchain
+            .get_block(block.header.prev_blockhash)
+            .map(|block_node_ref| block_node_ref.height + 1);
+        if let Err(e) = check_block(&block, self.config.network, height) {
+            if reconstructed {
+                info!("sync", peer = ip, block = block_hash.be_hex_string();
+                      "Rebuilt block is invalid ({}), fetching it in full", e);
+                self.send_to(ip, PeerMessage::GetData(
+                    vec![InvVector::new(MSG_WITNESS_BLOCK, block_hash)]));
+            } else {
+                warn!("sync", peer = ip, block = block_hash.be_hex_string();
+                      "Rejecting block: {}", e);
+                self.penalize(ip, BAN_SCORE);
             }
+            return Ok(());
+        }
+        if let Err(e) = check_header(&self.blockchain, self.config.network, &block.header,
+                                     unix_time() as u32) {
+            warn!("sync", peer = ip, block = block_hash.be_hex_string();
+                  "Rejecting block: {}", e);
+            self.penalize(ip, BAN_SCORE);
+            return Ok(());
+        }
+        self.partial_blocks.remove(&block_hash);
+        if self.missing_blocks.contains_key(&block_hash) {
+            return self.restore_block(block);
+        }
+        match self.blockchain.add_block(block.block()) {
+            Ok(()) => {
+                info!("sync", peer = ip, block = block_hash.be_hex_string(),
+                      height = height.unwrap_or(0), txs = block.txdata.len();
+                      "Block received");
+                if let Some(announced) = self.block_announced.remove(&block_hash) {
+                    metrics::observe(BLOCK_ARRIVAL, &[], metrics::seconds(announced.elapsed()));
+                }
+                try!(self.header_store.append(&[block.header]));
+                try!(self.block_store.put(&block));
+                try!(self.update_db(block));
+                self.update_height_metrics();
+                self.announce_tip();
+                if self.compact_peers.contains(ip) {
+                    self.promote_high_bandwidth(ip);
+                }
+            },
+            Err(Error::DuplicateHash) => (),
+            Err(Error::PrevHashNotFound) =>
+                debug!("sync", peer = ip, block = block_hash.be_hex_string();
+                       "Ignoring block with unknown parent"),
+            Err(e) => error!("sync", peer = ip, block = block_hash.be_hex_string();
+                             "Could not add block: {:?}", e),
+        }
+        Ok(())
+    }
 
-            // remove txout data
-            match conn.execute(
-                "DELETE FROM talk_txout WHERE tx_id IN (SELECT tx_hash FROM \
-                 talk_transaction WHERE block_hash_id = $1)",
-                &[&old_block_hash_string]) {
-                Ok(n) => println!("Successfully removed TxOut data: {}", n),
-                Err(e) => return Err(format!("Removing TxOut data: {:?}", e)),
+    /// Whether to ask for an announced block: we don't have it, and aren't
+    /// waiting on a peer for the rest of it
+    fn block_wanted(&self, hash: &Sha256dHash, now: u64) -> bool {
+        let have = self.blockchain.get_block(*hash)
+            .map_or(false, |block_node_ref| block_node_ref.has_txdata);
+        let pending = self.partial_blocks.get(hash)
+            .map_or(false, |entry| entry.1 + BLOCK_TXN_TIMEOUT > now);
+        !have && !pending
+    }
+
+    fn note_compact_support(&mut self, ip: &String, send_cmpct: SendCmpct) {
+        if send_cmpct.version == COMPACT_VERSION {
+            self.compact_peers.insert(ip.clone());
+        }
+    }
+
+    /// Rebuild a block from a compact block and the mempool, asking the
+    /// peer for the transactions we don't have
+    fn receive_compact_block(&mut self, ip: &String, compact: CompactBlock)
+                             -> Result<(), String> {
+        let block_hash = compact.header.bitcoin_hash();
+        let now = unix_time();
+        if !self.block_wanted(&block_hash, now) {
+            return Ok(());
+        }
+        self.block_announced.entry(block_hash).or_insert(Instant::now());
+        if let Err(e) = check_header(&self.blockchain, self.config.network,
+                                     &compact.header, now as u32) {
+            warn!("sync", peer = ip, block = block_hash.be_hex_string();
+                  "Rejecting compact block: {}", e);
+            self.penalize(ip, BAN_SCORE);
+            return Ok(());
+        }
+        let partial = match PartialBlock::new(&compact, &self.mempool) {
+            Ok(partial) => partial,
+            Err(e) => {
+                info!("sync", peer = ip, block = block_hash.be_hex_string();
+                      "Fetching block in full: {}", e);
+                self.send_to(ip, PeerMessage::GetData(
+                    vec![InvVector::new(MSG_WITNESS_BLOCK, block_hash)]));
+                return Ok(());
+            },
+        };
+        let missing = partial.missing();
+        debug!("sync", peer = ip, block = block_hash.be_hex_string(), missing = missing.len(),
+               txs = partial.len(); "Compact block received");
+        if missing.is_empty() {
+            return self.complete_block(ip, block_hash, partial, vec![]);
+        }
+        self.send_to(ip, PeerMessage::GetBlockTxn(BlockTxnRequest {
+            block_hash: block_hash,
+            indexes: missing,
+        }));
+        self.partial_blocks.insert(block_hash, (ip.clone(), now, partial));
+        Ok(())
+    }
+
+    /// Finish a compact block with the transactions the peer sent for it
+    fn receive_block_txn(&mut self, ip: &String, txn: BlockTxn) -> Result<(), String> {
+        let asked = self.partial_blocks.get(&txn.block_hash)
+            .map_or(false, |entry| entry.0 == *ip);
+        if !asked {
+            // unsolicited, or too late
+            return Ok(());
+        }
+        match self.partial_blocks.remove(&txn.block_hash) {
+            Some((_, _, partial)) => self.complete_block(ip, txn.block_hash, partial, txn.txs),
+            None => Ok(()),
+        }
+    }
+
+    fn complete_block(&mut self, ip: &String, block_hash: Sha256dHash, partial: PartialBlock,
+                      txs: Vec<WitnessTx>) -> Result<(), String> {
+        match partial.fill(txs) {
+            Ok(block) => self.receive_block(ip, block, true),
+            Err(e) => {
+                info!("sync", peer = ip, block = block_hash.be_hex_string();
+                      "Fetching block in full: {}", e);
+                self.send_to(ip, PeerMessage::GetData(
+                    vec![InvVector::new(MSG_WITNESS_BLOCK, block_hash)]));
+                Ok(())
+            },
+        }
+    }
+
+    /// Give up on peers that didn't send the rest of a compact block in
+    /// time, and fetch the block in full from another peer
+    fn expire_partial_blocks(&mut self) {
+        self.block_announced.retain(|_, announced| {
+            announced.elapsed() < Duration::from_secs(ANNOUNCEMENT_EXPIRY)
+        });
+        let now = unix_time();
+        let stale = self.partial_blocks.iter()
+            .filter(|&(_, entry)| entry.1 + BLOCK_TXN_TIMEOUT <= now)
+            .map(|(hash, entry)| (*hash, entry.0.clone()))
+            .collect::<Vec<(Sha256dHash, String)>>();
+        for (hash, staller) in stale {
+            self.partial_blocks.remove(&hash);
+            let active_cnx_map = self.active_connections.lock().unwrap();
+            if let Some((ip, sender)) = active_cnx_map.iter().find(|&(ip, _)| *ip != staller) {
+                info!("sync", peer = ip, staller = staller, block = hash.be_hex_string();
+                      "Compact block not completed in time, fetching it in full");
+                send_peer(ip, sender,
+                          PeerMessage::GetData(vec![InvVector::new(MSG_WITNESS_BLOCK, hash)]));
             }
+        }
+    }
 
-            // remove txin data
-            match conn.execute(
-                "DELETE FROM talk_txin WHERE tx_id IN (SELECT tx_hash FROM \
-                 talk_transaction WHERE block_hash_id = $1)",
-                &[&old_block_hash_string]) {
-                Ok(n) => println!("Successfully removed TxIn data: {}", n),
-                Err(e) => return Err(format!("Removing TxIn data: {:?}", e)),
+    /// Ask a peer that just gave us a new block to push the next ones as
+    /// compact blocks, releasing the longest serving of the others if that
+    /// makes too many, as BIP152 suggests
+    fn promote_high_bandwidth(&mut self, ip: &String) {
+        if self.high_bandwidth.contains(ip) {
+            return;
+        }
+        {
+            let active_cnx_map = self.active_connections.lock().unwrap();
+            self.high_bandwidth.retain(|peer| active_cnx_map.contains_key(peer));
+        }
+        if self.high_bandwidth.len() >= HIGH_BANDWIDTH_PEERS {
+            if let Some(released) = self.high_bandwidth.pop_front() {
+                self.send_to(&released, PeerMessage::SendCmpct(SendCmpct::new(false)));
             }
+        }
+        debug!("net", peer = ip; "Asking for compact blocks to be pushed");
+        self.send_to(ip, PeerMessage::SendCmpct(SendCmpct::new(true)));
+        self.high_bandwidth.push_back(ip.clone());
+    }
+
+    /// Ask filter-serving peers for the filter headers and filters we
+    /// couldn't compute ourselves
+    fn sync_filters(&mut self) {
+        let peers = self.peer_info.lock().unwrap().iter()
+            .filter(|&(_, info)| info.services & NODE_COMPACT_FILTERS != 0)
+            .map(|(ip, _)| ip.clone())
+            .collect::<Vec<String>>();
+        for (ip, msg) in self.filter_index.requests(&self.blockchain, &peers[..], unix_time()) {
+            self.send_to(&ip, msg);
+        }
+    }
+
+    fn send_to(&self, ip: &String, msg: PeerMessage) {
+        if let Some(sender) = self.active_connections.lock().unwrap().get(ip) {
+            send_peer(ip, sender, msg);
+        }
+    }
+
+    /// Run `f` against a read view of the daemon's state
+    fn with_node<F, T>(&mut self, f: F) -> T where F: FnOnce(&mut rpc::Node) -> T {
+        let peer_info = self.peer_info.clone();
+        let peers = peer_info.lock().unwrap();
+        let connection_count = self.active_connections.lock().unwrap().len();
+        let mut node = rpc::Node {
+            network: self.config.network,
+            blockchain: &self.blockchain,
+            block_store: &self.block_store,
+            tx_index: &self.tx_index,
+            filter_index: &self.filter_index,
+            mempool: &self.mempool,
+            utxo_source: self.config.utxo_source.as_ref(),
+            fee_estimator: &self.fee_estimator,
+            retained: &self.db_state,
+            peers: &*peers,
+            misbehavior: &self.misbehavior,
+            connection_count: connection_count,
+            storage: &mut *self.storage,
+            watch_list: &mut self.watch_list,
+            keystore: &mut self.keystore,
+            wallet: &mut self.wallet,
+            broadcaster: &mut self.broadcaster,
+        };
+        f(&mut node)
+    }
+
+    fn answer_rpc(&mut self, call: RpcCall) {
+        let result = self.with_node(|node| rpc::dispatch(node, &call.method, &call.params[..]));
+        if call.reply.send(result).is_err() {
+            debug!("api", method = call.method; "RPC caller hung up");
+        }
+        self.rebroadcast();
+    }
 
-            // remove transactions
-            match conn.execute(
-                "DELETE FROM talk_transaction WHERE block_hash_id = $1",
-                &[&old_block_hash_string]) {
-                Ok(n) => println!("Successfully removed Transaction data: {}", n),
-                Err(e) => return Err(format!("Removing Transaction data: {:?}", e)),
+    /// Put our new transactions in the mempool, and announce those that are
+    /// due
+    fn rebroadcast(&mut self) {
+        for tx in self.broadcaster.take_fresh() {
+            if let Err(e) = self.accept_tx(tx) {
+                warn!("sync", "Could not add our transaction to the mempool: {}", e);
             }
+        }
+        let active_cnx_map = self.active_connections.lock().unwrap();
+        let peers = active_cnx_map.keys().cloned().collect::<Vec<String>>();
+        let mut inventories: HashMap<String, Vec<InvVector>> = HashMap::new();
+        for (txid, chosen) in self.broadcaster.due(&peers[..], unix_time()) {
+            debug!("net", txid = txid.be_hex_string(), peers = chosen.len();
+                   "Announcing transaction");
+            for peer in chosen {
+                inventories.entry(peer).or_insert(vec![]).push(InvVector::new(MSG_TX, txid));
+            }
+        }
+        for (ip, inventory) in inventories {
+            if let Some(sender) = active_cnx_map.get(&ip) {
+                send_peer(&ip, sender, PeerMessage::Inv(inventory));
+            }
+        }
+    }
 
-            // delete the old block
-            match conn.execute(
-                "DELETE FROM talk_block WHERE block_hash = $1",
-                &[&old_block_hash_string]) {
-                Ok(n) => println!("Successfully removed old block: {}", n),
-                Err(e) => return Err(format!("Removing old block: {:?}", e)),
+    /// Send a peer the transactions of ours it asked for, and the retained
+    /// blocks it asked for filtered, which its thread matches against the
+    /// peer's bloom filter
+    fn serve_getdata(&mut self, ip: &String, inventory: Vec<InvVector>) {
+        let mut not_found = vec![];
+        let active_cnx_map = self.active_connections.lock().unwrap();
+        let sender = match active_cnx_map.get(ip) {
+            Some(sender) => sender,
+            None => return,
+        };
+        for inv in inventory {
+            if inv.inv_type == MSG_FILTERED_BLOCK {
+                match self.block_store.get(&inv.hash) {
+                    Some(block) => send_peer(ip, sender, PeerMessage::FilteredBlock(block)),
+                    None => not_found.push(inv),
+                }
+                continue;
             }
+            let tx = match self.broadcaster.requested(ip, &inv.hash) {
+                Some(tx) if inv.inv_type == MSG_WITNESS_TX => tx.clone(),
+                Some(tx) if inv.inv_type == MSG_TX => WitnessTx::from_tx(tx.tx.clone()),
+                _ => {
+                    not_found.push(inv);
+                    continue;
+                },
+            };
+            send_peer(ip, sender, PeerMessage::Tx(tx));
+        }
+        if !not_found.is_empty() {
+            send_peer(ip, sender, PeerMessage::NotFound(not_found));
+        }
+    }
+
+    fn answer_rest(&mut self, call: RestCall) {
+        let result = self.with_node(|node| rest::dispatch(node, &call.path[..], call.page));
+        if call.reply.send(result).is_err() {
+            debug!("api", path = call.path; "REST client hung up");
+        }
+    }
 
-            Ok(())
+    /// Add to a peer's misbehavior score, disconnecting and banning it once
+    /// the score reaches BAN_SCORE
+    fn penalize(&mut self, ip: &String, score: u32) {
+        let total = {
+            let entry = self.misbehavior.entry(ip.clone()).or_insert(0);
+            *entry += score;
+            *entry
+        };
+        debug!("net", peer = ip, score = total; "Misbehavior score raised");
+        if total >= BAN_SCORE {
+            warn!("net", peer = ip; "Banning peer");
+            self.banned.lock().unwrap().insert(ip.clone());
+            // dropping the peer's sender tears down its thread
+            let mut active_cnx_map = self.active_connections.lock().unwrap();
+            active_cnx_map.remove(ip);
+            metrics::set(PEER_CONNECTIONS, &[], active_cnx_map.len() as f64);
+            self.misbehavior.remove(ip);
         }
+    }
 
+    fn remove_old_blocks(&mut self) -> Result<(), String> {
         // remove old blocks
         while self.db_state.len() > MAX_BLCKS {
             match self.db_state.pop_front() {
                 Some(old_block_hash) => {
-                    let old_block_hash_string = old_block_hash.be_hex_string();
-                    try!(remove_old_block(&conn, &old_block_hash_string));
+                    if let Some(ref dir) = self.config.archive_dir {
+                        let rows = try!(storage::export_block(&mut *self.storage, dir,
+                                                              &old_block_hash));
+                        debug!("db", block = old_block_hash.be_hex_string(), rows = rows;
+                               "Exported pruned block");
+                    }
+                    try!(self.storage.remove_block(&old_block_hash));
+                    if let Some(block_node_ref) = self.blockchain.get_block(old_block_hash) {
+                        self.tx_index.remove_block(&block_node_ref.block);
+                        let txdata = &block_node_ref.block.txdata;
+                        count_rows(DB_ROWS_REMOVED, txdata.len(),
+                                   txdata.iter().map(|tx| tx.input.len()).sum(),
+                                   txdata.iter().map(|tx| tx.output.len()).sum());
+                    }
                     match self.blockchain.remove_txdata(old_block_hash) {
                         Ok(()) => (),
                         Err(e) =>
                             return Err(format!("Removing from blockchain: {:?}", e)),
                     }
+                    try!(self.block_store.remove(&old_block_hash));
+                    try!(self.filter_index.remove_block(&old_block_hash));
                 }
                 None => panic!("Failed to pop from block queue"),
             }
         }
+        let first_retained_height = self.db_state.front()
+            .and_then(|hash| self.blockchain.get_block(*hash))
+            .map_or(0, |block_node_ref| block_node_ref.height);
+        self.wallet.prune(first_retained_height);
 
         Ok(())
     }
 
-    fn insert_block(&mut self, conn: &Connection, block: &Block,
-                    block_hash: &Sha256dHash) -> Result<(), String> {
-        
-        fn insert_header(conn: &Connection, block: &Block,
-                         block_hash_string: &String, block_height: u32,
-                         prev_block_hash_option: &Option<String>)
-                         -> Result<(), String> {
-            
-            let mut total_val_placeholder: Option<i64> = None;
-            
-            match conn.execute(
-                "INSERT INTO talk_block (block_hash, prev_block_hash_id, \
-                 block_size, block_height, merkleroot, time, median_time, bits, \
-                 nonce, total_value) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
-                &[block_hash_string,
-                  prev_block_hash_option,
-                  &(1111 as i32),
-                  &(block_height as i32),
-                  &block.header.merkle_root.be_hex_string(),
-                  &(block.header.time as i32),
-                  &(1111 as i32),
-                  &(block.header.bits as i64),
-                  &(block.header.nonce as i64),
-                  &total_val_placeholder]) {
-                Ok(_) => Ok(()),
-                Err(e) => Err(format!("Error writing header to database: {:?}", e)),
-            }
+    fn insert_block(&mut self, block: &WitnessBlock, block_hash: &Sha256dHash,
+                    statuses: &Vec<Vec<InputStatus>>) -> Result<(), String> {
+        let block_height: u32 =
+            if let Some(block_node_ref) =
+            self.blockchain.get_block(block_hash.clone()) {
+                block_node_ref.height
+            } else { 1111 };
+
+        let prev_block_hash_option: Option<Sha256dHash> = self.db_state.iter()
+            .find(|&&x| x == block.header.prev_blockhash)
+            .map(|&pbh| pbh);
+
+        let record = BlockRecord::new(block, block_height, prev_block_hash_option, statuses);
+        let started = Instant::now();
+        try!(self.storage.insert_block(&record));
+        metrics::observe(DB_BLOCK_WRITE, &[], metrics::seconds(started.elapsed()));
+        count_rows(DB_ROWS_INSERTED, record.txs.len(),
+                   record.txs.iter().map(|tx| tx.inputs.len()).sum(),
+                   record.txs.iter().map(|tx| tx.outputs.len()).sum());
+        Ok(())
+    }
+
+    fn update_height_metrics(&self) {
+        metrics::set(HEADERS_HEIGHT, &[], self.best_height() as f64);
+        if let Some(block_node_ref) = self.db_state.back()
+            .and_then(|hash| self.blockchain.get_block(*hash)) {
+            metrics::set(BLOCKS_HEIGHT, &[], block_node_ref.height as f64);
         }
+    }
 
-        fn insert_txs(conn: &Connection, block: &Block, block_hash_string: &String)
-                      -> Result<i64, String> {
+    /// Add a relayed transaction to the mempool
+    fn accept_tx(&mut self, tx: WitnessTx) -> Result<(), String> {
+        if self.mempool.contains(&tx.txid()) {
+            return Ok(());
+        }
+        let fee = self.tx_fee(&tx);
+        let txid = tx.txid();
 
-            fn insert_inoutputs(conn: &Connection, tx: Transaction,
-                                tx_hash_string: &String) -> Result<i64, String> {
-                
-                // insert inputs
-                for input in tx.input {
-                    let output_id: String = input.prev_hash.be_hex_string() +
-                        &(input.prev_index.to_string());
-                    
-                    match conn.execute(
-                        "INSERT INTO talk_txin (tx_id, output_id) VALUES ($1, \
-                         (SELECT (CASE WHEN EXISTS (SELECT 1 FROM talk_txout WHERE \
-                         output=$2) THEN $2 ELSE NULL END)))",
-                        &[&tx_hash_string,
-                          &output_id]) {
-                        Ok(_) => (),
-                        Err(e) =>  return Err(format!("Error writing input to database: {:?}", e)),
-                    }
+        if let Some(evicted) = self.mempool.insert(tx.clone(), fee, unix_time()) {
+            self.check_watched(&tx, None);
+            self.notify(Event::MempoolTx(tx, fee));
+            for evicted_txid in evicted.iter() {
+                self.fee_estimator.dropped(evicted_txid);
+            }
+            if let Some(fee_rate) = self.mempool.get(&txid).and_then(|entry| entry.fee_rate()) {
+                let height = self.best_height();
+                self.fee_estimator.track(&txid, fee_rate, height);
+            }
+            if self.config.mirror_mempool {
+                try!(self.storage.remove_mempool_txs(&evicted[..]));
+                if let Some(entry) = self.mempool.get(&txid) {
+                    try!(self.storage.insert_mempool_tx(entry));
                 }
+            }
+        }
+        Ok(())
+    }
 
-                let mut total_amount: i64 = 0;
-
-                // insert outputs
-                for (i, output) in tx.output.iter().enumerate() {
-                    let output_id: String = tx_hash_string.clone() +
-                        &(i.to_string());
+    /// Announce the blocks that left and joined the best chain since the
+    /// last announced tip
+    fn announce_tip(&mut self) {
+        let best_tip = self.blockchain.best_tip_hash();
+        if best_tip == self.announced_tip {
+            return;
+        }
 
-                    if let Some(addr) = addr_from_output(&output) {
-                    
-                        // insert address
-                        match conn.execute(
-                            "INSERT INTO talk_address (address) (SELECT $1::VARCHAR \
-                             WHERE NOT EXISTS (SELECT $1::VARCHAR FROM talk_address \
-                             WHERE address=$1))",
-                            &[&addr]) {
-                            Ok(_) => (),
-                            Err(e) => return Err(format!("Writing address: {:?}", e)),
-                        }
+        // walk back from the old tip to where it forked from the best chain
+        let mut disconnected: Vec<(Sha256dHash, u32)> = vec![];
+        let mut fork_height = None;
+        for block_node_ref in self.blockchain.rev_iter(self.announced_tip) {
+            let hash = block_node_ref.block.bitcoin_hash();
+            if best_chain_hash_at(&self.blockchain, block_node_ref.height) == Some(hash) {
+                fork_height = Some(block_node_ref.height);
+                break;
+            }
+            disconnected.push((hash, block_node_ref.height));
+        }
+        let fork_height = match fork_height {
+            Some(height) => height,
+            None => {
+                warn!("sync", block = self.announced_tip.be_hex_string();
+                      "Announced tip is gone, not announcing blocks");
+                self.announced_tip = best_tip;
+                return;
+            },
+        };
+        let mut connected = self.blockchain.rev_iter(best_tip)
+            .take_while(|block_node_ref| block_node_ref.height > fork_height)
+            .map(|block_node_ref| (block_node_ref.block.bitcoin_hash(), block_node_ref.height))
+            .collect::<Vec<(Sha256dHash, u32)>>();
+        connected.reverse();
 
-                        // actually insert output (after inserting address)
-                        match conn.execute(
-                            "INSERT INTO talk_txout (tx_id, value, output_index, \
-                             address_id, output) VALUES ($1, $2, $3, $4, $5)",
-                            &[&tx_hash_string,
-                              &(output.value as i64),
-                              &(i as i32),
-                              &addr,
-                              &output_id]) {
-                            Ok(_) => total_amount += output.value as i64,
-                            Err(e) => return Err(format!("Writing txout: {:?}", e)),
-                        }
+        let reorg = !disconnected.is_empty();
+        if reorg {
+            metrics::inc(REORGS, &[]);
+            metrics::add(BLOCKS_DISCONNECTED, &[], disconnected.len() as f64);
+        }
+        for (hash, height) in disconnected {
+            info!("sync", block = hash.be_hex_string(), height = height; "Block disconnected");
+            self.broadcaster.unsettle(&hash);
+            self.notify(Event::BlockDisconnected(hash, height));
+        }
+        if reorg {
+            self.wallet.rescan(&self.blockchain, &self.block_store, &self.db_state);
+            if let Err(e) = self.filter_index.disconnect(fork_height) {
+                error!("db", height = fork_height; "Could not disconnect filters: {}", e);
+            }
+        }
+        for (hash, height) in connected {
+            match self.block_store.get(&hash) {
+                Some(block) => {
+                    for wtx in block.txdata.iter() {
+                        self.check_watched(wtx, Some((hash, height)));
                     }
-                }
-                Ok(total_amount)
+                    self.broadcaster.settle(&block);
+                    if !reorg {
+                        self.wallet.add_block(&block, hash, height);
+                    }
+                    self.notify(Event::BlockConnected(block, height));
+                },
+                None => warn!("sync", block = hash.be_hex_string(), height = height;
+                              "No data to announce block"),
             }
+        }
+        if let Err(e) = self.filter_index.extend(&self.blockchain) {
+            error!("db", "Could not extend filter headers: {}", e);
+        }
+        self.announced_tip = best_tip;
+        self.update_confirmations();
+    }
 
-            let mut block_total: i64 = 0;
+    /// Alert the webhook to watched scripts a transaction pays or spends,
+    /// `block` being the hash and height of the block confirming it
+    fn check_watched(&mut self, wtx: &WitnessTx, block: Option<(Sha256dHash, u32)>) {
+        if self.webhook.is_none() || self.watch_list.is_empty() {
+            return;
+        }
+        let alerts = {
+            let source = RetainedOutputs {
+                index: &self.tx_index,
+                blockchain: &self.blockchain,
+                mempool: &self.mempool,
+                fallback: self.config.utxo_source.as_ref(),
+            };
+            self.watch_list.scan_tx(wtx, block, &source)
+        };
+        for alert in alerts {
+            let confirmations = if alert.block.is_some() { 1 } else { 0 };
+            self.send_alert(&alert, confirmations);
+            self.confirming.track(alert, confirmations);
+        }
+    }
 
-            for tx in block.txdata.clone() {
-                let tx_hash_string = tx.bitcoin_hash().be_hex_string();
+    /// Send new confirmation counts for confirmed alerts, until they reach
+    /// webhook_confirmations or their block leaves the best chain
+    fn update_confirmations(&mut self) {
+        let changed = {
+            let blockchain = &self.blockchain;
+            self.confirming.update(|hash| confirmations(blockchain, hash))
+        };
+        for (alert, count) in changed {
+            self.send_alert(&alert, count);
+        }
+    }
 
-                let mut tx_total_placeholder: Option<i64> = None;
+    fn send_alert(&self, alert: &Alert, confirmations: i64) {
+        if let Some(ref webhook) = self.webhook {
+            webhook.send(json::encode(&alert.to_json(confirmations)).unwrap());
+        }
+    }
 
-                // insert transaction
-                match conn.execute(
-                    "INSERT INTO talk_transaction (tx_hash, block_hash_id, \
-                     total_value) VALUES ($1, $2, $3)",
-                    &[&tx_hash_string, block_hash_string, &tx_total_placeholder]) {
-                    Ok(_) => {
-                        let tx_total =
-                            try!(insert_inoutputs(&conn, tx, &tx_hash_string));
-                        
-                        block_total += tx_total;
-                        
-                        // update transaction total after inserting outputs
-                        match conn.execute(
-                            "UPDATE talk_transaction SET total_value = $1 WHERE \
-                             tx_hash = $2",
-                            &[&tx_total, &tx_hash_string]) {
-                            Ok(_) => (),
-                            Err(e) => return Err(format!("Updating tx total: {:?}", e)),
-                        }
-                    },
-                    Err(e) => println!("Duplicate transaction {:?}: {:?}",
-                                       tx_hash_string, e),
-                }
+    /// Send an event to the configured subscribers and database channel
+    fn notify(&mut self, event: Event) {
+        if let Some(ref channel) = self.config.notify_channel {
+            let payload = json::encode(&event.to_json()).unwrap();
+            if let Err(e) = self.storage.notify(channel, &payload) {
+                error!("api", channel = channel; "Could not notify: {}", e);
             }
-            Ok(block_total)
         }
+        if let Some(ref notifier) = self.notifier {
+            notifier.publish(event);
+        }
+    }
 
-        fn update_block_with_total(conn: &Connection, block_hash_string:
-                                   &String, total: &i64) -> Result<(), String> {
-            match conn.execute(
-                "UPDATE talk_block SET total_value = $1 WHERE block_hash = $2",
-                &[&total, block_hash_string]) {
-                Ok(_) => Ok(()),
-                Err(e) => return Err(format!("Updating block total: {:?}", e)),
+    /// The fee paid by a transaction, if all of its prevouts can be found in
+    /// the mempool or the storage
+    fn tx_fee(&mut self, tx: &WitnessTx) -> Option<u64> {
+        let mut input_total: u64 = 0;
+        for input in tx.tx.input.iter() {
+            input_total += match self.mempool.output_value(&input.prev_hash,
+                                                           input.prev_index) {
+                Some(value) => value,
+                None => match self.storage.output_value(&input.prev_hash,
+                                                        input.prev_index) {
+                    Ok(Some(value)) => value,
+                    _ => return None,
+                },
+            };
+        }
+        let output_total: u64 = tx.tx.output.iter().map(|output| output.value).sum();
+        input_total.checked_sub(output_total)
+    }
+
+    /// Forget mempool transactions confirmed by (or conflicting with) a new
+    /// block, as well as those that have expired, and feed their fates to
+    /// the fee estimator
+    fn update_mempool(&mut self, block: &WitnessBlock)
+                      -> Result<(), String> {
+        let height = match self.blockchain.get_block(block.bitcoin_hash()) {
+            Some(block_node_ref) => block_node_ref.height,
+            None => self.best_height(),
+        };
+        let confirmed_txids = block.txdata.iter()
+            .map(|wtx| wtx.txid())
+            .collect::<HashSet<Sha256dHash>>();
+
+        let mut removed: Vec<Sha256dHash> = vec![];
+        for entry in self.mempool.remove_confirmed(block) {
+            if confirmed_txids.contains(&entry.txid) {
+                self.fee_estimator.confirmed(&entry.txid, height);
+            } else {
+                self.fee_estimator.dropped(&entry.txid);
             }
+            removed.push(entry.txid);
+        }
+        for txid in self.mempool.expire(unix_time()) {
+            self.fee_estimator.dropped(&txid);
+            removed.push(txid);
         }
+        debug!("sync", height = height, size = self.mempool.len(), removed = removed.len();
+               "Mempool updated");
 
-        let block_hash_string: String = block_hash.be_hex_string();
-        println!("block hash: {}", block_hash_string.clone());
+        if self.config.mirror_mempool {
+            try!(self.storage.remove_mempool_txs(&removed[..]));
+        }
 
-        let block_height: u32 =
-            if let Some(block_node_ref) =
-            self.blockchain.get_block(block_hash.clone()) {
-                block_node_ref.height
-            } else { 1111 };
+        self.fee_estimator.process_block(height);
+        try!(self.fee_estimator.save(
+            &beside_path(&self.config.path_to_chain, "fee_estimates.json")));
+        let estimates = self.fee_estimator.estimates();
+        self.storage.save_fee_estimates(&estimates[..], height)
+    }
 
-        let prev_block_hash_option: Option<String> =
-            if let Some(pbh) = self.db_state.iter()
-            .find(|&&x| x == block.header.prev_blockhash) {
-                Some(pbh.be_hex_string())
-            } else { None };
-        
-        try!(insert_header(conn, block, &block_hash_string, block_height,
-                           &prev_block_hash_option));
-        
-        let block_total: i64 = try!(insert_txs(conn, block, &block_hash_string));
-        try!(update_block_with_total(conn, &block_hash_string, &block_total));
-        
-        Ok(())
+    fn best_height(&self) -> u32 {
+        match self.blockchain.get_block(self.blockchain.best_tip_hash()) {
+            Some(block_node_ref) => block_node_ref.height,
+            None => 0,
+        }
+    }
+
+    /// Verify input scripts if configured to, or mark them all unverified
+    fn verify_scripts(&self, block: &WitnessBlock) -> Vec<Vec<InputStatus>> {
+        if !self.config.verify_scripts {
+            return block.txdata.iter()
+                .map(|wtx| vec![InputStatus::Unverified; wtx.tx.input.len()])
+                .collect();
+        }
+
+        let source = RetainedOutputs {
+            index: &self.tx_index,
+            blockchain: &self.blockchain,
+            mempool: &self.mempool,
+            fallback: self.config.utxo_source.as_ref(),
+        };
+        let statuses = verify_block(block, &source);
+
+        let count = |wanted: InputStatus| statuses.iter()
+            .map(|tx_statuses| tx_statuses.iter().filter(|&&status| status == wanted).count())
+            .sum::<usize>();
+        debug!("sync", block = block.bitcoin_hash().be_hex_string(),
+               verified = count(InputStatus::Verified),
+               unverified = count(InputStatus::Unverified), failed = count(InputStatus::Failed);
+               "Scripts verified");
+        statuses
     }
 
-    fn update_db(&mut self, block: Block) -> Result<(), String> {
+    fn update_db(&mut self, block: WitnessBlock) -> Result<(), String> {
                 
         let block_hash: Sha256dHash = block.header.bitcoin_hash();
         self.db_state.push_back(block_hash);
-        let conn = Connection::connect(self.db_cnx.as_str(), SslMode::None).unwrap();
-        try!(self.remove_old_blocks(&conn));
-        try!(self.insert_block(&conn, &block, &block_hash));
+        try!(self.remove_old_blocks());
+        self.tx_index.add_block(&block.block());
+        let statuses = self.verify_scripts(&block);
+        {
+            let source = RetainedOutputs {
+                index: &self.tx_index,
+                blockchain: &self.blockchain,
+                mempool: &self.mempool,
+                fallback: self.config.utxo_source.as_ref(),
+            };
+            try!(self.filter_index.add_block(&block, &source));
+        }
+        try!(self.insert_block(&block, &block_hash, &statuses));
+        try!(self.update_mempool(&block));
         //self.save_scriptsigs(&block);
         try!(self.save_db_state());
         
@@ -517,8 +1302,8 @@ impl Bitcoind {
     }
     
     fn save_scriptsigs(&self, block: &Block) {
-        let path_to_scriptsigs = beside_path(&self.path_to_chain, "scriptsigs.txt");
-        let path_to_scriptpubkeys = beside_path(&self.path_to_chain, "scriptpubkeys.txt");
+        let path_to_scriptsigs = beside_path(&self.config.path_to_chain, "scriptsigs.txt");
+        let path_to_scriptpubkeys = beside_path(&self.config.path_to_chain, "scriptpubkeys.txt");
         
         match OpenOptions::new()
             .write(true)
@@ -537,7 +1322,7 @@ impl Bitcoind {
                         }
                     }
                 },
-                Err(e) => println!("Could not open inputs for saving"),
+                Err(e) => error!("db", "Could not open inputs for saving: {:?}", e),
             }
 
         match OpenOptions::new()
@@ -557,48 +1342,45 @@ impl Bitcoind {
                         }
                     }
                 },
-                Err(e) => println!("Could not open outputs for saving"),
+                Err(e) => error!("db", "Could not open outputs for saving: {:?}", e),
             }
     }
 
-    fn save_blockchain(&mut self) -> Result<(), String> {
-        match OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(&self.path_to_chain) {
-                Ok(file) => {
-                    let mut encoder = RawEncoder::new(
-                        BufWriter::new(file));
-                    match self.blockchain.consensus_encode(&mut encoder) {
-                        Ok(()) => println!("Done saving blockchain."),
-                        Err(e) => return Err(format!("Failed to write to blockchian: {:?}", e)),
-                    }
-                },
-                Err(e) => return Err(format!("Could not open blockchain for saving: {:?}", e)),
-            }
-        Ok(())
+    fn save_db_state(&mut self) -> Result<(), String> {
+        write_db_state(&self.config.path_to_chain, &self.db_state)
     }
+}
 
-    fn save_db_state(&mut self) -> Result<(), String> {
-        let queue_as_vec: Vec<Sha256dHash> = self.db_state.iter()
-            .map(|&hash| hash.clone()).collect();
+/// Open the configured keystore, creating it if it doesn't exist yet
+fn open_keystore(config: &Config, path: &str) -> Result<Keystore, String> {
+    let password = match config.keystore_password {
+        Some(ref password) if !password.is_empty() => password,
+        _ => return Err("keystore_path needs a keystore_password".to_string()),
+    };
+    if Path::new(path).exists() {
+        return Keystore::open(path, password);
+    }
+    let keystore = try!(Keystore::create(path, password,
+                                         config.keystore_mnemonic.as_ref().map(|m| m.as_str()),
+                                         "", config.network));
+    info!("wallet", path = path; "Created keystore");
+    Ok(keystore)
+}
 
-        let path = beside_path(&self.path_to_chain, "db_state.dat");
-        
-        match OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(&path) {
-                Ok(file) => {
-                    let mut encoder = RawEncoder::new(BufWriter::new(file));
-                    match queue_as_vec.consensus_encode(&mut encoder) {
-                        Ok(()) => println!("Done saving db state."),
-                        Err(e) => return Err(format!("Failed to write db state: {:?}", e)),
-                    }
-                }
-                Err(e) => return Err(format!("Failed to open db_state.dat: {:?}", e)),
-            }
-        Ok(())
+/// Send a message to a peer's thread, which may have stopped since
+fn send_peer(ip: &str, sender: &Sender<PeerMessage>, msg: PeerMessage) {
+    metrics::add(CHANNEL_BACKLOG, &[("channel", "peer"), ("peer", ip)], 1.0);
+    if sender.send(msg).is_err() {
+        metrics::add(CHANNEL_BACKLOG, &[("channel", "peer"), ("peer", ip)], -1.0);
+        debug!("net", peer = ip; "Peer is gone, dropping message");
+    }
+}
+
+/// Count a block's rows of the block, transaction, input and output tables
+fn count_rows(metric: &'static str, txs: usize, inputs: usize, outputs: usize) {
+    for &(table, rows) in [("block", 1), ("transaction", txs), ("txin", inputs),
+                           ("txout", outputs)].iter() {
+        metrics::add(metric, &[("table", table)], rows as f64);
     }
 }
 
@@ -619,15 +1401,14 @@ fn load_db_state(path_to_chain: &String) -> Vec<Sha256dHash> {
     let path = beside_path(path_to_chain, "db_state.dat");
     match File::open(&path) {
         Err(e) => {
-            println!("Could not open db_state file at {}", path);
+            warn!("db", path = path; "Could not open db_state file: {:?}", e);
             Vec::with_capacity(MAX_BLCKS + 1)
         },
         Ok(file) => {
             let mut decoder = RawDecoder::new(BufReader::new(file));
             match ConsensusDecodable::consensus_decode(&mut decoder) {
                 Err(e) => {
-                    println!("Could not load db_state at {}: {:?}",
-                             path, e);
+                    error!("db", path = path; "Could not load db_state: {:?}", e);
                     Vec::with_capacity(MAX_BLCKS + 1)
                 },
                 Ok(queue_as_vec) => queue_as_vec,
@@ -637,24 +1418,91 @@ fn load_db_state(path_to_chain: &String) -> Vec<Sha256dHash> {
     
 }
 
-fn load_blockchain(path_to_chain: &String) -> Blockchain {
-    match File::open(path_to_chain) {
-        Err(e) => {
-            println!("Count not open blockchain file at {}", path_to_chain);
-            Blockchain::new(Network::Bitcoin)
-        },
-        Ok(file) => {
-            let mut decoder = RawDecoder::new(BufReader::new(file));
-            match ConsensusDecodable::consensus_decode(&mut decoder) {
-                Err(e) => {
-                    println!("Could not load blockchain at {}: {:?}",
-                             path_to_chain, e);
-                    panic!("Could not load blockchain!");
-                    Blockchain::new(Network::Bitcoin)
-                },
-                Ok(blckchn) => blckchn,
-            }
+fn write_db_state(path_to_chain: &String, db_state: &VecDeque<Sha256dHash>)
+                  -> Result<(), String> {
+    let queue_as_vec: Vec<Sha256dHash> = db_state.iter()
+        .map(|&hash| hash.clone()).collect();
+
+    let path = beside_path(path_to_chain, "db_state.dat");
+
+    match serialize(&queue_as_vec) {
+        Ok(data) => {
+            try!(write_atomically(&path, &data[..]));
+            trace!("db", blocks = queue_as_vec.len(); "Saved db state");
         },
+        Err(e) => return Err(format!("Failed to encode db state: {:?}", e)),
+    }
+    Ok(())
+}
+
+/// Reconcile db_state with the stored blocks and the header store, which
+/// can disagree after a crash or a lost file. The storage is taken as the
+/// authority on which blocks are retained; headers that went missing are
+/// fetched from peers once we're connected. Returns the reconciled
+/// db_state and the repairs made.
+fn reconcile_db_state(storage: &mut (Storage + Send), db_state: VecDeque<Sha256dHash>,
+                      blockchain: &Blockchain, path_to_chain: &String)
+                      -> Result<(VecDeque<Sha256dHash>, Vec<String>), String> {
+    let db_blocks = try!(storage.block_hashes());
+
+    let mut db_state = db_state;
+    let mut repairs: Vec<String> = vec![];
+
+    let in_db = db_blocks.iter().cloned().collect::<HashSet<Sha256dHash>>();
+    let in_state = db_state.iter().cloned().collect::<HashSet<Sha256dHash>>();
+    let only_in_db = in_db.difference(&in_state).count();
+    let only_in_state = in_state.difference(&in_db).count();
+    if only_in_db > 0 || only_in_state > 0 {
+        db_state = db_blocks.iter().cloned().collect();
+        try!(write_db_state(path_to_chain, &db_state));
+        repairs.push(format!("rebuilt db_state from the database: added {} block(s), \
+                              dropped {} block(s) not in the database",
+                             only_in_db, only_in_state));
     }
+
+    let missing_headers = db_state.iter()
+        .filter(|&&hash| blockchain.get_block(hash).is_none())
+        .count();
+    if missing_headers > 0 {
+        repairs.push(format!("{} retained block(s) missing from the header store, \
+                              resyncing headers from peers", missing_headers));
+    }
+    Ok((db_state, repairs))
 }
 
+/// Open the header and block stores, moving the chain over from the legacy
+/// blockchain file the first time
+fn open_chain(config: &Config, db_state: &VecDeque<Sha256dHash>)
+              -> Result<(HeaderStore, BlockStore, Blockchain), String> {
+    let headers_path = beside_path(&config.path_to_chain, "headers.dat");
+    let first_run = !Path::new(&headers_path).exists();
+
+    let (mut header_store, mut blockchain) =
+        try!(HeaderStore::open(&headers_path, config.network));
+    let block_store = try!(BlockStore::open(&beside_path(&config.path_to_chain, "blocks")));
+
+    if first_run {
+        if let Some(legacy) = load_legacy_blockchain(&config.path_to_chain) {
+            let headers = best_chain_headers(&legacy, config.network);
+            for header in headers.iter() {
+                match blockchain.add_header(*header) {
+                    Ok(()) | Err(Error::DuplicateHash) => (),
+                    Err(e) => return Err(format!("Moving legacy header: {:?}", e)),
+                }
+            }
+            try!(header_store.append(&headers[..]));
+
+            for hash in db_state.iter() {
+                if let Some(block_node_ref) = legacy.get_block(*hash) {
+                    if block_node_ref.has_txdata {
+                        try!(block_store.put(
+                            &WitnessBlock::from_block(block_node_ref.block.clone())));
+                    }
+                }
+            }
+            info!("db", headers = headers.len(), path = config.path_to_chain;
+                  "Moved legacy headers");
+        }
+    }
+    Ok((header_store, block_store, blockchain))
+}
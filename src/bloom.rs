@@ -0,0 +1,286 @@
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::network::encodable::{ConsensusEncodable, ConsensusDecodable};
+use bitcoin::network::serialize::{SimpleEncoder, SimpleDecoder, BitcoinHash};
+use bitcoin::util::hash::Sha256dHash;
+
+use merkle::{MerkleBlock, PartialMerkleTree};
+use wire::{u32_from_le, u32_to_le};
+use witness::{WitnessBlock, WitnessTx};
+
+/// Largest filter a `filterload` may carry, in bytes
+pub const MAX_BLOOM_FILTER_SIZE: usize = 36000;
+/// Most hash functions a `filterload` may ask for
+pub const MAX_HASH_FUNCS: u32 = 50;
+/// Largest item a `filteradd` may carry, that of a script push
+pub const MAX_FILTERADD_SIZE: usize = 520;
+
+/// How matching an output script changes the filter, from the low bits of
+/// its flags: by adding the output, or by adding the output only for
+/// pay-to-pubkey and bare multisig scripts. Otherwise it doesn't.
+const BLOOM_UPDATE_ALL: u8 = 1;
+const BLOOM_UPDATE_P2PUBKEY_ONLY: u8 = 2;
+const BLOOM_UPDATE_MASK: u8 = 3;
+
+/// A peer's BIP37 bloom filter, as sent in `filterload`
+#[derive(Clone, Debug)]
+pub struct BloomFilter {
+    pub data: Vec<u8>,
+    pub hash_funcs: u32,
+    pub tweak: u32,
+    pub flags: u8,
+}
+
+impl BloomFilter {
+    /// Whether the filter is within BIP37's limits
+    pub fn check(&self) -> Result<(), String> {
+        if self.data.len() > MAX_BLOOM_FILTER_SIZE {
+            return Err(format!("Filter of {} bytes is too large", self.data.len()));
+        }
+        if self.hash_funcs > MAX_HASH_FUNCS {
+            return Err(format!("Filter uses too many hash functions: {}", self.hash_funcs));
+        }
+        Ok(())
+    }
+
+    fn bit_index(&self, n: u32, item: &[u8]) -> usize {
+        let seed = n.wrapping_mul(0xfba4c795).wrapping_add(self.tweak);
+        murmur3(seed, item) as usize % (self.data.len() * 8)
+    }
+
+    pub fn insert(&mut self, item: &[u8]) {
+        if self.data.is_empty() {
+            return;
+        }
+        for n in 0..self.hash_funcs {
+            let index = self.bit_index(n, item);
+            self.data[index >> 3] |= 1 << (index & 7);
+        }
+    }
+
+    pub fn contains(&self, item: &[u8]) -> bool {
+        // an empty filter matches everything, as in Core
+        if self.data.is_empty() {
+            return true;
+        }
+        (0..self.hash_funcs).all(|n| {
+            let index = self.bit_index(n, item);
+            self.data[index >> 3] & 1 << (index & 7) != 0
+        })
+    }
+
+    /// Whether a transaction matches the filter: by txid, by a data push
+    /// in an output script or input script, or by an outpoint it spends.
+    /// Outputs matched are added to the filter as the flags say, so that
+    /// transactions spending them match too.
+    pub fn matches_tx(&mut self, tx: &Transaction) -> bool {
+        let txid = tx.bitcoin_hash();
+        let mut found = self.contains(&txid[..]);
+        for (index, output) in tx.output.iter().enumerate() {
+            let script = output.script_pubkey.clone().into_vec();
+            if !pushes(&script[..]).iter().any(|data| !data.is_empty() && self.contains(data)) {
+                continue;
+            }
+            found = true;
+            match self.flags & BLOOM_UPDATE_MASK {
+                BLOOM_UPDATE_ALL => self.insert(&outpoint(&txid, index as u32)[..]),
+                BLOOM_UPDATE_P2PUBKEY_ONLY if is_pubkey_or_multisig(&script[..]) =>
+                    self.insert(&outpoint(&txid, index as u32)[..]),
+                _ => (),
+            }
+        }
+        if found {
+            return true;
+        }
+        tx.input.iter().any(|input| {
+            let script = input.script_sig.clone().into_vec();
+            self.contains(&outpoint(&input.prev_hash, input.prev_index)[..]) ||
+                pushes(&script[..]).iter().any(|data| !data.is_empty() && self.contains(data))
+        })
+    }
+
+    /// Match a block's transactions against the filter, for a
+    /// `merkleblock` and the matched transactions that follow it
+    pub fn filter_block(&mut self, block: &WitnessBlock) -> (MerkleBlock, Vec<WitnessTx>) {
+        let mut txids = vec![];
+        let mut matches = vec![];
+        let mut matched = vec![];
+        for wtx in block.txdata.iter() {
+            let is_match = self.matches_tx(&wtx.tx);
+            txids.push(wtx.txid());
+            matches.push(is_match);
+            if is_match {
+                matched.push(WitnessTx::from_tx(wtx.tx.clone()));
+            }
+        }
+        let merkle_block = MerkleBlock {
+            header: block.header,
+            tree: PartialMerkleTree::new(&txids[..], &matches[..]),
+        };
+        (merkle_block, matched)
+    }
+}
+
+/// An outpoint as the filter holds it: txid, then output index
+fn outpoint(txid: &Sha256dHash, index: u32) -> Vec<u8> {
+    let mut data = txid[..].to_vec();
+    data.extend_from_slice(&u32_to_le(index)[..]);
+    data
+}
+
+/// The data pushed by a script, up to the first malformed push
+fn pushes(script: &[u8]) -> Vec<&[u8]> {
+    let mut pushes = vec![];
+    let mut i = 0;
+    while i < script.len() {
+        let opcode = script[i];
+        i += 1;
+        let (len_size, len) = match opcode {
+            0x00...0x4b => (0, opcode as usize),
+            // OP_PUSHDATA1, OP_PUSHDATA2 and OP_PUSHDATA4
+            0x4c if i < script.len() => (1, script[i] as usize),
+            0x4d if i + 2 <= script.len() =>
+                (2, script[i] as usize | (script[i + 1] as usize) << 8),
+            0x4e if i + 4 <= script.len() => (4, u32_from_le(&script[i..i + 4]) as usize),
+            0x4c...0x4e => break,
+            _ => continue,
+        };
+        i += len_size;
+        if len > script.len() - i {
+            break;
+        }
+        pushes.push(&script[i..i + len]);
+        i += len;
+    }
+    pushes
+}
+
+/// Pay-to-pubkey, or bare multisig
+fn is_pubkey_or_multisig(script: &[u8]) -> bool {
+    let len = script.len();
+    // <pubkey> OP_CHECKSIG, with a compressed or uncompressed key
+    if (len == 35 && script[0] == 33 || len == 67 && script[0] == 65) && script[len - 1] == 0xac {
+        return true;
+    }
+    // OP_m <pubkeys> OP_n OP_CHECKMULTISIG
+    let is_small_int = |opcode: u8| opcode >= 0x51 && opcode <= 0x60;
+    len >= 3 && script[len - 1] == 0xae && is_small_int(script[0]) && is_small_int(script[len - 2])
+}
+
+/// 32 bit MurmurHash3, which BIP37 filters hash items with
+fn murmur3(seed: u32, data: &[u8]) -> u32 {
+    let (c1, c2) = (0xcc9e2d51u32, 0x1b873593u32);
+    let mix = |k: u32| k.wrapping_mul(c1).rotate_left(15).wrapping_mul(c2);
+    let mut h = seed;
+    let blocks = data.len() / 4;
+    for i in 0..blocks {
+        h ^= mix(u32_from_le(&data[i * 4..i * 4 + 4]));
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe6546b64);
+    }
+    let tail = &data[blocks * 4..];
+    if !tail.is_empty() {
+        let k = tail.iter().enumerate()
+            .fold(0, |k, (i, &byte)| k | (byte as u32) << (8 * i));
+        h ^= mix(k);
+    }
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85ebca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2ae35);
+    h ^ h >> 16
+}
+
+impl<S: SimpleEncoder> ConsensusEncodable<S> for BloomFilter {
+    fn consensus_encode(&self, s: &mut S) -> Result<(), S::Error> {
+        try!(self.data.consensus_encode(s));
+        try!(self.hash_funcs.consensus_encode(s));
+        try!(self.tweak.consensus_encode(s));
+        self.flags.consensus_encode(s)
+    }
+}
+
+impl<D: SimpleDecoder> ConsensusDecodable<D> for BloomFilter {
+    fn consensus_decode(d: &mut D) -> Result<BloomFilter, D::Error> {
+        Ok(BloomFilter {
+            data: try!(ConsensusDecodable::consensus_decode(d)),
+            hash_funcs: try!(ConsensusDecodable::consensus_decode(d)),
+            tweak: try!(ConsensusDecodable::consensus_decode(d)),
+            flags: try!(ConsensusDecodable::consensus_decode(d)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::hex::{FromHex, ToHex};
+
+    use bitcoin::network::serialize::{deserialize, serialize};
+
+    use super::{murmur3, BloomFilter, BLOOM_UPDATE_ALL};
+
+    /// Bitcoin Core's MurmurHash3 vectors as (expected hash, seed, data)
+    const MURMUR3_VECTORS: [(u32, u32, &'static str); 14] = [
+        (0x00000000, 0x00000000, ""),
+        (0x6a396f08, 0xfba4c795, ""),
+        (0x81f16f39, 0xffffffff, ""),
+        (0x514e28b7, 0x00000000, "00"),
+        (0xea3f0b17, 0xfba4c795, "00"),
+        (0xfd6cf10d, 0x00000000, "ff"),
+        (0x16c6b7ab, 0x00000000, "0011"),
+        (0x8eb51c3d, 0x00000000, "001122"),
+        (0xb4471bf8, 0x00000000, "00112233"),
+        (0xe2301fa8, 0x00000000, "0011223344"),
+        (0xfc2e4a15, 0x00000000, "001122334455"),
+        (0xb074502c, 0x00000000, "00112233445566"),
+        (0x8034d2a0, 0x00000000, "0011223344556677"),
+        (0xb4698def, 0x00000000, "001122334455667788"),
+    ];
+
+    #[test]
+    fn murmur3_vectors() {
+        for &(expected, seed, data) in MURMUR3_VECTORS.iter() {
+            assert_eq!(murmur3(seed, &data.from_hex().unwrap()), expected, "{} {:x}", data, seed);
+        }
+    }
+
+    /// Fills the filter Bitcoin Core sizes for 3 elements at a 0.01 false
+    /// positive rate and checks it serializes to `expected`
+    fn check_insert_serialize(tweak: u32, expected: &str) {
+        let mut filter = BloomFilter {
+            data: vec![0; 3],
+            hash_funcs: 5,
+            tweak: tweak,
+            flags: BLOOM_UPDATE_ALL,
+        };
+        assert!(filter.check().is_ok());
+
+        filter.insert(&"99108ad8ed9bb6274d3980bab5a85c048f0950c8".from_hex().unwrap());
+        assert!(filter.contains(&"99108ad8ed9bb6274d3980bab5a85c048f0950c8".from_hex().unwrap()));
+        // One bit different in the first byte
+        assert!(!filter.contains(&"19108ad8ed9bb6274d3980bab5a85c048f0950c8".from_hex().unwrap()));
+
+        filter.insert(&"b5a2c786d9ef4658287ced5914b37a1b4aa32eee".from_hex().unwrap());
+        assert!(filter.contains(&"b5a2c786d9ef4658287ced5914b37a1b4aa32eee".from_hex().unwrap()));
+        filter.insert(&"b9300670b4c5366e95b2699e8b18bc75e5f729c5".from_hex().unwrap());
+        assert!(filter.contains(&"b9300670b4c5366e95b2699e8b18bc75e5f729c5".from_hex().unwrap()));
+
+        let data = serialize(&filter).unwrap();
+        assert_eq!(data.to_hex(), expected);
+
+        let decoded: BloomFilter = deserialize(&data).unwrap();
+        assert_eq!(decoded.data, filter.data);
+        assert_eq!(decoded.hash_funcs, filter.hash_funcs);
+        assert_eq!(decoded.tweak, filter.tweak);
+        assert_eq!(decoded.flags, filter.flags);
+    }
+
+    #[test]
+    fn bloom_create_insert_serialize() {
+        check_insert_serialize(0, "03614e9b050000000000000001");
+    }
+
+    #[test]
+    fn bloom_create_insert_serialize_with_tweak() {
+        check_insert_serialize(2147483649, "03ce4299050000000100008001");
+    }
+}
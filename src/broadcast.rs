@@ -0,0 +1,301 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+
+use rand::{self, OsRng};
+use rustc_serialize::hex::{FromHex, ToHex};
+use rustc_serialize::json::{self, Json};
+
+use bitcoin::network::serialize::{BitcoinHash, deserialize, serialize};
+use bitcoin::util::hash::Sha256dHash;
+
+use rpc::object;
+use store::write_atomically;
+use witness::{WitnessBlock, WitnessTx};
+
+/// Seconds between announcements of a transaction that hasn't confirmed
+pub const REBROADCAST_INTERVAL: u64 = 15 * 60;
+/// Transactions still unconfirmed after this many seconds are given up on,
+/// as peers will have expired them from their mempools
+const MAX_AGE: u64 = 14 * 24 * 60 * 60;
+/// Number of peers a transaction is first announced to. Later
+/// announcements go to every peer.
+pub const FIRST_ANNOUNCEMENT_PEERS: usize = 8;
+
+enum Outcome {
+    /// Mined in the block with this hash
+    Confirmed(Sha256dHash),
+    /// One of its inputs was spent by another transaction in the block
+    /// with this hash
+    Conflicted(Sha256dHash),
+}
+
+struct Pending {
+    tx: WitnessTx,
+    created: u64,
+    /// Unix time of the last announcement, or 0 if it's yet to be announced
+    announced: u64,
+    announcements: u32,
+    announced_to: HashSet<String>,
+    /// Peers that asked for the transaction with getdata
+    requested_by: HashSet<String>,
+    /// Peers that announced the transaction to us after we announced it.
+    /// Peers don't announce transactions back to where they got them, so
+    /// this means it spread past the peers we told.
+    relayed_by: HashSet<String>,
+    outcome: Option<Outcome>,
+}
+
+impl Pending {
+    fn new(tx: WitnessTx, created: u64) -> Pending {
+        Pending {
+            tx: tx,
+            created: created,
+            announced: 0,
+            announcements: 0,
+            announced_to: HashSet::new(),
+            requested_by: HashSet::new(),
+            relayed_by: HashSet::new(),
+            outcome: None,
+        }
+    }
+}
+
+/// Transactions we created or were given, announced to peers until they
+/// confirm, along with what peers did with them. Settled transactions are
+/// remembered until they're as old as unconfirmed ones get.
+pub struct Broadcaster {
+    pending: HashMap<Sha256dHash, Pending>,
+    /// Created since the daemon last took them into its mempool
+    fresh: Vec<WitnessTx>,
+    path: String,
+}
+
+impl Broadcaster {
+    /// Load the unconfirmed transactions saved at `path`. A file that can't
+    /// be read is an error rather than an empty list, since the next save
+    /// would overwrite it.
+    pub fn load(path: &str) -> Result<Broadcaster, String> {
+        let mut broadcaster = Broadcaster {
+            pending: HashMap::new(),
+            fresh: vec![],
+            path: path.to_string(),
+        };
+        let mut contents = String::new();
+        match File::open(path).map(|mut file| file.read_to_string(&mut contents)) {
+            Ok(Ok(_)) => (),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(broadcaster),
+            Ok(Err(e)) | Err(e) => return Err(format!("Reading {}: {:?}", path, e)),
+        }
+        let entries = match Json::from_str(&contents) {
+            Ok(Json::Array(entries)) => entries,
+            Ok(_) => return Err(format!("Parsing {}: expected an array", path)),
+            Err(e) => return Err(format!("Parsing {}: {:?}", path, e)),
+        };
+        for entry in entries.iter() {
+            let tx = entry.find("hex")
+                .and_then(|hex| hex.as_string())
+                .and_then(|hex| hex.from_hex().ok())
+                .and_then(|data| deserialize::<WitnessTx>(&data).ok());
+            let created = entry.find("created").and_then(|created| created.as_u64());
+            match (tx, created) {
+                (Some(tx), Some(created)) => {
+                    // our mempool doesn't survive restarts either
+                    broadcaster.fresh.push(tx.clone());
+                    broadcaster.pending.insert(tx.txid(), Pending::new(tx, created));
+                },
+                _ => return Err(format!("Parsing {}: malformed transaction {}", path, entry)),
+            }
+        }
+        Ok(broadcaster)
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let entries = self.pending.values()
+            .filter(|pending| pending.outcome.is_none())
+            .map(|pending| object(vec![
+                ("hex", Json::String(serialize(&pending.tx).unwrap().to_hex())),
+                ("created", Json::U64(pending.created)),
+            ]))
+            .collect::<Vec<Json>>();
+        write_atomically(&self.path, json::encode(&Json::Array(entries)).unwrap().as_bytes())
+    }
+
+    /// Queue a transaction for announcement
+    pub fn submit(&mut self, tx: WitnessTx, now: u64) -> Result<(), String> {
+        self.fresh.push(tx.clone());
+        self.pending.insert(tx.txid(), Pending::new(tx, now));
+        self.save()
+    }
+
+    pub fn contains(&self, txid: &Sha256dHash) -> bool {
+        self.pending.contains_key(txid)
+    }
+
+    /// Outpoints spent by transactions that haven't settled, including
+    /// those the daemon is yet to take into its mempool
+    pub fn spent_outpoints(&self) -> HashSet<(Sha256dHash, u32)> {
+        self.pending.values()
+            .filter(|pending| pending.outcome.is_none())
+            .flat_map(|pending| pending.tx.tx.input.iter()
+                      .map(|input| (input.prev_hash, input.prev_index)))
+            .collect()
+    }
+
+    /// Transactions submitted since the last call
+    pub fn take_fresh(&mut self) -> Vec<WitnessTx> {
+        self.fresh.drain(..).collect()
+    }
+
+    /// Which of `peers` to announce each transaction that's due to, which
+    /// are then considered announced. Transactions too old to bother with
+    /// are dropped.
+    pub fn due(&mut self, peers: &[String], now: u64) -> Vec<(Sha256dHash, Vec<String>)> {
+        let before = self.pending.len();
+        self.pending.retain(|_, pending| pending.created + MAX_AGE > now);
+        if self.pending.len() < before {
+            if let Err(e) = self.save() {
+                error!("db", "Could not save broadcasts: {}", e);
+            }
+        }
+        if peers.is_empty() {
+            return vec![];
+        }
+        let mut due = vec![];
+        for (txid, pending) in self.pending.iter_mut() {
+            if pending.outcome.is_some() || pending.announced + REBROADCAST_INTERVAL > now {
+                continue;
+            }
+            let chosen = if pending.announcements == 0 {
+                match OsRng::new() {
+                    Ok(mut rng) => rand::sample(&mut rng, peers.iter().cloned(),
+                                                FIRST_ANNOUNCEMENT_PEERS),
+                    Err(_) => peers.iter().take(FIRST_ANNOUNCEMENT_PEERS).cloned().collect(),
+                }
+            } else {
+                peers.to_vec()
+            };
+            pending.announced = now;
+            pending.announcements += 1;
+            pending.announced_to.extend(chosen.iter().cloned());
+            due.push((*txid, chosen));
+        }
+        due
+    }
+
+    /// The transaction a peer asked for, if it's one of ours
+    pub fn requested(&mut self, peer: &str, txid: &Sha256dHash) -> Option<&WitnessTx> {
+        match self.pending.get_mut(txid) {
+            Some(pending) => {
+                pending.requested_by.insert(peer.to_string());
+                Some(&pending.tx)
+            },
+            None => None,
+        }
+    }
+
+    /// Note a peer announcing a transaction to us
+    pub fn announced_by(&mut self, peer: &str, txid: &Sha256dHash) {
+        if let Some(pending) = self.pending.get_mut(txid) {
+            if pending.announcements > 0 && !pending.relayed_by.contains(peer) {
+                debug!("net", peer = peer, txid = txid.be_hex_string();
+                       "Transaction relayed back");
+                pending.relayed_by.insert(peer.to_string());
+            }
+        }
+    }
+
+    /// Stop announcing transactions a block confirmed or conflicted with
+    pub fn settle(&mut self, block: &WitnessBlock) {
+        let mut spent: HashSet<(Sha256dHash, u32)> = HashSet::new();
+        let mut confirmed: HashSet<Sha256dHash> = HashSet::new();
+        for wtx in block.txdata.iter() {
+            confirmed.insert(wtx.txid());
+            for input in wtx.tx.input.iter() {
+                spent.insert((input.prev_hash, input.prev_index));
+            }
+        }
+        let block_hash = block.bitcoin_hash();
+        let mut settled = false;
+        for (txid, pending) in self.pending.iter_mut() {
+            if pending.outcome.is_some() {
+                continue;
+            }
+            if confirmed.contains(txid) {
+                info!("net", txid = txid.be_hex_string(), block = block_hash.be_hex_string();
+                      "Transaction confirmed");
+                pending.outcome = Some(Outcome::Confirmed(block_hash));
+            } else if pending.tx.tx.input.iter()
+                .any(|input| spent.contains(&(input.prev_hash, input.prev_index))) {
+                warn!("net", txid = txid.be_hex_string(), block = block_hash.be_hex_string();
+                      "Transaction conflicts with block, dropping it");
+                pending.outcome = Some(Outcome::Conflicted(block_hash));
+            } else {
+                continue;
+            }
+            settled = true;
+        }
+        if settled {
+            if let Err(e) = self.save() {
+                error!("db", "Could not save broadcasts: {}", e);
+            }
+        }
+    }
+
+    /// Announce again the transactions a block confirmed or conflicted
+    /// with, now that a reorg has disconnected it
+    pub fn unsettle(&mut self, block_hash: &Sha256dHash) {
+        let mut unsettled = false;
+        for (txid, pending) in self.pending.iter_mut() {
+            let settled_by = match pending.outcome {
+                Some(Outcome::Confirmed(hash)) | Some(Outcome::Conflicted(hash)) => hash,
+                None => continue,
+            };
+            if settled_by == *block_hash {
+                info!("net", txid = txid.be_hex_string(), block = block_hash.be_hex_string();
+                      "Transaction's block was disconnected, announcing it again");
+                pending.outcome = None;
+                pending.announced = 0;
+                unsettled = true;
+            }
+        }
+        if unsettled {
+            if let Err(e) = self.save() {
+                error!("db", "Could not save broadcasts: {}", e);
+            }
+        }
+    }
+
+    /// What became of a transaction and how peers took it
+    pub fn status_json(&self, txid: &Sha256dHash) -> Option<Json> {
+        let pending = match self.pending.get(txid) {
+            Some(pending) => pending,
+            None => return None,
+        };
+        let peers = |set: &HashSet<String>| {
+            let mut peers = set.iter().cloned().collect::<Vec<String>>();
+            peers.sort();
+            Json::Array(peers.into_iter().map(Json::String).collect())
+        };
+        let (status, block) = match pending.outcome {
+            None => ("pending", Json::Null),
+            Some(Outcome::Confirmed(hash)) => ("confirmed", Json::String(hash.be_hex_string())),
+            Some(Outcome::Conflicted(hash)) => ("conflicted", Json::String(hash.be_hex_string())),
+        };
+        Some(object(vec![
+            ("txid", Json::String(txid.be_hex_string())),
+            ("status", Json::String(status.to_string())),
+            ("block_hash", block),
+            ("created", Json::U64(pending.created)),
+            ("last_announced", if pending.announced > 0 {
+                Json::U64(pending.announced)
+            } else {
+                Json::Null
+            }),
+            ("announcements", Json::U64(pending.announcements as u64)),
+            ("announced_to", peers(&pending.announced_to)),
+            ("requested_by", peers(&pending.requested_by)),
+            ("relayed_by", peers(&pending.relayed_by)),
+        ]))
+    }
+}
@@ -0,0 +1,423 @@
+use std::collections::{HashMap, HashSet};
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::network::encodable::{ConsensusEncodable, ConsensusDecodable, VarInt};
+use bitcoin::network::serialize::{SimpleEncoder, SimpleDecoder, serialize};
+use bitcoin::util::hash::Sha256dHash;
+
+use address::sha256;
+use mempool::Mempool;
+use util::{siphash, u64_from_le};
+use validation::MAX_BLOCK_TXS;
+use witness::{WitnessBlock, WitnessTx};
+
+/// The compact block version that commits to wtxids, the only one useful to
+/// a node that wants witness data
+pub const COMPACT_VERSION: u64 = 2;
+/// Number of peers asked to push new blocks to us as compact blocks
+pub const HIGH_BANDWIDTH_PEERS: usize = 3;
+/// Seconds to wait on a peer for the transactions of a compact block before
+/// fetching the block in full from another peer
+pub const BLOCK_TXN_TIMEOUT: u64 = 10;
+
+/// The `sendcmpct` message: whether the sender wants new blocks pushed to
+/// it as `cmpctblock`, and the compact block version it speaks
+#[derive(Clone, Copy, Debug)]
+pub struct SendCmpct {
+    pub announce: bool,
+    pub version: u64,
+}
+
+impl SendCmpct {
+    pub fn new(announce: bool) -> SendCmpct {
+        SendCmpct {
+            announce: announce,
+            version: COMPACT_VERSION,
+        }
+    }
+}
+
+/// A transaction sent along with a compact block, at `index` in the block
+#[derive(Clone, Debug)]
+pub struct PrefilledTx {
+    pub index: usize,
+    pub tx: WitnessTx,
+}
+
+/// The `cmpctblock` message (BIP152's HeaderAndShortIDs): a block header
+/// with a short ID for each transaction the receiver is expected to have,
+/// and the transactions it's not, such as the coinbase, in full
+#[derive(Clone, Debug)]
+pub struct CompactBlock {
+    pub header: BlockHeader,
+    pub nonce: u64,
+    /// Of the transactions not prefilled, in block order
+    pub short_ids: Vec<u64>,
+    /// In ascending order of index
+    pub prefilled: Vec<PrefilledTx>,
+}
+
+impl CompactBlock {
+    /// The SipHash keys of the short IDs, taken from the SHA256 of the
+    /// header and nonce
+    fn siphash_keys(&self) -> (u64, u64) {
+        let mut data = serialize(&self.header).unwrap();
+        data.extend(serialize(&self.nonce).unwrap());
+        let hash = sha256(&data[..]);
+        (u64_from_le(&hash[0..8]), u64_from_le(&hash[8..16]))
+    }
+}
+
+/// The `getblocktxn` message, asking for transactions of a block by index
+#[derive(Clone, Debug)]
+pub struct BlockTxnRequest {
+    pub block_hash: Sha256dHash,
+    /// In ascending order
+    pub indexes: Vec<usize>,
+}
+
+/// The `blocktxn` message, with the transactions a `getblocktxn` asked for
+#[derive(Clone, Debug)]
+pub struct BlockTxn {
+    pub block_hash: Sha256dHash,
+    pub txs: Vec<WitnessTx>,
+}
+
+/// A block being rebuilt from a compact block, with a slot for each of its
+/// transactions
+pub struct PartialBlock {
+    header: BlockHeader,
+    slots: Vec<Option<WitnessTx>>,
+}
+
+impl PartialBlock {
+    /// Lay out the transactions of a compact block, taking those it doesn't
+    /// carry from the mempool. Fails if the compact block is malformed or
+    /// two of its short IDs are the same, in which case the block should be
+    /// fetched in full.
+    pub fn new(block: &CompactBlock, mempool: &Mempool) -> Result<PartialBlock, String> {
+        let count = block.short_ids.len() + block.prefilled.len();
+        if count == 0 || count > MAX_BLOCK_TXS {
+            return Err(format!("Bad transaction count {}", count));
+        }
+        let mut slots: Vec<Option<WitnessTx>> = vec![None; count];
+        for prefilled in block.prefilled.iter() {
+            if prefilled.index >= count || slots[prefilled.index].is_some() {
+                return Err(format!("Bad prefilled transaction index {}", prefilled.index));
+            }
+            slots[prefilled.index] = Some(prefilled.tx.clone());
+        }
+
+        // the short IDs fill the remaining slots in order
+        let mut wanted: HashMap<u64, usize> = HashMap::new();
+        let mut short_ids = block.short_ids.iter();
+        for (index, slot) in slots.iter().enumerate() {
+            if slot.is_some() {
+                continue;
+            }
+            if let Some(&short_id) = short_ids.next() {
+                if wanted.insert(short_id, index).is_some() {
+                    return Err("Duplicate short IDs".to_string());
+                }
+            }
+        }
+
+        let (k0, k1) = block.siphash_keys();
+        let mut collided: HashSet<usize> = HashSet::new();
+        for entry in mempool.entries() {
+            if let Some(&index) = wanted.get(&short_id(k0, k1, &entry.wtxid)) {
+                if slots[index].is_some() || collided.contains(&index) {
+                    // more than one candidate, so ask the peer which it is
+                    slots[index] = None;
+                    collided.insert(index);
+                } else {
+                    slots[index] = Some(entry.tx.clone());
+                }
+            }
+        }
+        Ok(PartialBlock {
+            header: block.header,
+            slots: slots,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Indexes of the transactions still to get from the peer
+    pub fn missing(&self) -> Vec<usize> {
+        self.slots.iter().enumerate()
+            .filter(|&(_, slot)| slot.is_none())
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Complete the block with the transactions of a `blocktxn`, which come
+    /// in the order of the missing slots
+    pub fn fill(self, txs: Vec<WitnessTx>) -> Result<WitnessBlock, String> {
+        let missing = self.slots.iter().filter(|slot| slot.is_none()).count();
+        if txs.len() != missing {
+            return Err(format!("Expected {} transactions, got {}", missing, txs.len()));
+        }
+        let mut txs = txs.into_iter();
+        let txdata = self.slots.into_iter()
+            .map(|slot| slot.or_else(|| txs.next()).unwrap())
+            .collect();
+        Ok(WitnessBlock {
+            header: self.header,
+            txdata: txdata,
+        })
+    }
+}
+
+/// The 6 byte short ID of a transaction, from its wtxid
+fn short_id(k0: u64, k1: u64, wtxid: &Sha256dHash) -> u64 {
+    siphash(k0, k1, &wtxid[..]) & 0xffffffffffff
+}
+
+/// A list length, refusing more entries than a block can have
+fn decode_length<D: SimpleDecoder>(d: &mut D) -> Result<usize, D::Error> {
+    let VarInt(length) = try!(ConsensusDecodable::consensus_decode(d));
+    if length > MAX_BLOCK_TXS as u64 {
+        return Err(d.error(format!("Too many entries: {}", length)));
+    }
+    Ok(length as usize)
+}
+
+/// Transaction indexes are sent as the difference from the previous index
+/// plus one
+fn decode_index<D: SimpleDecoder>(d: &mut D, next: usize) -> Result<usize, D::Error> {
+    let VarInt(difference) = try!(ConsensusDecodable::consensus_decode(d));
+    if difference >= (MAX_BLOCK_TXS - next) as u64 {
+        return Err(d.error("Transaction index out of range".to_string()));
+    }
+    Ok(next + difference as usize)
+}
+
+impl<S: SimpleEncoder> ConsensusEncodable<S> for SendCmpct {
+    fn consensus_encode(&self, s: &mut S) -> Result<(), S::Error> {
+        try!(self.announce.consensus_encode(s));
+        self.version.consensus_encode(s)
+    }
+}
+
+impl<D: SimpleDecoder> ConsensusDecodable<D> for SendCmpct {
+    fn consensus_decode(d: &mut D) -> Result<SendCmpct, D::Error> {
+        Ok(SendCmpct {
+            announce: try!(ConsensusDecodable::consensus_decode(d)),
+            version: try!(ConsensusDecodable::consensus_decode(d)),
+        })
+    }
+}
+
+impl<S: SimpleEncoder> ConsensusEncodable<S> for CompactBlock {
+    fn consensus_encode(&self, s: &mut S) -> Result<(), S::Error> {
+        try!(self.header.consensus_encode(s));
+        try!(self.nonce.consensus_encode(s));
+        try!(VarInt(self.short_ids.len() as u64).consensus_encode(s));
+        for &short_id in self.short_ids.iter() {
+            try!(s.emit_u32(short_id as u32));
+            try!(s.emit_u16((short_id >> 32) as u16));
+        }
+        try!(VarInt(self.prefilled.len() as u64).consensus_encode(s));
+        let mut next = 0;
+        for prefilled in self.prefilled.iter() {
+            try!(VarInt((prefilled.index - next) as u64).consensus_encode(s));
+            try!(prefilled.tx.consensus_encode(s));
+            next = prefilled.index + 1;
+        }
+        Ok(())
+    }
+}
+
+impl<D: SimpleDecoder> ConsensusDecodable<D> for CompactBlock {
+    fn consensus_decode(d: &mut D) -> Result<CompactBlock, D::Error> {
+        let header = try!(ConsensusDecodable::consensus_decode(d));
+        let nonce = try!(ConsensusDecodable::consensus_decode(d));
+        let mut short_ids = vec![];
+        for _ in 0..try!(decode_length(d)) {
+            let low = try!(d.read_u32()) as u64;
+            let high = try!(d.read_u16()) as u64;
+            short_ids.push(low | high << 32);
+        }
+        let mut prefilled = vec![];
+        let mut next = 0;
+        for _ in 0..try!(decode_length(d)) {
+            let index = try!(decode_index(d, next));
+            prefilled.push(PrefilledTx {
+                index: index,
+                tx: try!(ConsensusDecodable::consensus_decode(d)),
+            });
+            next = index + 1;
+        }
+        Ok(CompactBlock {
+            header: header,
+            nonce: nonce,
+            short_ids: short_ids,
+            prefilled: prefilled,
+        })
+    }
+}
+
+impl<S: SimpleEncoder> ConsensusEncodable<S> for BlockTxnRequest {
+    fn consensus_encode(&self, s: &mut S) -> Result<(), S::Error> {
+        try!(self.block_hash.consensus_encode(s));
+        try!(VarInt(self.indexes.len() as u64).consensus_encode(s));
+        let mut next = 0;
+        for &index in self.indexes.iter() {
+            try!(VarInt((index - next) as u64).consensus_encode(s));
+            next = index + 1;
+        }
+        Ok(())
+    }
+}
+
+impl<D: SimpleDecoder> ConsensusDecodable<D> for BlockTxnRequest {
+    fn consensus_decode(d: &mut D) -> Result<BlockTxnRequest, D::Error> {
+        let block_hash = try!(ConsensusDecodable::consensus_decode(d));
+        let mut indexes = vec![];
+        let mut next = 0;
+        for _ in 0..try!(decode_length(d)) {
+            let index = try!(decode_index(d, next));
+            indexes.push(index);
+            next = index + 1;
+        }
+        Ok(BlockTxnRequest {
+            block_hash: block_hash,
+            indexes: indexes,
+        })
+    }
+}
+
+impl<S: SimpleEncoder> ConsensusEncodable<S> for BlockTxn {
+    fn consensus_encode(&self, s: &mut S) -> Result<(), S::Error> {
+        try!(self.block_hash.consensus_encode(s));
+        self.txs.consensus_encode(s)
+    }
+}
+
+impl<D: SimpleDecoder> ConsensusDecodable<D> for BlockTxn {
+    fn consensus_decode(d: &mut D) -> Result<BlockTxn, D::Error> {
+        Ok(BlockTxn {
+            block_hash: try!(ConsensusDecodable::consensus_decode(d)),
+            txs: try!(ConsensusDecodable::consensus_decode(d)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
+    use bitcoin::network::constants::Network;
+    use bitcoin::network::serialize::{BitcoinHash, deserialize, serialize};
+    use bitcoin::util::hash::Sha256dHash;
+
+    use mempool::Mempool;
+    use witness::WitnessTx;
+
+    use super::{CompactBlock, PrefilledTx, PartialBlock, short_id};
+
+    /// A transaction told apart from the others by the output it spends
+    fn tx(n: u32) -> WitnessTx {
+        WitnessTx::from_tx(Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                prev_hash: Sha256dHash::from_data(&[1]),
+                prev_index: n,
+                script_sig: Script::new(),
+                sequence: 0xffffffff,
+            }],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::from(vec![0x51]),
+            }],
+        })
+    }
+
+    /// A compact block of the genesis header, with `txs[0]` prefilled and
+    /// short IDs for the rest
+    fn compact_block(txs: &[WitnessTx]) -> CompactBlock {
+        let mut block = CompactBlock {
+            header: genesis_block(Network::Bitcoin).header,
+            nonce: 0x0123456789abcdef,
+            short_ids: vec![],
+            prefilled: vec![PrefilledTx { index: 0, tx: txs[0].clone() }],
+        };
+        let (k0, k1) = block.siphash_keys();
+        block.short_ids = txs[1..].iter().map(|tx| short_id(k0, k1, &tx.wtxid())).collect();
+        block
+    }
+
+    #[test]
+    fn short_ids() {
+        let genesis = genesis_block(Network::Bitcoin);
+        let block = CompactBlock {
+            header: genesis.header,
+            nonce: 0x0123456789abcdef,
+            short_ids: vec![],
+            prefilled: vec![],
+        };
+        let (k0, k1) = block.siphash_keys();
+        assert_eq!((k0, k1), (0x52f1458bc3816130, 0xd82182c249af75c6));
+        // the coinbase has no witness, so its wtxid is its txid
+        assert_eq!(short_id(k0, k1, &genesis.txdata[0].bitcoin_hash()), 0xd97c3183bddd);
+    }
+
+    #[test]
+    fn round_trips() {
+        let txs = (0..4).map(tx).collect::<Vec<WitnessTx>>();
+        let block = compact_block(&txs[..]);
+        let decoded: CompactBlock = deserialize(&serialize(&block).unwrap()[..]).unwrap();
+        assert_eq!(decoded.header.bitcoin_hash(), block.header.bitcoin_hash());
+        assert_eq!(decoded.nonce, block.nonce);
+        assert_eq!(decoded.short_ids, block.short_ids);
+        assert_eq!(decoded.prefilled.len(), 1);
+        assert_eq!(decoded.prefilled[0].index, 0);
+        assert_eq!(decoded.prefilled[0].tx, txs[0]);
+        assert!(block.short_ids.iter().all(|&id| id < 1 << 48));
+    }
+
+    #[test]
+    fn reconstructs_from_mempool_and_blocktxn() {
+        let txs = (0..4).map(tx).collect::<Vec<WitnessTx>>();
+        let mut mempool = Mempool::new(10);
+        for &i in [1, 3].iter() {
+            assert!(mempool.insert(txs[i].clone(), Some(1000), 0).is_some());
+        }
+        // a transaction we have that isn't in the block is ignored
+        assert!(mempool.insert(tx(9), Some(1000), 0).is_some());
+
+        let partial = PartialBlock::new(&compact_block(&txs[..]), &mempool).unwrap();
+        assert_eq!(partial.len(), 4);
+        assert_eq!(partial.missing(), vec![2]);
+        let block = partial.fill(vec![txs[2].clone()]).unwrap();
+        assert_eq!(block.header.bitcoin_hash(), genesis_block(Network::Bitcoin).bitcoin_hash());
+        assert_eq!(block.txdata, txs);
+
+        let partial = PartialBlock::new(&compact_block(&txs[..]), &mempool).unwrap();
+        assert!(partial.fill(vec![]).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_compact_blocks() {
+        let txs = (0..4).map(tx).collect::<Vec<WitnessTx>>();
+        let mempool = Mempool::new(10);
+
+        let mut block = compact_block(&txs[..]);
+        block.short_ids[1] = block.short_ids[0];
+        assert!(PartialBlock::new(&block, &mempool).is_err());
+
+        let mut block = compact_block(&txs[..]);
+        block.prefilled[0].index = 4;
+        assert!(PartialBlock::new(&block, &mempool).is_err());
+
+        let mut block = compact_block(&txs[..]);
+        block.short_ids.clear();
+        block.prefilled.clear();
+        assert!(PartialBlock::new(&block, &mempool).is_err());
+    }
+}
@@ -0,0 +1,114 @@
+use bitcoin::network::constants::Network;
+
+use log::LogConfig;
+use mempool::MAX_MEMPOOL_TXS;
+use storage::DependentTable;
+use verify::UtxoSource;
+
+/// Settings for the daemon
+pub struct Config {
+    pub network: Network,
+    /// Address of the first peer to connect to
+    pub peer_ip: String,
+    pub peer_port: u16,
+    /// Blockchain file written by earlier versions. The header store, block
+    /// store and other state files are kept beside it.
+    pub path_to_chain: String,
+    /// Where to store indexed data: a Postgres connection string,
+    /// `sqlite:<path>` for a SQLite file, or `memory` to keep it in memory
+    pub db_cnx: String,
+    /// Prefix of the tables we write to, such as `talk_` for `talk_block`.
+    /// With Postgres it may start with a schema name, as in `chain.`.
+    pub table_prefix: String,
+    /// Tables of other apps whose rows refer to blocks, cleared of a block's
+    /// rows before it is pruned
+    pub dependent_tables: Vec<DependentTable>,
+    /// Copy pruned blocks, transactions, inputs, outputs and rows of
+    /// dependent tables into `<table>_archive` tables before deleting them
+    pub archive: bool,
+    /// Directory to export the rows of pruned blocks to before deleting
+    /// them, one NDJSON file per block
+    pub archive_dir: Option<String>,
+    /// Maximum number of unconfirmed transactions kept in memory
+    pub mempool_size: usize,
+    /// Keep a copy of the mempool in the `<prefix>mempool` table
+    pub mirror_mempool: bool,
+    /// Verify input scripts with libbitcoinconsensus. Requires building with
+    /// the `libbitcoinconsensus` feature; otherwise the daemon won't start.
+    pub verify_scripts: bool,
+    /// Where to find prevouts outside the retention window and the mempool
+    pub utxo_source: Option<Box<UtxoSource + Send>>,
+    /// Address to serve JSON-RPC on, such as `127.0.0.1:8332`
+    pub rpc_address: Option<String>,
+    /// User name and password RPC clients must send. The keystore's RPC
+    /// methods are only served when this is set.
+    pub rpc_auth: Option<(String, String)>,
+    /// Address to serve the explorer's REST API on, such as `127.0.0.1:3000`
+    pub rest_address: Option<String>,
+    /// Address to serve Prometheus metrics on, at `/metrics`
+    pub metrics_address: Option<String>,
+    /// Address to push block and mempool events to WebSocket clients on
+    pub websocket_address: Option<String>,
+    /// Address to publish raw blocks and transactions on, framed as
+    /// described in the README
+    pub raw_publish_address: Option<String>,
+    /// Postgres channel to send block and mempool events to with NOTIFY.
+    /// Only allowed when `db_cnx` is a Postgres database.
+    pub notify_channel: Option<String>,
+    /// JSON file of addresses, scripts and xpubs to watch, which the
+    /// `importaddress`, `importxpub` and `removewatched` RPCs update
+    pub watch_list_path: Option<String>,
+    /// `http://` URL to POST alerts about watched scripts to
+    pub webhook_url: Option<String>,
+    /// Confirmation count up to which alerts are updated
+    pub webhook_confirmations: u32,
+    /// Encrypted file holding the wallet's keys, created on startup if it
+    /// doesn't exist
+    pub keystore_path: Option<String>,
+    /// Password the keystore is encrypted with, required with `keystore_path`
+    pub keystore_password: Option<String>,
+    /// BIP39 mnemonic to create the keystore from, instead of a new one
+    pub keystore_mnemonic: Option<String>,
+    /// JSON file of descriptors the watch-only wallet tracks, which the
+    /// `importdescriptor` RPC updates
+    pub wallet_path: Option<String>,
+    /// Levels, format and file of the log, which the `logging` RPC changes
+    /// while running
+    pub log: LogConfig,
+}
+
+impl Config {
+    pub fn new(peer_ip: &str, peer_port: u16, path_to_chain: String, db_cnx: String)
+               -> Config {
+        Config {
+            network: Network::Bitcoin,
+            peer_ip: peer_ip.to_string(),
+            peer_port: peer_port,
+            path_to_chain: path_to_chain,
+            db_cnx: db_cnx,
+            table_prefix: "talk_".to_string(),
+            dependent_tables: vec![],
+            archive: false,
+            archive_dir: None,
+            mempool_size: MAX_MEMPOOL_TXS,
+            mirror_mempool: false,
+            verify_scripts: false,
+            utxo_source: None,
+            rpc_address: None,
+            rpc_auth: None,
+            rest_address: None,
+            metrics_address: None,
+            websocket_address: None,
+            raw_publish_address: None,
+            notify_channel: None,
+            watch_list_path: None,
+            webhook_url: None,
+            webhook_confirmations: 6,
+            keystore_path: None,
+            keystore_password: None,
+            keystore_mnemonic: None,
+            wallet_path: None,
+            log: LogConfig::new(),
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+
+use rustc_serialize::json;
+
+use bitcoin::util::hash::Sha256dHash;
+
+use store::write_atomically;
+
+/// Confirmation targets, in blocks, that estimates are produced for
+pub const TARGETS: [u32; 5] = [1, 3, 6, 12, 24];
+
+/// Fee rate buckets start at 1 sat/vB and grow by 10% up to 10,000 sat/vB
+const MIN_BUCKET_FEE_RATE: f64 = 1.0;
+const MAX_BUCKET_FEE_RATE: f64 = 10000.0;
+const BUCKET_SPACING: f64 = 1.1;
+
+/// Old data points are decayed by this factor with every block, giving a
+/// half-life of roughly 350 blocks
+const DECAY: f64 = 0.998;
+/// Share of transactions in a range of buckets that must have confirmed
+/// within the target for the range to be considered sufficient
+const SUCCESS_THRESHOLD: f64 = 0.85;
+/// Minimum (decayed) number of data points a range of buckets needs before
+/// its success rate is trusted
+const SUFFICIENT_DATA: f64 = 2.0;
+
+/// Estimates fee rates from how long transactions we saw in the mempool
+/// took to confirm
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct FeeEstimator {
+    /// Lower bound of each bucket, in satoshis per virtual byte
+    buckets: Vec<f64>,
+    /// Per target, per bucket: transactions confirmed within the target
+    confirmed: Vec<Vec<f64>>,
+    /// Per bucket: transactions whose fate we know, confirmed or not
+    resolved: Vec<f64>,
+    best_height: u32,
+    /// Mempool transactions being followed: bucket and height when seen.
+    /// Dropped on load, as the mempool isn't saved.
+    tracked: HashMap<String, (usize, u32)>,
+}
+
+impl FeeEstimator {
+    pub fn new() -> FeeEstimator {
+        let mut buckets = vec![];
+        let mut fee_rate = MIN_BUCKET_FEE_RATE;
+        while fee_rate <= MAX_BUCKET_FEE_RATE {
+            buckets.push(fee_rate);
+            fee_rate *= BUCKET_SPACING;
+        }
+        let n_buckets = buckets.len();
+        FeeEstimator {
+            buckets: buckets,
+            confirmed: vec![vec![0.0; n_buckets]; TARGETS.len()],
+            resolved: vec![0.0; n_buckets],
+            best_height: 0,
+            tracked: HashMap::new(),
+        }
+    }
+
+    /// Load saved state, starting from scratch if there is none
+    pub fn load(path: &str) -> FeeEstimator {
+        let mut contents = String::new();
+        match File::open(path).map(|mut file| file.read_to_string(&mut contents)) {
+            Ok(Ok(_)) => match json::decode::<FeeEstimator>(&contents) {
+                Ok(mut estimator) => {
+                    // the mempool starts empty, so saved transactions would
+                    // never resolve and only count as failures
+                    estimator.tracked.clear();
+                    estimator
+                },
+                Err(e) => {
+                    warn!("db", path = path; "Could not load fee estimates: {:?}", e);
+                    FeeEstimator::new()
+                },
+            },
+            _ => {
+                info!("db", path = path; "No fee estimates file, starting afresh");
+                FeeEstimator::new()
+            },
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let encoded = match json::encode(self) {
+            Ok(encoded) => encoded,
+            Err(e) => return Err(format!("Encoding fee estimates: {:?}", e)),
+        };
+        write_atomically(path, encoded.as_bytes())
+    }
+
+    /// Start following a transaction that just entered the mempool
+    pub fn track(&mut self, txid: &Sha256dHash, fee_rate: f64, height: u32) {
+        let bucket = self.bucket_of(fee_rate);
+        self.tracked.insert(txid.be_hex_string(), (bucket, height));
+    }
+
+    /// A tracked transaction was confirmed in the block at `height`
+    pub fn confirmed(&mut self, txid: &Sha256dHash, height: u32) {
+        if let Some((bucket, seen_height)) = self.tracked.remove(&txid.be_hex_string()) {
+            let blocks_to_confirm = if height > seen_height { height - seen_height } else { 1 };
+            for (i, &target) in TARGETS.iter().enumerate() {
+                if blocks_to_confirm <= target {
+                    self.confirmed[i][bucket] += 1.0;
+                }
+            }
+            self.resolved[bucket] += 1.0;
+        }
+    }
+
+    /// A tracked transaction left the mempool without confirming
+    pub fn dropped(&mut self, txid: &Sha256dHash) {
+        if let Some((bucket, _)) = self.tracked.remove(&txid.be_hex_string()) {
+            self.resolved[bucket] += 1.0;
+        }
+    }
+
+    /// Age the statistics after a block has been processed
+    pub fn process_block(&mut self, height: u32) {
+        if height <= self.best_height {
+            return;
+        }
+        self.best_height = height;
+        for counts in self.confirmed.iter_mut() {
+            for count in counts.iter_mut() {
+                *count *= DECAY;
+            }
+        }
+        for count in self.resolved.iter_mut() {
+            *count *= DECAY;
+        }
+    }
+
+    /// Estimated fee rate in sat/vB for confirmation within `target` blocks,
+    /// using the first supported target at or above the one requested
+    pub fn estimate(&self, target: u32) -> Option<f64> {
+        let i = match TARGETS.iter().position(|&t| t >= target) {
+            Some(i) => i,
+            None => return None,
+        };
+        let target = TARGETS[i];
+
+        // transactions still waiting past the target count as failures
+        let mut pending_failures = vec![0.0; self.buckets.len()];
+        for &(bucket, seen_height) in self.tracked.values() {
+            if self.best_height >= seen_height + target {
+                pending_failures[bucket] += 1.0;
+            }
+        }
+
+        // walk down from the highest fee rate, grouping buckets until there
+        // is enough data, for as long as each group confirms reliably
+        let mut estimate = None;
+        let (mut confirmed, mut total) = (0.0, 0.0);
+        for bucket in (0..self.buckets.len()).rev() {
+            confirmed += self.confirmed[i][bucket];
+            total += self.resolved[bucket] + pending_failures[bucket];
+            if total < SUFFICIENT_DATA {
+                continue;
+            }
+            if confirmed / total < SUCCESS_THRESHOLD {
+                break;
+            }
+            estimate = Some(self.buckets[bucket]);
+            confirmed = 0.0;
+            total = 0.0;
+        }
+        estimate
+    }
+
+    /// Estimates for every supported target
+    pub fn estimates(&self) -> Vec<(u32, Option<f64>)> {
+        TARGETS.iter().map(|&target| (target, self.estimate(target))).collect()
+    }
+
+    fn bucket_of(&self, fee_rate: f64) -> usize {
+        match self.buckets.iter().rposition(|&lower| lower <= fee_rate) {
+            Some(bucket) => bucket,
+            None => 0,
+        }
+    }
+}
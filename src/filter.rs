@@ -0,0 +1,1068 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+
+use bitcoin::blockdata::blockchain::Blockchain;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::blockdata::transaction::TxOut;
+use bitcoin::network::constants::Network;
+use bitcoin::network::encodable::{ConsensusEncodable, ConsensusDecodable, VarInt};
+use bitcoin::network::serialize::{SimpleEncoder, SimpleDecoder, BitcoinHash, serialize,
+                                  deserialize};
+use bitcoin::util::hash::Sha256dHash;
+
+use rand::{self, Rng};
+
+use merkle::hash_pair;
+use rpc::{best_height, best_chain_hash_at};
+use store::write_atomically;
+use util::{siphash, u64_from_le};
+use validation::is_coinbase;
+use verify::UtxoSource;
+use wire::{PeerMessage, checksum};
+use witness::WitnessBlock;
+
+/// The only filter type BIP158 defines
+pub const BASIC_FILTER: u8 = 0;
+/// Golomb-Rice parameter and false positive rate of basic filters
+const P: u8 = 19;
+const M: u64 = 784931;
+/// Most filters a `getcfilters` may ask for
+pub const MAX_GETCFILTERS: u32 = 1000;
+/// Most filter headers a `getcfheaders` may ask for
+pub const MAX_GETCFHEADERS: u32 = 2000;
+/// Blocks between the filter headers of a `cfcheckpt`
+pub const CHECKPOINT_INTERVAL: u32 = 1000;
+/// Seconds to wait on a peer for filters or filter headers before asking
+/// again
+const FILTER_REQUEST_TIMEOUT: u64 = 60;
+
+/// The basic filter of a block: its output scripts, bar OP_RETURN outputs,
+/// and the scripts of the outputs its inputs spend. None if one of those
+/// can't be found.
+pub fn basic_filter(block: &WitnessBlock, source: &UtxoSource) -> Option<Vec<u8>> {
+    let mut items: HashSet<Vec<u8>> = HashSet::new();
+    for wtx in block.txdata.iter() {
+        for output in wtx.tx.output.iter() {
+            let script = output.script_pubkey.clone().into_vec();
+            // OP_RETURN
+            if !script.is_empty() && script[0] != 0x6a {
+                items.insert(script);
+            }
+        }
+        if is_coinbase(&wtx.tx) {
+            continue;
+        }
+        for input in wtx.tx.input.iter() {
+            let script = match source.prevout(&input.prev_hash, input.prev_index) {
+                Some(prevout) => prevout.script_pubkey.into_vec(),
+                None => return None,
+            };
+            if !script.is_empty() {
+                items.insert(script);
+            }
+        }
+    }
+    Some(encode_gcs(&block.bitcoin_hash(), &items))
+}
+
+/// The filter header committing to a filter and every filter before it
+pub fn filter_header(filter_hash: &Sha256dHash, prev_header: &Sha256dHash) -> Sha256dHash {
+    hash_pair(filter_hash, prev_header)
+}
+
+/// Golomb-coded set of the items, keyed by the block hash: the item count,
+/// then the sorted hashes of the items as Rice-coded differences
+fn encode_gcs(block_hash: &Sha256dHash, items: &HashSet<Vec<u8>>) -> Vec<u8> {
+    let count = items.len() as u64;
+    let mut writer = BitWriter::new(serialize(&VarInt(count)).unwrap());
+    if count == 0 {
+        return writer.finish();
+    }
+    let key = &block_hash[..];
+    let (k0, k1) = (u64_from_le(&key[0..8]), u64_from_le(&key[8..16]));
+    let mut values = items.iter()
+        .map(|item| mul_high(siphash(k0, k1, &item[..]), count * M))
+        .collect::<Vec<u64>>();
+    values.sort();
+
+    let mut last = 0;
+    for value in values {
+        let delta = value - last;
+        for _ in 0..(delta >> P) {
+            writer.write_bit(true);
+        }
+        writer.write_bit(false);
+        writer.write_bits(delta, P);
+        last = value;
+    }
+    writer.finish()
+}
+
+/// The high 64 bits of the 128 bit product, which maps a hash uniformly
+/// onto [0, b)
+fn mul_high(a: u64, b: u64) -> u64 {
+    let (a_high, a_low) = (a >> 32, a & 0xffffffff);
+    let (b_high, b_low) = (b >> 32, b & 0xffffffff);
+    let low = a_low * b_low;
+    let middle_a = a_high * b_low;
+    let middle_b = a_low * b_high;
+    let carry = (low >> 32) + (middle_a & 0xffffffff) + middle_b;
+    a_high * b_high + (middle_a >> 32) + (carry >> 32)
+}
+
+/// Packs bits into bytes, most significant bit first
+struct BitWriter {
+    data: Vec<u8>,
+    byte: u8,
+    used: u8,
+}
+
+impl BitWriter {
+    fn new(data: Vec<u8>) -> BitWriter {
+        BitWriter {
+            data: data,
+            byte: 0,
+            used: 0,
+        }
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        if bit {
+            self.byte |= 0x80 >> self.used;
+        }
+        self.used += 1;
+        if self.used == 8 {
+            self.data.push(self.byte);
+            self.byte = 0;
+            self.used = 0;
+        }
+    }
+
+    /// The low `count` bits of `value`
+    fn write_bits(&mut self, value: u64, count: u8) {
+        for i in (0..count).rev() {
+            self.write_bit(value >> i & 1 == 1);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.used > 0 {
+            self.data.push(self.byte);
+        }
+        self.data
+    }
+}
+
+/// Stands in for prevouts where there are none to look up, i.e. genesis
+struct NoOutputs;
+
+impl UtxoSource for NoOutputs {
+    fn prevout(&self, _: &Sha256dHash, _: u32) -> Option<TxOut> {
+        None
+    }
+}
+
+/// The `getcfilters` and `getcfheaders` messages, asking for the filters
+/// or filter hashes of the best chain from `start_height` to `stop_hash`
+#[derive(Clone, Debug)]
+pub struct FilterRequest {
+    pub filter_type: u8,
+    pub start_height: u32,
+    pub stop_hash: Sha256dHash,
+}
+
+/// The `getcfcheckpt` message
+#[derive(Clone, Debug)]
+pub struct CheckptRequest {
+    pub filter_type: u8,
+    pub stop_hash: Sha256dHash,
+}
+
+/// The `cfilter` message, with one block's filter
+#[derive(Clone, Debug)]
+pub struct CFilter {
+    pub filter_type: u8,
+    pub block_hash: Sha256dHash,
+    pub filter: Vec<u8>,
+}
+
+/// The `cfheaders` message: the filter hashes of a range of blocks ending
+/// at `stop_hash`, and the filter header before them to chain them onto
+#[derive(Clone, Debug)]
+pub struct CFHeaders {
+    pub filter_type: u8,
+    pub stop_hash: Sha256dHash,
+    pub prev_filter_header: Sha256dHash,
+    pub filter_hashes: Vec<Sha256dHash>,
+}
+
+/// The `cfcheckpt` message, with the filter header of every
+/// CHECKPOINT_INTERVAL-th block up to `stop_hash`
+#[derive(Clone, Debug)]
+pub struct CFCheckpt {
+    pub filter_type: u8,
+    pub stop_hash: Sha256dHash,
+    pub filter_headers: Vec<Sha256dHash>,
+}
+
+/// The filters of the retention window, one file per block
+struct FilterStore {
+    dir: String,
+}
+
+impl FilterStore {
+    fn open(dir: &str) -> Result<FilterStore, String> {
+        match fs::create_dir_all(dir) {
+            Ok(()) => Ok(FilterStore { dir: dir.to_string() }),
+            Err(e) => Err(format!("Could not create filter store {}: {:?}", dir, e)),
+        }
+    }
+
+    fn put(&self, block_hash: &Sha256dHash, filter: &[u8]) -> Result<(), String> {
+        write_atomically(&self.path_of(block_hash), filter)
+    }
+
+    fn get(&self, block_hash: &Sha256dHash) -> Option<Vec<u8>> {
+        let mut data = vec![];
+        match File::open(self.path_of(block_hash)).map(|mut file| file.read_to_end(&mut data)) {
+            Ok(Ok(_)) => Some(data),
+            _ => None,
+        }
+    }
+
+    fn remove(&self, block_hash: &Sha256dHash) -> Result<(), String> {
+        match fs::remove_file(self.path_of(block_hash)) {
+            Ok(()) => Ok(()),
+            // the filter was never computed or received
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(format!("Removing filter {}: {:?}", block_hash.be_hex_string(), e)),
+        }
+    }
+
+    fn path_of(&self, block_hash: &Sha256dHash) -> String {
+        format!("{}/{}.cf", self.dir, block_hash.be_hex_string())
+    }
+}
+
+/// A block hash and its filter hash
+const ENTRY_SIZE: usize = 64;
+/// An entry followed by the first four bytes of its hash
+const RECORD_SIZE: usize = ENTRY_SIZE + 4;
+
+/// A filter header chain entry
+#[derive(Clone, Copy)]
+struct Entry {
+    block_hash: Sha256dHash,
+    filter_hash: Sha256dHash,
+    header: Sha256dHash,
+}
+
+/// Append-only log of the filter hashes of the best chain from genesis, in
+/// height order, laid out like the header store. The filter headers are
+/// chained back together when it's opened.
+struct FilterHeaderStore {
+    file: File,
+    path: String,
+    entries: Vec<Entry>,
+    heights: HashMap<Sha256dHash, u32>,
+}
+
+impl FilterHeaderStore {
+    fn open(path: &str) -> Result<FilterHeaderStore, String> {
+        let mut file = match OpenOptions::new().read(true).append(true).create(true).open(path) {
+            Ok(file) => file,
+            Err(e) => return Err(format!("Could not open filter header store {}: {:?}", path, e)),
+        };
+        let mut data = vec![];
+        if let Err(e) = file.read_to_end(&mut data) {
+            return Err(format!("Could not read filter header store {}: {:?}", path, e));
+        }
+
+        let mut store = FilterHeaderStore {
+            file: file,
+            path: path.to_string(),
+            entries: vec![],
+            heights: HashMap::new(),
+        };
+        for record in data.chunks(RECORD_SIZE) {
+            if record.len() < RECORD_SIZE ||
+                checksum(&record[..ENTRY_SIZE]) != record[ENTRY_SIZE..] {
+                break;
+            }
+            let block_hash = deserialize(&record[..32]).unwrap();
+            let filter_hash = deserialize(&record[32..ENTRY_SIZE]).unwrap();
+            store.push(block_hash, filter_hash);
+        }
+        let good_len = store.len() as usize * RECORD_SIZE;
+        if good_len < data.len() {
+            warn!("db", path = path, bytes = data.len() - good_len;
+                  "Truncating damaged data from filter header store");
+            try!(store.truncate(good_len as u32 / RECORD_SIZE as u32));
+        }
+        info!("db", path = path, headers = store.len(); "Loaded filter headers");
+        Ok(store)
+    }
+
+    fn len(&self) -> u32 {
+        self.entries.len() as u32
+    }
+
+    fn get(&self, height: u32) -> Option<&Entry> {
+        self.entries.get(height as usize)
+    }
+
+    fn height_of(&self, block_hash: &Sha256dHash) -> Option<u32> {
+        self.heights.get(block_hash).map(|&height| height)
+    }
+
+    fn push(&mut self, block_hash: Sha256dHash, filter_hash: Sha256dHash) {
+        let prev_header = self.entries.last().map_or(Default::default(), |entry| entry.header);
+        self.heights.insert(block_hash, self.entries.len() as u32);
+        self.entries.push(Entry {
+            block_hash: block_hash,
+            filter_hash: filter_hash,
+            header: filter_header(&filter_hash, &prev_header),
+        });
+    }
+
+    /// Durably add the filter hashes of the blocks following the last entry
+    fn append(&mut self, hashes: &[(Sha256dHash, Sha256dHash)]) -> Result<(), String> {
+        let mut data = Vec::with_capacity(hashes.len() * RECORD_SIZE);
+        for &(block_hash, filter_hash) in hashes.iter() {
+            let mut entry = block_hash[..].to_vec();
+            entry.extend_from_slice(&filter_hash[..]);
+            data.extend_from_slice(&entry[..]);
+            data.extend_from_slice(&checksum(&entry[..])[..]);
+        }
+        if let Err(e) = self.file.write_all(&data[..]).and_then(|_| self.file.sync_data()) {
+            return Err(format!("Failed to append to filter header store {}: {:?}",
+                               self.path, e));
+        }
+        for &(block_hash, filter_hash) in hashes.iter() {
+            self.push(block_hash, filter_hash);
+        }
+        Ok(())
+    }
+
+    /// Drop the entries from `height` on
+    fn truncate(&mut self, height: u32) -> Result<(), String> {
+        let size = height as u64 * RECORD_SIZE as u64;
+        if let Err(e) = self.file.set_len(size).and_then(|_| self.file.sync_all()) {
+            return Err(format!("Could not truncate filter header store {}: {:?}",
+                               self.path, e));
+        }
+        for entry in self.entries.drain(height as usize..) {
+            self.heights.remove(&entry.block_hash);
+        }
+        Ok(())
+    }
+}
+
+/// BIP157 filters and filter headers. Filters are computed for the blocks
+/// whose prevouts can all be found and kept for the retention window.
+/// Filter headers are kept for the whole best chain, those of blocks we
+/// have no filter for coming from peers, as do those filters.
+pub struct FilterIndex {
+    filters: FilterStore,
+    headers: FilterHeaderStore,
+    /// Retained blocks we couldn't compute the filter of, with when it was
+    /// last asked for
+    missing: HashMap<Sha256dHash, u64>,
+    /// The peer last asked for filter headers, and when
+    syncing: Option<(String, u64)>,
+    /// Filter headers a peer sent for the blocks from a height on, held
+    /// until another peer sends the same
+    pending: Option<(String, u32, CFHeaders)>,
+}
+
+impl FilterIndex {
+    /// Open the filter store in `dir` and the filter header log at
+    /// `headers_path`, dropping filter headers that are no longer on the
+    /// best chain
+    pub fn open(dir: &str, headers_path: &str, blockchain: &Blockchain, network: Network)
+                -> Result<FilterIndex, String> {
+        let mut index = FilterIndex {
+            filters: try!(FilterStore::open(dir)),
+            headers: try!(FilterHeaderStore::open(headers_path)),
+            missing: HashMap::new(),
+            syncing: None,
+            pending: None,
+        };
+
+        let genesis = genesis_block(network);
+        let on_best_chain = blockchain.iter(genesis.bitcoin_hash())
+            .zip(index.headers.entries.iter())
+            .take_while(|&(block_node_ref, entry)|
+                        block_node_ref.block.bitcoin_hash() == entry.block_hash)
+            .count() as u32;
+        if on_best_chain < index.headers.len() {
+            info!("db", headers = index.headers.len() - on_best_chain;
+                  "Dropping filter headers no longer on the best chain");
+            try!(index.headers.truncate(on_best_chain));
+        }
+
+        if index.headers.len() == 0 {
+            let genesis = WitnessBlock::from_block(genesis);
+            let filter = basic_filter(&genesis, &NoOutputs).unwrap();
+            try!(index.filters.put(&genesis.bitcoin_hash(), &filter[..]));
+            try!(index.headers.append(&[(genesis.bitcoin_hash(),
+                                         Sha256dHash::from_data(&filter[..]))]));
+        }
+        Ok(index)
+    }
+
+    pub fn has_filter(&self, block_hash: &Sha256dHash) -> bool {
+        self.filters.get(block_hash).is_some()
+    }
+
+    /// Compute and store the filter of a block entering the retention
+    /// window, or note that it must come from a peer
+    pub fn add_block(&mut self, block: &WitnessBlock, source: &UtxoSource)
+                     -> Result<(), String> {
+        let block_hash = block.bitcoin_hash();
+        match basic_filter(block, source) {
+            Some(filter) => self.filters.put(&block_hash, &filter[..]),
+            None => {
+                self.missing.insert(block_hash, 0);
+                Ok(())
+            },
+        }
+    }
+
+    /// Drop the filter of a block leaving the retention window
+    pub fn remove_block(&mut self, block_hash: &Sha256dHash) -> Result<(), String> {
+        self.missing.remove(block_hash);
+        self.filters.remove(block_hash)
+    }
+
+    /// Drop the filter headers of blocks above `fork_height`, which left
+    /// the best chain
+    pub fn disconnect(&mut self, fork_height: u32) -> Result<(), String> {
+        if fork_height + 1 < self.headers.len() {
+            self.pending = None;
+            try!(self.headers.truncate(fork_height + 1));
+        }
+        Ok(())
+    }
+
+    /// Chain the filters we have onto the filter headers, for as long as
+    /// the best chain's next block has one
+    pub fn extend(&mut self, blockchain: &Blockchain) -> Result<(), String> {
+        let next = self.headers.len();
+        // hashing every header behind the tip would be wasted while the
+        // filter headers are far behind, so hash only as far as we get
+        let mut block_node_refs = blockchain.rev_iter(blockchain.best_tip_hash())
+            .take_while(|block_node_ref| block_node_ref.height >= next)
+            .collect::<Vec<_>>();
+        block_node_refs.reverse();
+
+        let mut found = vec![];
+        for block_node_ref in block_node_refs {
+            let block_hash = block_node_ref.block.bitcoin_hash();
+            match self.filters.get(&block_hash) {
+                Some(filter) => found.push((block_hash, Sha256dHash::from_data(&filter[..]))),
+                None => break,
+            }
+        }
+        if found.is_empty() {
+            return Ok(());
+        }
+        self.headers.append(&found[..])
+    }
+
+    /// Messages asking filter-serving peers for the filter headers we
+    /// lack, and the filters of retained blocks we couldn't compute
+    pub fn requests(&mut self, blockchain: &Blockchain, peers: &[String], now: u64)
+                    -> Vec<(String, PeerMessage)> {
+        let mut requests = vec![];
+        let mut rng = rand::thread_rng();
+        let peer = match rng.choose(peers) {
+            Some(peer) => peer.clone(),
+            None => return requests,
+        };
+
+        let next = self.headers.len();
+        let tip = best_height(blockchain);
+        let stale = match self.pending {
+            Some((_, start_height, ref cfheaders)) =>
+                start_height != next ||
+                best_chain_hash_at(blockchain, next + cfheaders.filter_hashes.len() as u32 - 1)
+                    != Some(cfheaders.stop_hash),
+            None => false,
+        };
+        if stale {
+            self.pending = None;
+        }
+        let idle = self.syncing.as_ref()
+            .map_or(true, |&(_, asked)| asked + FILTER_REQUEST_TIMEOUT <= now);
+        if next <= tip && idle {
+            // headers a peer sent are only taken once another peer agrees,
+            // so ask a different peer for the same ones
+            let request = match self.pending {
+                Some((ref first, _, ref cfheaders)) => {
+                    let others = peers.iter()
+                        .filter(|&peer| peer != first)
+                        .collect::<Vec<&String>>();
+                    rng.choose(&others[..])
+                        .map(|&other| (other.clone(), cfheaders.stop_hash))
+                },
+                None => best_chain_hash_at(blockchain, (next + MAX_GETCFHEADERS - 1).min(tip))
+                    .map(|stop_hash| (peer.clone(), stop_hash)),
+            };
+            if let Some((peer, stop_hash)) = request {
+                requests.push((peer.clone(), PeerMessage::GetCFHeaders(FilterRequest {
+                    filter_type: BASIC_FILTER,
+                    start_height: next,
+                    stop_hash: stop_hash,
+                })));
+                self.syncing = Some((peer, now));
+            }
+        }
+
+        for (block_hash, asked) in self.missing.iter_mut() {
+            if *asked + FILTER_REQUEST_TIMEOUT > now {
+                continue;
+            }
+            // the filter can only be checked once its filter hash is known
+            if let Some(height) = self.headers.height_of(block_hash) {
+                requests.push((peer.clone(), PeerMessage::GetCFilters(FilterRequest {
+                    filter_type: BASIC_FILTER,
+                    start_height: height,
+                    stop_hash: *block_hash,
+                })));
+                *asked = now;
+            }
+        }
+        requests
+    }
+
+    /// Chain filter headers from a peer onto ours once a second peer has
+    /// sent the same. Our own filters are taken over the peer's filter
+    /// hashes, and an Err means the peer contradicted them or its own
+    /// earlier filter headers.
+    pub fn add_headers(&mut self, ip: &String, blockchain: &Blockchain, cfheaders: CFHeaders)
+                       -> Result<(), String> {
+        self.syncing = None;
+        let count = cfheaders.filter_hashes.len() as u32;
+        if cfheaders.filter_type != BASIC_FILTER || count == 0 {
+            return Ok(());
+        }
+        let stop_height = match blockchain.get_block(cfheaders.stop_hash) {
+            Some(block_node_ref) => block_node_ref.height,
+            None => return Ok(()),
+        };
+        if count > stop_height + 1 || stop_height + 1 - count != self.headers.len() ||
+            best_chain_hash_at(blockchain, stop_height) != Some(cfheaders.stop_hash) {
+            // an answer we no longer need, after a reorg or our own filters
+            return Ok(());
+        }
+        let prev_header = self.headers.entries.last().unwrap().header;
+        if cfheaders.prev_filter_header != prev_header {
+            return Err(format!("Filter header {} before block {} disagrees with ours",
+                               cfheaders.prev_filter_header.be_hex_string(),
+                               self.headers.len()));
+        }
+
+        let mut block_hashes = blockchain.rev_iter(cfheaders.stop_hash)
+            .take(count as usize)
+            .map(|block_node_ref| block_node_ref.block.bitcoin_hash())
+            .collect::<Vec<Sha256dHash>>();
+        block_hashes.reverse();
+        let mut hashes = Vec::with_capacity(count as usize);
+        for (block_hash, &filter_hash) in block_hashes.into_iter()
+            .zip(cfheaders.filter_hashes.iter()) {
+            if let Some(filter) = self.filters.get(&block_hash) {
+                if Sha256dHash::from_data(&filter[..]) != filter_hash {
+                    return Err(format!("Filter hash for block {} disagrees with ours",
+                                       block_hash.be_hex_string()));
+                }
+            }
+            hashes.push((block_hash, filter_hash));
+        }
+
+        let next = self.headers.len();
+        match self.pending.take() {
+            Some((ref first, start_height, ref earlier))
+                if first != ip && start_height == next &&
+                earlier.stop_hash == cfheaders.stop_hash => {
+                if earlier.filter_hashes != cfheaders.filter_hashes {
+                    // we can't tell which of them is lying, so start over
+                    warn!("sync", peer = ip, other = first;
+                          "Peers disagree on filter headers from height {}", next);
+                    return Ok(());
+                }
+            },
+            _ => {
+                debug!("sync", peer = ip, height = next;
+                       "Holding filter headers until another peer confirms them");
+                self.pending = Some((ip.clone(), next, cfheaders));
+                return Ok(());
+            },
+        }
+        if let Err(e) = self.headers.append(&hashes[..]).and_then(|_| self.extend(blockchain)) {
+            error!("db", "Could not store filter headers: {}", e);
+        }
+        debug!("sync", height = self.headers.len() - 1; "Filter headers synced");
+        Ok(())
+    }
+
+    /// Store a filter we asked a peer for, if it matches its filter hash
+    pub fn add_filter(&mut self, cfilter: CFilter) -> Result<(), String> {
+        if cfilter.filter_type != BASIC_FILTER || !self.missing.contains_key(&cfilter.block_hash) {
+            return Ok(());
+        }
+        let filter_hash = match self.headers.height_of(&cfilter.block_hash)
+            .and_then(|height| self.headers.get(height)) {
+            Some(entry) => entry.filter_hash,
+            None => return Ok(()),
+        };
+        if Sha256dHash::from_data(&cfilter.filter[..]) != filter_hash {
+            return Err(format!("Filter for block {} does not match its filter header",
+                               cfilter.block_hash.be_hex_string()));
+        }
+        self.missing.remove(&cfilter.block_hash);
+        if let Err(e) = self.filters.put(&cfilter.block_hash, &cfilter.filter[..]) {
+            error!("db", block = cfilter.block_hash.be_hex_string(); "Could not store filter: {}", e);
+        }
+        Ok(())
+    }
+
+    /// Height of the stop block of a request, which must be on the best
+    /// chain and no more than `limit` blocks after `start_height`
+    fn stop_height(&self, blockchain: &Blockchain, filter_type: u8, start_height: u32,
+                   stop_hash: &Sha256dHash, limit: u32) -> Result<u32, String> {
+        if filter_type != BASIC_FILTER {
+            return Err(format!("Unknown filter type {}", filter_type));
+        }
+        let stop_height = match blockchain.get_block(*stop_hash) {
+            Some(block_node_ref) => block_node_ref.height,
+            None => return Err(format!("Unknown block {}", stop_hash.be_hex_string())),
+        };
+        if best_chain_hash_at(blockchain, stop_height) != Some(*stop_hash) {
+            return Err(format!("Block {} is not on the best chain", stop_hash.be_hex_string()));
+        }
+        if start_height > stop_height || stop_height - start_height >= limit {
+            return Err(format!("Bad range from height {} to {}", start_height, stop_height));
+        }
+        Ok(stop_height)
+    }
+
+    /// Answer a `getcfilters`
+    pub fn cfilters(&self, blockchain: &Blockchain, request: &FilterRequest)
+                    -> Result<Vec<CFilter>, String> {
+        let stop_height = try!(self.stop_height(blockchain, request.filter_type,
+                                                request.start_height, &request.stop_hash,
+                                                MAX_GETCFILTERS));
+        let mut block_hashes = blockchain.rev_iter(request.stop_hash)
+            .take((stop_height - request.start_height + 1) as usize)
+            .map(|block_node_ref| block_node_ref.block.bitcoin_hash())
+            .collect::<Vec<Sha256dHash>>();
+        block_hashes.reverse();
+        block_hashes.into_iter()
+            .map(|block_hash| match self.filters.get(&block_hash) {
+                Some(filter) => Ok(CFilter {
+                    filter_type: BASIC_FILTER,
+                    block_hash: block_hash,
+                    filter: filter,
+                }),
+                None => Err(format!("No filter for block {}", block_hash.be_hex_string())),
+            })
+            .collect()
+    }
+
+    /// Answer a `getcfheaders`
+    pub fn cfheaders(&self, blockchain: &Blockchain, request: &FilterRequest)
+                     -> Result<CFHeaders, String> {
+        let stop_height = try!(self.stop_height(blockchain, request.filter_type,
+                                                request.start_height, &request.stop_hash,
+                                                MAX_GETCFHEADERS));
+        if stop_height >= self.headers.len() {
+            return Err(format!("No filter headers past height {}", self.headers.len() - 1));
+        }
+        let prev_filter_header = match request.start_height {
+            0 => Default::default(),
+            height => self.headers.get(height - 1).unwrap().header,
+        };
+        Ok(CFHeaders {
+            filter_type: BASIC_FILTER,
+            stop_hash: request.stop_hash,
+            prev_filter_header: prev_filter_header,
+            filter_hashes: self.headers.entries[request.start_height as usize..
+                                                stop_height as usize + 1].iter()
+                .map(|entry| entry.filter_hash)
+                .collect(),
+        })
+    }
+
+    /// Answer a `getcfcheckpt`
+    pub fn cfcheckpt(&self, blockchain: &Blockchain, request: &CheckptRequest)
+                     -> Result<CFCheckpt, String> {
+        let stop_height = try!(self.stop_height(blockchain, request.filter_type, 0,
+                                                &request.stop_hash, u32::max_value()));
+        let count = stop_height / CHECKPOINT_INTERVAL;
+        if count * CHECKPOINT_INTERVAL >= self.headers.len() {
+            return Err(format!("No filter headers past height {}", self.headers.len() - 1));
+        }
+        Ok(CFCheckpt {
+            filter_type: BASIC_FILTER,
+            stop_hash: request.stop_hash,
+            filter_headers: (1..count + 1)
+                .map(|i| self.headers.get(i * CHECKPOINT_INTERVAL).unwrap().header)
+                .collect(),
+        })
+    }
+
+    /// A block's filter, and its filter header if it's on the best chain
+    pub fn block_filter(&self, block_hash: &Sha256dHash)
+                        -> Option<(Vec<u8>, Option<Sha256dHash>)> {
+        self.filters.get(block_hash).map(|filter| {
+            let header = self.headers.height_of(block_hash)
+                .and_then(|height| self.headers.get(height))
+                .map(|entry| entry.header);
+            (filter, header)
+        })
+    }
+}
+
+impl<S: SimpleEncoder> ConsensusEncodable<S> for FilterRequest {
+    fn consensus_encode(&self, s: &mut S) -> Result<(), S::Error> {
+        try!(self.filter_type.consensus_encode(s));
+        try!(self.start_height.consensus_encode(s));
+        self.stop_hash.consensus_encode(s)
+    }
+}
+
+impl<D: SimpleDecoder> ConsensusDecodable<D> for FilterRequest {
+    fn consensus_decode(d: &mut D) -> Result<FilterRequest, D::Error> {
+        Ok(FilterRequest {
+            filter_type: try!(ConsensusDecodable::consensus_decode(d)),
+            start_height: try!(ConsensusDecodable::consensus_decode(d)),
+            stop_hash: try!(ConsensusDecodable::consensus_decode(d)),
+        })
+    }
+}
+
+impl<S: SimpleEncoder> ConsensusEncodable<S> for CheckptRequest {
+    fn consensus_encode(&self, s: &mut S) -> Result<(), S::Error> {
+        try!(self.filter_type.consensus_encode(s));
+        self.stop_hash.consensus_encode(s)
+    }
+}
+
+impl<D: SimpleDecoder> ConsensusDecodable<D> for CheckptRequest {
+    fn consensus_decode(d: &mut D) -> Result<CheckptRequest, D::Error> {
+        Ok(CheckptRequest {
+            filter_type: try!(ConsensusDecodable::consensus_decode(d)),
+            stop_hash: try!(ConsensusDecodable::consensus_decode(d)),
+        })
+    }
+}
+
+impl<S: SimpleEncoder> ConsensusEncodable<S> for CFilter {
+    fn consensus_encode(&self, s: &mut S) -> Result<(), S::Error> {
+        try!(self.filter_type.consensus_encode(s));
+        try!(self.block_hash.consensus_encode(s));
+        self.filter.consensus_encode(s)
+    }
+}
+
+impl<D: SimpleDecoder> ConsensusDecodable<D> for CFilter {
+    fn consensus_decode(d: &mut D) -> Result<CFilter, D::Error> {
+        Ok(CFilter {
+            filter_type: try!(ConsensusDecodable::consensus_decode(d)),
+            block_hash: try!(ConsensusDecodable::consensus_decode(d)),
+            filter: try!(ConsensusDecodable::consensus_decode(d)),
+        })
+    }
+}
+
+impl<S: SimpleEncoder> ConsensusEncodable<S> for CFHeaders {
+    fn consensus_encode(&self, s: &mut S) -> Result<(), S::Error> {
+        try!(self.filter_type.consensus_encode(s));
+        try!(self.stop_hash.consensus_encode(s));
+        try!(self.prev_filter_header.consensus_encode(s));
+        self.filter_hashes.consensus_encode(s)
+    }
+}
+
+impl<D: SimpleDecoder> ConsensusDecodable<D> for CFHeaders {
+    fn consensus_decode(d: &mut D) -> Result<CFHeaders, D::Error> {
+        let filter_type = try!(ConsensusDecodable::consensus_decode(d));
+        let stop_hash = try!(ConsensusDecodable::consensus_decode(d));
+        let prev_filter_header = try!(ConsensusDecodable::consensus_decode(d));
+        let VarInt(count) = try!(ConsensusDecodable::consensus_decode(d));
+        if count > MAX_GETCFHEADERS as u64 {
+            return Err(d.error(format!("Too many filter hashes: {}", count)));
+        }
+        let mut filter_hashes = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            filter_hashes.push(try!(ConsensusDecodable::consensus_decode(d)));
+        }
+        Ok(CFHeaders {
+            filter_type: filter_type,
+            stop_hash: stop_hash,
+            prev_filter_header: prev_filter_header,
+            filter_hashes: filter_hashes,
+        })
+    }
+}
+
+impl<S: SimpleEncoder> ConsensusEncodable<S> for CFCheckpt {
+    fn consensus_encode(&self, s: &mut S) -> Result<(), S::Error> {
+        try!(self.filter_type.consensus_encode(s));
+        try!(self.stop_hash.consensus_encode(s));
+        self.filter_headers.consensus_encode(s)
+    }
+}
+
+impl<D: SimpleDecoder> ConsensusDecodable<D> for CFCheckpt {
+    fn consensus_decode(d: &mut D) -> Result<CFCheckpt, D::Error> {
+        Ok(CFCheckpt {
+            filter_type: try!(ConsensusDecodable::consensus_decode(d)),
+            stop_hash: try!(ConsensusDecodable::consensus_decode(d)),
+            filter_headers: try!(ConsensusDecodable::consensus_decode(d)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rustc_serialize::hex::{FromHex, ToHex};
+
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::TxOut;
+    use bitcoin::network::serialize::deserialize;
+    use bitcoin::util::hash::Sha256dHash;
+
+    use verify::UtxoSource;
+    use witness::WitnessBlock;
+
+    use super::{basic_filter, filter_header};
+
+    /// The scripts of the outputs a block's inputs spend
+    struct Prevouts(HashMap<(Sha256dHash, u32), Vec<u8>>);
+
+    impl UtxoSource for Prevouts {
+        fn prevout(&self, txid: &Sha256dHash, index: u32) -> Option<TxOut> {
+            self.0.get(&(*txid, index)).map(|script| TxOut {
+                value: 0,
+                script_pubkey: Script::from(script.clone()),
+            })
+        }
+    }
+
+    /// A block, the scripts its inputs spend in order, and the basic
+    /// filter and filter headers BIP158 gives for it
+    struct Bip158Vector {
+        block: &'static str,
+        prev_scripts: &'static [&'static str],
+        prev_header: &'static str,
+        filter: &'static str,
+        header: &'static str,
+    }
+
+    const BIP158_VECTORS: [Bip158Vector; 6] = [
+        // testnet block 0: genesis block
+        Bip158Vector {
+            block:
+                "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a\
+                 7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4adae5494dffff001d1aa4ae180101\
+                 000000010000000000000000000000000000000000000000000000000000000000000000ffffffff4d\
+                 04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f7220\
+                 6f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73ffffffff01\
+                 00f2052a01000000434104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61\
+                 deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac00000000",
+            prev_scripts: &[],
+            prev_header: "0000000000000000000000000000000000000000000000000000000000000000",
+            filter:
+                "019dfca8",
+            header: "21584579b7eb08997773e5aeff3a7f932700042d0ed2a6129012b7d7ae81b750",
+        },
+        // testnet block 15007: tx has non-standard OP_RETURN output followed by opcodes
+        Bip158Vector {
+            block:
+                "0100000002394092aa378fe35d7e9ac79c869b975c4de4374cd75eb5484b0e1e00000000eb9b8670ab\
+                 d44ad6c55cee18e3020fb0c6519e7004b01a16e9164867531b67afc33bc94fffff001d123f10050101\
+                 000000010000000000000000000000000000000000000000000000000000000000000000ffffffff0e\
+                 04c33bc94f0115062f503253482fffffffff0100f2052a01000000232103f268e9ae07e0f8cb2f6e90\
+                 1d87c510d650b97230c0365b021df8f467363cafb1ac00000000",
+            prev_scripts: &[],
+            prev_header: "18b5c2b0146d2d09d24fb00ff5b52bd0742f36c9e65527abdb9de30c027a4748",
+            filter:
+                "013c3710",
+            header: "07384b01311867949e0c046607c66b7a766d338474bb67f66c8ae9dbd454b20e",
+        },
+        // testnet block 49291: tx pays to empty output script
+        Bip158Vector {
+            block:
+                "02000000abfaf47274223ca2fea22797e44498240e482cb4c2f2baea088962f800000000604b5b52c3\
+                 2305b15d7542071d8b04e750a547500005d4010727694b6e72a776e55d0d51ffff001d211806480201\
+                 000000010000000000000000000000000000000000000000000000000000000000000000ffffffff0d\
+                 038bc0000102062f503253482fffffffff01a078072a01000000232102971dd6034ed0cf52450b608d\
+                 196c07d6345184fcb14deb277a6b82d526a6163dac0000000001000000081cefd96060ecb1c4fbe675\
+                 ad8a4f8bdc61d634c52b3a1c4116dee23749fe80ff000000009300493046022100866859c21f306538\
+                 152e83f115bcfbf59ab4bb34887a88c03483a5dff9895f96022100a6dfd83caa609bf0516debc2bf65\
+                 c3df91813a4842650a1858b3f61cfa8af249014730440220296d4b818bb037d0f83f9f7111665f4953\
+                 2dfdcbec1e6b784526e9ac4046eaa602204acf3a5cb2695e8404d80bf49ab04828bcbe6fc31d25a284\
+                 4ced7a8d24afbdff01ffffffff1cefd96060ecb1c4fbe675ad8a4f8bdc61d634c52b3a1c4116dee237\
+                 49fe80ff020000009400483045022100e87899175991aa008176cb553c6f2badbb5b741f328c9845fc\
+                 ab89f8b18cae2302200acce689896dc82933015e7230e5230d5cff8a1ffe82d334d60162ac2c5b0c96\
+                 01493046022100994ad29d1e7b03e41731a4316e5f4992f0d9b6e2efc40a1ccd2c949b461175c50221\
+                 0099b69fdc2db00fbba214f16e286f6a49e2d8a0d5ffc6409d87796add475478d601ffffffff1e4a6d\
+                 2d280ea06680d6cf8788ac90344a9c67cca9b06005bbd6d3f6945c8272010000009500493046022100\
+                 a27400ba52fd842ce07398a1de102f710a10c5599545e6c95798934352c2e4df022100f6383b0b14c9\
+                 f64b6718139f55b6b9494374755b86bae7d63f5d3e583b57255a01493046022100fdf543292f34e1ee\
+                 b1703b264965339ec4a450ec47585009c606b3edbc5b617b022100a5fbb1c8de8aaaa582988cdb2362\
+                 2838e38de90bebcaab3928d949aa502a65d401ffffffff1e4a6d2d280ea06680d6cf8788ac90344a9c\
+                 67cca9b06005bbd6d3f6945c8272020000009400493046022100ac626ac3051f875145b4fe4cfe089e\
+                 a895aac73f65ab837b1ac30f5d875874fa022100bc03e79fa4b7eb707fb735b95ff6613ca33adeaf3a\
+                 0607cdcead4cfd3b51729801483045022100b720b04a5c5e2f61b7df0fcf334ab6fea167b7aaede569\
+                 5d3f7c6973496adbf1022043328c4cc1cdc3e5db7bb895ccc37133e960b2fd3ece98350f774596badb\
+                 387201ffffffff23a8733e349c97d6cd90f520fdd084ba15ce0a395aad03cd51370602bb9e5db30100\
+                 00004a00483045022100e8556b72c5e9c0da7371913a45861a61c5df434dfd962de7b23848e1a28c86\
+                 ca02205d41ceda00136267281be0974be132ac4cda1459fe2090ce455619d8b91045e901ffffffff68\
+                 56d609b881e875a5ee141c235e2a82f6b039f2b9babe82333677a5570285a6000000006a4730440220\
+                 40a1c631554b8b210fbdf2a73f191b2851afb51d5171fb53502a3a040a38d2c0022040d11cf6e7b41f\
+                 e1b66c3d08f6ada1aee07a047cb77f242b8ecc63812c832c9a012102bcfad931b502761e452962a597\
+                 6c79158a0f6d307ad31b739611dac6a297c256ffffffff6856d609b881e875a5ee141c235e2a82f6b0\
+                 39f2b9babe82333677a5570285a601000000930048304502205b109df098f7e932fbf71a45869c3f80\
+                 323974a826ee2770789eae178a21bfc8022100c0e75615e53ee4b6e32b9bb5faa36ac539e9c05fa2ae\
+                 6b6de5d09c08455c8b9601483045022009fb7d27375c47bea23b24818634df6a54ecf72d52e0c1268f\
+                 b2a2c84f1885de022100e0ed4f15d62e7f537da0d0f1863498f9c7c0c0a4e00e4679588c8d1a9eb20b\
+                 b801ffffffffa563c3722b7b39481836d5edfc1461f97335d5d1e9a23ade13680d0e2c1c371f030000\
+                 006c493046022100ecc38ae2b1565643dc3c0dad5e961a5f0ea09cab28d024f92fa05c922924157e02\
+                 2100ebc166edf6fbe4004c72bfe8cf40130263f98ddff728c8e67b113dbd621906a601210211a4ed24\
+                 1174708c07206601b44a4c1c29e5ad8b1f731c50ca7e1d4b2a06dc1fffffffff02d0223a0000000000\
+                 1976a91445db0b779c0b9fa207f12a8218c94fc77aff504588ac80f0fa02000000000000000000",
+            prev_scripts: &[
+                "5221033423007d8f263819a2e42becaaf5b06f34cb09919e06304349d950668209eaed21021d69e2b6\
+                 8c3960903b702af7829fadcd80bd89b158150c85c4a75b2c8cb9c39452ae",
+                "52210279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f8179821021d69e2b6\
+                 8c3960903b702af7829fadcd80bd89b158150c85c4a75b2c8cb9c39452ae",
+                "522102a7ae1e0971fc1689bd66d2a7296da3a1662fd21a53c9e38979e0f090a375c12d21022adb6233\
+                 5f41eb4e27056ac37d462cda5ad783fa8e0e526ed79c752475db285d52ae",
+                "52210279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f8179821022adb6233\
+                 5f41eb4e27056ac37d462cda5ad783fa8e0e526ed79c752475db285d52ae",
+                "512103b9d1d0e2b4355ec3cdef7c11a5c0beff9e8b8d8372ab4b4e0aaf30e80173001951ae",
+                "76a9149144761ebaccd5b4bbdc2a35453585b5637b2f8588ac",
+                "522103f1848b40621c5d48471d9784c8174ca060555891ace6d2b03c58eece946b1a9121020ee5d32b\
+                 54d429c152fdc7b1db84f2074b0564d35400d89d11870f9273ec140c52ae",
+                "76a914f4fa1cc7de742d135ea82c17adf0bb9cf5f4fb8388ac",
+            ],
+            prev_header: "ed47705334f4643892ca46396eb3f4196a5e30880589e4009ef38eae895d4a13",
+            filter:
+                "0afbc2920af1b027f31f87b592276eb4c32094bb4d3697021b4c6380",
+            header: "b6d98692cec5145f67585f3434ec3c2b3030182e1cb3ec58b855c5c164dfaaa3",
+        },
+        // testnet block 180480: tx spends from empty output script
+        Bip158Vector {
+            block:
+                "020000006058aa080a655aa991a444bd7d1f2defd9a3bbe68aabb69030cf3b4e00000000d2e826bfd7\
+                 ef0beaa891a7eedbc92cd6a544a6cb61c7bdaa436762eb2123ef9790f5f552ffff001d0002c90f0501\
+                 000000010000000000000000000000000000000000000000000000000000000000000000ffffffff0e\
+                 0300c102024608062f503253482fffffffff01c0c6072a01000000232102e769e60137a4df6b0df8eb\
+                 d387cca44c4c57ae74cc0114a8e8317c8f3bfd85e9ac00000000010000000381a0802911a01ffb025c\
+                 4dea0bc77963e8c1bb46313b71164c53f72f37fe5248010000000151ffffffffc904b267833d215e21\
+                 28bd9575242232ac2bc311550c7fc1f0ef6f264b40d14c010000000151ffffffffdf0915666649dba8\
+                 1886519c531649b7b02180b4af67d6885e871299e9d5f775000000000151ffffffff0180817dcb0000\
+                 0000232103bb52138972c48a132fc1f637858c5189607dd0f7fe40c4f20f6ad65f2d389ba4ac000000\
+                 0001000000018da38b434fba82d66052af74fc5e4e94301b114d9bc03f819dc876398404c8b4010000\
+                 006c493046022100fe738b7580dc5fb5168e51fc61b5aed211125eb71068031009a22d9bbad752c502\
+                 2100be5086baa384d40bcab0fa586e4f728397388d86e18b66cc417dc4f7fa4f9878012103f2332994\
+                 55134caa2687bdf15cb0becdfb03bd0ff2ff38e65ec6b7834295c34fffffffff022ebc140000000000\
+                 1976a9147779b7fba1c1e06b717069b80ca170e8b04458a488ac9879c40f000000001976a9142a0307\
+                 cd925dbb66b534c4db33003dd18c57015788ac0000000001000000026139a62e3422a602de36c873a2\
+                 25c1d3ca5aeee598539ceecb9f0dc8d1ad0f83010000006b483045022100ad9f32b4a0a2ddc19b5a74\
+                 eba78123e57616f1b3cfd72ce68c03ea35a3dda1f002200dbd22aa6da17213df5e70dfc3b2611d40f7\
+                 0c98ed9626aa5e2cde9d97461f0a012103ddb295d2f1e8319187738fb4b230fdd9aa29d0e01647f69f\
+                 6d770b9ab24eea90ffffffff983c82c87cf020040d671956525014d5c2b28c6d948c85e1a522362c00\
+                 59eeae010000006b4830450221009ca544274c786d30a5d5d25e17759201ea16d3aedddf0b9e972124\
+                 6f7ef6b32e02202cfa5564b6e87dfd9fd98957820e4d4e6238baeb0f65fe305d91506bb13f5f4f0121\
+                 03c99113deac0d5d044e3ac0346abc02501542af8c8d3759f1382c72ff84e704f7ffffffff02c0c62d\
+                 00000000001976a914ae19d27efe12f5a886dc79af37ad6805db6f922d88ac70ce2000000000001976\
+                 a9143b8d051d37a07ea1042067e93efe63dbf73920b988ac000000000100000002be566e8cd9933f0c\
+                 75c4a82c027f7d0c544d5c101d0607ef6ae5d07b98e7f1dc000000006b483045022036a8cdfd5ea7eb\
+                 c06c2bfb6e4f942bbf9a1caeded41680d11a3a9f5d8284abad022100cacb92a5be3f39e8bc14db1710\
+                 910ef7b395fa1e18f45d41c28d914fcdde33be012102bf59abf110b5131fae0a3ce1ec379329b4c896\
+                 a6ae5d443edb68529cc2bc7816ffffffff96cf67645b76ceb23fe922874847456a15feee1655082ff3\
+                 2d25a6bf2c0dfc90000000006a47304402203471ca2001784a5ac0abab583581f2613523da47ec5f53\
+                 df833c117b5abd81500220618a2847723d57324f2984678db556dbca1a72230fc7e39df04c2239942b\
+                 a942012102925c9794fd7bb9f8b29e207d5fc491b1150135a21f505041858889fa4edf436fffffffff\
+                 026c840f00000000001976a914797fb8777d7991d8284d88bfd421ce520f0f843188ac00ca9a3b0000\
+                 00001976a9146d10f3f592699265d10b106eda37c3ce793f7a8588ac00000000",
+            prev_scripts: &[
+                "",
+                "",
+                "",
+                "76a9142903b138c24be9e070b3e73ec495d77a204615e788ac",
+                "76a91433a1941fd9a37b9821d376f5a51bd4b52fa50e2888ac",
+                "76a914e4374e8155d0865742ca12b8d4d14d41b57d682f88ac",
+                "76a914001fa7459a6cfc64bdc178ba7e7a21603bb2568f88ac",
+                "76a914f6039952bc2b307aeec5371bfb96b66078ec17f688ac",
+            ],
+            prev_header: "d34ef98386f413769502808d4bac5f20f8dfd5bffc9eedafaa71de0eb1f01489",
+            filter:
+                "0db414c859a07e8205876354a210a75042d0463404913d61a8e068e58a3ae2aa080026",
+            header: "c582d51c0ca365e3fcf36c51cb646d7f83a67e867cb4743fd2128e3e022b700c",
+        },
+        // testnet block 1263442: includes witness data
+        Bip158Vector {
+            block:
+                "000000201c8d1a529c39a396db2db234d5ec152fa651a2872966daccbde028b400000000083f144926\
+                 79151dbfaa1a825ef4c18518e780c1f91044180280a7d33f4a98ff5f45765aaddc001d38333b9a0201\
+                 0000000001010000000000000000000000000000000000000000000000000000000000000000ffffff\
+                 ff230352471300fe5f45765afe94690a000963676d696e6572343208000000000000000000ffffffff\
+                 024423a804000000001976a914f2c25ac3d59f3d674b1d1d0a25c27339aaac0ba688ac000000000000\
+                 0000266a24aa21a9edcb26cb3052426b9ebb4d19c819ef87c19677bbf3a7c46ef0855bd1b2abe83491\
+                 0120000000000000000000000000000000000000000000000000000000000000000000000000020000\
+                 00000101d20978463906ba4ff5e7192494b88dd5eb0de85d900ab253af909106faa22cc50100000000\
+                 04000000014777ff000000000016001446c29eabe8208a33aa1023c741fa79aa92e881ff0347304402\
+                 207d7ca96134f2bcfdd6b536536fdd39ad17793632016936f777ebb32c22943fda02206014d2fb8a6a\
+                 a58279797f861042ba604ebd2f8f61e5bddbd9d3be5a245047b201004b632103eeaeba7ce5dc247022\
+                 1e9517fb498e8d6bd4e73b85b8be655196972eb9ccd5566754b2752103a40b74d43df244799d041f32\
+                 ce1ad515a6cd99501701540e38750d883ae21d3a68ac00000000",
+            prev_scripts: &[
+                "002027a5000c7917f785d8fc6e5a55adfca8717ecb973ebb7743849ff956d896a7ed",
+            ],
+            prev_header: "31d66d516a9eda7de865df29f6ef6cb8e4bf9309e5dac899968a9a62a5df61e3",
+            filter:
+                "0385acb4f0fe889ef0",
+            header: "4e6d564c2a2452065c205dd7eb2791124e0c4e0dbb064c410c24968572589dec",
+        },
+        // testnet block 1414221: empty data
+        Bip158Vector {
+            block:
+                "000000204ea88307a7959d8207968f152bedca5a93aefab253f1fb2cfb032a400000000070cebb14ec\
+                 6dbc27a9dfd066d9849a4d3bac5f674665f73a5fe1de01a022a0c851fda85bf05f4c19a779d1450102\
+                 000000010000000000000000000000000000000000000000000000000000000000000000ffffffff18\
+                 034d94154d696e6572476174653030310d000000f238f401ffffffff01c817a8040000000000000000\
+                 00",
+            prev_scripts: &[],
+            prev_header: "5e5e12d90693c8e936f01847859404c67482439681928353ca1296982042864e",
+            filter:
+                "00",
+            header: "021e8882ef5a0ed932edeebbecfeda1d7ce528ec7b3daa27641acf1189d7b5dc",
+        },
+
+    ];
+
+    #[test]
+    fn bip158_basic_filters() {
+        for vector in BIP158_VECTORS.iter() {
+            let block: WitnessBlock = deserialize(&vector.block.from_hex().unwrap()[..]).unwrap();
+            let mut prevouts = Prevouts(HashMap::new());
+            let inputs = block.txdata.iter().skip(1).flat_map(|wtx| wtx.tx.input.iter());
+            for (input, script) in inputs.zip(vector.prev_scripts.iter()) {
+                prevouts.0.insert((input.prev_hash, input.prev_index), script.from_hex().unwrap());
+            }
+            let filter = basic_filter(&block, &prevouts).unwrap();
+            assert_eq!(filter.to_hex(), vector.filter);
+            let prev_header = Sha256dHash::from_hex(vector.prev_header).unwrap();
+            let header = filter_header(&Sha256dHash::from_data(&filter[..]), &prev_header);
+            assert_eq!(header.be_hex_string(), vector.header);
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Largest request body we'll read
+const MAX_BODY_SIZE: usize = 8 * 1024 * 1024;
+/// Longest request line or header line we'll read, with its line ending
+const MAX_LINE_LENGTH: usize = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+/// Connections served at once by each server; more are answered with 503
+const MAX_CONNECTIONS: usize = 64;
+const READ_TIMEOUT: u64 = 30;
+
+pub struct Request {
+    pub method: String,
+    /// Path and query string, as sent
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Value of a header, matching its name case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|&&(ref key, _)| key.to_lowercase() == name.to_lowercase())
+            .map(|&(_, ref value)| value.as_str())
+    }
+}
+
+/// A request we won't serve, with the status to answer it with
+#[derive(Debug)]
+pub struct BadRequest {
+    pub status: u16,
+    pub message: String,
+}
+
+impl BadRequest {
+    fn new(status: u16, message: String) -> BadRequest {
+        BadRequest {
+            status: status,
+            message: message,
+        }
+    }
+
+    pub fn response(&self) -> Response {
+        Response::text(self.status, &self.message)
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: Vec<u8>) -> Response {
+        Response {
+            status: status,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body: body,
+        }
+    }
+
+    pub fn json(status: u16, body: String) -> Response {
+        Response::new(status, "application/json", body.into_bytes())
+    }
+
+    pub fn text(status: u16, body: &str) -> Response {
+        Response::new(status, "text/plain", body.as_bytes().to_vec())
+    }
+}
+
+/// Counts a connection as open until it's dropped
+struct OpenConnection(Arc<AtomicUsize>);
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Accept connections on `address` in a new thread, answering one request
+/// per connection with `handler`. Returns the address listened on, which
+/// tells the port picked for port 0.
+pub fn serve<H>(address: &str, handler: H) -> Result<SocketAddr, String>
+    where H: Fn(Request) -> Response + Send + Sync + 'static {
+    let listener = match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(e) => return Err(format!("Could not listen on {}: {:?}", address, e)),
+    };
+    let local_address = match listener.local_addr() {
+        Ok(local_address) => local_address,
+        Err(e) => return Err(format!("Could not listen on {}: {:?}", address, e)),
+    };
+    let handler = Arc::new(handler);
+    let open = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(mut stream) => {
+                    if open.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                        open.fetch_sub(1, Ordering::SeqCst);
+                        debug!("api", "Refusing HTTP connection, {} are open", MAX_CONNECTIONS);
+                        let response = Response::text(503, "Too many connections");
+                        let _ = write_response(&mut stream, &response);
+                        continue;
+                    }
+                    let connection = OpenConnection(open.clone());
+                    let handler = handler.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = handle_connection(stream, &*handler) {
+                            debug!("api", "HTTP connection failed: {}", e);
+                        }
+                        drop(connection);
+                    });
+                },
+                Err(e) => warn!("api", "Failed to accept HTTP connection: {:?}", e),
+            }
+        }
+    });
+    Ok(local_address)
+}
+
+fn handle_connection<H>(stream: TcpStream, handler: &H) -> Result<(), String>
+    where H: Fn(Request) -> Response {
+    if let Err(e) = stream.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT))) {
+        return Err(format!("Setting read timeout: {:?}", e));
+    }
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(e) => return Err(format!("Cloning stream: {:?}", e)),
+    };
+    let response = match read_request(stream) {
+        Ok(request) => handler(request),
+        Err(e) => e.response(),
+    };
+    write_response(&mut writer, &response)
+}
+
+/// Read a line of the request head, refusing one longer than
+/// MAX_LINE_LENGTH with `too_long`
+fn read_line<R: BufRead>(reader: &mut R, what: &str, too_long: u16)
+                         -> Result<String, BadRequest> {
+    let mut line = String::new();
+    if let Err(e) = reader.by_ref().take(MAX_LINE_LENGTH as u64 + 1).read_line(&mut line) {
+        return Err(BadRequest::new(400, format!("Reading {}: {:?}", what, e)));
+    }
+    if line.len() > MAX_LINE_LENGTH {
+        return Err(BadRequest::new(too_long, format!("{} too long", what)));
+    }
+    Ok(line)
+}
+
+pub fn read_request(stream: TcpStream) -> Result<Request, BadRequest> {
+    let mut reader = BufReader::new(stream);
+
+    let request_line = try!(read_line(&mut reader, "Request line", 414));
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => return Err(BadRequest::new(400, "Malformed request line".to_string())),
+    };
+
+    let mut headers: Vec<(String, String)> = vec![];
+    loop {
+        let line = try!(read_line(&mut reader, "Header", 431));
+        let line = line.trim_right();
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(BadRequest::new(431, "Too many headers".to_string()));
+        }
+        match line.find(':') {
+            Some(i) => headers.push((line[..i].trim().to_string(),
+                                     line[i + 1..].trim().to_string())),
+            None => return Err(BadRequest::new(400, format!("Malformed header {}", line))),
+        }
+    }
+
+    let mut request = Request {
+        method: method,
+        path: path,
+        headers: headers,
+        body: vec![],
+    };
+    let length = match request.header("Content-Length") {
+        Some(length) => match length.parse::<usize>() {
+            Ok(length) => length,
+            Err(_) => return Err(BadRequest::new(400, "Bad Content-Length".to_string())),
+        },
+        None => 0,
+    };
+    if length > MAX_BODY_SIZE {
+        return Err(BadRequest::new(413, "Request body too large".to_string()));
+    }
+    let mut body = vec![0; length];
+    if let Err(e) = reader.read_exact(&mut body) {
+        return Err(BadRequest::new(400, format!("Reading body: {:?}", e)));
+    }
+    request.body = body;
+    Ok(request)
+}
+
+pub fn write_response(stream: &mut TcpStream, response: &Response) -> Result<(), String> {
+    let mut data = format!("HTTP/1.1 {} {}\r\n", response.status, reason(response.status));
+    for &(ref name, ref value) in response.headers.iter() {
+        data.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    data.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n",
+                           response.body.len()));
+    let mut bytes = data.into_bytes();
+    bytes.extend_from_slice(&response.body[..]);
+    match stream.write_all(&bytes[..]).and_then(|_| stream.flush()) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(format!("Writing response: {:?}", e)),
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+/// POST a JSON body to a plain `http://` URL, returning the response status
+pub fn post_json(url: &str, body: &str) -> Result<u16, String> {
+    if !url.starts_with("http://") {
+        return Err(format!("Only http:// URLs are supported, not {}", url));
+    }
+    let rest = &url["http://".len()..];
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let address = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+
+    let mut stream = match TcpStream::connect(&address[..]) {
+        Ok(stream) => stream,
+        Err(e) => return Err(format!("Connecting to {}: {:?}", address, e)),
+    };
+    let timeout = Some(Duration::from_secs(READ_TIMEOUT));
+    if let Err(e) = stream.set_read_timeout(timeout).and_then(|_| stream.set_write_timeout(timeout)) {
+        return Err(format!("Setting timeouts: {:?}", e));
+    }
+    let request = format!("POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+                           Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                          path, host, body.len(), body);
+    if let Err(e) = stream.write_all(request.as_bytes()).and_then(|_| stream.flush()) {
+        return Err(format!("Writing request: {:?}", e));
+    }
+
+    let mut status_line = String::new();
+    if let Err(e) = BufReader::new(stream).read_line(&mut status_line) {
+        return Err(format!("Reading response: {:?}", e));
+    }
+    match status_line.split_whitespace().nth(1).and_then(|status| status.parse::<u16>().ok()) {
+        Some(status) => Ok(status),
+        None => Err(format!("Malformed status line {}", status_line.trim_right())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::iter;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use super::{BadRequest, Request, read_request, MAX_HEADERS, MAX_LINE_LENGTH};
+
+    /// Send `data` to a fresh connection and read a request from it
+    fn read(data: String) -> Result<Request, BadRequest> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            // the server may stop reading and close early, failing the write
+            let _ = stream.write_all(data.as_bytes());
+            stream
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let request = read_request(stream);
+        client.join().unwrap();
+        request
+    }
+
+    fn status(data: String) -> u16 {
+        read(data).err().expect("request was accepted").status
+    }
+
+    #[test]
+    fn reads_requests() {
+        let request = read("POST /api/tip HTTP/1.1\r\nHost: localhost\r\n\
+                            content-length: 4\r\n\r\nbody".to_string()).unwrap();
+        assert_eq!((&request.method[..], &request.path[..]), ("POST", "/api/tip"));
+        assert_eq!(request.header("Content-Length"), Some("4"));
+        assert_eq!(request.header("host"), Some("localhost"));
+        assert_eq!(request.body, b"body".to_vec());
+    }
+
+    #[test]
+    fn caps_request_heads() {
+        let long = iter::repeat('a').take(MAX_LINE_LENGTH).collect::<String>();
+        assert_eq!(status(format!("GET /{} HTTP/1.1\r\n\r\n", long)), 414);
+        assert_eq!(status(format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", long)), 431);
+
+        let headers = (0..MAX_HEADERS).map(|i| format!("X-{}: {}\r\n", i, i))
+            .collect::<String>();
+        assert!(read(format!("GET / HTTP/1.1\r\n{}\r\n", headers)).is_ok());
+        assert_eq!(status(format!("GET / HTTP/1.1\r\n{}X-More: 1\r\n\r\n", headers)), 431);
+
+        assert_eq!(status("GET / HTTP/1.1\r\nContent-Length: 9999999999\r\n\r\n".to_string()),
+                   413);
+        assert_eq!(status("GET / HTTP/1.1\r\nNo colon\r\n\r\n".to_string()), 400);
+    }
+}
@@ -0,0 +1,438 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Mutex, Once, ONCE_INIT};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rustc_serialize::json::Json;
+
+/// The subsystems messages are logged under, each of which can be given
+/// its own level
+pub const TARGETS: [&'static str; 6] = ["net", "sync", "db", "storage", "api", "wallet"];
+
+/// Log a message under a target, with optional `key = value` fields before
+/// a semicolon, as in `info!("net", peer = ip; "Connected")`. The message
+/// isn't formatted unless the level is enabled for the target.
+macro_rules! log {
+    ($level:expr, $target:expr, $($key:ident = $value:expr),+; $($arg:tt)+) => ({
+        let level = $level;
+        if $crate::log::enabled(level, $target) {
+            $crate::log::log(level, $target, &[$((stringify!($key), $value.to_string())),+],
+                             format!($($arg)+));
+        }
+    });
+    ($level:expr, $target:expr, $($arg:tt)+) => ({
+        let level = $level;
+        if $crate::log::enabled(level, $target) {
+            $crate::log::log(level, $target, &[], format!($($arg)+));
+        }
+    });
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => (log!($crate::log::Level::Error, $($arg)+))
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => (log!($crate::log::Level::Warn, $($arg)+))
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => (log!($crate::log::Level::Info, $($arg)+))
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => (log!($crate::log::Level::Debug, $($arg)+))
+}
+
+macro_rules! trace {
+    ($($arg:tt)+) => (log!($crate::log::Level::Trace, $($arg)+))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Level, String> {
+        match name {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(format!("Unknown log level {}", name)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    /// One line per message: time, level, target, message, then fields
+    Text,
+    /// One JSON object per line, with the fields alongside the message
+    Json,
+}
+
+impl Format {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Format::Text => "text",
+            Format::Json => "json",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<Format, String> {
+        match name {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("Unknown log format {}", name)),
+        }
+    }
+}
+
+/// What to log, how, and where
+#[derive(Clone)]
+pub struct LogConfig {
+    /// Level of the targets not given their own
+    pub level: Level,
+    pub target_levels: HashMap<String, Level>,
+    pub format: Format,
+    /// File to log to instead of stdout
+    pub path: Option<String>,
+    /// Size in bytes past which the log file is rotated
+    pub max_size: u64,
+    /// Number of rotated files kept, `<path>.1` being the newest
+    pub max_files: u32,
+}
+
+impl LogConfig {
+    pub fn new() -> LogConfig {
+        LogConfig {
+            level: Level::Info,
+            target_levels: HashMap::new(),
+            format: Format::Text,
+            path: None,
+            max_size: 10 * 1024 * 1024,
+            max_files: 5,
+        }
+    }
+
+    fn level_of(&self, target: &str) -> Level {
+        self.target_levels.get(target).map_or(self.level, |&level| level)
+    }
+
+    pub fn to_json(&self) -> Json {
+        let mut targets = BTreeMap::new();
+        for target in TARGETS.iter() {
+            targets.insert(target.to_string(), Json::String(self.level_of(target).as_str()
+                                                             .to_string()));
+        }
+        let mut result = BTreeMap::new();
+        result.insert("level".to_string(), Json::String(self.level.as_str().to_string()));
+        result.insert("targets".to_string(), Json::Object(targets));
+        result.insert("format".to_string(), Json::String(self.format.as_str().to_string()));
+        result.insert("path".to_string(),
+                      self.path.as_ref().map_or(Json::Null, |path| Json::String(path.clone())));
+        result.insert("max_size".to_string(), Json::U64(self.max_size));
+        result.insert("max_files".to_string(), Json::U64(self.max_files as u64));
+        Json::Object(result)
+    }
+}
+
+struct Logger {
+    config: LogConfig,
+    file: Option<File>,
+    /// Bytes in the log file
+    size: u64,
+}
+
+impl Logger {
+    fn open(&mut self) -> Result<(), String> {
+        self.file = None;
+        if let Some(ref path) = self.config.path {
+            let file = match OpenOptions::new().append(true).create(true).open(path) {
+                Ok(file) => file,
+                Err(e) => return Err(format!("Could not open log file {}: {:?}", path, e)),
+            };
+            self.size = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+            self.file = Some(file);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, line: &str) {
+        let written = match self.file {
+            Some(_) if self.size + line.len() as u64 > self.config.max_size && self.size > 0 => {
+                self.rotate().and_then(|_| self.append(line))
+            },
+            Some(_) => self.append(line),
+            None => {
+                let stdout = io::stdout();
+                let mut handle = stdout.lock();
+                handle.write_all(line.as_bytes()).map_err(|e| format!("{:?}", e))
+            },
+        };
+        if let Err(e) = written {
+            // nowhere left to report it but stderr
+            let _ = writeln!(io::stderr(), "Logging failed ({}): {}", e, line.trim_right());
+        }
+    }
+
+    fn append(&mut self, line: &str) -> Result<(), String> {
+        let file = match self.file {
+            Some(ref mut file) => file,
+            None => return Err("No log file".to_string()),
+        };
+        match file.write_all(line.as_bytes()) {
+            Ok(()) => {
+                self.size += line.len() as u64;
+                Ok(())
+            },
+            Err(e) => Err(format!("{:?}", e)),
+        }
+    }
+
+    /// Move the log file to `<path>.1`, shifting the older ones along and
+    /// dropping the oldest, and start a new one
+    fn rotate(&mut self) -> Result<(), String> {
+        let path = match self.config.path {
+            Some(ref path) => path.clone(),
+            None => return Ok(()),
+        };
+        self.file = None;
+        let rotated = |i: u32| format!("{}.{}", path, i);
+        let shifted = if self.config.max_files == 0 {
+            fs::remove_file(&path)
+        } else {
+            let _ = fs::remove_file(rotated(self.config.max_files));
+            for i in (1..self.config.max_files).rev() {
+                if Path::new(&rotated(i)).exists() {
+                    let _ = fs::rename(rotated(i), rotated(i + 1));
+                }
+            }
+            fs::rename(&path, rotated(1))
+        };
+        if let Err(e) = shifted {
+            return Err(format!("Could not rotate log file {}: {:?}", path, e));
+        }
+        self.open()
+    }
+}
+
+fn logger() -> &'static Mutex<Logger> {
+    static INIT: Once = ONCE_INIT;
+    static mut LOGGER: *const Mutex<Logger> = 0 as *const Mutex<Logger>;
+    unsafe {
+        INIT.call_once(|| {
+            LOGGER = Box::into_raw(Box::new(Mutex::new(Logger {
+                config: LogConfig::new(),
+                file: None,
+                size: 0,
+            })));
+        });
+        &*LOGGER
+    }
+}
+
+/// Log as configured from now on
+pub fn init(config: LogConfig) -> Result<(), String> {
+    for target in config.target_levels.keys() {
+        try!(check_target(target));
+    }
+    let mut logger = logger().lock().unwrap();
+    logger.config = config;
+    logger.open()
+}
+
+pub fn config() -> LogConfig {
+    logger().lock().unwrap().config.clone()
+}
+
+/// Set the level of one target, or with no target the level of those not
+/// given their own
+pub fn set_level(target: Option<&str>, level: Level) -> Result<(), String> {
+    let mut logger = logger().lock().unwrap();
+    match target {
+        Some(target) => {
+            try!(check_target(target));
+            logger.config.target_levels.insert(target.to_string(), level);
+        },
+        None => logger.config.level = level,
+    }
+    Ok(())
+}
+
+pub fn set_format(format: Format) {
+    logger().lock().unwrap().config.format = format;
+}
+
+fn check_target(target: &str) -> Result<(), String> {
+    if TARGETS.contains(&target) {
+        Ok(())
+    } else {
+        Err(format!("Unknown log target {}, expected one of {}", target, TARGETS.join(", ")))
+    }
+}
+
+pub fn enabled(level: Level, target: &str) -> bool {
+    level <= logger().lock().unwrap().config.level_of(target)
+}
+
+/// Write a message out, which the logging macros do once they know its
+/// level is enabled
+pub fn log(level: Level, target: &str, fields: &[(&str, String)], message: String) {
+    let mut logger = logger().lock().unwrap();
+    let line = match logger.config.format {
+        Format::Text => {
+            let mut line = format!("{} {:5} {}: {}", timestamp(), level.as_str().to_uppercase(),
+                                   target, message);
+            for &(key, ref value) in fields.iter() {
+                if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"') {
+                    line.push_str(&format!(" {}={:?}", key, value));
+                } else {
+                    line.push_str(&format!(" {}={}", key, value));
+                }
+            }
+            line
+        },
+        Format::Json => {
+            let mut object = BTreeMap::new();
+            for &(key, ref value) in fields.iter() {
+                object.insert(key.to_string(), Json::String(value.clone()));
+            }
+            object.insert("time".to_string(), Json::String(timestamp()));
+            object.insert("level".to_string(), Json::String(level.as_str().to_string()));
+            object.insert("target".to_string(), Json::String(target.to_string()));
+            object.insert("message".to_string(), Json::String(message));
+            Json::Object(object).to_string()
+        },
+    };
+    logger.write(&format!("{}\n", line));
+}
+
+/// The current UTC time in RFC 3339 form, to the millisecond
+fn timestamp() -> String {
+    format_time(SystemTime::now().duration_since(UNIX_EPOCH).unwrap())
+}
+
+/// A time since the Unix epoch in RFC 3339 form, to the millisecond
+fn format_time(since_epoch: Duration) -> String {
+    let (days, seconds) = (since_epoch.as_secs() / 86400, since_epoch.as_secs() % 86400);
+    // the civil date of a day count, after Howard Hinnant's algorithm
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 -
+                       day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day,
+            seconds / 3600, seconds % 3600 / 60, seconds % 60,
+            since_epoch.subsec_nanos() / 1000000)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::path::Path;
+    use std::process;
+    use std::time::Duration;
+
+    use super::{Level, LogConfig, Logger, enabled, format_time, set_level};
+
+    #[test]
+    fn formats_times() {
+        let times = [
+            (0, 0, "1970-01-01T00:00:00.000Z"),
+            (1234567890, 500, "2009-02-13T23:31:30.500Z"),
+            // leap days, including in a year divisible by 400 but not in
+            // one divisible by only 100
+            (951782400, 0, "2000-02-29T00:00:00.000Z"),
+            (951868799, 999, "2000-02-29T23:59:59.999Z"),
+            (1582934399, 0, "2020-02-28T23:59:59.000Z"),
+            (1709164800, 1, "2024-02-29T00:00:00.001Z"),
+            (4107542400, 0, "2100-03-01T00:00:00.000Z"),
+            (253402300799, 0, "9999-12-31T23:59:59.000Z"),
+        ];
+        for &(seconds, millis, expected) in times.iter() {
+            assert_eq!(format_time(Duration::new(seconds, millis * 1000000)), expected);
+        }
+    }
+
+    #[test]
+    fn rotates_log_files() {
+        let dir = env::temp_dir().join(format!("talk_log_{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("talk.log").to_string_lossy().into_owned();
+        let mut config = LogConfig::new();
+        config.path = Some(path.clone());
+        config.max_size = 10;
+        config.max_files = 2;
+        let mut logger = Logger { config: config, file: None, size: 0 };
+        logger.open().unwrap();
+
+        let read = |suffix: &str| {
+            let mut data = String::new();
+            File::open(format!("{}{}", path, suffix)).unwrap().read_to_string(&mut data).unwrap();
+            data
+        };
+        // a line longer than max_size still goes into an empty file
+        logger.write("first line\n");
+        assert_eq!(read(""), "first line\n");
+        logger.write("second\n");
+        assert_eq!((read(""), read(".1")), ("second\n".to_string(), "first line\n".to_string()));
+        logger.write("third\n");
+        logger.write("fourth\n");
+        // .1 moved to .2, and the oldest was dropped
+        assert_eq!(read(""), "fourth\n");
+        assert_eq!(read(".1"), "third\n");
+        assert_eq!(read(".2"), "second\n");
+        assert!(!Path::new(&format!("{}.3", path)).exists());
+
+        // the size is picked up again on reopening
+        let mut reopened = Logger { config: logger.config.clone(), file: None, size: 0 };
+        reopened.open().unwrap();
+        assert_eq!(reopened.size, 7);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn levels_per_target() {
+        let mut config = LogConfig::new();
+        config.level = Level::Warn;
+        config.target_levels.insert("net".to_string(), Level::Trace);
+        assert_eq!(config.level_of("net"), Level::Trace);
+        assert_eq!(config.level_of("sync"), Level::Warn);
+
+        assert!(set_level(Some("nonsense"), Level::Debug).is_err());
+        set_level(Some("wallet"), Level::Error).unwrap();
+        assert!(enabled(Level::Error, "wallet"));
+        assert!(!enabled(Level::Warn, "wallet"));
+        set_level(Some("wallet"), Level::Debug).unwrap();
+        assert!(enabled(Level::Debug, "wallet"));
+        assert!(!enabled(Level::Trace, "wallet"));
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Values;
+
+use bitcoin::util::hash::Sha256dHash;
+
+use witness::{WitnessBlock, WitnessTx};
+
+pub const MAX_MEMPOOL_TXS: usize = 50000;
+/// Unconfirmed transactions are forgotten after two weeks, as in Core
+pub const MEMPOOL_EXPIRY: u64 = 14 * 24 * 60 * 60;
+/// How long to wait on a peer for a transaction we asked for before
+/// asking someone else
+const REQUEST_TIMEOUT: u64 = 60;
+
+pub struct MempoolEntry {
+    pub tx: WitnessTx,
+    pub txid: Sha256dHash,
+    /// Kept for matching compact block short IDs
+    pub wtxid: Sha256dHash,
+    /// Unix time at which we first received the transaction
+    pub first_seen: u64,
+    /// Only known if every prevout could be found
+    pub fee: Option<u64>,
+    pub vsize: usize,
+}
+
+impl MempoolEntry {
+    /// Fee rate in satoshis per virtual byte
+    pub fn fee_rate(&self) -> Option<f64> {
+        self.fee.map(|fee| fee as f64 / self.vsize as f64)
+    }
+}
+
+/// A bounded pool of unconfirmed transactions relayed to us by peers
+pub struct Mempool {
+    entries: HashMap<Sha256dHash, MempoolEntry>,
+    /// Transactions requested from a peer but not yet received
+    in_flight: HashMap<Sha256dHash, u64>,
+    max_txs: usize,
+}
+
+impl Mempool {
+    pub fn new(max_txs: usize) -> Mempool {
+        Mempool {
+            entries: HashMap::new(),
+            in_flight: HashMap::new(),
+            max_txs: max_txs,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn contains(&self, txid: &Sha256dHash) -> bool {
+        self.entries.contains_key(txid)
+    }
+
+    pub fn get(&self, txid: &Sha256dHash) -> Option<&MempoolEntry> {
+        self.entries.get(txid)
+    }
+
+    pub fn entries(&self) -> Values<Sha256dHash, MempoolEntry> {
+        self.entries.values()
+    }
+
+    /// Value of an unconfirmed output, for computing the fee of its spender
+    pub fn output_value(&self, txid: &Sha256dHash, index: u32) -> Option<u64> {
+        self.entries.get(txid)
+            .and_then(|entry| entry.tx.tx.output.get(index as usize))
+            .map(|output| output.value)
+    }
+
+    /// Whether an announced transaction should be requested. Marks it as
+    /// in flight so that the same transaction isn't fetched from every peer.
+    pub fn want(&mut self, txid: &Sha256dHash, now: u64) -> bool {
+        if self.entries.contains_key(txid) {
+            return false;
+        }
+        if let Some(&requested) = self.in_flight.get(txid) {
+            if now < requested + REQUEST_TIMEOUT {
+                return false;
+            }
+        }
+        self.in_flight.insert(*txid, now);
+        true
+    }
+
+    /// Add a transaction, evicting the lowest fee rate entry if the pool is
+    /// full. Returns the txids evicted to make room, or `None` if the
+    /// transaction itself was not admitted.
+    pub fn insert(&mut self, tx: WitnessTx, fee: Option<u64>, now: u64)
+                  -> Option<Vec<Sha256dHash>> {
+        let txid = tx.txid();
+        self.in_flight.remove(&txid);
+        if self.entries.contains_key(&txid) {
+            return None;
+        }
+
+        let entry = MempoolEntry {
+            txid: txid,
+            wtxid: tx.wtxid(),
+            first_seen: now,
+            fee: fee,
+            vsize: (tx.weight() + 3) / 4,
+            tx: tx,
+        };
+
+        // settle on what to evict before evicting anything, so that nothing
+        // goes if the transaction isn't admitted after all
+        let excess = (self.entries.len() + 1).saturating_sub(self.max_txs);
+        let evicted = self.lowest_fee_rates(excess);
+        if evicted.iter().any(|txid| rate_of(&self.entries[txid]) >= rate_of(&entry)) {
+            return None;
+        }
+        for txid in evicted.iter() {
+            self.entries.remove(txid);
+        }
+        self.entries.insert(txid, entry);
+        Some(evicted)
+    }
+
+    /// Remove transactions confirmed by a block, along with any that
+    /// conflict with it by spending the same outputs
+    pub fn remove_confirmed(&mut self, block: &WitnessBlock) -> Vec<MempoolEntry> {
+        let mut spent: HashSet<(Sha256dHash, u32)> = HashSet::new();
+        let mut doomed: Vec<Sha256dHash> = vec![];
+        for wtx in block.txdata.iter() {
+            doomed.push(wtx.txid());
+            for input in wtx.tx.input.iter() {
+                spent.insert((input.prev_hash, input.prev_index));
+            }
+        }
+        for entry in self.entries.values() {
+            if entry.tx.tx.input.iter()
+                .any(|input| spent.contains(&(input.prev_hash, input.prev_index))) {
+                doomed.push(entry.txid);
+            }
+        }
+
+        let mut removed = vec![];
+        for txid in doomed {
+            self.in_flight.remove(&txid);
+            if let Some(entry) = self.entries.remove(&txid) {
+                removed.push(entry);
+            }
+        }
+        removed
+    }
+
+    /// Drop transactions that have gone unconfirmed for too long
+    pub fn expire(&mut self, now: u64) -> Vec<Sha256dHash> {
+        let expired = self.entries.values()
+            .filter(|entry| entry.first_seen + MEMPOOL_EXPIRY < now)
+            .map(|entry| entry.txid)
+            .collect::<Vec<Sha256dHash>>();
+        for txid in expired.iter() {
+            self.entries.remove(txid);
+        }
+        let stale = self.in_flight.iter()
+            .filter(|&(_, &requested)| requested + REQUEST_TIMEOUT <= now)
+            .map(|(txid, _)| *txid)
+            .collect::<Vec<Sha256dHash>>();
+        for txid in stale.iter() {
+            self.in_flight.remove(txid);
+        }
+        expired
+    }
+
+    /// The `count` entries to evict first: lowest fee rate, then oldest
+    fn lowest_fee_rates(&self, count: usize) -> Vec<Sha256dHash> {
+        let order = |a: &&MempoolEntry, b: &&MempoolEntry| {
+            (rate_of(a), a.first_seen).partial_cmp(&(rate_of(b), b.first_seen)).unwrap()
+        };
+        match count {
+            0 => vec![],
+            // the usual case, when the pool is just full
+            1 => self.entries.values().min_by(&order).map(|entry| entry.txid).into_iter().collect(),
+            _ => {
+                let mut entries = self.entries.values().collect::<Vec<&MempoolEntry>>();
+                entries.sort_by(&order);
+                entries.iter().take(count).map(|entry| entry.txid).collect()
+            },
+        }
+    }
+}
+
+/// Entries of unknown fee are the first to go
+fn rate_of(entry: &MempoolEntry) -> f64 {
+    entry.fee_rate().unwrap_or(-1.0)
+}
@@ -0,0 +1,261 @@
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::network::encodable::{ConsensusEncodable, ConsensusDecodable};
+use bitcoin::network::serialize::{SimpleEncoder, SimpleDecoder};
+use bitcoin::util::hash::Sha256dHash;
+
+use validation::MAX_BLOCK_TXS;
+
+/// Hash two merkle tree nodes into their parent
+pub fn hash_pair(left: &Sha256dHash, right: &Sha256dHash) -> Sha256dHash {
+    let mut data = left[..].to_vec();
+    data.extend_from_slice(&right[..]);
+    Sha256dHash::from_data(&data[..])
+}
+
+/// Compute the merkle root of a list of hashes, duplicating the last hash
+/// of any level with an odd number of nodes, as Bitcoin does
+pub fn merkle_root(hashes: &[Sha256dHash]) -> Sha256dHash {
+    if hashes.is_empty() {
+        return Default::default();
+    }
+    let mut level = hashes.to_vec();
+    while level.len() > 1 {
+        level = level.chunks(2)
+            .map(|pair| {
+                if pair.len() == 2 {
+                    hash_pair(&pair[0], &pair[1])
+                } else {
+                    hash_pair(&pair[0], &pair[0])
+                }
+            })
+            .collect();
+    }
+    level[0]
+}
+
+/// A merkle tree pruned down to the branches of some of its transactions
+/// (BIP37): the hashes of the subtrees left out, and a bit per node
+/// visited depth first telling whether it leads to one of them
+#[derive(Clone, Debug)]
+pub struct PartialMerkleTree {
+    pub total_txs: u32,
+    pub hashes: Vec<Sha256dHash>,
+    pub bits: Vec<bool>,
+}
+
+impl PartialMerkleTree {
+    /// The tree of a block's txids keeping the branches of those matched
+    pub fn new(txids: &[Sha256dHash], matches: &[bool]) -> PartialMerkleTree {
+        let mut tree = PartialMerkleTree {
+            total_txs: txids.len() as u32,
+            hashes: vec![],
+            bits: vec![],
+        };
+        let mut height = 0;
+        while tree.width(height) > 1 {
+            height += 1;
+        }
+        tree.build(height, 0, txids, matches);
+        tree
+    }
+
+    /// Walk the tree back up to its merkle root, collecting the matched
+    /// txids. Fails if the tree is malformed, including when it has spare
+    /// hashes or bits, or two identical siblings, which would let the same
+    /// root stand for different transactions (CVE-2012-2459).
+    pub fn extract_matches(&self) -> Result<(Sha256dHash, Vec<Sha256dHash>), String> {
+        if self.total_txs == 0 || self.total_txs as usize > MAX_BLOCK_TXS {
+            return Err(format!("Bad transaction count {}", self.total_txs));
+        }
+        if self.hashes.len() > self.total_txs as usize {
+            return Err("More hashes than transactions".to_string());
+        }
+        if self.bits.len() < self.hashes.len() {
+            return Err("Fewer bits than hashes".to_string());
+        }
+        let mut height = 0;
+        while self.width(height) > 1 {
+            height += 1;
+        }
+        let mut bits_used = 0;
+        let mut hashes_used = 0;
+        let mut matches = vec![];
+        let root = try!(self.extract(height, 0, &mut bits_used, &mut hashes_used, &mut matches));
+        // the bits are sent whole bytes at a time
+        if (bits_used + 7) / 8 != (self.bits.len() + 7) / 8 {
+            return Err("Not all bits were used".to_string());
+        }
+        if hashes_used != self.hashes.len() {
+            return Err("Not all hashes were used".to_string());
+        }
+        Ok((root, matches))
+    }
+
+    fn extract(&self, height: u32, pos: usize, bits_used: &mut usize, hashes_used: &mut usize,
+               matches: &mut Vec<Sha256dHash>) -> Result<Sha256dHash, String> {
+        let has_match = match self.bits.get(*bits_used) {
+            Some(&bit) => bit,
+            None => return Err("Ran out of bits".to_string()),
+        };
+        *bits_used += 1;
+        if height == 0 || !has_match {
+            let hash = match self.hashes.get(*hashes_used) {
+                Some(&hash) => hash,
+                None => return Err("Ran out of hashes".to_string()),
+            };
+            *hashes_used += 1;
+            if height == 0 && has_match {
+                matches.push(hash);
+            }
+            return Ok(hash);
+        }
+        let left = try!(self.extract(height - 1, pos * 2, bits_used, hashes_used, matches));
+        let right = if pos * 2 + 1 < self.width(height - 1) {
+            let right = try!(self.extract(height - 1, pos * 2 + 1, bits_used, hashes_used,
+                                          matches));
+            if right == left {
+                return Err("Identical sibling hashes".to_string());
+            }
+            right
+        } else {
+            left
+        };
+        Ok(hash_pair(&left, &right))
+    }
+
+    /// Number of nodes at `height` above the leaves
+    fn width(&self, height: u32) -> usize {
+        (self.total_txs as usize + (1 << height) - 1) >> height
+    }
+
+    fn hash_at(&self, height: u32, pos: usize, txids: &[Sha256dHash]) -> Sha256dHash {
+        if height == 0 {
+            return txids[pos];
+        }
+        let left = self.hash_at(height - 1, pos * 2, txids);
+        let right = if pos * 2 + 1 < self.width(height - 1) {
+            self.hash_at(height - 1, pos * 2 + 1, txids)
+        } else {
+            left
+        };
+        hash_pair(&left, &right)
+    }
+
+    fn build(&mut self, height: u32, pos: usize, txids: &[Sha256dHash], matches: &[bool]) {
+        let end = ((pos + 1) << height).min(txids.len());
+        let has_match = matches[pos << height..end].iter().any(|&matched| matched);
+        self.bits.push(has_match);
+        if height == 0 || !has_match {
+            let hash = self.hash_at(height, pos, txids);
+            self.hashes.push(hash);
+        } else {
+            self.build(height - 1, pos * 2, txids, matches);
+            if pos * 2 + 1 < self.width(height - 1) {
+                self.build(height - 1, pos * 2 + 1, txids, matches);
+            }
+        }
+    }
+}
+
+/// The `merkleblock` message: a block header and the branches of its
+/// transactions matched by a peer's filter
+#[derive(Clone, Debug)]
+pub struct MerkleBlock {
+    pub header: BlockHeader,
+    pub tree: PartialMerkleTree,
+}
+
+impl<S: SimpleEncoder> ConsensusEncodable<S> for MerkleBlock {
+    fn consensus_encode(&self, s: &mut S) -> Result<(), S::Error> {
+        try!(self.header.consensus_encode(s));
+        try!(self.tree.total_txs.consensus_encode(s));
+        try!(self.tree.hashes.consensus_encode(s));
+        // the bits are packed least significant first
+        let mut flags = vec![0u8; (self.tree.bits.len() + 7) / 8];
+        for (i, &bit) in self.tree.bits.iter().enumerate() {
+            if bit {
+                flags[i / 8] |= 1 << (i % 8);
+            }
+        }
+        flags.consensus_encode(s)
+    }
+}
+
+impl<D: SimpleDecoder> ConsensusDecodable<D> for MerkleBlock {
+    fn consensus_decode(d: &mut D) -> Result<MerkleBlock, D::Error> {
+        let header = try!(ConsensusDecodable::consensus_decode(d));
+        let total_txs = try!(ConsensusDecodable::consensus_decode(d));
+        let hashes = try!(ConsensusDecodable::consensus_decode(d));
+        let flags: Vec<u8> = try!(ConsensusDecodable::consensus_decode(d));
+        let bits = (0..flags.len() * 8)
+            .map(|i| flags[i / 8] & 1 << (i % 8) != 0)
+            .collect();
+        Ok(MerkleBlock {
+            header: header,
+            tree: PartialMerkleTree {
+                total_txs: total_txs,
+                hashes: hashes,
+                bits: bits,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::constants::Network;
+    use bitcoin::network::serialize::{BitcoinHash, deserialize, serialize};
+    use bitcoin::util::hash::Sha256dHash;
+
+    use super::{merkle_root, MerkleBlock, PartialMerkleTree};
+
+    fn txids(count: usize) -> Vec<Sha256dHash> {
+        (0..count).map(|i| Sha256dHash::from_data(&[i as u8, (i >> 8) as u8])).collect()
+    }
+
+    #[test]
+    fn merkleblock_round_trip() {
+        let header = genesis_block(Network::Bitcoin).header;
+        for &count in [1, 4, 7, 17, 56, 100, 127, 256, 312, 513, 1000, 4095].iter() {
+            let txids = txids(count);
+            // no matches, every transaction, and sparser selections
+            for &step in [0, 1, 2, 3, 7, 31, 1000].iter() {
+                let matches: Vec<bool> = (0..count).map(|i| step != 0 && i % step == 0).collect();
+                let expected: Vec<Sha256dHash> = txids.iter()
+                    .zip(matches.iter())
+                    .filter(|&(_, &matched)| matched)
+                    .map(|(txid, _)| *txid)
+                    .collect();
+
+                let block = MerkleBlock {
+                    header: header,
+                    tree: PartialMerkleTree::new(&txids, &matches),
+                };
+                assert!(block.tree.hashes.len() <= count);
+                let data = serialize(&block).unwrap();
+                let decoded: MerkleBlock = deserialize(&data).unwrap();
+                assert_eq!(decoded.header.bitcoin_hash(), header.bitcoin_hash());
+
+                let (root, found) = decoded.tree.extract_matches().unwrap();
+                assert_eq!(root, merkle_root(&txids), "{} txs, step {}", count, step);
+                assert_eq!(found, expected, "{} txs, step {}", count, step);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_identical_siblings() {
+        // the last two txids repeated give the same root as the first ten
+        // alone (CVE-2012-2459)
+        let mut txids = txids(10);
+        let (ninth, tenth) = (txids[8], txids[9]);
+        txids.push(ninth);
+        txids.push(tenth);
+        assert_eq!(merkle_root(&txids), merkle_root(&txids[..10]));
+
+        let matches: Vec<bool> = (0..12).map(|i| i == 9 || i == 10).collect();
+        let tree = PartialMerkleTree::new(&txids, &matches);
+        assert!(tree.extract_matches().is_err());
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::BTreeMap;
+use std::sync::{Mutex, Once, ONCE_INIT};
+use std::time::Duration;
+
+use http::{self, Request, Response};
+
+pub const PEER_CONNECTIONS: &'static str = "bitcoind_peer_connections";
+pub const ADDRESS_BOOK_SIZE: &'static str = "bitcoind_address_book_size";
+pub const HEADERS_HEIGHT: &'static str = "bitcoind_headers_height";
+pub const BLOCKS_HEIGHT: &'static str = "bitcoind_blocks_height";
+pub const BLOCK_ARRIVAL: &'static str = "bitcoind_block_arrival_seconds";
+pub const PEER_MESSAGES: &'static str = "bitcoind_peer_messages_total";
+pub const DB_BLOCK_WRITE: &'static str = "bitcoind_db_block_write_seconds";
+pub const DB_ROWS_INSERTED: &'static str = "bitcoind_db_rows_inserted_total";
+pub const DB_ROWS_REMOVED: &'static str = "bitcoind_db_rows_removed_total";
+pub const REORGS: &'static str = "bitcoind_reorgs_total";
+pub const BLOCKS_DISCONNECTED: &'static str = "bitcoind_blocks_disconnected_total";
+pub const CHANNEL_BACKLOG: &'static str = "bitcoind_channel_backlog";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Counter,
+    Gauge,
+    /// With the upper bounds of its buckets
+    Histogram(&'static [f64]),
+}
+
+/// Every metric we export, in the order they're rendered
+const FAMILIES: [(&'static str, Kind, &'static str); 12] = [
+    (PEER_CONNECTIONS, Kind::Gauge, "Open peer connections"),
+    (ADDRESS_BOOK_SIZE, Kind::Gauge, "Peer addresses waiting to be connected to"),
+    (HEADERS_HEIGHT, Kind::Gauge, "Height of the best header chain"),
+    (BLOCKS_HEIGHT, Kind::Gauge, "Height of the last block written to the database"),
+    (BLOCK_ARRIVAL, Kind::Histogram(&[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0]),
+     "Seconds from a block's first announcement to having it in full"),
+    (PEER_MESSAGES, Kind::Counter, "Messages sent to and received from peers, by command"),
+    (DB_BLOCK_WRITE, Kind::Histogram(&[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+                                       5.0, 10.0]),
+     "Seconds taken to write a block to the database"),
+    (DB_ROWS_INSERTED, Kind::Counter, "Block, transaction, input and output rows inserted"),
+    (DB_ROWS_REMOVED, Kind::Counter, "Block, transaction, input and output rows pruned"),
+    (REORGS, Kind::Counter, "Reorganizations of the best chain"),
+    (BLOCKS_DISCONNECTED, Kind::Counter, "Blocks that left the best chain in a reorganization"),
+    (CHANNEL_BACKLOG, Kind::Gauge, "Messages waiting in the daemon's and peers' channels"),
+];
+
+enum Value {
+    Number(f64),
+    /// Count per bucket, then sum and count of all observations
+    Histogram(Vec<u64>, f64, u64),
+}
+
+/// Series by metric name, then by labels
+type Registry = BTreeMap<&'static str, BTreeMap<Vec<(String, String)>, Value>>;
+
+fn registry() -> &'static Mutex<Registry> {
+    static INIT: Once = ONCE_INIT;
+    static mut REGISTRY: *const Mutex<Registry> = 0 as *const Mutex<Registry>;
+    unsafe {
+        INIT.call_once(|| {
+            REGISTRY = Box::into_raw(Box::new(Mutex::new(BTreeMap::new())));
+        });
+        &*REGISTRY
+    }
+}
+
+fn kind_of(name: &str) -> Kind {
+    match FAMILIES.iter().find(|family| family.0 == name) {
+        Some(family) => family.1,
+        None => panic!("Undeclared metric {}", name),
+    }
+}
+
+fn key(labels: &[(&str, &str)]) -> Vec<(String, String)> {
+    labels.iter().map(|&(label, value)| (label.to_string(), value.to_string())).collect()
+}
+
+/// Add to a counter or gauge
+pub fn add(name: &'static str, labels: &[(&str, &str)], delta: f64) {
+    debug_assert!(kind_of(name) != Kind::Counter || delta >= 0.0);
+    let mut registry = registry().lock().unwrap();
+    let series = registry.entry(name).or_insert(BTreeMap::new());
+    match *series.entry(key(labels)).or_insert(Value::Number(0.0)) {
+        Value::Number(ref mut value) => *value += delta,
+        Value::Histogram(..) => panic!("{} is a histogram", name),
+    }
+}
+
+pub fn inc(name: &'static str, labels: &[(&str, &str)]) {
+    add(name, labels, 1.0);
+}
+
+pub fn set(name: &'static str, labels: &[(&str, &str)], value: f64) {
+    registry().lock().unwrap().entry(name).or_insert(BTreeMap::new())
+        .insert(key(labels), Value::Number(value));
+}
+
+/// Record an observation in a histogram
+pub fn observe(name: &'static str, labels: &[(&str, &str)], value: f64) {
+    let bounds = match kind_of(name) {
+        Kind::Histogram(bounds) => bounds,
+        _ => panic!("{} is not a histogram", name),
+    };
+    let mut registry = registry().lock().unwrap();
+    let series = registry.entry(name).or_insert(BTreeMap::new());
+    let entry = series.entry(key(labels))
+        .or_insert(Value::Histogram(vec![0; bounds.len()], 0.0, 0));
+    if let Value::Histogram(ref mut counts, ref mut sum, ref mut count) = *entry {
+        if let Some(i) = bounds.iter().position(|&bound| value <= bound) {
+            counts[i] += 1;
+        }
+        *sum += value;
+        *count += 1;
+    }
+}
+
+/// Drop a series, such as that of a peer that's gone
+pub fn remove(name: &'static str, labels: &[(&str, &str)]) {
+    if let Some(series) = registry().lock().unwrap().get_mut(name) {
+        series.remove(&key(labels));
+    }
+}
+
+pub fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+/// All metrics in Prometheus' text format
+pub fn render() -> String {
+    let registry = registry().lock().unwrap();
+    let mut text = String::new();
+    for &(name, kind, help) in FAMILIES.iter() {
+        text.push_str(&format!("# HELP {} {}\n", name, help));
+        text.push_str(&format!("# TYPE {} {}\n", name, match kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram(_) => "histogram",
+        }));
+        let series = match registry.get(name) {
+            Some(series) => series,
+            None => continue,
+        };
+        for (labels, value) in series.iter() {
+            match (value, kind) {
+                (&Value::Number(value), _) => {
+                    text.push_str(&format!("{}{} {}\n", name, render_labels(labels, None), value));
+                },
+                (&Value::Histogram(ref counts, sum, count), Kind::Histogram(bounds)) => {
+                    let mut cumulative = 0;
+                    for (bound, bucket) in bounds.iter().zip(counts.iter()) {
+                        cumulative += *bucket;
+                        text.push_str(&format!("{}_bucket{} {}\n", name,
+                                               render_labels(labels, Some(&bound.to_string())),
+                                               cumulative));
+                    }
+                    text.push_str(&format!("{}_bucket{} {}\n", name,
+                                           render_labels(labels, Some("+Inf")), count));
+                    text.push_str(&format!("{}_sum{} {}\n", name, render_labels(labels, None),
+                                           sum));
+                    text.push_str(&format!("{}_count{} {}\n", name, render_labels(labels, None),
+                                           count));
+                },
+                _ => (),
+            }
+        }
+    }
+    text
+}
+
+fn render_labels(labels: &[(String, String)], le: Option<&str>) -> String {
+    let mut pairs = labels.iter()
+        .map(|&(ref label, ref value)| format!("{}=\"{}\"", label, escape(value)))
+        .collect::<Vec<String>>();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Serve the metrics at `/metrics` on `address`, for Prometheus to scrape
+pub fn start(address: &str) -> Result<(), String> {
+    try!(http::serve(address, |request: Request| {
+        if request.method != "GET" {
+            return Response::text(405, "Only GET is supported");
+        }
+        if request.path != "/metrics" {
+            return Response::text(404, "Not found");
+        }
+        Response::new(200, "text/plain; version=0.0.4", render().into_bytes())
+    }));
+    info!("api", address = address; "Metrics listening");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CHANNEL_BACKLOG, DB_BLOCK_WRITE, kind_of, observe, render, set};
+
+    /// Lines of the rendered metrics with a label only these tests use, so
+    /// that metrics recorded by other tests don't get in the way
+    fn rendered(label: &str) -> Vec<String> {
+        render().lines()
+            .filter(|line| line.starts_with('#') || line.contains(label))
+            .map(|line| line.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn renders_histograms() {
+        // powers of two, so that the sum is exact
+        for &value in [0.00390625, 0.015625, 0.015625, 7.0, 100.0].iter() {
+            observe(DB_BLOCK_WRITE, &[("test", "histogram")], value);
+        }
+        let lines = rendered("test=\"histogram\"");
+        let at = lines.iter()
+            .position(|line| line == "# TYPE bitcoind_db_block_write_seconds histogram")
+            .unwrap();
+        let mut expected = [("0.005", 1), ("0.01", 1), ("0.025", 3), ("0.05", 3), ("0.1", 3),
+                            ("0.25", 3), ("0.5", 3), ("1", 3), ("2.5", 3), ("5", 3), ("10", 4),
+                            ("+Inf", 5)].iter()
+            .map(|&(le, count)| format!("bitcoind_db_block_write_seconds_bucket\
+                                         {{test=\"histogram\",le=\"{}\"}} {}", le, count))
+            .collect::<Vec<String>>();
+        expected.push("bitcoind_db_block_write_seconds_sum{test=\"histogram\"} 107.03515625"
+                      .to_string());
+        expected.push("bitcoind_db_block_write_seconds_count{test=\"histogram\"} 5".to_string());
+        assert_eq!(lines[at + 1..at + 1 + expected.len()].to_vec(), expected);
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        set(CHANNEL_BACKLOG, &[("channel", "escape \"quoted\" back\\slash\nnewline")], 2.0);
+        assert!(rendered("escape").contains(
+            &"bitcoind_channel_backlog{channel=\"escape \\\"quoted\\\" back\\\\slash\\nnewline\"} 2"
+                .to_string()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Undeclared metric bitcoind_undeclared_total")]
+    fn undeclared_metrics_panic() {
+        kind_of("bitcoind_undeclared_total");
+    }
+}
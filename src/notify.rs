@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{SyncSender, Receiver, TrySendError, sync_channel};
+use std::thread;
+use std::time::Duration;
+
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use rustc_serialize::json::{self, Json};
+
+use bitcoin::network::serialize::{BitcoinHash, serialize};
+use bitcoin::util::hash::Sha256dHash;
+
+use http::{self, Response};
+use rpc::object;
+use witness::{WitnessBlock, WitnessTx};
+
+/// Appended to a client's key to accept a WebSocket handshake (RFC 6455)
+const WEBSOCKET_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Subscribers that can't take a message, or finish their handshake, within
+/// this many seconds are dropped
+const TIMEOUT: u64 = 10;
+/// Messages a subscriber may have waiting before it's dropped as too slow
+const CLIENT_QUEUE: usize = 100;
+/// Events that may wait for the notifier thread before new ones are dropped
+const EVENT_QUEUE: usize = 1000;
+
+/// A change to the best chain or the mempool
+pub enum Event {
+    BlockConnected(WitnessBlock, u32),
+    /// A block that left the best chain in a reorg, with its height
+    BlockDisconnected(Sha256dHash, u32),
+    /// A transaction accepted to the mempool, with its fee if known
+    MempoolTx(WitnessTx, Option<u64>),
+}
+
+impl Event {
+    pub fn to_json(&self) -> Json {
+        match *self {
+            Event::BlockConnected(ref block, height) => object(vec![
+                ("type", Json::String("block_connected".to_string())),
+                ("hash", Json::String(block.bitcoin_hash().be_hex_string())),
+                ("height", Json::U64(height as u64)),
+                ("prev_hash", Json::String(block.header.prev_blockhash.be_hex_string())),
+                ("time", Json::U64(block.header.time as u64)),
+                ("size", Json::U64(block.size() as u64)),
+                ("tx_count", Json::U64(block.txdata.len() as u64)),
+            ]),
+            Event::BlockDisconnected(ref hash, height) => object(vec![
+                ("type", Json::String("block_disconnected".to_string())),
+                ("hash", Json::String(hash.be_hex_string())),
+                ("height", Json::U64(height as u64)),
+            ]),
+            Event::MempoolTx(ref tx, fee) => object(vec![
+                ("type", Json::String("mempool_tx".to_string())),
+                ("txid", Json::String(tx.txid().be_hex_string())),
+                ("fee", fee.map_or(Json::Null, |fee| Json::U64(fee))),
+                ("vsize", Json::U64(((tx.weight() + 3) / 4) as u64)),
+            ]),
+        }
+    }
+
+    /// Topic and serialized data for raw subscribers, as with Core's
+    /// `rawblock` and `rawtx` ZMQ topics
+    fn raw(&self) -> Option<(&'static str, Vec<u8>)> {
+        match *self {
+            Event::BlockConnected(ref block, _) =>
+                Some(("rawblock", serialize(block).unwrap())),
+            Event::BlockDisconnected(_, _) => None,
+            Event::MempoolTx(ref tx, _) => Some(("rawtx", serialize(tx).unwrap())),
+        }
+    }
+}
+
+/// Pushes events to WebSocket clients and raw subscribers from a thread
+/// of its own, so that slow subscribers don't hold up the daemon
+pub struct Notifier {
+    events: SyncSender<Event>,
+}
+
+impl Notifier {
+    /// Accept WebSocket clients on `websocket_address` and raw subscribers
+    /// on `raw_address`
+    pub fn start(websocket_address: Option<&String>, raw_address: Option<&String>)
+                 -> Result<Notifier, String> {
+        let websocket_clients = Arc::new(Mutex::new(vec![]));
+        let raw_clients = Arc::new(Mutex::new(vec![]));
+        if let Some(address) = websocket_address {
+            try!(accept(address, websocket_clients.clone(), websocket_handshake));
+            info!("api", address = address; "WebSocket notifications listening");
+        }
+        if let Some(address) = raw_address {
+            try!(accept(address, raw_clients.clone(), |_| Ok(())));
+            info!("api", address = address; "Raw block and transaction publisher listening");
+        }
+
+        let (sender, receiver): (SyncSender<Event>, Receiver<Event>) = sync_channel(EVENT_QUEUE);
+        thread::spawn(move || {
+            let mut sequences: HashMap<&'static str, u32> = HashMap::new();
+            for event in receiver.iter() {
+                let text = json::encode(&event.to_json()).unwrap();
+                broadcast(&websocket_clients, Arc::new(websocket_frame(&text)));
+                if let Some((topic, data)) = event.raw() {
+                    let sequence = sequences.entry(topic).or_insert(0);
+                    broadcast(&raw_clients, Arc::new(raw_message(topic, &data[..], *sequence)));
+                    *sequence = sequence.wrapping_add(1);
+                }
+            }
+        });
+        Ok(Notifier { events: sender })
+    }
+
+    /// Queue an event for subscribers. The daemon never waits on them: if
+    /// the queue is full, the event is dropped.
+    pub fn publish(&self, event: Event) {
+        match self.events.try_send(event) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => warn!("api", "Notifier is behind, dropping event"),
+            Err(TrySendError::Disconnected(_)) =>
+                error!("api", "Notifier thread is gone, dropping event"),
+        }
+    }
+}
+
+/// A subscriber's queue of messages, which its own thread writes out
+struct Client {
+    address: String,
+    queue: SyncSender<Arc<Vec<u8>>>,
+    /// Shut down to stop the client's thread when it's dropped
+    stream: TcpStream,
+}
+
+/// Accept connections on `address`, each on a thread of its own that adds
+/// it to `clients` once `handshake` accepts it and then writes its messages
+fn accept<F>(address: &str, clients: Arc<Mutex<Vec<Client>>>, handshake: F)
+             -> Result<(), String>
+    where F: Fn(&mut TcpStream) -> Result<(), String> + Send + Sync + 'static {
+    let listener = match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(e) => return Err(format!("Could not listen on {}: {:?}", address, e)),
+    };
+    let address = address.to_string();
+    let handshake = Arc::new(handshake);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("api", address = address; "Failed to accept subscriber: {:?}", e);
+                    continue;
+                },
+            };
+            let clients = clients.clone();
+            let handshake = handshake.clone();
+            let address = address.clone();
+            // a client that is slow to shake hands only holds up itself
+            thread::spawn(move || serve_client(stream, &address, clients, &*handshake));
+        }
+    });
+    Ok(())
+}
+
+/// Shake hands with a subscriber, then write its messages until it fails
+/// to take one or falls too far behind
+fn serve_client<F>(mut stream: TcpStream, address: &str, clients: Arc<Mutex<Vec<Client>>>,
+                   handshake: &F)
+    where F: Fn(&mut TcpStream) -> Result<(), String> {
+    let timeout = Some(Duration::from_secs(TIMEOUT));
+    if let Err(e) = stream.set_read_timeout(timeout)
+        .and_then(|_| stream.set_write_timeout(timeout)) {
+        debug!("api", address = address; "Setting subscriber timeouts: {:?}", e);
+        return;
+    }
+    if let Err(e) = handshake(&mut stream) {
+        debug!("api", address = address; "Rejected subscriber: {}", e);
+        return;
+    }
+    let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or(String::new());
+    let shutdown = match stream.try_clone() {
+        Ok(shutdown) => shutdown,
+        Err(e) => {
+            debug!("api", address = address; "Cloning subscriber stream: {:?}", e);
+            return;
+        },
+    };
+    let (queue, messages) = sync_channel(CLIENT_QUEUE);
+    clients.lock().unwrap().push(Client { address: peer.clone(), queue: queue, stream: shutdown });
+    // ends once the notifier drops the client
+    for message in messages.iter() {
+        if let Err(e) = stream.write_all(&message[..]).and_then(|_| stream.flush()) {
+            debug!("api", address = address, subscriber = peer; "Dropping subscriber: {:?}", e);
+            return;
+        }
+    }
+}
+
+/// Queue a message for every client, dropping those whose queue is full
+/// or whose thread has stopped
+fn broadcast(clients: &Arc<Mutex<Vec<Client>>>, message: Arc<Vec<u8>>) {
+    clients.lock().unwrap().retain(|client| {
+        match client.queue.try_send(message.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                info!("api", subscriber = client.address; "Dropping subscriber that fell behind");
+                let _ = client.stream.shutdown(Shutdown::Both);
+                false
+            },
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    });
+}
+
+fn websocket_handshake(stream: &mut TcpStream) -> Result<(), String> {
+    let reader = match stream.try_clone() {
+        Ok(reader) => reader,
+        Err(e) => return Err(format!("Cloning stream: {:?}", e)),
+    };
+    let request = match http::read_request(reader) {
+        Ok(request) => request,
+        Err(e) => {
+            try!(http::write_response(stream, &e.response()));
+            return Err(e.message);
+        },
+    };
+    let key = match request.header("Sec-WebSocket-Key") {
+        Some(key) if request.method == "GET" => key.to_string(),
+        _ => {
+            let response = Response::text(400, "Expected a WebSocket upgrade");
+            try!(http::write_response(stream, &response));
+            return Err("Not a WebSocket upgrade".to_string());
+        },
+    };
+
+    let response = format!("HTTP/1.1 101 Switching Protocols\r\n\
+                            Upgrade: websocket\r\n\
+                            Connection: Upgrade\r\n\
+                            Sec-WebSocket-Accept: {}\r\n\r\n",
+                           websocket_accept(&key));
+    match stream.write_all(response.as_bytes()) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(format!("Writing handshake: {:?}", e)),
+    }
+}
+
+/// The `Sec-WebSocket-Accept` answer to a client's `Sec-WebSocket-Key`
+fn websocket_accept(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.input_str(&format!("{}{}", key, WEBSOCKET_GUID));
+    let mut digest = [0u8; 20];
+    sha1.result(&mut digest);
+    digest.to_base64(STANDARD)
+}
+
+/// An unmasked text frame, as servers send them
+fn websocket_frame(text: &str) -> Vec<u8> {
+    let payload = text.as_bytes();
+    let len = payload.len();
+    let mut frame = vec![0x81];
+    if len < 126 {
+        frame.push(len as u8);
+    } else if len <= 0xffff {
+        frame.push(126);
+        frame.push((len >> 8) as u8);
+        frame.push(len as u8);
+    } else {
+        frame.push(127);
+        for i in (0..8).rev() {
+            frame.push(((len as u64) >> (i * 8)) as u8);
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Topic, data and the topic's sequence number as little-endian u32, each
+/// preceded by its length as a little-endian u32
+fn raw_message(topic: &str, data: &[u8], sequence: u32) -> Vec<u8> {
+    let sequence = [sequence as u8, (sequence >> 8) as u8,
+                    (sequence >> 16) as u8, (sequence >> 24) as u8];
+    let mut message = vec![];
+    for part in [topic.as_bytes(), data, &sequence[..]].iter() {
+        let len = part.len() as u32;
+        message.extend_from_slice(&[len as u8, (len >> 8) as u8,
+                                    (len >> 16) as u8, (len >> 24) as u8]);
+        message.extend_from_slice(part);
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::iter::repeat;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use super::{raw_message, websocket_accept, websocket_frame, websocket_handshake};
+
+    #[test]
+    fn websocket_accept_key() {
+        // RFC 6455 section 1.3
+        assert_eq!(websocket_accept("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn websocket_handshake_answers_upgrade() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            websocket_handshake(&mut stream)
+        });
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"GET /chat HTTP/1.1\r\n\
+                           Host: server.example.com\r\n\
+                           Upgrade: websocket\r\n\
+                           Connection: Upgrade\r\n\
+                           Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                           Sec-WebSocket-Version: 13\r\n\r\n").unwrap();
+        assert_eq!(server.join().unwrap(), Ok(()));
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    }
+
+    #[test]
+    fn websocket_frame_lengths() {
+        // up to 125 bytes, the length fits in the second byte
+        let text: String = repeat('a').take(125).collect();
+        let frame = websocket_frame(&text);
+        assert_eq!(&frame[..2], &[0x81, 125]);
+        assert_eq!(&frame[2..], text.as_bytes());
+
+        // then comes a 16 bit length
+        let text: String = repeat('b').take(126).collect();
+        let frame = websocket_frame(&text);
+        assert_eq!(&frame[..4], &[0x81, 126, 0x00, 0x7e]);
+        assert_eq!(&frame[4..], text.as_bytes());
+
+        // and past 65535 bytes a 64 bit one
+        let text: String = repeat('c').take(65536).collect();
+        let frame = websocket_frame(&text);
+        assert_eq!(&frame[..10], &[0x81, 127, 0, 0, 0, 0, 0, 0x01, 0x00, 0x00]);
+        assert_eq!(&frame[10..], text.as_bytes());
+    }
+
+    #[test]
+    fn raw_message_layout() {
+        let message = raw_message("rawtx", &[0xde, 0xad, 0xbe, 0xef], 0x01020304);
+        assert_eq!(message, vec![0x05, 0x00, 0x00, 0x00, b'r', b'a', b'w', b't', b'x',
+                                 0x04, 0x00, 0x00, 0x00, 0xde, 0xad, 0xbe, 0xef,
+                                 0x04, 0x00, 0x00, 0x00, 0x04, 0x03, 0x02, 0x01]);
+    }
+}
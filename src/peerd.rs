@@ -2,12 +2,15 @@ use std::time::Duration;
 use std::thread;
 use std::sync::mpsc::{Sender, Receiver, channel, TryRecvError};
 
-use bitcoin::network::listener::Listener;
 use bitcoin::network::constants::Network;
-use bitcoin::network::socket::Socket;
-use bitcoin::network::message::{SocketResponse, NetworkMessage};
+use bitcoin::network::message::NetworkMessage;
 
-use util::ThreadResponse;
+use bloom::{BloomFilter, MAX_FILTERADD_SIZE};
+use compact::SendCmpct;
+use metrics::{self, CHANNEL_BACKLOG};
+use socket::{PeerSocket, SocketResponse};
+use wire::{PeerMessage, NODE_WITNESS};
+use util::{ThreadResponse, unix_time};
 
 #[derive(Clone)]
 pub struct Peerd {
@@ -21,7 +24,7 @@ impl Peerd {
         }
     }
     
-    pub fn listen(&mut self, master: Receiver<NetworkMessage>)
+    pub fn listen(&mut self, master: Receiver<PeerMessage>)
                   -> Result<Receiver<ThreadResponse>, String> {
         let (sender, receiver): (Sender<ThreadResponse>,
                                  Receiver<ThreadResponse>) = channel();
@@ -29,120 +32,201 @@ impl Peerd {
         let self_clone = self.clone();
         
         thread::spawn(move || {
-            println!("Trying to connect to {}", self_clone.config.peer_addr);
+            let ip = self_clone.config.peer_addr.clone();
+            debug!("net", peer = ip; "Connecting");
+            let close = |err: String| {
+                let(txx, rxx) = channel(); // notify parent thread
+                if sender.send(ThreadResponse::CloseThread((err, txx))).is_ok() {
+                    let _ = rxx.recv(); // tear down this thread
+                }
+            };
             match self_clone.loop_connect() {
                 Ok((net_chan, mut sock)) => {
-                    println!("Connected to {}", self_clone.config.peer_addr);
+                    info!("net", peer = ip; "Connected");
+                    // the BIP37 filter the peer loaded, if any
+                    let mut bloom: Option<BloomFilter> = None;
                     
                     loop {
-                        match master.try_recv() {
+                        let received = master.try_recv();
+                        if received.is_ok() {
+                            metrics::add(CHANNEL_BACKLOG,
+                                         &[("channel", "peer"), ("peer", &ip[..])], -1.0);
+                        }
+                        match received {
+                            Ok(PeerMessage::FilteredBlock(block)) => {
+                                // peers that didn't load a filter get nothing,
+                                // as from Core
+                                if let Some(ref mut filter) = bloom {
+                                    let (merkle_block, txs) = filter.filter_block(&block);
+                                    let mut msgs = vec![PeerMessage::MerkleBlock(merkle_block)];
+                                    msgs.extend(txs.into_iter().map(PeerMessage::Tx));
+                                    for msg in msgs {
+                                        if let Err(e) = sock.send_message(msg) {
+                                            warn!("net", peer = ip;
+                                                  "Failed to send filtered block: {:?}", e);
+                                        }
+                                    }
+                                }
+                            },
                             Ok(msg) => {
+                                let command = msg.command();
                                 match sock.send_message(msg) {
-                                    Ok(()) => (),
-                                    Err(e) => println!("Failed to send message: {:?}", e),
+                                    Ok(()) => trace!("net", peer = ip; "Sent {}", command),
+                                    Err(e) => warn!("net", peer = ip;
+                                                    "Failed to send {}: {:?}", command, e),
                                 }
                             },
                             Err(TryRecvError::Empty) => (),
                             Err(TryRecvError::Disconnected) => {
-                                println!("Channel disconnected");
+                                // the daemon dropped us, e.g. for misbehaving
+                                debug!("net", peer = ip; "Daemon dropped the connection");
+                                break;
                             }
                         }
-                        match net_chan.recv() {
-                            Ok(SocketResponse::MessageReceived(msg)) => {
-                                match msg {
-                                    NetworkMessage::Version(version) => {
-                                        println!("Message received: Version");
-                                        match sock.send_message(NetworkMessage::Verack) {
-                                            Ok(()) => (),
-                                            Err(e) => println!("Failed to send verack message: {:?}", e),
-                                        }
-                                    },
-                                    NetworkMessage::Verack => {
-                                        println!("Message received: Verack");
-                                        match sock.send_message(NetworkMessage::GetAddr) {
-                                            Ok(()) => (),
-                                            Err(e) => println!("Failed to send getaddr message: {:?}", e),
-                                        }
-                                    },
-                                    NetworkMessage::Addr(addresses) => {
-                                        println!("Message received: Addresses");
-                                        sender.send(ThreadResponse::Addresses(addresses)).unwrap();
-                                    },
-                                    NetworkMessage::Ping(nonce) => {
-                                        println!("Message received: Ping");
-                                        match sock.send_message(NetworkMessage::Pong(nonce)) {
-                                            Ok(()) => (),
-                                            Err(e) => println!("Failed to send pong response to ping: {:?}", e),
-                                        }
-                                    },
-                                    NetworkMessage::Pong(nonce) => {
-                                        println!("Message received: Pong");
-                                    },
-                                    NetworkMessage::Inv(inventory) => {
-                                        println!("Message received: Inventory");
-                                        sender.send(ThreadResponse::Inv(
-                                            self_clone.peer().to_string(),
-                                            inventory)).unwrap();
-                                    },
-                                    NetworkMessage::GetData(inventory) => {
-                                        println!("Message received: GetData");
-                                    },
-                                    NetworkMessage::NotFound(inventory) => {
-                                        println!("Message received: NotFound");
-                                    },
-                                    NetworkMessage::GetBlocks(blockdata) => {
-                                        println!("Message received: GetBlocks");
-                                    },
-                                    NetworkMessage::GetHeaders(blockdata) => {
-                                        println!("Message received: GetHeaders");
-                                    },
-                                    NetworkMessage::MemPool => {
-                                        println!("Message received: MemPool");
-                                    },
-                                    NetworkMessage::Tx(transaction) => {
-                                        println!("Message received: Tx");
-                                        sender.send(ThreadResponse::Tx(transaction)).unwrap();
-                                    },
-                                    NetworkMessage::Block(block) => {
-                                        println!("Message received: Block");
-                                        sender.send(ThreadResponse::Block(block)).unwrap();
-                                    },
-                                    NetworkMessage::Headers(lone_block_headers) => {
-                                        println!("Message received: Headers");
-                                        sender.send(ThreadResponse::Headers(
-                                            self_clone.peer().to_string(),
-                                            lone_block_headers)).unwrap();
-                                    },
-                                    NetworkMessage::GetAddr => {
-                                        println!("Message received: GetAddr");
+                        let msg = match net_chan.recv() {
+                            Ok(SocketResponse::MessageReceived(msg)) => msg,
+                            Ok(SocketResponse::ConnectionFailed(err)) => {
+                                close(err);
+                                break;
+                            },
+                            Err(_) => {
+                                close("Socket reader stopped".to_string());
+                                break;
+                            },
+                        };
+                        trace!("net", peer = ip; "Received {}", msg.command());
+                        // what to hand the daemon
+                        let response = match msg {
+                            PeerMessage::Network(NetworkMessage::Version(version)) => {
+                                if version.services & NODE_WITNESS == 0 {
+                                    // we can't get witness blocks from this peer
+                                    close(format!("{} does not serve witness data", ip));
+                                    break;
+                                }
+                                if let Err(e) = sock.send_message(PeerMessage::Network(
+                                    NetworkMessage::Verack)) {
+                                    warn!("net", peer = ip; "Failed to send verack: {:?}", e);
+                                }
+                                Some(ThreadResponse::Connected(ip.clone(), PeerInfo {
+                                    port: self_clone.config.peer_port,
+                                    services: version.services,
+                                    version: version.version,
+                                    user_agent: version.user_agent.clone(),
+                                    start_height: version.start_height,
+                                    connected_at: unix_time(),
+                                }))
+                            },
+                            PeerMessage::Network(NetworkMessage::Verack) => {
+                                if let Err(e) = sock.send_message(PeerMessage::Network(
+                                    NetworkMessage::GetAddr)) {
+                                    warn!("net", peer = ip; "Failed to send getaddr: {:?}", e);
+                                }
+                                // we can take compact blocks, but only ask
+                                // for them to be pushed once the peer has
+                                // proven quick to deliver blocks
+                                if let Err(e) = sock.send_message(PeerMessage::SendCmpct(
+                                    SendCmpct::new(false))) {
+                                    warn!("net", peer = ip; "Failed to send sendcmpct: {:?}", e);
+                                }
+                                None
+                            },
+                            PeerMessage::Network(NetworkMessage::Addr(addresses)) =>
+                                Some(ThreadResponse::Addresses(addresses)),
+                            PeerMessage::Network(NetworkMessage::Ping(nonce)) => {
+                                if let Err(e) = sock.send_message(PeerMessage::Network(
+                                    NetworkMessage::Pong(nonce))) {
+                                    warn!("net", peer = ip; "Failed to send pong: {:?}", e);
+                                }
+                                None
+                            },
+                            PeerMessage::Inv(inventory) =>
+                                Some(ThreadResponse::Inv(ip.clone(), inventory)),
+                            PeerMessage::GetData(inventory) =>
+                                Some(ThreadResponse::GetData(ip.clone(), inventory)),
+                            PeerMessage::Tx(transaction) => Some(ThreadResponse::Tx(transaction)),
+                            PeerMessage::Block(block) =>
+                                Some(ThreadResponse::Block(ip.clone(), block)),
+                            PeerMessage::SendCmpct(send_cmpct) =>
+                                Some(ThreadResponse::SendCmpct(ip.clone(), send_cmpct)),
+                            PeerMessage::CmpctBlock(block) =>
+                                Some(ThreadResponse::CmpctBlock(ip.clone(), block)),
+                            PeerMessage::GetBlockTxn(_) => {
+                                // we never announce blocks, so there's
+                                // nothing a peer should be asking for
+                                None
+                            },
+                            PeerMessage::BlockTxn(txn) =>
+                                Some(ThreadResponse::BlockTxn(ip.clone(), txn)),
+                            PeerMessage::GetCFilters(request) =>
+                                Some(ThreadResponse::GetCFilters(ip.clone(), request)),
+                            PeerMessage::CFilter(cfilter) =>
+                                Some(ThreadResponse::CFilter(ip.clone(), cfilter)),
+                            PeerMessage::GetCFHeaders(request) =>
+                                Some(ThreadResponse::GetCFHeaders(ip.clone(), request)),
+                            PeerMessage::CFHeaders(cfheaders) =>
+                                Some(ThreadResponse::CFHeaders(ip.clone(), cfheaders)),
+                            PeerMessage::GetCFCheckpt(request) =>
+                                Some(ThreadResponse::GetCFCheckpt(ip.clone(), request)),
+                            PeerMessage::FilterLoad(filter) => {
+                                match filter.check() {
+                                    Ok(()) => {
+                                        bloom = Some(filter);
+                                        None
                                     },
+                                    Err(e) => Some(ThreadResponse::Misbehaved(ip.clone(), e)),
                                 }
                             },
-                            Ok(SocketResponse::ConnectionFailed(err, tx)) => {
-                                tx.send(()); // tear down failing thread
-                                let(txx, rxx) = channel(); // notify parent thread of failure
-                                sender.send(ThreadResponse::CloseThread((format!("{:?}", err), txx)));
-                                rxx.recv().unwrap(); // tear down this thread
-                                break;
+                            PeerMessage::FilterAdd(data) => {
+                                if bloom.is_none() || data.len() > MAX_FILTERADD_SIZE {
+                                    Some(ThreadResponse::Misbehaved(
+                                        ip.clone(),
+                                        "filteradd without a filter, or too large".to_string()))
+                                } else {
+                                    if let Some(ref mut filter) = bloom {
+                                        filter.insert(&data[..]);
+                                    }
+                                    None
+                                }
                             },
-                            Err(_) => {
-                                println!("YIKES!");
+                            PeerMessage::FilterClear => {
+                                bloom = None;
+                                None
+                            },
+                            PeerMessage::CFCheckpt(_) => {
+                                // we sync filter headers from genesis
+                                // rather than from checkpoints
+                                None
                             },
+                            PeerMessage::Network(NetworkMessage::Headers(lone_block_headers)) =>
+                                Some(ThreadResponse::Headers(ip.clone(), lone_block_headers)),
+                            PeerMessage::NotFound(_) |
+                            PeerMessage::MerkleBlock(_) |
+                            PeerMessage::FilteredBlock(_) |
+                            PeerMessage::Network(_) => {
+                                // the last three aren't decoded from the wire;
+                                // inventory, blocks and transactions are decoded
+                                // as witness-aware PeerMessages
+                                None
+                            },
+                        };
+                        if let Some(response) = response {
+                            if sender.send(response).is_err() {
+                                debug!("net", peer = ip; "Daemon is gone, disconnecting");
+                                break;
+                            }
                         }
                     }
                 },
                 Err(e) => {
-                    println!("Could not connect to {}", self_clone.config.peer_addr);
-                    let(txx, rxx) = channel(); // notify parent thread of failure
-                    sender.send(ThreadResponse::CloseThread((e, txx)));
-                    rxx.recv().unwrap(); // tear down this thread
+                    info!("net", peer = ip; "Could not connect: {}", e);
+                    close(e);
                 },
             }
         });
         Ok(receiver)
     }
         
-    fn loop_connect(&self) -> Result<(Receiver<SocketResponse>, Socket), String> {
+    fn loop_connect(&self) -> Result<(Receiver<SocketResponse>, PeerSocket), String> {
         let max_attempts = 3;
         let mut err: String = "".to_string();
         for _ in 0..max_attempts {
@@ -154,20 +238,29 @@ impl Peerd {
         }
         Err(err)
     }
-}
-
-impl Listener for Peerd {
-    fn peer<'a>(&'a self) -> &'a str {
-        &(self.config.peer_addr)
-    }
 
-    fn port(&self) -> u16 {
-        self.config.peer_port
+    /// Connect, send our version message and start reading from the peer
+    fn start(&self) -> Result<(Receiver<SocketResponse>, PeerSocket), String> {
+        let mut sock = try!(PeerSocket::connect(self.config.network,
+                                                &self.config.peer_addr,
+                                                self.config.peer_port));
+        let version = try!(sock.version_message(0));
+        try!(sock.send_message(PeerMessage::Network(NetworkMessage::Version(version))));
+        let chan = try!(sock.start_reader());
+        Ok((chan, sock))
     }
+}
 
-    fn network(&self) -> Network {
-        self.config.network
-    }
+/// What a peer told us about itself when we connected
+#[derive(Clone)]
+pub struct PeerInfo {
+    pub port: u16,
+    pub services: u64,
+    pub version: u32,
+    pub user_agent: String,
+    pub start_height: i32,
+    /// Unix time at which the peer accepted our connection
+    pub connected_at: u64,
 }
 
 #[derive(Clone)]
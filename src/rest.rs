@@ -0,0 +1,480 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::mpsc::{Sender, channel};
+
+use rustc_serialize::hex::{FromHex, ToHex};
+use rustc_serialize::json::{self, Json};
+
+use bitcoin::network::serialize::{BitcoinHash, deserialize, serialize};
+use bitcoin::util::hash::Sha256dHash;
+
+use filter::{BASIC_FILTER, FilterRequest, CheckptRequest};
+use http::{self, Request, Response};
+use mempool::MempoolEntry;
+use merkle::MerkleBlock;
+use metrics::{self, CHANNEL_BACKLOG};
+use rpc::{Node, authorized, object, tx_json, tx_proof, verify_tx_proof, best_height,
+          best_chain_hash_at, confirmations};
+use util::ThreadResponse;
+use wallet::{balances, utxo_json};
+
+const DEFAULT_PAGE_SIZE: usize = 25;
+const MAX_PAGE_SIZE: usize = 100;
+/// First path parts of the routes about our own coins and transactions,
+/// which need rpc_auth
+const PRIVATE_ROUTES: [&'static str; 2] = ["wallet", "broadcast"];
+
+/// A request handed from the REST server to the daemon
+pub struct RestCall {
+    /// Path below `/api/`, split on slashes
+    pub path: Vec<String>,
+    pub page: Page,
+    pub reply: Sender<(u16, Json)>,
+}
+
+#[derive(Clone, Copy)]
+pub struct Page {
+    pub offset: usize,
+    pub limit: usize,
+}
+
+/// Serve the explorer's REST API under `/api/` on `address`, passing
+/// requests to the daemon through `events`. The wallet and broadcast routes
+/// need the `auth` credentials with HTTP basic authentication, and aren't
+/// served without them.
+pub fn start(address: &str, auth: Option<(String, String)>, events: Sender<ThreadResponse>)
+             -> Result<(), String> {
+    let events = Mutex::new(events);
+    try!(http::serve(address, move |request: Request| {
+        if request.method != "GET" {
+            return error_response(405, "Only GET is supported");
+        }
+        let (path, query) = match request.path.find('?') {
+            Some(i) => (&request.path[..i], &request.path[i + 1..]),
+            None => (&request.path[..], ""),
+        };
+        if !path.starts_with("/api/") {
+            return error_response(404, "Not found");
+        }
+        let page = match parse_page(query) {
+            Ok(page) => page,
+            Err(e) => return error_response(400, &e),
+        };
+
+        let parts = path["/api/".len()..].split('/')
+            .filter(|part| !part.is_empty())
+            .map(|part| part.to_string())
+            .collect::<Vec<String>>();
+        if parts.first().map_or(false, |part| PRIVATE_ROUTES.iter().any(|&route| route == *part)) {
+            match auth {
+                Some((ref user, ref password)) if authorized(&request, user, password) => (),
+                Some(_) => {
+                    let mut response = error_response(401, "Unauthorized");
+                    response.headers.push(("WWW-Authenticate".to_string(),
+                                           "Basic realm=\"api\"".to_string()));
+                    return response;
+                },
+                None => return error_response(403, "Wallet routes need rpc_auth to be set"),
+            }
+        }
+
+        let (reply_sender, reply_receiver) = channel();
+        let call = RestCall {
+            path: parts,
+            page: page,
+            reply: reply_sender,
+        };
+        metrics::add(CHANNEL_BACKLOG, &[("channel", "daemon")], 1.0);
+        let sent = events.lock().unwrap().send(ThreadResponse::Rest(call));
+        let (status, body) = match sent.ok().and_then(|_| reply_receiver.recv().ok()) {
+            Some((status, body)) => (status, json::encode(&body).unwrap()),
+            None => return error_response(503, "Daemon did not answer"),
+        };
+
+        // the body is the version of the resource
+        let etag = format!("\"{}\"", Sha256dHash::from_data(body.as_bytes()).be_hex_string());
+        if status == 200 && request.header("If-None-Match") == Some(&etag[..]) {
+            let mut response = Response::new(304, "application/json", vec![]);
+            response.headers.push(("ETag".to_string(), etag));
+            return response;
+        }
+        let mut response = Response::json(status, body);
+        if status == 200 {
+            response.headers.push(("ETag".to_string(), etag));
+        }
+        response
+    }));
+    info!("api", address = address; "REST API listening");
+    Ok(())
+}
+
+fn error_response(status: u16, message: &str) -> Response {
+    Response::json(status, json::encode(&error_json(message)).unwrap())
+}
+
+fn error_json(message: &str) -> Json {
+    object(vec![("error", Json::String(message.to_string()))])
+}
+
+fn parse_page(query: &str) -> Result<Page, String> {
+    let mut page = Page { offset: 0, limit: DEFAULT_PAGE_SIZE };
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = match pair.find('=') {
+            Some(i) => (&pair[..i], &pair[i + 1..]),
+            None => (pair, ""),
+        };
+        let number = || value.parse::<usize>()
+            .map_err(|_| format!("{} must be a non-negative integer", key));
+        match key {
+            "offset" => page.offset = try!(number()),
+            "limit" => page.limit = try!(number()),
+            _ => (),
+        }
+    }
+    if page.limit == 0 || page.limit > MAX_PAGE_SIZE {
+        return Err(format!("limit must be between 1 and {}", MAX_PAGE_SIZE));
+    }
+    Ok(page)
+}
+
+pub fn dispatch(node: &mut Node, path: &[String], page: Page) -> (u16, Json) {
+    let parts = path.iter().map(|part| part.as_str()).collect::<Vec<&str>>();
+    let result = match (parts.len(), parts.first().map_or("", |part| *part)) {
+        (1, "tip") => Ok(tip(node)),
+        (1, "blocks") => Ok(recent_blocks(node, page)),
+        (2, "block") => parse_hash(parts[1]).and_then(|hash| block(node, &hash, page)),
+        (2, "block-height") => match parts[1].parse::<u32>() {
+            Ok(height) => match best_chain_hash_at(node.blockchain, height) {
+                Some(hash) => block(node, &hash, page),
+                None => Err((404, "No block at that height".to_string())),
+            },
+            Err(_) => Err((400, "Height must be a non-negative integer".to_string())),
+        },
+        (2, "tx") => parse_hash(parts[1]).and_then(|txid| tx(node, &txid)),
+        (3, "tx") if parts[2] == "proof" => parse_hash(parts[1]).and_then(|txid| proof(node, txid)),
+        (2, "proof") => verify_proof(node, parts[1]),
+        (2, "address") => address_history(node, parts[1], page),
+        (1, "mempool") => Ok(mempool(node, page)),
+        (1, "wallet") => Ok(wallet(node)),
+        (2, "broadcast") => parse_hash(parts[1]).and_then(|txid| {
+            node.broadcaster.status_json(&txid)
+                .ok_or((404, "Transaction was not broadcast by this node".to_string()))
+        }),
+        (2, "wallet") if parts[1] == "utxos" => Ok(wallet_utxos(node, page)),
+        (3, "block") if parts[2] == "filter" =>
+            parse_hash(parts[1]).and_then(|hash| block_filter(node, &hash)),
+        (3, "cfilters") => parse_filter_request(parts[1], parts[2])
+            .and_then(|request| cfilters(node, &request)),
+        (3, "cfheaders") => parse_filter_request(parts[1], parts[2])
+            .and_then(|request| cfheaders(node, &request)),
+        (2, "cfcheckpt") => parse_hash(parts[1]).and_then(|stop_hash| cfcheckpt(node, stop_hash)),
+        _ => Err((404, "Not found".to_string())),
+    };
+    match result {
+        Ok(json) => (200, json),
+        Err((status, message)) => (status, error_json(&message)),
+    }
+}
+
+fn tip(node: &Node) -> Json {
+    let hash = node.blockchain.best_tip_hash();
+    let time = node.blockchain.get_block(hash)
+        .map_or(0, |block_node_ref| block_node_ref.block.header.time);
+    object(vec![
+        ("hash", Json::String(hash.be_hex_string())),
+        ("height", Json::U64(best_height(node.blockchain) as u64)),
+        ("time", Json::U64(time as u64)),
+    ])
+}
+
+/// Best chain blocks from the tip down
+fn recent_blocks(node: &Node, page: Page) -> Json {
+    let hashes = node.blockchain.rev_iter(node.blockchain.best_tip_hash())
+        .skip(page.offset)
+        .take(page.limit + 1)
+        .map(|block_node_ref| block_node_ref.block.bitcoin_hash())
+        .collect::<Vec<Sha256dHash>>();
+    let blocks = hashes.iter()
+        .take(page.limit)
+        .map(|hash| Json::Object(block_summary(node, hash)))
+        .collect::<Vec<Json>>();
+    Json::Object(paged(blocks, page, hashes.len() > page.limit))
+}
+
+fn block(node: &Node, hash: &Sha256dHash, page: Page) -> Result<Json, (u16, String)> {
+    if node.blockchain.get_block(*hash).is_none() {
+        return Err((404, "Block not found".to_string()));
+    }
+    let mut result = block_summary(node, hash);
+    if let Some(block) = node.block_store.get(hash) {
+        let txids = block.txdata.iter()
+            .skip(page.offset)
+            .take(page.limit)
+            .map(|wtx| Json::String(wtx.txid().be_hex_string()))
+            .collect::<Vec<Json>>();
+        result.insert("txids".to_string(),
+                      Json::Object(paged(txids, page,
+                                         block.txdata.len() > page.offset + page.limit)));
+    }
+    Ok(Json::Object(result))
+}
+
+/// A block's header fields, plus its size and transaction count if it's
+/// still retained
+fn block_summary(node: &Node, hash: &Sha256dHash) -> BTreeMap<String, Json> {
+    let mut result = BTreeMap::new();
+    if let Some(block_node_ref) = node.blockchain.get_block(*hash) {
+        let header = &block_node_ref.block.header;
+        result.insert("hash".to_string(), Json::String(hash.be_hex_string()));
+        result.insert("height".to_string(), Json::U64(block_node_ref.height as u64));
+        result.insert("confirmations".to_string(),
+                      Json::I64(confirmations(node.blockchain, hash)));
+        result.insert("time".to_string(), Json::U64(header.time as u64));
+        result.insert("prev_hash".to_string(), Json::String(header.prev_blockhash.be_hex_string()));
+        result.insert("merkle_root".to_string(), Json::String(header.merkle_root.be_hex_string()));
+        result.insert("bits".to_string(), Json::U64(header.bits as u64));
+        result.insert("nonce".to_string(), Json::U64(header.nonce as u64));
+    }
+    match node.block_store.get(hash) {
+        Some(block) => {
+            result.insert("retained".to_string(), Json::Boolean(true));
+            result.insert("size".to_string(), Json::U64(block.size() as u64));
+            result.insert("weight".to_string(), Json::U64(block.weight() as u64));
+            result.insert("tx_count".to_string(), Json::U64(block.txdata.len() as u64));
+        },
+        None => {
+            result.insert("retained".to_string(), Json::Boolean(false));
+        },
+    }
+    result
+}
+
+fn tx(node: &Node, txid: &Sha256dHash) -> Result<Json, (u16, String)> {
+    if let Some(entry) = node.mempool.get(txid) {
+        let mut result = tx_json(&entry.tx);
+        result.insert("status".to_string(), object(vec![
+            ("confirmed", Json::Boolean(false)),
+            ("first_seen", Json::U64(entry.first_seen)),
+            ("fee", entry.fee.map_or(Json::Null, |fee| Json::U64(fee))),
+        ]));
+        return Ok(Json::Object(result));
+    }
+    let (block_hash, position) = match node.tx_index.get(txid) {
+        Some(location) => location,
+        None => return Err((404, "Transaction not found".to_string())),
+    };
+    let block = match node.block_store.get(&block_hash) {
+        Some(block) => block,
+        None => return Err((404, "Transaction not found".to_string())),
+    };
+    let height = node.blockchain.get_block(block_hash)
+        .map_or(0, |block_node_ref| block_node_ref.height);
+    let mut result = tx_json(&block.txdata[position]);
+    result.insert("status".to_string(), object(vec![
+        ("confirmed", Json::Boolean(true)),
+        ("block_hash", Json::String(block_hash.be_hex_string())),
+        ("block_height", Json::U64(height as u64)),
+        ("confirmations", Json::I64(confirmations(node.blockchain, &block_hash))),
+        ("block_time", Json::U64(block.header.time as u64)),
+    ]));
+    Ok(Json::Object(result))
+}
+
+/// A proof that a transaction is in a retained block, as `gettxoutproof`
+/// gives it
+fn proof(node: &Node, txid: Sha256dHash) -> Result<Json, (u16, String)> {
+    let proof = try!(tx_proof(node, &[txid], None).map_err(|e| (404, e.message)));
+    Ok(object(vec![
+        ("txid", Json::String(txid.be_hex_string())),
+        ("block_hash", Json::String(proof.header.bitcoin_hash().be_hex_string())),
+        ("proof", Json::String(serialize(&proof).unwrap().to_hex())),
+    ]))
+}
+
+/// The transactions a hex proof commits to, and where its block is
+fn verify_proof(node: &Node, hex: &str) -> Result<Json, (u16, String)> {
+    let proof = match hex.from_hex().ok().and_then(|data| deserialize::<MerkleBlock>(&data).ok()) {
+        Some(proof) => proof,
+        None => return Err((400, "Proof decode failed".to_string())),
+    };
+    let txids = try!(verify_tx_proof(node, &proof).map_err(|e| (404, e.message)));
+    let block_hash = proof.header.bitcoin_hash();
+    // a proof that fails to verify may be of a block we don't know
+    let height = node.blockchain.get_block(block_hash)
+        .map_or(Json::Null, |block_node_ref| Json::U64(block_node_ref.height as u64));
+    Ok(object(vec![
+        ("block_hash", Json::String(block_hash.be_hex_string())),
+        ("block_height", height),
+        ("confirmations", Json::I64(confirmations(node.blockchain, &block_hash))),
+        ("txids", Json::Array(txids.iter()
+                              .map(|txid| Json::String(txid.be_hex_string()))
+                              .collect())),
+    ]))
+}
+
+fn address_history(node: &mut Node, address: &str, page: Page)
+                   -> Result<Json, (u16, String)> {
+    let outputs = match node.storage.address_history(address, page.offset, page.limit + 1) {
+        Ok(outputs) => outputs,
+        Err(e) => return Err((500, e)),
+    };
+    let has_more = outputs.len() > page.limit;
+    let items = outputs.iter()
+        .take(page.limit)
+        .map(|output| object(vec![
+            ("txid", Json::String(output.txid.be_hex_string())),
+            ("vout", Json::U64(output.index as u64)),
+            ("value", Json::U64(output.value)),
+            ("block_hash", Json::String(output.block_hash.be_hex_string())),
+            ("block_height", Json::U64(output.height as u64)),
+            ("spent_by", output.spent_by.map_or(Json::Null, |txid| {
+                Json::String(txid.be_hex_string())
+            })),
+        ]))
+        .collect::<Vec<Json>>();
+    Ok(Json::Object(paged(items, page, has_more)))
+}
+
+/// Unconfirmed transactions, highest fee rate first
+fn mempool(node: &Node, page: Page) -> Json {
+    let mut entries = node.mempool.entries().collect::<Vec<&MempoolEntry>>();
+    entries.sort_by(|a, b| {
+        let (a_rate, b_rate) = (a.fee_rate().unwrap_or(0.0), b.fee_rate().unwrap_or(0.0));
+        b_rate.partial_cmp(&a_rate).unwrap()
+            .then_with(|| a.txid.be_hex_string().cmp(&b.txid.be_hex_string()))
+    });
+    let items = entries.iter()
+        .skip(page.offset)
+        .take(page.limit)
+        .map(|entry| object(vec![
+            ("txid", Json::String(entry.txid.be_hex_string())),
+            ("first_seen", Json::U64(entry.first_seen)),
+            ("fee", entry.fee.map_or(Json::Null, |fee| Json::U64(fee))),
+            ("vsize", Json::U64(entry.vsize as u64)),
+            ("fee_rate", entry.fee_rate().map_or(Json::Null, |rate| Json::F64(rate))),
+        ]))
+        .collect::<Vec<Json>>();
+    let mut result = paged(items, page, entries.len() > page.offset + page.limit);
+    result.insert("count".to_string(), Json::U64(entries.len() as u64));
+    result.insert("vsize".to_string(),
+                  Json::U64(entries.iter().map(|entry| entry.vsize as u64).sum::<u64>()));
+    Json::Object(result)
+}
+
+/// Balances of the watch-only wallet in satoshis
+fn wallet(node: &mut Node) -> Json {
+    let utxos = node.wallet.unspent(node.mempool);
+    let balances = balances(&utxos[..], node.blockchain);
+    object(vec![
+        ("descriptors", node.wallet.descriptors_json()),
+        ("confirmed", Json::U64(balances.confirmed)),
+        ("unconfirmed", Json::U64(balances.unconfirmed)),
+        ("immature", Json::U64(balances.immature)),
+        ("utxo_count", Json::U64(utxos.len() as u64)),
+    ])
+}
+
+/// Unspent outputs of the watch-only wallet, oldest first
+fn wallet_utxos(node: &mut Node, page: Page) -> Json {
+    let utxos = node.wallet.unspent(node.mempool);
+    let items = utxos.iter()
+        .skip(page.offset)
+        .take(page.limit)
+        .map(|utxo| utxo_json(utxo, node.blockchain, node.network))
+        .collect::<Vec<Json>>();
+    Json::Object(paged(items, page, utxos.len() > page.offset + page.limit))
+}
+
+/// A block's basic filter, and its filter header if known
+fn block_filter(node: &Node, hash: &Sha256dHash) -> Result<Json, (u16, String)> {
+    match node.filter_index.block_filter(hash) {
+        Some((filter, header)) => Ok(object(vec![
+            ("block_hash", Json::String(hash.be_hex_string())),
+            ("filter", Json::String(filter.to_hex())),
+            ("header", header.map_or(Json::Null,
+                                     |header| Json::String(header.be_hex_string()))),
+        ])),
+        None => Err((404, "Filter not available".to_string())),
+    }
+}
+
+/// The basic filters of a range of best chain blocks, as `getcfilters`
+/// would send them
+fn cfilters(node: &Node, request: &FilterRequest) -> Result<Json, (u16, String)> {
+    let cfilters = try!(node.filter_index.cfilters(node.blockchain, request)
+                        .map_err(|e| (404, e)));
+    Ok(Json::Array(cfilters.iter()
+                   .map(|cfilter| object(vec![
+                       ("block_hash", Json::String(cfilter.block_hash.be_hex_string())),
+                       ("filter", Json::String(cfilter.filter.to_hex())),
+                   ]))
+                   .collect()))
+}
+
+/// The filter hashes of a range of best chain blocks, as `getcfheaders`
+/// would send them
+fn cfheaders(node: &Node, request: &FilterRequest) -> Result<Json, (u16, String)> {
+    let cfheaders = try!(node.filter_index.cfheaders(node.blockchain, request)
+                         .map_err(|e| (404, e)));
+    Ok(object(vec![
+        ("stop_hash", Json::String(cfheaders.stop_hash.be_hex_string())),
+        ("prev_filter_header", Json::String(cfheaders.prev_filter_header.be_hex_string())),
+        ("filter_hashes", Json::Array(cfheaders.filter_hashes.iter()
+                                      .map(|hash| Json::String(hash.be_hex_string()))
+                                      .collect())),
+    ]))
+}
+
+/// Every 1000th filter header up to a block, as `getcfcheckpt` would send
+/// them
+fn cfcheckpt(node: &Node, stop_hash: Sha256dHash) -> Result<Json, (u16, String)> {
+    let request = CheckptRequest {
+        filter_type: BASIC_FILTER,
+        stop_hash: stop_hash,
+    };
+    let cfcheckpt = try!(node.filter_index.cfcheckpt(node.blockchain, &request)
+                         .map_err(|e| (404, e)));
+    Ok(object(vec![
+        ("stop_hash", Json::String(cfcheckpt.stop_hash.be_hex_string())),
+        ("filter_headers", Json::Array(cfcheckpt.filter_headers.iter()
+                                       .map(|header| Json::String(header.be_hex_string()))
+                                       .collect())),
+    ]))
+}
+
+/// A page of items, with the offset of the next page if there is one
+fn paged(items: Vec<Json>, page: Page, has_more: bool) -> BTreeMap<String, Json> {
+    let mut result = BTreeMap::new();
+    result.insert("items".to_string(), Json::Array(items));
+    result.insert("offset".to_string(), Json::U64(page.offset as u64));
+    result.insert("limit".to_string(), Json::U64(page.limit as u64));
+    result.insert("next_offset".to_string(), if has_more {
+        Json::U64((page.offset + page.limit) as u64)
+    } else {
+        Json::Null
+    });
+    result
+}
+
+fn parse_filter_request(start_height: &str, stop_hash: &str)
+                        -> Result<FilterRequest, (u16, String)> {
+    let start_height = match start_height.parse::<u32>() {
+        Ok(height) => height,
+        Err(_) => return Err((400, "Height must be a non-negative integer".to_string())),
+    };
+    Ok(FilterRequest {
+        filter_type: BASIC_FILTER,
+        start_height: start_height,
+        stop_hash: try!(parse_hash(stop_hash)),
+    })
+}
+
+fn parse_hash(hex: &str) -> Result<Sha256dHash, (u16, String)> {
+    if hex.len() != 64 {
+        return Err((400, "Expected a 64 character hash".to_string()));
+    }
+    match Sha256dHash::from_hex(hex) {
+        Ok(hash) => Ok(hash),
+        Err(_) => Err((400, "Hash must be hexadecimal".to_string())),
+    }
+}
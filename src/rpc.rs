@@ -0,0 +1,1155 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Mutex;
+use std::sync::mpsc::{Sender, channel};
+
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::hex::{FromHex, ToHex};
+use rustc_serialize::json::{self, Json};
+use secp256k1::Secp256k1;
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::blockchain::Blockchain;
+use bitcoin::network::constants::Network;
+use bitcoin::network::serialize::{BitcoinHash, deserialize, serialize};
+use bitcoin::util::base58::ToBase58;
+use bitcoin::util::hash::Sha256dHash;
+
+use address::{AddressType, Keystore, address_from_script, to_wif};
+use broadcast::Broadcaster;
+use fees::FeeEstimator;
+use filter::FilterIndex;
+use http::{self, Request, Response};
+use log;
+use mempool::Mempool;
+use merkle::{MerkleBlock, PartialMerkleTree};
+use metrics::{self, CHANNEL_BACKLOG};
+use peerd::PeerInfo;
+use spend::{Coin, Spend, create_tx};
+use storage::Storage;
+use store::BlockStore;
+use util::{ThreadResponse, addr_from_output, script_from_address, unix_time};
+use validation::{check_transaction, is_coinbase};
+use verify::{InputStatus, RetainedOutputs, TxIndex, UtxoSource, verify_tx};
+use wallet::{COINBASE_MATURITY, Wallet, balances};
+use watch::WatchList;
+use witness::WitnessTx;
+
+// error codes used by Bitcoin Core
+pub const RPC_INVALID_REQUEST: i64 = -32600;
+pub const RPC_METHOD_NOT_FOUND: i64 = -32601;
+pub const RPC_INVALID_PARAMS: i64 = -32602;
+pub const RPC_PARSE_ERROR: i64 = -32700;
+pub const RPC_MISC_ERROR: i64 = -1;
+pub const RPC_INVALID_ADDRESS_OR_KEY: i64 = -5;
+pub const RPC_INVALID_PARAMETER: i64 = -8;
+pub const RPC_WALLET_ERROR: i64 = -4;
+pub const RPC_WALLET_INSUFFICIENT_FUNDS: i64 = -6;
+pub const RPC_WALLET_NOT_FOUND: i64 = -18;
+pub const RPC_DESERIALIZATION_ERROR: i64 = -22;
+pub const RPC_VERIFY_ERROR: i64 = -25;
+pub const RPC_VERIFY_REJECTED: i64 = -26;
+pub const RPC_VERIFY_ALREADY_IN_CHAIN: i64 = -27;
+
+/// Highest fee rate `sendrawtransaction` accepts by default, in BTC/kvB as
+/// in Core
+const DEFAULT_MAX_FEE_RATE: f64 = 0.10;
+
+/// Methods that hand out, reveal or spend with the keystore's keys, which
+/// are only served when clients must authenticate
+const KEYSTORE_METHODS: [&'static str; 6] = ["getnewaddress", "importprivkey", "dumpprivkey",
+                                             "getaccountxpub", "dumpmnemonic", "sendtoaddress"];
+
+/// A call handed from the RPC server to the daemon, which owns the data
+/// needed to answer it
+pub struct RpcCall {
+    pub method: String,
+    pub params: Vec<Json>,
+    pub reply: Sender<Result<Json, RpcError>>,
+}
+
+#[derive(Debug)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: &str) -> RpcError {
+        RpcError {
+            code: code,
+            message: message.to_string(),
+        }
+    }
+
+    fn to_json(&self) -> Json {
+        object(vec![("code", Json::I64(self.code)),
+                    ("message", Json::String(self.message.clone()))])
+    }
+}
+
+/// Serve JSON-RPC on `address`, passing calls to the daemon through
+/// `events`. With `auth` set, requests must carry those credentials using
+/// HTTP basic authentication, as bitcoin-cli does. Without it the keystore
+/// methods aren't served, since any local process or web page could call
+/// them.
+pub fn start(address: &str, auth: Option<(String, String)>,
+             events: Sender<ThreadResponse>) -> Result<(), String> {
+    let serve_keystore = auth.is_some();
+    let events = Mutex::new(events);
+    try!(http::serve(address, move |request: Request| {
+        if request.method != "POST" {
+            return Response::text(405, "JSON-RPC server handles only POST requests");
+        }
+        if let Some((ref user, ref password)) = auth {
+            if !authorized(&request, user, password) {
+                let mut response = Response::text(401, "Unauthorized");
+                response.headers.push(("WWW-Authenticate".to_string(),
+                                       "Basic realm=\"jsonrpc\"".to_string()));
+                return response;
+            }
+        }
+        // browsers can't send this type cross-origin without a preflight,
+        // which we never answer, so pages can't forge calls
+        let json_body = request.header("Content-Type").map_or(false, |content_type| {
+            content_type.split(';').next().unwrap().trim().to_lowercase() == "application/json"
+        });
+        if !json_body {
+            return Response::text(415, "JSON-RPC requests must have Content-Type \
+                                        application/json");
+        }
+        let events = events.lock().unwrap().clone();
+        let body = String::from_utf8_lossy(&request.body[..]).into_owned();
+        let (status, reply) = match Json::from_str(&body) {
+            Ok(Json::Array(requests)) => {
+                let replies = requests.iter()
+                    .map(|request| handle_request(request, serve_keystore, &events).1)
+                    .collect::<Vec<Json>>();
+                (200, Json::Array(replies))
+            },
+            Ok(request) => handle_request(&request, serve_keystore, &events),
+            Err(_) => (500, reply_json(Err(RpcError::new(RPC_PARSE_ERROR, "Parse error")),
+                                      Json::Null)),
+        };
+        Response::json(status, json::encode(&reply).unwrap())
+    }));
+    info!("api", address = address; "JSON-RPC server listening");
+    Ok(())
+}
+
+/// Whether a request carries the credentials with HTTP basic
+/// authentication
+pub fn authorized(request: &Request, user: &str, password: &str) -> bool {
+    let encoded = match request.header("Authorization") {
+        Some(value) if value.starts_with("Basic ") => &value["Basic ".len()..],
+        _ => return false,
+    };
+    match encoded.from_base64() {
+        Ok(decoded) => decoded == format!("{}:{}", user, password).into_bytes(),
+        Err(_) => false,
+    }
+}
+
+/// Answer one request object, returning the HTTP status to use if it
+/// isn't part of a batch
+fn handle_request(request: &Json, serve_keystore: bool, events: &Sender<ThreadResponse>)
+                  -> (u16, Json) {
+    let id = request.find("id").map_or(Json::Null, |id| id.clone());
+    let method = match request.find("method").and_then(|method| method.as_string()) {
+        Some(method) => method.to_string(),
+        None => return (400, reply_json(Err(RpcError::new(RPC_INVALID_REQUEST,
+                                                          "Missing method")), id)),
+    };
+    let params = match request.find("params") {
+        None | Some(&Json::Null) => vec![],
+        Some(&Json::Array(ref params)) => params.clone(),
+        Some(_) => return (500, reply_json(Err(RpcError::new(RPC_INVALID_PARAMS,
+                                                             "Params must be an array")), id)),
+    };
+    if !serve_keystore && KEYSTORE_METHODS.contains(&&method[..]) {
+        return (404, reply_json(Err(RpcError::new(RPC_METHOD_NOT_FOUND,
+                                                  "Method not found (wallet methods need \
+                                                   rpc_auth to be set)")), id));
+    }
+
+    let (reply_sender, reply_receiver) = channel();
+    let call = RpcCall { method: method, params: params, reply: reply_sender };
+    metrics::add(CHANNEL_BACKLOG, &[("channel", "daemon")], 1.0);
+    let result = match events.send(ThreadResponse::Rpc(call)) {
+        Ok(()) => match reply_receiver.recv() {
+            Ok(result) => result,
+            Err(_) => Err(RpcError::new(RPC_MISC_ERROR, "Daemon did not answer")),
+        },
+        Err(_) => Err(RpcError::new(RPC_MISC_ERROR, "Daemon is shutting down")),
+    };
+    let status = match result {
+        Ok(_) => 200,
+        Err(ref e) if e.code == RPC_METHOD_NOT_FOUND => 404,
+        Err(_) => 500,
+    };
+    (status, reply_json(result, id))
+}
+
+fn reply_json(result: Result<Json, RpcError>, id: Json) -> Json {
+    let (result, error) = match result {
+        Ok(result) => (result, Json::Null),
+        Err(e) => (Json::Null, e.to_json()),
+    };
+    object(vec![("result", result), ("error", error), ("id", id)])
+}
+
+/// What the daemon knows, borrowed to answer a call
+pub struct Node<'a> {
+    pub network: Network,
+    pub blockchain: &'a Blockchain,
+    pub block_store: &'a BlockStore,
+    pub tx_index: &'a TxIndex,
+    pub filter_index: &'a FilterIndex,
+    pub mempool: &'a Mempool,
+    /// Where to find prevouts outside the retention window
+    pub utxo_source: Option<&'a Box<UtxoSource + Send>>,
+    pub fee_estimator: &'a FeeEstimator,
+    /// Hashes of the retained blocks, oldest first
+    pub retained: &'a VecDeque<Sha256dHash>,
+    pub peers: &'a HashMap<String, PeerInfo>,
+    pub misbehavior: &'a HashMap<String, u32>,
+    pub connection_count: usize,
+    pub storage: &'a mut Storage,
+    pub watch_list: &'a mut WatchList,
+    pub keystore: &'a mut Option<Keystore>,
+    pub wallet: &'a mut Wallet,
+    pub broadcaster: &'a mut Broadcaster,
+}
+
+pub fn dispatch(node: &mut Node, method: &str, params: &[Json]) -> Result<Json, RpcError> {
+    match method {
+        "getblockchaininfo" => getblockchaininfo(node),
+        "getbestblockhash" => Ok(Json::String(node.blockchain.best_tip_hash().be_hex_string())),
+        "getblockhash" => getblockhash(node, params),
+        "getblockheader" => getblockheader(node, params),
+        "getblock" => getblock(node, params),
+        "getblockfilter" => getblockfilter(node, params),
+        "getrawtransaction" => getrawtransaction(node, params),
+        "gettxoutproof" => gettxoutproof(node, params),
+        "verifytxoutproof" => verifytxoutproof(node, params),
+        "getpeerinfo" => getpeerinfo(node),
+        "getconnectioncount" => Ok(Json::U64(node.connection_count as u64)),
+        "getmempoolinfo" => getmempoolinfo(node),
+        "logging" => logging(params),
+        "importaddress" => importaddress(node, params),
+        "importxpub" => importxpub(node, params),
+        "removewatched" => removewatched(node, params),
+        "listwatched" => Ok(node.watch_list.to_json()),
+        "getnewaddress" => getnewaddress(node, params),
+        "importprivkey" => importprivkey(node, params),
+        "dumpprivkey" => dumpprivkey(node, params),
+        "getaccountxpub" => getaccountxpub(node, params),
+        "dumpmnemonic" => match *node.keystore {
+            Some(ref keystore) => Ok(keystore.mnemonic().map_or(Json::Null, |mnemonic| {
+                Json::String(mnemonic.to_string())
+            })),
+            None => Err(no_keystore()),
+        },
+        "importdescriptor" => importdescriptor(node, params),
+        "listdescriptors" => Ok(object(vec![
+            ("descriptors", Json::Array(node.wallet.descriptors().iter()
+                                        .map(|text| object(vec![
+                                            ("desc", Json::String(text.to_string())),
+                                        ]))
+                                        .collect())),
+        ])),
+        "getbalances" => getbalances(node),
+        "listunspent" => listunspent(node, params),
+        "sendtoaddress" => sendtoaddress(node, params),
+        "sendrawtransaction" => sendrawtransaction(node, params),
+        "getbroadcastinfo" => {
+            let txid = try!(param_hash(params, 0));
+            match node.broadcaster.status_json(&txid) {
+                Some(status) => Ok(status),
+                None => Err(RpcError::new(RPC_INVALID_ADDRESS_OR_KEY,
+                                          "Transaction was not broadcast by this node")),
+            }
+        },
+        _ => Err(RpcError::new(RPC_METHOD_NOT_FOUND, "Method not found")),
+    }
+}
+
+fn getblockchaininfo(node: &Node) -> Result<Json, RpcError> {
+    let tip = node.blockchain.best_tip_hash();
+    let height = best_height(node.blockchain);
+    let header = node.blockchain.get_block(tip).map(|block_node_ref| block_node_ref.block.header);
+    let prune_height = node.retained.front()
+        .and_then(|&hash| node.blockchain.get_block(hash))
+        .map_or(height, |block_node_ref| block_node_ref.height);
+    Ok(object(vec![
+        ("chain", Json::String(match node.network {
+            Network::Bitcoin => "main".to_string(),
+            Network::Testnet => "test".to_string(),
+        })),
+        ("blocks", Json::U64(height as u64)),
+        ("headers", Json::U64(height as u64)),
+        ("bestblockhash", Json::String(tip.be_hex_string())),
+        ("difficulty", Json::F64(header.map_or(1.0, |header| difficulty(header.bits)))),
+        ("mediantime", Json::U64(median_time(node.blockchain, tip) as u64)),
+        ("pruned", Json::Boolean(true)),
+        ("pruneheight", Json::U64(prune_height as u64)),
+        ("warnings", Json::String("".to_string())),
+    ]))
+}
+
+fn getblockhash(node: &Node, params: &[Json]) -> Result<Json, RpcError> {
+    let height = try!(param_u64(params, 0));
+    if height > u32::max_value() as u64 {
+        return Err(RpcError::new(RPC_INVALID_PARAMETER, "Block height out of range"));
+    }
+    match best_chain_hash_at(node.blockchain, height as u32) {
+        Some(hash) => Ok(Json::String(hash.be_hex_string())),
+        None => Err(RpcError::new(RPC_INVALID_PARAMETER, "Block height out of range")),
+    }
+}
+
+fn getblockheader(node: &Node, params: &[Json]) -> Result<Json, RpcError> {
+    let hash = try!(param_hash(params, 0));
+    let verbose = try!(param_verbosity(params, 1, 1)) > 0;
+    let header = match node.blockchain.get_block(hash) {
+        Some(block_node_ref) => block_node_ref.block.header,
+        None => return Err(RpcError::new(RPC_INVALID_ADDRESS_OR_KEY, "Block not found")),
+    };
+    if !verbose {
+        return Ok(Json::String(serialize(&header).unwrap().to_hex()));
+    }
+    Ok(Json::Object(header_json(node, &header)))
+}
+
+fn getblock(node: &Node, params: &[Json]) -> Result<Json, RpcError> {
+    let hash = try!(param_hash(params, 0));
+    let verbosity = try!(param_verbosity(params, 1, 1));
+    if node.blockchain.get_block(hash).is_none() {
+        return Err(RpcError::new(RPC_INVALID_ADDRESS_OR_KEY, "Block not found"));
+    }
+    let block = match node.block_store.get(&hash) {
+        Some(block) => block,
+        None => return Err(RpcError::new(RPC_MISC_ERROR,
+                                         "Block not available (pruned data)")),
+    };
+    if verbosity == 0 {
+        return Ok(Json::String(serialize(&block).unwrap().to_hex()));
+    }
+
+    let mut result = header_json(node, &block.header);
+    result.insert("size".to_string(), Json::U64(block.size() as u64));
+    result.insert("strippedsize".to_string(),
+                  Json::U64(serialize(&block.block()).unwrap().len() as u64));
+    result.insert("weight".to_string(), Json::U64(block.weight() as u64));
+    result.insert("nTx".to_string(), Json::U64(block.txdata.len() as u64));
+    let txs = block.txdata.iter()
+        .map(|wtx| if verbosity > 1 {
+            Json::Object(tx_json(wtx))
+        } else {
+            Json::String(wtx.txid().be_hex_string())
+        })
+        .collect::<Vec<Json>>();
+    result.insert("tx".to_string(), Json::Array(txs));
+    Ok(Json::Object(result))
+}
+
+/// A block's basic filter and filter header, as in Core
+fn getblockfilter(node: &Node, params: &[Json]) -> Result<Json, RpcError> {
+    let hash = try!(param_hash(params, 0));
+    if params.len() > 1 && try!(param_str(params, 1)) != "basic" {
+        return Err(RpcError::new(RPC_INVALID_ADDRESS_OR_KEY, "Unknown filtertype"));
+    }
+    if node.blockchain.get_block(hash).is_none() {
+        return Err(RpcError::new(RPC_INVALID_ADDRESS_OR_KEY, "Block not found"));
+    }
+    match node.filter_index.block_filter(&hash) {
+        Some((filter, header)) => Ok(object(vec![
+            ("filter", Json::String(filter.to_hex())),
+            ("header", header.map_or(Json::Null,
+                                     |header| Json::String(header.be_hex_string()))),
+        ])),
+        None => Err(RpcError::new(RPC_MISC_ERROR, "Filter not available for this block")),
+    }
+}
+
+/// Hex of a proof that transactions are in a retained block, as in Core
+fn gettxoutproof(node: &Node, params: &[Json]) -> Result<Json, RpcError> {
+    let txids = match params.get(0).and_then(|param| param.as_array()) {
+        Some(array) if !array.is_empty() => {
+            let mut txids = vec![];
+            for i in 0..array.len() {
+                let txid = try!(param_hash(&array[..], i));
+                if txids.contains(&txid) {
+                    return Err(RpcError::new(RPC_INVALID_PARAMETER,
+                                             "Invalid parameter, duplicated txid"));
+                }
+                txids.push(txid);
+            }
+            txids
+        },
+        _ => return Err(RpcError::new(RPC_INVALID_PARAMETER,
+                                      "Expected a non-empty array of txids")),
+    };
+    let block_hash = match params.get(1) {
+        None | Some(&Json::Null) => None,
+        Some(_) => Some(try!(param_hash(params, 1))),
+    };
+    let proof = try!(tx_proof(node, &txids[..], block_hash));
+    Ok(Json::String(serialize(&proof).unwrap().to_hex()))
+}
+
+/// The txids a proof from `gettxoutproof` commits to, as in Core
+fn verifytxoutproof(node: &Node, params: &[Json]) -> Result<Json, RpcError> {
+    let decoded = try!(param_str(params, 0)).from_hex().ok()
+        .and_then(|data| deserialize::<MerkleBlock>(&data).ok());
+    let proof = match decoded {
+        Some(proof) => proof,
+        None => return Err(RpcError::new(RPC_DESERIALIZATION_ERROR, "Proof decode failed")),
+    };
+    let txids = try!(verify_tx_proof(node, &proof));
+    Ok(Json::Array(txids.iter().map(|txid| Json::String(txid.be_hex_string())).collect()))
+}
+
+fn getrawtransaction(node: &Node, params: &[Json]) -> Result<Json, RpcError> {
+    let txid = try!(param_hash(params, 0));
+    let verbose = try!(param_verbosity(params, 1, 0)) > 0;
+
+    if let Some(entry) = node.mempool.get(&txid) {
+        if !verbose {
+            return Ok(Json::String(serialize(&entry.tx).unwrap().to_hex()));
+        }
+        let mut result = tx_json(&entry.tx);
+        result.insert("time".to_string(), Json::U64(entry.first_seen));
+        return Ok(Json::Object(result));
+    }
+
+    let (block_hash, position) = match node.tx_index.get(&txid) {
+        Some(location) => location,
+        None => return Err(RpcError::new(RPC_INVALID_ADDRESS_OR_KEY,
+                                         "No such mempool or retained transaction")),
+    };
+    let (block, wtx) = match node.block_store.get(&block_hash) {
+        Some(block) => {
+            let wtx = block.txdata[position].clone();
+            (block, wtx)
+        },
+        None => return Err(RpcError::new(RPC_MISC_ERROR, "Block not available")),
+    };
+    if !verbose {
+        return Ok(Json::String(serialize(&wtx).unwrap().to_hex()));
+    }
+    let mut result = tx_json(&wtx);
+    result.insert("blockhash".to_string(), Json::String(block_hash.be_hex_string()));
+    result.insert("confirmations".to_string(),
+                  Json::I64(confirmations(node.blockchain, &block_hash)));
+    result.insert("time".to_string(), Json::U64(block.header.time as u64));
+    result.insert("blocktime".to_string(), Json::U64(block.header.time as u64));
+    Ok(Json::Object(result))
+}
+
+fn getpeerinfo(node: &Node) -> Result<Json, RpcError> {
+    let mut addrs = node.peers.keys().collect::<Vec<&String>>();
+    addrs.sort();
+    let peers = addrs.into_iter().enumerate()
+        .map(|(id, addr)| {
+            let info = &node.peers[addr];
+            object(vec![
+                ("id", Json::U64(id as u64)),
+                ("addr", Json::String(format!("{}:{}", addr, info.port))),
+                ("services", Json::String(format!("{:016x}", info.services))),
+                ("version", Json::U64(info.version as u64)),
+                ("subver", Json::String(info.user_agent.clone())),
+                ("inbound", Json::Boolean(false)),
+                ("startingheight", Json::I64(info.start_height as i64)),
+                ("conntime", Json::U64(info.connected_at)),
+                ("banscore", Json::U64(*node.misbehavior.get(addr).unwrap_or(&0) as u64)),
+            ])
+        })
+        .collect::<Vec<Json>>();
+    Ok(Json::Array(peers))
+}
+
+fn getmempoolinfo(node: &Node) -> Result<Json, RpcError> {
+    let (mut bytes, mut usage) = (0, 0);
+    for entry in node.mempool.entries() {
+        bytes += entry.vsize as u64;
+        usage += entry.tx.total_size() as u64;
+    }
+    Ok(object(vec![
+        ("loaded", Json::Boolean(true)),
+        ("size", Json::U64(node.mempool.len() as u64)),
+        ("bytes", Json::U64(bytes)),
+        ("usage", Json::U64(usage)),
+    ]))
+}
+
+/// Change log settings with an object of `"level"`, `"format"` and
+/// per-target levels, such as `{"level": "info", "net": "debug"}`, and
+/// return the settings in effect
+fn logging(params: &[Json]) -> Result<Json, RpcError> {
+    let changes = match params.get(0) {
+        Some(&Json::Object(ref changes)) => changes.clone(),
+        Some(&Json::Null) | None => BTreeMap::new(),
+        Some(_) => return Err(RpcError::new(RPC_INVALID_PARAMETER,
+                                            "Expected an object of log settings")),
+    };
+    // check everything before changing anything
+    let mut levels = vec![];
+    let mut format = None;
+    for (key, value) in changes.iter() {
+        let value = match value.as_string() {
+            Some(value) => value,
+            None => return Err(RpcError::new(RPC_INVALID_PARAMETER,
+                                             &format!("Expected a string for {}", key))),
+        };
+        let invalid = |e: String| RpcError::new(RPC_INVALID_PARAMETER, &e);
+        match key.as_str() {
+            "format" => format = Some(try!(log::Format::parse(value).map_err(invalid))),
+            "level" => levels.push((None, try!(log::Level::parse(value).map_err(invalid)))),
+            target if log::TARGETS.contains(&target) =>
+                levels.push((Some(target), try!(log::Level::parse(value).map_err(invalid)))),
+            _ => return Err(RpcError::new(RPC_INVALID_PARAMETER,
+                                          &format!("Unknown log setting {}", key))),
+        }
+    }
+    for (target, level) in levels {
+        if let Err(e) = log::set_level(target, level) {
+            return Err(RpcError::new(RPC_INVALID_PARAMETER, &e));
+        }
+    }
+    if let Some(format) = format {
+        log::set_format(format);
+    }
+    if !changes.is_empty() {
+        info!("api", settings = Json::Object(changes.clone()); "Log settings changed");
+    }
+    Ok(log::config().to_json())
+}
+
+fn header_json(node: &Node, header: &BlockHeader) -> BTreeMap<String, Json> {
+    let hash = header.bitcoin_hash();
+    let height = node.blockchain.get_block(hash).map_or(0, |block_node_ref| block_node_ref.height);
+    let mut result = BTreeMap::new();
+    result.insert("hash".to_string(), Json::String(hash.be_hex_string()));
+    result.insert("confirmations".to_string(),
+                  Json::I64(confirmations(node.blockchain, &hash)));
+    result.insert("height".to_string(), Json::U64(height as u64));
+    result.insert("version".to_string(), Json::U64(header.version as u64));
+    result.insert("versionHex".to_string(), Json::String(format!("{:08x}", header.version)));
+    result.insert("merkleroot".to_string(), Json::String(header.merkle_root.be_hex_string()));
+    result.insert("time".to_string(), Json::U64(header.time as u64));
+    result.insert("mediantime".to_string(),
+                  Json::U64(median_time(node.blockchain, hash) as u64));
+    result.insert("nonce".to_string(), Json::U64(header.nonce as u64));
+    result.insert("bits".to_string(), Json::String(format!("{:08x}", header.bits)));
+    result.insert("difficulty".to_string(), Json::F64(difficulty(header.bits)));
+    if height > 0 {
+        result.insert("previousblockhash".to_string(),
+                      Json::String(header.prev_blockhash.be_hex_string()));
+    }
+    if confirmations(node.blockchain, &hash) > 0 {
+        if let Some(next) = best_chain_hash_at(node.blockchain, height + 1) {
+            result.insert("nextblockhash".to_string(), Json::String(next.be_hex_string()));
+        }
+    }
+    result
+}
+
+/// A transaction as Bitcoin Core shows it, without script disassembly
+pub fn tx_json(wtx: &WitnessTx) -> BTreeMap<String, Json> {
+    let vin = wtx.tx.input.iter().enumerate()
+        .map(|(i, input)| {
+            let mut vin = BTreeMap::new();
+            let script_hex = input.script_sig.clone().into_vec().to_hex();
+            if is_coinbase(&wtx.tx) {
+                vin.insert("coinbase".to_string(), Json::String(script_hex));
+            } else {
+                vin.insert("txid".to_string(), Json::String(input.prev_hash.be_hex_string()));
+                vin.insert("vout".to_string(), Json::U64(input.prev_index as u64));
+                vin.insert("scriptSig".to_string(),
+                           object(vec![("hex", Json::String(script_hex))]));
+            }
+            if let Some(stack) = wtx.witness.get(i) {
+                if !stack.is_empty() {
+                    vin.insert("txinwitness".to_string(), Json::Array(
+                        stack.iter().map(|item| Json::String(item.to_hex())).collect()));
+                }
+            }
+            vin.insert("sequence".to_string(), Json::U64(input.sequence as u64));
+            Json::Object(vin)
+        })
+        .collect::<Vec<Json>>();
+
+    let vout = wtx.tx.output.iter().enumerate()
+        .map(|(n, output)| {
+            let mut script_pubkey = BTreeMap::new();
+            script_pubkey.insert("hex".to_string(),
+                                 Json::String(output.script_pubkey.clone().into_vec().to_hex()));
+            if let Some(address) = addr_from_output(output) {
+                script_pubkey.insert("addresses".to_string(),
+                                     Json::Array(vec![Json::String(address)]));
+            }
+            object(vec![
+                ("value", Json::F64(output.value as f64 / 100000000.0)),
+                ("n", Json::U64(n as u64)),
+                ("scriptPubKey", Json::Object(script_pubkey)),
+            ])
+        })
+        .collect::<Vec<Json>>();
+
+    let mut result = BTreeMap::new();
+    result.insert("txid".to_string(), Json::String(wtx.txid().be_hex_string()));
+    result.insert("hash".to_string(), Json::String(wtx.wtxid().be_hex_string()));
+    result.insert("version".to_string(), Json::U64(wtx.tx.version as u64));
+    result.insert("size".to_string(), Json::U64(wtx.total_size() as u64));
+    result.insert("vsize".to_string(), Json::U64(((wtx.weight() + 3) / 4) as u64));
+    result.insert("weight".to_string(), Json::U64(wtx.weight() as u64));
+    result.insert("locktime".to_string(), Json::U64(wtx.tx.lock_time as u64));
+    result.insert("vin".to_string(), Json::Array(vin));
+    result.insert("vout".to_string(), Json::Array(vout));
+    result.insert("hex".to_string(), Json::String(serialize(wtx).unwrap().to_hex()));
+    result
+}
+
+pub fn object(pairs: Vec<(&str, Json)>) -> Json {
+    Json::Object(pairs.into_iter()
+                 .map(|(key, value)| (key.to_string(), value))
+                 .collect::<BTreeMap<String, Json>>())
+}
+
+/// A proof that transactions are in a retained block: its header and the
+/// merkle branches of the transactions. Without `block_hash`, the block is
+/// the one holding the first transaction.
+pub fn tx_proof(node: &Node, txids: &[Sha256dHash], block_hash: Option<Sha256dHash>)
+                -> Result<MerkleBlock, RpcError> {
+    let block_hash = match block_hash {
+        Some(hash) => {
+            if node.blockchain.get_block(hash).is_none() {
+                return Err(RpcError::new(RPC_INVALID_ADDRESS_OR_KEY, "Block not found"));
+            }
+            hash
+        },
+        None => match txids.first().and_then(|txid| node.tx_index.get(txid)) {
+            Some((hash, _)) => hash,
+            None => return Err(RpcError::new(RPC_INVALID_ADDRESS_OR_KEY,
+                                             "Transaction not in a retained block")),
+        },
+    };
+    let block = match node.block_store.get(&block_hash) {
+        Some(block) => block,
+        None => return Err(RpcError::new(RPC_MISC_ERROR, "Block not available (pruned data)")),
+    };
+    let block_txids = block.txdata.iter().map(|wtx| wtx.txid()).collect::<Vec<Sha256dHash>>();
+    let matches = block_txids.iter()
+        .map(|txid| txids.contains(txid))
+        .collect::<Vec<bool>>();
+    if matches.iter().filter(|&&matched| matched).count() != txids.len() {
+        return Err(RpcError::new(RPC_INVALID_ADDRESS_OR_KEY,
+                                 "Not all transactions found in specified or retrieved block"));
+    }
+    Ok(MerkleBlock {
+        header: block.header,
+        tree: PartialMerkleTree::new(&block_txids[..], &matches[..]),
+    })
+}
+
+/// The txids a proof commits to, once its block is found on the best
+/// chain. A proof whose branches don't lead to its block's merkle root
+/// proves nothing, so gives no txids.
+pub fn verify_tx_proof(node: &Node, proof: &MerkleBlock) -> Result<Vec<Sha256dHash>, RpcError> {
+    let txids = match proof.tree.extract_matches() {
+        Ok((root, txids)) if root == proof.header.merkle_root => txids,
+        _ => return Ok(vec![]),
+    };
+    if confirmations(node.blockchain, &proof.header.bitcoin_hash()) < 0 {
+        return Err(RpcError::new(RPC_INVALID_ADDRESS_OR_KEY, "Block not found in chain"));
+    }
+    Ok(txids)
+}
+
+pub fn best_height(blockchain: &Blockchain) -> u32 {
+    blockchain.get_block(blockchain.best_tip_hash())
+        .map_or(0, |block_node_ref| block_node_ref.height)
+}
+
+/// Hash of the block at `height` on the best chain
+pub fn best_chain_hash_at(blockchain: &Blockchain, height: u32) -> Option<Sha256dHash> {
+    let best = best_height(blockchain);
+    if height > best {
+        return None;
+    }
+    blockchain.rev_iter(blockchain.best_tip_hash())
+        .nth((best - height) as usize)
+        .map(|block_node_ref| block_node_ref.block.bitcoin_hash())
+}
+
+/// Confirmations of a block, or -1 if it isn't on the best chain
+pub fn confirmations(blockchain: &Blockchain, hash: &Sha256dHash) -> i64 {
+    let height = match blockchain.get_block(*hash) {
+        Some(block_node_ref) => block_node_ref.height,
+        None => return -1,
+    };
+    if best_chain_hash_at(blockchain, height) == Some(*hash) {
+        (best_height(blockchain) - height + 1) as i64
+    } else {
+        -1
+    }
+}
+
+/// Watch an address or hex script, as Core's `importaddress` does for its
+/// wallet
+fn importaddress(node: &mut Node, params: &[Json]) -> Result<Json, RpcError> {
+    let value = try!(param_str(params, 0));
+    let added = match node.watch_list.add_address(value) {
+        Ok(added) => added,
+        Err(e) => match node.watch_list.add_script(value) {
+            Ok(added) => added,
+            Err(_) => return Err(RpcError::new(RPC_INVALID_ADDRESS_OR_KEY, &e)),
+        },
+    };
+    save_watch_list(node, added)
+}
+
+fn importxpub(node: &mut Node, params: &[Json]) -> Result<Json, RpcError> {
+    let xpub = try!(param_str(params, 0));
+    match node.watch_list.add_xpub(xpub) {
+        Ok(added) => save_watch_list(node, added),
+        Err(e) => Err(RpcError::new(RPC_INVALID_ADDRESS_OR_KEY, &e)),
+    }
+}
+
+fn removewatched(node: &mut Node, params: &[Json]) -> Result<Json, RpcError> {
+    let watched = try!(param_str(params, 0));
+    if !node.watch_list.remove(watched) {
+        return Err(RpcError::new(RPC_INVALID_PARAMETER, "Not watched"));
+    }
+    save_watch_list(node, true)
+}
+
+fn save_watch_list(node: &Node, changed: bool) -> Result<Json, RpcError> {
+    if changed {
+        if let Err(e) = node.watch_list.save() {
+            return Err(RpcError::new(RPC_MISC_ERROR, &e));
+        }
+    }
+    Ok(Json::Null)
+}
+
+fn no_keystore() -> RpcError {
+    RpcError::new(RPC_WALLET_NOT_FOUND, "No keystore is configured")
+}
+
+/// Address type parameter by Core's names, bech32 if left out
+fn param_address_type(params: &[Json], i: usize) -> Result<AddressType, RpcError> {
+    match params.get(i) {
+        None | Some(&Json::Null) => Ok(AddressType::P2wpkh),
+        Some(param) => match param.as_string().and_then(AddressType::from_name) {
+            Some(address_type) => Ok(address_type),
+            None => Err(RpcError::new(RPC_INVALID_PARAMETER, "Unknown address type")),
+        },
+    }
+}
+
+/// Next receive address of the keystore. The first parameter is a label,
+/// which is accepted for compatibility and ignored.
+fn getnewaddress(node: &mut Node, params: &[Json]) -> Result<Json, RpcError> {
+    let address_type = try!(param_address_type(params, 1));
+    match *node.keystore {
+        Some(ref mut keystore) => {
+            match hand_out_address(keystore, node.wallet, address_type, false) {
+                Ok(address) => Ok(Json::String(address)),
+                Err(e) => Err(RpcError::new(RPC_MISC_ERROR, &e)),
+            }
+        },
+        None => Err(no_keystore()),
+    }
+}
+
+/// Hand out the keystore's next address, keeping the wallet watching
+/// past it
+fn hand_out_address(keystore: &mut Keystore, wallet: &mut Wallet, address_type: AddressType,
+                    change: bool) -> Result<String, String> {
+    let address = try!(keystore.new_address(address_type, change));
+    let descriptor = try!(keystore.account_descriptor(address_type));
+    try!(wallet.extend(&descriptor, if change { 1 } else { 0 },
+                       keystore.handed_out(address_type, change)));
+    Ok(address)
+}
+
+/// Import a key, and have the wallet watch and rescan for its outputs
+fn importprivkey(node: &mut Node, params: &[Json]) -> Result<Json, RpcError> {
+    let wif = try!(param_str(params, 0));
+    let descriptors = match *node.keystore {
+        Some(ref mut keystore) => {
+            match keystore.import_wif(wif).and_then(|_| keystore.descriptors()) {
+                Ok(descriptors) => descriptors,
+                Err(e) => return Err(RpcError::new(RPC_INVALID_ADDRESS_OR_KEY, &e)),
+            }
+        },
+        None => return Err(no_keystore()),
+    };
+    let mut added = false;
+    for descriptor in descriptors.iter() {
+        match node.wallet.add_descriptor(descriptor) {
+            Ok(new) => added |= new,
+            Err(e) => return Err(RpcError::new(RPC_MISC_ERROR, &e)),
+        }
+    }
+    if added {
+        node.wallet.rescan(node.blockchain, node.block_store, node.retained);
+    }
+    Ok(Json::Null)
+}
+
+fn dumpprivkey(node: &mut Node, params: &[Json]) -> Result<Json, RpcError> {
+    let address = try!(param_str(params, 0));
+    let keystore = match *node.keystore {
+        Some(ref keystore) => keystore,
+        None => return Err(no_keystore()),
+    };
+    let script = match script_from_address(address) {
+        Ok(script) => script,
+        Err(e) => return Err(RpcError::new(RPC_INVALID_ADDRESS_OR_KEY, &e)),
+    };
+    match keystore.key_for_script(&script[..]) {
+        Some((key, compressed)) => Ok(Json::String(to_wif(keystore.network, &key, compressed))),
+        None => Err(RpcError::new(RPC_WALLET_ERROR, "Private key for address is not known")),
+    }
+}
+
+/// Account xpub of an address type, to watch the account elsewhere
+fn getaccountxpub(node: &mut Node, params: &[Json]) -> Result<Json, RpcError> {
+    let address_type = try!(param_address_type(params, 0));
+    let account = match params.get(1) {
+        None | Some(&Json::Null) => 0,
+        Some(_) => try!(param_u64(params, 1)),
+    };
+    if account >= 1 << 31 {
+        return Err(RpcError::new(RPC_INVALID_PARAMETER, "Account out of range"));
+    }
+    match *node.keystore {
+        Some(ref keystore) => match keystore.account_xpub(address_type, account as u32) {
+            Ok(xpub) => Ok(Json::String(xpub.to_base58check())),
+            Err(e) => Err(RpcError::new(RPC_MISC_ERROR, &e)),
+        },
+        None => Err(no_keystore()),
+    }
+}
+
+/// Track an output descriptor in the watch-only wallet, scanning the
+/// retained blocks for its outputs
+fn importdescriptor(node: &mut Node, params: &[Json]) -> Result<Json, RpcError> {
+    let descriptor = try!(param_str(params, 0));
+    match node.wallet.add_descriptor(descriptor) {
+        Ok(false) => return Ok(Json::Boolean(false)),
+        Ok(true) => (),
+        Err(e) => return Err(RpcError::new(RPC_INVALID_ADDRESS_OR_KEY, &e)),
+    }
+    if let Err(e) = node.wallet.save() {
+        return Err(RpcError::new(RPC_MISC_ERROR, &e));
+    }
+    node.wallet.rescan(node.blockchain, node.block_store, node.retained);
+    Ok(Json::Boolean(true))
+}
+
+/// The watch-only wallet's balances in BTC, in the shape of Core's
+/// `getbalances`
+fn getbalances(node: &mut Node) -> Result<Json, RpcError> {
+    let utxos = node.wallet.unspent(node.mempool);
+    let balances = balances(&utxos[..], node.blockchain);
+    let btc = |satoshis: u64| Json::F64(satoshis as f64 / 100000000.0);
+    Ok(object(vec![
+        ("watchonly", object(vec![
+            ("trusted", btc(balances.confirmed)),
+            ("untrusted_pending", btc(balances.unconfirmed)),
+            ("immature", btc(balances.immature)),
+        ])),
+    ]))
+}
+
+/// The watch-only wallet's unspent outputs with at least `minconf` and at
+/// most `maxconf` confirmations
+fn listunspent(node: &mut Node, params: &[Json]) -> Result<Json, RpcError> {
+    let min_confirmations = match params.get(0) {
+        None | Some(&Json::Null) => 1,
+        Some(_) => try!(param_u64(params, 0)) as i64,
+    };
+    let max_confirmations = match params.get(1) {
+        None | Some(&Json::Null) => 9999999,
+        Some(_) => try!(param_u64(params, 1)) as i64,
+    };
+    let utxos = node.wallet.unspent(node.mempool);
+    let unspent = utxos.iter()
+        .map(|utxo| {
+            let count = utxo.block.map_or(0, |(hash, _)| confirmations(node.blockchain, &hash).max(0));
+            (utxo, count)
+        })
+        .filter(|&(_, count)| count >= min_confirmations && count <= max_confirmations)
+        .map(|(utxo, count)| {
+            let address = address_from_script(node.network, &utxo.script[..]);
+            object(vec![
+                ("txid", Json::String(utxo.txid.be_hex_string())),
+                ("vout", Json::U64(utxo.vout as u64)),
+                ("address", address.map_or(Json::Null, |address| Json::String(address))),
+                ("scriptPubKey", Json::String(utxo.script.to_hex())),
+                ("amount", Json::F64(utxo.value as f64 / 100000000.0)),
+                ("confirmations", Json::I64(count)),
+                ("desc", Json::String(utxo.descriptor.clone())),
+                ("spendable", Json::Boolean(false)),
+                ("solvable", Json::Boolean(true)),
+                ("safe", Json::Boolean(count > 0)),
+            ])
+        })
+        .collect::<Vec<Json>>();
+    Ok(Json::Array(unspent))
+}
+
+/// Pay an address from the keystore's coins and broadcast the payment, as
+/// Core's `sendtoaddress`. Parameters are in Core's order. The comments,
+/// estimate mode and avoid_reuse are ignored, and the fee can't be taken
+/// from the amount. Change goes to a new bech32 address.
+fn sendtoaddress(node: &mut Node, params: &[Json]) -> Result<Json, RpcError> {
+    let script = match script_from_address(try!(param_str(params, 0))) {
+        Ok(script) => script,
+        Err(e) => return Err(RpcError::new(RPC_INVALID_ADDRESS_OR_KEY, &e)),
+    };
+    let amount = try!(param_amount(params, 1));
+    if try!(param_bool(params, 4, false)) {
+        return Err(RpcError::new(RPC_INVALID_PARAMETER,
+                                 "subtractfeefromamount is not supported"));
+    }
+    let spend = Spend {
+        recipients: vec![(script, amount)],
+        fee_rate: try!(param_fee_rate(node, params, 6, 9)),
+        replaceable: try!(param_bool(params, 5, true)),
+        lock_time: best_height(node.blockchain),
+    };
+    let coins = try!(spendable_coins(node));
+
+    let secp = Secp256k1::new();
+    let wallet = &mut *node.wallet;
+    let created = match *node.keystore {
+        Some(ref mut keystore) => {
+            create_tx(&secp, &spend, &coins[..], AddressType::P2wpkh, || {
+                let address = try!(hand_out_address(keystore, wallet, AddressType::P2wpkh, true));
+                script_from_address(&address)
+            })
+        },
+        None => return Err(no_keystore()),
+    };
+    let (wtx, fee) = match created {
+        Ok(created) => created,
+        Err(ref e) if e == "Insufficient funds" => {
+            return Err(RpcError::new(RPC_WALLET_INSUFFICIENT_FUNDS, e));
+        },
+        Err(e) => return Err(RpcError::new(RPC_WALLET_ERROR, &e)),
+    };
+    let txid = wtx.txid();
+    info!("wallet", txid = txid.be_hex_string(), amount = amount, fee = fee;
+          "Created transaction");
+    if let Err(e) = node.broadcaster.submit(wtx, unix_time()) {
+        return Err(RpcError::new(RPC_MISC_ERROR, &e));
+    }
+    Ok(Json::String(txid.be_hex_string()))
+}
+
+/// Check a signed transaction and announce it to a few peers, as Core's
+/// `sendrawtransaction`. `getbroadcastinfo` then tells whether peers asked
+/// for it and relayed it. The fee can only be checked against `maxfeerate`
+/// when every prevout is retained, unconfirmed or in the UTXO source.
+fn sendrawtransaction(node: &mut Node, params: &[Json]) -> Result<Json, RpcError> {
+    let decoded = try!(param_str(params, 0)).from_hex().ok()
+        .and_then(|data| deserialize::<WitnessTx>(&data).ok());
+    let wtx = match decoded {
+        Some(wtx) => wtx,
+        None => return Err(RpcError::new(RPC_DESERIALIZATION_ERROR, "TX decode failed")),
+    };
+    let max_fee_rate = match params.get(1) {
+        None | Some(&Json::Null) => DEFAULT_MAX_FEE_RATE,
+        Some(param) => match param.as_f64() {
+            Some(rate) if rate >= 0.0 => rate,
+            _ => return Err(RpcError::new(RPC_INVALID_PARAMETER,
+                                          "maxfeerate must be a non-negative number")),
+        },
+    };
+    let txid = wtx.txid();
+    if node.broadcaster.contains(&txid) {
+        return Ok(Json::String(txid.be_hex_string()));
+    }
+    if let Err(e) = check_transaction(&wtx.tx) {
+        return Err(RpcError::new(RPC_VERIFY_REJECTED, &e));
+    }
+    if is_coinbase(&wtx.tx) {
+        return Err(RpcError::new(RPC_VERIFY_REJECTED, "coinbase"));
+    }
+    if node.tx_index.get(&txid).is_some() {
+        return Err(RpcError::new(RPC_VERIFY_ALREADY_IN_CHAIN,
+                                 "Transaction already in block chain"));
+    }
+
+    let source = RetainedOutputs {
+        index: node.tx_index,
+        blockchain: node.blockchain,
+        mempool: node.mempool,
+        fallback: node.utxo_source,
+    };
+    let mut input_value = Some(0);
+    for input in wtx.tx.input.iter() {
+        input_value = match (input_value, source.prevout(&input.prev_hash, input.prev_index)) {
+            (Some(total), Some(prevout)) => Some(total + prevout.value),
+            _ => None,
+        };
+    }
+    if let Some(input_value) = input_value {
+        let output_value = wtx.tx.output.iter().map(|output| output.value).sum::<u64>();
+        if input_value < output_value {
+            return Err(RpcError::new(RPC_VERIFY_REJECTED, "bad-txns-in-belowout"));
+        }
+        let vsize = (wtx.weight() + 3) / 4;
+        let max_fee = max_fee_rate * 100000000.0 * vsize as f64 / 1000.0;
+        if max_fee_rate > 0.0 && (input_value - output_value) as f64 > max_fee {
+            return Err(RpcError::new(RPC_VERIFY_ERROR,
+                                     "Fee exceeds maximum configured by user (maxfeerate)"));
+        }
+    }
+    if verify_tx(&wtx, &source).iter().any(|status| *status == InputStatus::Failed) {
+        return Err(RpcError::new(RPC_VERIFY_REJECTED, "mandatory-script-verify-flag-failed"));
+    }
+
+    if let Err(e) = node.broadcaster.submit(wtx, unix_time()) {
+        return Err(RpcError::new(RPC_MISC_ERROR, &e));
+    }
+    Ok(Json::String(txid.be_hex_string()))
+}
+
+/// Confirmed, mature wallet outputs the keystore has keys for. Outputs
+/// spent by our unsettled broadcasts are left out too, since they may not
+/// have reached the mempool yet.
+fn spendable_coins(node: &mut Node) -> Result<Vec<Coin>, RpcError> {
+    let keystore = match *node.keystore {
+        Some(ref keystore) => keystore,
+        None => return Err(no_keystore()),
+    };
+    let blockchain = node.blockchain;
+    let spent = node.broadcaster.spent_outpoints();
+    Ok(node.wallet.unspent(node.mempool).into_iter()
+       .filter(|utxo| !spent.contains(&(utxo.txid, utxo.vout)))
+       .filter(|utxo| match utxo.block {
+           Some((ref hash, _)) => {
+               !utxo.coinbase || confirmations(blockchain, hash) >= COINBASE_MATURITY
+           },
+           None => false,
+       })
+       .filter_map(|utxo| keystore.key_for_script(&utxo.script[..]).map(|(key, compressed)| {
+           Coin { utxo: utxo, key: key, compressed: compressed }
+       }))
+       .collect())
+}
+
+/// An explicit fee rate in sat/vB at `rate_index`, or else an estimate for
+/// the confirmation target at `target_index`, 6 blocks by default
+fn param_fee_rate(node: &Node, params: &[Json], target_index: usize, rate_index: usize)
+                  -> Result<f64, RpcError> {
+    let fee_rate = match params.get(rate_index) {
+        None | Some(&Json::Null) => {
+            let target = match params.get(target_index) {
+                None | Some(&Json::Null) => 6,
+                Some(_) => try!(param_u64(params, target_index)),
+            };
+            match node.fee_estimator.estimate(target as u32) {
+                Some(fee_rate) => fee_rate,
+                None => return Err(RpcError::new(RPC_WALLET_ERROR,
+                                                 "Fee estimation failed; set fee_rate")),
+            }
+        },
+        Some(param) => match param.as_f64() {
+            Some(fee_rate) => fee_rate,
+            None => return Err(RpcError::new(RPC_INVALID_PARAMETER,
+                                             "fee_rate must be a number in sat/vB")),
+        },
+    };
+    if fee_rate < 1.0 {
+        return Err(RpcError::new(RPC_INVALID_PARAMETER,
+                                 "Fee rate is below the minimum relay fee of 1 sat/vB"));
+    }
+    Ok(fee_rate)
+}
+
+fn median_time(blockchain: &Blockchain, hash: Sha256dHash) -> u32 {
+    let mut times = blockchain.rev_iter(hash)
+        .take(11)
+        .map(|block_node_ref| block_node_ref.block.header.time)
+        .collect::<Vec<u32>>();
+    times.sort();
+    times.get(times.len() / 2).map_or(0, |&time| time)
+}
+
+/// Difficulty relative to the lowest, computed from compact bits as
+/// Bitcoin Core does
+pub fn difficulty(bits: u32) -> f64 {
+    let mut shift = (bits >> 24) & 0xff;
+    let mut difficulty = 0x0000ffff as f64 / (bits & 0x00ffffff) as f64;
+    while shift < 29 {
+        difficulty *= 256.0;
+        shift += 1;
+    }
+    while shift > 29 {
+        difficulty /= 256.0;
+        shift -= 1;
+    }
+    difficulty
+}
+
+fn param_hash(params: &[Json], i: usize) -> Result<Sha256dHash, RpcError> {
+    match params.get(i).and_then(|param| param.as_string()) {
+        Some(hex) if hex.len() == 64 => match Sha256dHash::from_hex(hex) {
+            Ok(hash) => Ok(hash),
+            Err(_) => Err(RpcError::new(RPC_INVALID_PARAMETER, "Hash must be hexadecimal")),
+        },
+        _ => Err(RpcError::new(RPC_INVALID_PARAMETER, "Expected a 64 character hash")),
+    }
+}
+
+fn param_str(params: &[Json], i: usize) -> Result<&str, RpcError> {
+    match params.get(i).and_then(|param| param.as_string()) {
+        Some(value) => Ok(value),
+        None => Err(RpcError::new(RPC_INVALID_PARAMETER, "Expected a string")),
+    }
+}
+
+/// An amount in BTC, as satoshis
+fn param_amount(params: &[Json], i: usize) -> Result<u64, RpcError> {
+    match params.get(i).and_then(|param| param.as_f64()) {
+        Some(amount) if amount > 0.0 && amount <= 21000000.0 => {
+            Ok((amount * 100000000.0).round() as u64)
+        },
+        _ => Err(RpcError::new(RPC_INVALID_PARAMETER, "Invalid amount")),
+    }
+}
+
+fn param_bool(params: &[Json], i: usize, default: bool) -> Result<bool, RpcError> {
+    match params.get(i) {
+        None | Some(&Json::Null) => Ok(default),
+        Some(&Json::Boolean(value)) => Ok(value),
+        Some(_) => Err(RpcError::new(RPC_INVALID_PARAMETER, "Expected a boolean")),
+    }
+}
+
+fn param_u64(params: &[Json], i: usize) -> Result<u64, RpcError> {
+    match params.get(i).and_then(|param| param.as_u64()) {
+        Some(n) => Ok(n),
+        None => Err(RpcError::new(RPC_INVALID_PARAMETER, "Expected a non-negative integer")),
+    }
+}
+
+/// Verbose flags are booleans in some methods and integers in others, and
+/// clients send either
+fn param_verbosity(params: &[Json], i: usize, default: u64) -> Result<u64, RpcError> {
+    match params.get(i) {
+        None | Some(&Json::Null) => Ok(default),
+        Some(&Json::Boolean(verbose)) => Ok(if verbose { 1 } else { 0 }),
+        Some(param) => match param.as_u64() {
+            Some(n) => Ok(n),
+            None => Err(RpcError::new(RPC_INVALID_PARAMETER, "Expected a verbosity")),
+        },
+    }
+}
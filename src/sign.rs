@@ -0,0 +1,428 @@
+use secp256k1::{Message, Secp256k1};
+use secp256k1::key::SecretKey;
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{Transaction, TxOut};
+use bitcoin::network::serialize::serialize;
+use bitcoin::util::hash::Sha256dHash;
+
+use address::{AddressType, pub_from_pri, random_bytes, sha256, tagged_hash};
+use util::{hash160, p2pkh_script, witness_script};
+use witness::WitnessTx;
+
+pub const SIGHASH_ALL: u32 = 1;
+/// Order of the secp256k1 group, big-endian
+const ORDER: [u8; 32] = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+                         0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+                         0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b,
+                         0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x41];
+
+/// Sign every input of a transaction. `prevouts` are the outputs the
+/// inputs spend, and `keys` their keys along with whether the public key
+/// is compressed. Taproot outputs are signed on the key path, as BIP86
+/// outputs are spent.
+pub fn sign_tx(secp: &Secp256k1, wtx: &mut WitnessTx, prevouts: &[TxOut],
+               keys: &[(SecretKey, bool)]) -> Result<(), String> {
+    wtx.witness = vec![vec![]; wtx.tx.input.len()];
+    for i in 0..wtx.tx.input.len() {
+        let script = prevouts[i].script_pubkey.clone().into_vec();
+        let (ref key, compressed) = keys[i];
+        let public_key = pub_from_pri(secp, key).serialize_vec(secp, compressed);
+        let key_hash = hash160(&public_key[..]);
+        match AddressType::of_script(&script[..]) {
+            Some(AddressType::P2pkh) => {
+                let hash = legacy_sighash(&wtx.tx, i, &script[..]);
+                let mut script_sig = push(&try!(ecdsa_sign(secp, key, &hash[..]))[..]);
+                script_sig.extend(push(&public_key[..]));
+                wtx.tx.input[i].script_sig = Script::from(script_sig);
+            },
+            Some(AddressType::P2shP2wpkh) | Some(AddressType::P2wpkh) => {
+                let hash = segwit_v0_sighash(&wtx.tx, i, &p2pkh_script(&key_hash),
+                                             prevouts[i].value);
+                if script[0] == 0xa9 {
+                    let redeem_script = witness_script(0, &key_hash);
+                    wtx.tx.input[i].script_sig = Script::from(push(&redeem_script[..]));
+                }
+                wtx.witness[i] = vec![try!(ecdsa_sign(secp, key, &hash[..])), public_key];
+            },
+            Some(AddressType::P2tr) => {
+                let hash = taproot_sighash(&wtx.tx, i, prevouts);
+                let tweaked = try!(taproot_tweak(secp, key));
+                let mut aux = [0u8; 32];
+                aux.copy_from_slice(&try!(random_bytes(32))[..]);
+                wtx.witness[i] = vec![try!(schnorr_sign(secp, &tweaked, &hash, &aux)).to_vec()];
+            },
+            None => return Err(format!("Cannot sign for output script {:?}", script)),
+        }
+    }
+    Ok(())
+}
+
+/// A push of fewer than 76 bytes
+fn push(data: &[u8]) -> Vec<u8> {
+    let mut script = vec![data.len() as u8];
+    script.extend_from_slice(data);
+    script
+}
+
+/// DER signature followed by the sighash type
+fn ecdsa_sign(secp: &Secp256k1, key: &SecretKey, hash: &[u8]) -> Result<Vec<u8>, String> {
+    let message = match Message::from_slice(hash) {
+        Ok(message) => message,
+        Err(e) => return Err(format!("Signing: {:?}", e)),
+    };
+    match secp.sign(&message, key) {
+        Ok(signature) => {
+            let mut signature = signature.serialize_der(secp);
+            signature.push(SIGHASH_ALL as u8);
+            Ok(signature)
+        },
+        Err(e) => Err(format!("Signing: {:?}", e)),
+    }
+}
+
+/// The signature hash of pre-segwit outputs
+fn legacy_sighash(tx: &Transaction, index: usize, script_code: &[u8]) -> Sha256dHash {
+    let mut tx = tx.clone();
+    for (i, input) in tx.input.iter_mut().enumerate() {
+        input.script_sig = if i == index {
+            Script::from(script_code.to_vec())
+        } else {
+            Script::new()
+        };
+    }
+    let mut data = serialize(&tx).unwrap();
+    data.extend(serialize(&SIGHASH_ALL).unwrap());
+    Sha256dHash::from_data(&data[..])
+}
+
+/// The signature hash of version 0 witness outputs (BIP143)
+fn segwit_v0_sighash(tx: &Transaction, index: usize, script_code: &[u8], value: u64)
+                     -> Sha256dHash {
+    let (mut prevouts, mut sequences, mut outputs) = (vec![], vec![], vec![]);
+    for input in tx.input.iter() {
+        prevouts.extend(serialize(&input.prev_hash).unwrap());
+        prevouts.extend(serialize(&input.prev_index).unwrap());
+        sequences.extend(serialize(&input.sequence).unwrap());
+    }
+    for output in tx.output.iter() {
+        outputs.extend(serialize(output).unwrap());
+    }
+    let input = &tx.input[index];
+
+    let mut data = serialize(&tx.version).unwrap();
+    data.extend_from_slice(&Sha256dHash::from_data(&prevouts[..])[..]);
+    data.extend_from_slice(&Sha256dHash::from_data(&sequences[..])[..]);
+    data.extend(serialize(&input.prev_hash).unwrap());
+    data.extend(serialize(&input.prev_index).unwrap());
+    data.extend(serialize(&Script::from(script_code.to_vec())).unwrap());
+    data.extend(serialize(&value).unwrap());
+    data.extend(serialize(&input.sequence).unwrap());
+    data.extend_from_slice(&Sha256dHash::from_data(&outputs[..])[..]);
+    data.extend(serialize(&tx.lock_time).unwrap());
+    data.extend(serialize(&SIGHASH_ALL).unwrap());
+    Sha256dHash::from_data(&data[..])
+}
+
+/// The key path signature hash of taproot outputs with SIGHASH_DEFAULT
+/// (BIP341)
+fn taproot_sighash(tx: &Transaction, index: usize, prevouts: &[TxOut]) -> [u8; 32] {
+    let (mut outpoints, mut amounts, mut scripts, mut sequences, mut outputs) =
+        (vec![], vec![], vec![], vec![], vec![]);
+    for (input, prevout) in tx.input.iter().zip(prevouts.iter()) {
+        outpoints.extend(serialize(&input.prev_hash).unwrap());
+        outpoints.extend(serialize(&input.prev_index).unwrap());
+        amounts.extend(serialize(&prevout.value).unwrap());
+        scripts.extend(serialize(&prevout.script_pubkey).unwrap());
+        sequences.extend(serialize(&input.sequence).unwrap());
+    }
+    for output in tx.output.iter() {
+        outputs.extend(serialize(output).unwrap());
+    }
+
+    // epoch and hash type
+    let mut data = vec![0, 0];
+    data.extend(serialize(&tx.version).unwrap());
+    data.extend(serialize(&tx.lock_time).unwrap());
+    for part in [outpoints, amounts, scripts, sequences, outputs].iter() {
+        data.extend_from_slice(&sha256(&part[..]));
+    }
+    // key path spend without annex
+    data.push(0);
+    data.extend(serialize(&(index as u32)).unwrap());
+    tagged_hash("TapSighash", &data[..])
+}
+
+/// The key of a BIP86 output: the internal key, negated if its public key
+/// has an odd y, plus the TapTweak hash
+fn taproot_tweak(secp: &Secp256k1, key: &SecretKey) -> Result<SecretKey, String> {
+    let public_key = pub_from_pri(secp, key).serialize_vec(secp, true);
+    let d = even_y_scalar(secp, key);
+    let tweak = reduce(&tagged_hash("TapTweak", &public_key[1..33]));
+    SecretKey::from_slice(secp, &add_mod(&d, &tweak))
+        .map_err(|e| format!("Tweaking taproot key: {:?}", e))
+}
+
+/// A BIP340 signature, with `aux` as auxiliary randomness
+fn schnorr_sign(secp: &Secp256k1, key: &SecretKey, message: &[u8; 32], aux: &[u8; 32])
+                -> Result<[u8; 64], String> {
+    let public_key = pub_from_pri(secp, key).serialize_vec(secp, true);
+    let d = even_y_scalar(secp, key);
+
+    let mut t = tagged_hash("BIP0340/aux", aux);
+    for (byte, d_byte) in t.iter_mut().zip(d.iter()) {
+        *byte ^= *d_byte;
+    }
+    let mut nonce_data = t.to_vec();
+    nonce_data.extend_from_slice(&public_key[1..33]);
+    nonce_data.extend_from_slice(message);
+    let nonce = match SecretKey::from_slice(secp, &reduce(&tagged_hash("BIP0340/nonce",
+                                                                       &nonce_data[..]))) {
+        Ok(nonce) => nonce,
+        Err(e) => return Err(format!("Deriving signature nonce: {:?}", e)),
+    };
+    let r = pub_from_pri(secp, &nonce).serialize_vec(secp, true);
+    let k = even_y_scalar(secp, &nonce);
+
+    let mut challenge_data = r[1..33].to_vec();
+    challenge_data.extend_from_slice(&public_key[1..33]);
+    challenge_data.extend_from_slice(message);
+    let e = reduce(&tagged_hash("BIP0340/challenge", &challenge_data[..]));
+
+    let mut signature = [0u8; 64];
+    signature[..32].copy_from_slice(&r[1..33]);
+    signature[32..].copy_from_slice(&add_mod(&k, &mul_mod(&e, &d)));
+    Ok(signature)
+}
+
+/// A secret key as a scalar, negated if its public key has an odd y, as
+/// BIP340 takes keys
+fn even_y_scalar(secp: &Secp256k1, key: &SecretKey) -> [u8; 32] {
+    let mut scalar = [0u8; 32];
+    scalar.copy_from_slice(&key[..]);
+    if pub_from_pri(secp, key).serialize_vec(secp, true)[0] == 3 {
+        scalar = sub(&ORDER, &scalar).0;
+    }
+    scalar
+}
+
+// Arithmetic modulo the group order on big-endian scalars. secp256k1 only
+// offers a few of these operations on keys, and none that can yield zero.
+// The scalars are secret, so none of these branch on their values.
+
+/// a if `choose_a`, otherwise b
+fn select(choose_a: bool, a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let mask = 0u8.wrapping_sub(choose_a as u8);
+    let mut result = [0u8; 32];
+    for i in 0..32 {
+        result[i] = (a[i] & mask) | (b[i] & !mask);
+    }
+    result
+}
+
+/// a + b with the carry out
+fn add(a: &[u8; 32], b: &[u8; 32]) -> ([u8; 32], bool) {
+    let mut sum = [0u8; 32];
+    let mut carry = 0u16;
+    for i in (0..32).rev() {
+        let total = a[i] as u16 + b[i] as u16 + carry;
+        sum[i] = total as u8;
+        carry = total >> 8;
+    }
+    (sum, carry == 1)
+}
+
+/// a - b with the borrow out
+fn sub(a: &[u8; 32], b: &[u8; 32]) -> ([u8; 32], bool) {
+    let mut difference = [0u8; 32];
+    let mut borrow = 0i16;
+    for i in (0..32).rev() {
+        let total = a[i] as i16 - b[i] as i16 - borrow;
+        difference[i] = total as u8;
+        // the sign bit
+        borrow = (total >> 15) & 1;
+    }
+    (difference, borrow == 1)
+}
+
+/// A 256 bit value modulo the order, which is less than twice the order
+fn reduce(a: &[u8; 32]) -> [u8; 32] {
+    let (difference, borrow) = sub(a, &ORDER);
+    select(borrow, a, &difference)
+}
+
+fn add_mod(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let (sum, carry) = add(a, b);
+    let (difference, borrow) = sub(&sum, &ORDER);
+    select(borrow & !carry, &sum, &difference)
+}
+
+/// Double and add, from the top bit of b, adding whether or not the bit
+/// is set
+fn mul_mod(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let mut product = [0u8; 32];
+    for byte in b.iter() {
+        for bit in (0..8).rev() {
+            product = add_mod(&product, &product);
+            let sum = add_mod(&product, a);
+            product = select((byte >> bit) & 1 == 1, &sum, &product);
+        }
+    }
+    product
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::hex::{FromHex, ToHex};
+    use secp256k1::Secp256k1;
+    use secp256k1::key::SecretKey;
+
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::{Transaction, TxOut};
+    use bitcoin::network::serialize::deserialize;
+
+    use super::{ecdsa_sign, legacy_sighash, schnorr_sign, segwit_v0_sighash, taproot_sighash,
+                taproot_tweak};
+
+    fn key(secp: &Secp256k1, hex: &str) -> SecretKey {
+        SecretKey::from_slice(secp, &hex.from_hex().unwrap()[..]).unwrap()
+    }
+
+    fn bytes32(hex: &str) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes.copy_from_slice(&hex.from_hex().unwrap()[..]);
+        bytes
+    }
+
+    fn tx(hex: &str) -> Transaction {
+        deserialize(&hex.from_hex().unwrap()[..]).unwrap()
+    }
+
+    /// The signing vectors of BIP340: secret key, aux, message and signature
+    const BIP340_VECTORS: [(&'static str, &'static str, &'static str, &'static str); 4] = [
+        ("0000000000000000000000000000000000000000000000000000000000000003",
+         "0000000000000000000000000000000000000000000000000000000000000000",
+         "0000000000000000000000000000000000000000000000000000000000000000",
+         "e907831f80848d1069a5371b402410364bdf1c5f8307b0084c55f1ce2dca8215\
+          25f66a4a85ea8b71e482a74f382d2ce5ebeee8fdb2172f477df4900d310536c0"),
+        ("b7e151628aed2a6abf7158809cf4f3c762e7160f38b4da56a784d9045190cfef",
+         "0000000000000000000000000000000000000000000000000000000000000001",
+         "243f6a8885a308d313198a2e03707344a4093822299f31d0082efa98ec4e6c89",
+         "6896bd60eeae296db48a229ff71dfe071bde413e6d43f917dc8dcf8c78de3341\
+          8906d11ac976abccb20b091292bff4ea897efcb639ea871cfa95f6de339e4b0a"),
+        ("c90fdaa22168c234c4c6628b80dc1cd129024e088a67cc74020bbea63b14e5c9",
+         "c87aa53824b4d7ae2eb035a2b5bbbccc080e76cdc6d1692c4b0b62d798e6d906",
+         "7e2d58d8b3bcdf1abadec7829054f90dda9805aab56c77333024b9d0a508b75c",
+         "5831aaeed7b44bb74e5eab94ba9d4294c49bcf2a60728d8b4c200f50dd313c1b\
+          ab745879a5ad954a72c45a91c3a51d3c7adea98d82f8481e0e1e03674a6f3fb7"),
+        ("0b432b2677937381aef05bb02a66ecd012773062cf3fa2549e44f58ed2401710",
+         "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+         "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+         "7eb0509757e246f19449885651611cb965ecc1a187dd51b64fda1edc9637d5ec\
+          97582b9cb13db3933705b32ba982af5af25fd78881ebb32771fc5922efc66ea3"),
+    ];
+
+    #[test]
+    fn bip340_signatures() {
+        let secp = Secp256k1::new();
+        for &(secret_key, aux, message, signature) in BIP340_VECTORS.iter() {
+            let signed = schnorr_sign(&secp, &key(&secp, secret_key), &bytes32(message),
+                                      &bytes32(aux)).unwrap();
+            assert_eq!(signed[..].to_hex(), signature);
+        }
+    }
+
+    /// The native P2WPKH example of BIP143, whose first input spends a
+    /// P2PK output
+    const BIP143_UNSIGNED_TX: &'static str =
+        "0100000002fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000000\
+         eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ff\
+         ffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d\
+         000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac11000000";
+
+    #[test]
+    fn bip143_legacy_input() {
+        let secp = Secp256k1::new();
+        let tx = tx(BIP143_UNSIGNED_TX);
+        let script_code = "2103c9f4836b9a4f77fc0d81f7bcb01b7f1b35916864b9476c241ce9fc198bd25432ac"
+            .from_hex().unwrap();
+        let hash = legacy_sighash(&tx, 0, &script_code[..]);
+        let key = key(&secp, "bbc27228ddcb9209d7fd6f36b02f7dfa6252af40bb2f1cbc7a557da8027ff866");
+        // signatures are deterministic (RFC 6979), so this is the one in the BIP
+        assert_eq!(ecdsa_sign(&secp, &key, &hash[..]).unwrap().to_hex(),
+                   "30450221008b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be\
+                    022040529b194ba3f9281a99f2b1c0a19c0489bc22ede944ccf4ecbab4cc618ef3ed01");
+    }
+
+    #[test]
+    fn bip143_p2wpkh_input() {
+        let secp = Secp256k1::new();
+        let tx = tx(BIP143_UNSIGNED_TX);
+        let script_code = "76a9141d0f172a0ecb48aee1be1f2687d2963ae33f71a188ac".from_hex().unwrap();
+        let hash = segwit_v0_sighash(&tx, 1, &script_code[..], 600000000);
+        assert_eq!(hash[..].to_hex(),
+                   "c37af31116d1b27caf68aae9e3ac82f1477929014d5b917657d0eb49478cb670");
+        let key = key(&secp, "619c335025c7f4012e556c2a58b2506e30b8511b53ade95ea316fd8c3286feb9");
+        assert_eq!(ecdsa_sign(&secp, &key, &hash[..]).unwrap().to_hex(),
+                   "304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a\
+                    0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee01");
+    }
+
+    /// The key path spending transaction of BIP341's wallet test vectors,
+    /// and the outputs it spends as (value, script)
+    const BIP341_UNSIGNED_TX: &'static str =
+        "02000000097de20cbff686da83a54981d2b9bab3586f4ca7e48f57f5b55963115f3b334e9c0100000000\
+         00000000d7b7cab57b1393ace2d064f4d4a2cb8af6def61273e127517d44759b6dafdd990000000000ff\
+         fffffff8e1f583384333689228c5d28eac13366be082dc57441760d957275419a418420000000000ffff\
+         fffff0689180aa63b30cb162a73c6d2a38b7eeda2a83ece74310fda0843ad604853b0100000000feffff\
+         ffaa5202bdf6d8ccd2ee0f0202afbbb7461d9264a25e5bfd3c5a52ee1239e0ba6c0000000000feffffff\
+         956149bdc66faa968eb2be2d2faa29718acbfe3941215893a2a3446d32acd050000000000000000000e6\
+         64b9773b88c09c32cb70a2a3e4da0ced63b7ba3b22f848531bbb1d5d5f4c94010000000000000000e9aa\
+         6b8e6c9de67619e6a3924ae25696bb7b694bb677a632a74ef7eadfd4eabf0000000000ffffffffa778eb\
+         6a263dc090464cd125c466b5a99667720b1c110468831d058aa1b82af10100000000ffffffff0200ca9a\
+         3b000000001976a91406afd46bcdfd22ef94ac122aa11f241244a37ecc88ac807840cb0000000020ac9a\
+         87f5594be208f8532db38cff670c450ed2fea8fcdefcc9a663f78bab962b0065cd1d";
+    const BIP341_SPENT: [(u64, &'static str); 9] = [
+        (420000000, "512053a1f6e454df1aa2776a2814a721372d6258050de330b3c6d10ee8f4e0dda343"),
+        (462000000, "5120147c9c57132f6e7ecddba9800bb0c4449251c92a1e60371ee77557b6620f3ea3"),
+        (294000000, "76a914751e76e8199196d454941c45d1b3a323f1433bd688ac"),
+        (504000000, "5120e4d810fd50586274face62b8a807eb9719cef49c04177cc6b76a9a4251d5450e"),
+        (630000000, "512091b64d5324723a985170e4dc5a0f84c041804f2cd12660fa5dec09fc21783605"),
+        (378000000, "00147dd65592d0ab2fe0d0257d571abf032cd9db93dc"),
+        (672000000, "512075169f4001aa68f15bbed28b218df1d0a62cbbcf1188c6665110c293c907b831"),
+        (546000000, "5120712447206d7a5238acc7ff53fbe94a3b64539ad291c7cdbc490b7577e4b17df5"),
+        (588000000, "512077e30a5522dd9f894c3f8b8bd4c4b2cf82ca7da8a3ea6a239655c39c050ab220"),
+    ];
+
+    #[test]
+    fn bip341_key_path_tweak() {
+        // input 0 commits to no script tree, as BIP86 outputs don't
+        let secp = Secp256k1::new();
+        let internal = key(&secp,
+                           "6b973d88838f27366ed61c9ad6367663045cb456e28335c109e30717ae0c6baa");
+        assert_eq!(taproot_tweak(&secp, &internal).unwrap()[..].to_hex(),
+                   "2405b971772ad26915c8dcdf10f238753a9b837e5f8e6a86fd7c0cce5b7296d9");
+    }
+
+    #[test]
+    fn bip341_key_path_signature() {
+        // input 4 is the vector signed with SIGHASH_DEFAULT, the only type
+        // we sign with, and all-zero aux
+        let secp = Secp256k1::new();
+        let tx = tx(BIP341_UNSIGNED_TX);
+        let prevouts = BIP341_SPENT.iter()
+            .map(|&(value, script)| TxOut {
+                value: value,
+                script_pubkey: Script::from(script.from_hex().unwrap()),
+            })
+            .collect::<Vec<TxOut>>();
+        let hash = taproot_sighash(&tx, 4, &prevouts[..]);
+        assert_eq!(hash[..].to_hex(),
+                   "4f900a0bae3f1446fd48490c2958b5a023228f01661cda3496a11da502a7f7ef");
+        let tweaked = key(&secp,
+                          "a8e7aa924f0d58854185a490e6c41f6efb7b675c0f3331b7f14b549400b4d501");
+        assert_eq!(schnorr_sign(&secp, &tweaked, &hash, &[0u8; 32]).unwrap()[..].to_hex(),
+                   "b4010dd48a617db09926f729e79c33ae0b4e94b79f04a1ae93ede6315eb3669d\
+                    e185a17d2b0ac9ee09fd4c64b678a0b61a0a86fa888a273c8511be83bfd6810f");
+    }
+}
@@ -0,0 +1,137 @@
+use std::thread;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{Sender, Receiver, channel};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bitcoin::network::constants::{Network, magic};
+use bitcoin::network::address::Address;
+use bitcoin::network::message_network::VersionMessage;
+
+use metrics::{self, PEER_MESSAGES};
+use wire::{self, PeerMessage, PROTOCOL_VERSION, USER_AGENT, NODE_BLOOM, NODE_WITNESS,
+           HEADER_SIZE, MAX_PAYLOAD_SIZE};
+
+pub enum SocketResponse {
+    MessageReceived(PeerMessage),
+    ConnectionFailed(String),
+}
+
+/// A connection to a single peer. This replaces rust-bitcoin's `Socket`,
+/// which can neither send nor parse the witness-serialized messages we need.
+pub struct PeerSocket {
+    stream: TcpStream,
+    magic: u32,
+}
+
+impl PeerSocket {
+    pub fn connect(network: Network, addr: &str, port: u16)
+                   -> Result<PeerSocket, String> {
+        match TcpStream::connect((addr, port)) {
+            Ok(stream) => Ok(PeerSocket {
+                stream: stream,
+                magic: magic(network),
+            }),
+            Err(e) => Err(format!("Connecting to {}:{}: {:?}", addr, port, e)),
+        }
+    }
+
+    pub fn try_clone(&self) -> Result<PeerSocket, String> {
+        match self.stream.try_clone() {
+            Ok(stream) => Ok(PeerSocket {
+                stream: stream,
+                magic: self.magic,
+            }),
+            Err(e) => Err(format!("Cloning socket: {:?}", e)),
+        }
+    }
+
+    pub fn send_message(&mut self, msg: PeerMessage) -> Result<(), String> {
+        let data = try!(wire::encode_message(self.magic, &msg));
+        match self.stream.write_all(&data[..]) {
+            Ok(()) => {
+                metrics::inc(PEER_MESSAGES, &[("direction", "sent"),
+                                              ("command", &msg.command()[..])]);
+                Ok(())
+            },
+            Err(e) => Err(format!("Sending {}: {:?}", msg.command(), e)),
+        }
+    }
+
+    /// Block until a full message arrives. Messages with commands we don't
+    /// understand are skipped rather than treated as errors.
+    pub fn receive_message(&mut self) -> Result<PeerMessage, String> {
+        loop {
+            let mut message = vec![0u8; HEADER_SIZE];
+            if let Err(e) = self.stream.read_exact(&mut message[..]) {
+                return Err(format!("Reading message header: {:?}", e));
+            }
+            let header = wire::parse_header(&message[..]);
+            if header.magic != self.magic {
+                return Err(format!("Bad network magic {:x}", header.magic));
+            }
+            if header.length > MAX_PAYLOAD_SIZE {
+                return Err(format!("Oversized {} message: {} bytes",
+                                   header.command, header.length));
+            }
+
+            message.resize(HEADER_SIZE + header.length, 0);
+            if let Err(e) = self.stream.read_exact(&mut message[HEADER_SIZE..]) {
+                return Err(format!("Reading {} payload: {:?}", header.command, e));
+            }
+            if wire::checksum(&message[HEADER_SIZE..]) != header.checksum {
+                return Err(format!("Bad checksum on {} message", header.command));
+            }
+
+            if let Some(msg) = try!(wire::decode_message(&header.command, &message[..])) {
+                metrics::inc(PEER_MESSAGES, &[("direction", "received"),
+                                              ("command", &msg.command()[..])]);
+                return Ok(msg);
+            }
+        }
+    }
+
+    pub fn version_message(&self, start_height: i32) -> Result<VersionMessage, String> {
+        let (local, remote) = match (self.stream.local_addr(), self.stream.peer_addr()) {
+            (Ok(local), Ok(remote)) => (local, remote),
+            _ => return Err("Socket is not connected".to_string()),
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+        Ok(VersionMessage {
+            version: PROTOCOL_VERSION,
+            services: NODE_WITNESS | NODE_BLOOM,
+            timestamp: now.as_secs() as i64,
+            receiver: Address::new(&remote, 0),
+            sender: Address::new(&local, NODE_WITNESS | NODE_BLOOM),
+            nonce: now.as_secs() ^ (now.subsec_nanos() as u64) << 32,
+            user_agent: USER_AGENT.to_string(),
+            start_height: start_height,
+            relay: true,
+        })
+    }
+
+    /// Spawn a thread reading messages off the socket and into a channel
+    pub fn start_reader(&self) -> Result<Receiver<SocketResponse>, String> {
+        let mut reader = try!(self.try_clone());
+        let (sender, receiver): (Sender<SocketResponse>,
+                                 Receiver<SocketResponse>) = channel();
+        thread::spawn(move || {
+            loop {
+                match reader.receive_message() {
+                    Ok(msg) => {
+                        if sender.send(SocketResponse::MessageReceived(msg)).is_err() {
+                            break;
+                        }
+                    },
+                    Err(e) => {
+                        // the peer's thread may have stopped already
+                        let _ = sender.send(SocketResponse::ConnectionFailed(e));
+                        break;
+                    },
+                }
+            }
+        });
+        Ok(receiver)
+    }
+}
@@ -0,0 +1,155 @@
+use rand::{OsRng, Rng};
+use secp256k1::Secp256k1;
+use secp256k1::key::SecretKey;
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
+
+use address::AddressType;
+use sign::sign_tx;
+use wallet::Utxo;
+use witness::WitnessTx;
+
+/// Outputs smaller than this aren't created; smaller change goes to the fee
+pub const DUST_LIMIT: u64 = 546;
+/// Fee above which a transaction is refused as a likely mistake, as with
+/// Core's maxtxfee
+pub const MAX_FEE: u64 = 10000000;
+/// Sequence signaling that the transaction may be replaced (BIP125)
+const SEQUENCE_REPLACEABLE: u32 = 0xfffffffd;
+/// Highest sequence that still enables the lock time
+const SEQUENCE_LOCK_TIME: u32 = 0xfffffffe;
+
+/// What a transaction should pay
+pub struct Spend {
+    /// Output scripts and values in satoshis
+    pub recipients: Vec<(Vec<u8>, u64)>,
+    /// In sat/vB
+    pub fee_rate: f64,
+    pub replaceable: bool,
+    /// The current height, so the transaction can't be mined in a block
+    /// rewriting history, which discourages fee sniping
+    pub lock_time: u32,
+}
+
+/// A wallet output we hold the key to
+pub struct Coin {
+    pub utxo: Utxo,
+    pub key: SecretKey,
+    pub compressed: bool,
+}
+
+/// Build and sign a transaction paying `spend` from `coins`, returning it
+/// with its fee. Coins are taken largest first until they cover the
+/// recipients and the fee. Change goes to the script `change_script` gives,
+/// of type `change_type`, unless it would be dust, in which case it's left
+/// to the fee.
+pub fn create_tx<F>(secp: &Secp256k1, spend: &Spend, coins: &[Coin], change_type: AddressType,
+                    change_script: F) -> Result<(WitnessTx, u64), String>
+    where F: FnOnce() -> Result<Vec<u8>, String> {
+    if spend.recipients.is_empty() {
+        return Err("No recipients".to_string());
+    }
+    if spend.recipients.iter().any(|&(_, value)| value < DUST_LIMIT) {
+        return Err(format!("Amounts must be at least {} satoshis", DUST_LIMIT));
+    }
+    let target = spend.recipients.iter().map(|&(_, value)| value).sum::<u64>();
+
+    let mut sorted = coins.iter().collect::<Vec<&Coin>>();
+    sorted.sort_by(|a, b| b.utxo.value.cmp(&a.utxo.value));
+    let mut vsize = 11.0 + spend.recipients.iter()
+        .map(|&(ref script, _)| output_vsize(script.len()))
+        .sum::<f64>();
+    let change_vsize = output_vsize(match change_type {
+        AddressType::P2pkh => 25,
+        AddressType::P2shP2wpkh => 23,
+        AddressType::P2wpkh => 22,
+        AddressType::P2tr => 34,
+    });
+    let (mut selected, mut total) = (vec![], 0);
+    let mut outcome = None;
+    for coin in sorted {
+        selected.push(coin);
+        total += coin.utxo.value;
+        vsize += input_vsize(&coin.utxo.script[..], coin.compressed);
+        let fee = (vsize * spend.fee_rate).ceil() as u64;
+        if total < target + fee {
+            continue;
+        }
+        let fee_with_change = ((vsize + change_vsize) * spend.fee_rate).ceil() as u64;
+        outcome = if total >= target + fee_with_change + DUST_LIMIT {
+            Some((fee_with_change, Some(total - target - fee_with_change)))
+        } else {
+            Some((total - target, None))
+        };
+        break;
+    }
+    let (fee, change) = match outcome {
+        Some(outcome) => outcome,
+        None => return Err("Insufficient funds".to_string()),
+    };
+    if fee > MAX_FEE {
+        return Err(format!("Fee of {} satoshis exceeds the maximum of {}", fee, MAX_FEE));
+    }
+
+    let sequence = if spend.replaceable { SEQUENCE_REPLACEABLE } else { SEQUENCE_LOCK_TIME };
+    let mut output = spend.recipients.iter()
+        .map(|&(ref script, value)| TxOut {
+            value: value,
+            script_pubkey: Script::from(script.clone()),
+        })
+        .collect::<Vec<TxOut>>();
+    if let Some(change) = change {
+        // put the change anywhere, so it can't be told apart by position
+        let position = match OsRng::new() {
+            Ok(mut rng) => rng.gen_range(0, output.len() + 1),
+            Err(e) => return Err(format!("Opening the OS random number generator: {:?}", e)),
+        };
+        output.insert(position, TxOut {
+            value: change,
+            script_pubkey: Script::from(try!(change_script())),
+        });
+    }
+    let mut wtx = WitnessTx::from_tx(Transaction {
+        version: 2,
+        lock_time: spend.lock_time,
+        input: selected.iter()
+            .map(|coin| TxIn {
+                prev_hash: coin.utxo.txid,
+                prev_index: coin.utxo.vout,
+                script_sig: Script::new(),
+                sequence: sequence,
+            })
+            .collect(),
+        output: output,
+    });
+    let prevouts = selected.iter()
+        .map(|coin| TxOut {
+            value: coin.utxo.value,
+            script_pubkey: Script::from(coin.utxo.script.clone()),
+        })
+        .collect::<Vec<TxOut>>();
+    let keys = selected.iter()
+        .map(|coin| (coin.key.clone(), coin.compressed))
+        .collect::<Vec<(SecretKey, bool)>>();
+    try!(sign_tx(secp, &mut wtx, &prevouts[..], &keys[..]));
+    Ok((wtx, fee))
+}
+
+/// Virtual size an output adds
+fn output_vsize(script_len: usize) -> f64 {
+    // value, script length and script
+    (8 + 1 + script_len) as f64
+}
+
+/// Virtual size an input adds once signed, allowing for the longest
+/// signatures
+fn input_vsize(script: &[u8], compressed: bool) -> f64 {
+    match AddressType::of_script(script) {
+        Some(AddressType::P2pkh) => if compressed { 148.0 } else { 180.0 },
+        Some(AddressType::P2shP2wpkh) => 91.0,
+        Some(AddressType::P2wpkh) => 68.0,
+        Some(AddressType::P2tr) => 57.5,
+        None => 180.0,
+    }
+}
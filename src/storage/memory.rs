@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+
+use rustc_serialize::json::Json;
+
+use bitcoin::util::hash::Sha256dHash;
+
+use mempool::MempoolEntry;
+use rpc::object;
+use storage::{Storage, Schema, BlockRecord, AddressOutput, output_id};
+
+/// Keeps everything in memory and loses it on exit. Useful for trying the
+/// daemon out and for tests that shouldn't need a database.
+pub struct MemoryStorage {
+    blocks: HashMap<Sha256dHash, BlockRecord>,
+    /// Value of every stored output, by output id
+    outputs: HashMap<String, u64>,
+    /// Mirrored mempool: fee rate, if known, by txid
+    mempool: HashMap<Sha256dHash, Option<f64>>,
+    /// Fee rate and block height, by target
+    fee_estimates: HashMap<u32, (Option<f64>, u32)>,
+    /// Only used to name the tables of exported rows
+    schema: Schema,
+}
+
+impl MemoryStorage {
+    pub fn new(schema: Schema) -> MemoryStorage {
+        MemoryStorage {
+            blocks: HashMap::new(),
+            outputs: HashMap::new(),
+            mempool: HashMap::new(),
+            fee_estimates: HashMap::new(),
+            schema: schema,
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn prepare(&mut self, _mirror_mempool: bool) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn block_hashes(&mut self) -> Result<Vec<Sha256dHash>, String> {
+        let mut blocks = self.blocks.values()
+            .map(|block| (block.height, block.hash))
+            .collect::<Vec<(u32, Sha256dHash)>>();
+        blocks.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(blocks.into_iter().map(|(_, hash)| hash).collect())
+    }
+
+    fn insert_block(&mut self, block: &BlockRecord) -> Result<(), String> {
+        if self.blocks.contains_key(&block.hash) {
+            return Err(format!("Block {} is already stored", block.hash.be_hex_string()));
+        }
+        for tx in block.txs.iter() {
+            for output in tx.outputs.iter() {
+                self.outputs.insert(output.output_id.clone(), output.value);
+            }
+        }
+        self.blocks.insert(block.hash, block.clone());
+        Ok(())
+    }
+
+    fn remove_block(&mut self, block_hash: &Sha256dHash) -> Result<(), String> {
+        if let Some(block) = self.blocks.remove(block_hash) {
+            for tx in block.txs.iter() {
+                for output in tx.outputs.iter() {
+                    self.outputs.remove(&output.output_id);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The rows the SQL backends would hold for the block
+    fn export_rows(&mut self, block_hash: &Sha256dHash) -> Result<Vec<(String, String)>, String> {
+        let block = match self.blocks.get(block_hash) {
+            Some(block) => block,
+            None => return Ok(vec![]),
+        };
+        let block_hash_json = Json::String(block.hash.be_hex_string());
+        let mut rows: Vec<(String, Json)> = vec![];
+        for tx in block.txs.iter() {
+            let tx_id = Json::String(tx.txid.be_hex_string());
+            for output in tx.outputs.iter() {
+                rows.push((self.schema.txout.clone(), object(vec![
+                    ("output", Json::String(output.output_id.clone())),
+                    ("tx_id", tx_id.clone()),
+                    ("value", Json::U64(output.value)),
+                    ("output_index", Json::U64(output.index as u64)),
+                    ("address_id", Json::String(output.address.clone())),
+                ])));
+            }
+            for input in tx.inputs.iter() {
+                rows.push((self.schema.txin.clone(), object(vec![
+                    ("tx_id", tx_id.clone()),
+                    ("output_id", Json::String(input.output_id.clone())),
+                    ("witness", input.witness.clone().map_or(Json::Null, Json::String)),
+                    ("script_status",
+                     Json::String(input.script_status.as_str().to_string())),
+                ])));
+            }
+            rows.push((self.schema.transaction.clone(), object(vec![
+                ("tx_hash", tx_id.clone()),
+                ("wtx_hash", Json::String(tx.wtxid.be_hex_string())),
+                ("block_hash_id", block_hash_json.clone()),
+                ("total_value", Json::U64(tx.total_value)),
+            ])));
+        }
+        rows.push((self.schema.block.clone(), object(vec![
+            ("block_hash", block_hash_json.clone()),
+            ("prev_block_hash_id",
+             block.prev_hash.map_or(Json::Null, |hash| Json::String(hash.be_hex_string()))),
+            ("block_size", Json::U64(block.size as u64)),
+            ("block_height", Json::U64(block.height as u64)),
+            ("merkleroot", Json::String(block.merkle_root.be_hex_string())),
+            ("time", Json::U64(block.time as u64)),
+            ("bits", Json::U64(block.bits as u64)),
+            ("nonce", Json::U64(block.nonce as u64)),
+            ("total_value", Json::U64(block.total_value)),
+        ])));
+        Ok(rows.into_iter().map(|(table, row)| (table, row.to_string())).collect())
+    }
+
+    fn output_value(&mut self, txid: &Sha256dHash, index: u32) -> Result<Option<u64>, String> {
+        Ok(self.outputs.get(&output_id(txid, index)).map(|&value| value))
+    }
+
+    fn address_history(&mut self, address: &str, offset: usize, limit: usize)
+                       -> Result<Vec<AddressOutput>, String> {
+        let mut spenders: HashMap<&String, Sha256dHash> = HashMap::new();
+        for block in self.blocks.values() {
+            for tx in block.txs.iter() {
+                for input in tx.inputs.iter() {
+                    spenders.insert(&input.output_id, tx.txid);
+                }
+            }
+        }
+
+        let mut outputs: Vec<AddressOutput> = vec![];
+        for block in self.blocks.values() {
+            for tx in block.txs.iter() {
+                for output in tx.outputs.iter().filter(|output| output.address == address) {
+                    outputs.push(AddressOutput {
+                        txid: tx.txid,
+                        index: output.index,
+                        value: output.value,
+                        block_hash: block.hash,
+                        height: block.height,
+                        spent_by: spenders.get(&output.output_id).map(|&txid| txid),
+                    });
+                }
+            }
+        }
+        outputs.sort_by(|a, b| b.height.cmp(&a.height)
+                        .then_with(|| a.txid.be_hex_string().cmp(&b.txid.be_hex_string()))
+                        .then_with(|| a.index.cmp(&b.index)));
+        Ok(outputs.into_iter().skip(offset).take(limit).collect())
+    }
+
+    fn insert_mempool_tx(&mut self, entry: &MempoolEntry) -> Result<(), String> {
+        self.mempool.insert(entry.txid, entry.fee_rate());
+        Ok(())
+    }
+
+    fn remove_mempool_txs(&mut self, txids: &[Sha256dHash]) -> Result<(), String> {
+        for txid in txids.iter() {
+            self.mempool.remove(txid);
+        }
+        Ok(())
+    }
+
+    fn save_fee_estimates(&mut self, estimates: &[(u32, Option<f64>)], height: u32)
+                          -> Result<(), String> {
+        for &(target, fee_rate) in estimates.iter() {
+            self.fee_estimates.insert(target, (fee_rate, height));
+        }
+        Ok(())
+    }
+
+    fn notify(&mut self, channel: &str, _payload: &str) -> Result<(), String> {
+        Err(format!("Cannot notify {}: LISTEN/NOTIFY needs Postgres", channel))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::util::hash::Sha256dHash;
+
+    use storage::{Storage, Schema, DependentTable, BlockRecord, TxRecord, TxInRecord, TxOutRecord,
+                  open, output_id};
+    use verify::InputStatus;
+
+    use super::MemoryStorage;
+
+    const ADDRESS: &'static str = "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2";
+
+    fn hash(n: u8) -> Sha256dHash {
+        Sha256dHash::from_data(&[n])
+    }
+
+    /// A transaction spending `spends` and paying `values` to ADDRESS
+    fn tx(txid: Sha256dHash, spends: Vec<String>, values: Vec<u64>) -> TxRecord {
+        TxRecord {
+            txid: txid,
+            wtxid: txid,
+            total_value: values.iter().sum(),
+            inputs: spends.into_iter()
+                .map(|spent| TxInRecord {
+                    output_id: spent,
+                    witness: None,
+                    script_status: InputStatus::Unverified,
+                })
+                .collect(),
+            outputs: values.into_iter().enumerate()
+                .map(|(i, value)| TxOutRecord {
+                    output_id: output_id(&txid, i as u32),
+                    value: value,
+                    index: i as u32,
+                    address: ADDRESS.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    fn block(hash: Sha256dHash, height: u32, txs: Vec<TxRecord>) -> BlockRecord {
+        BlockRecord {
+            hash: hash,
+            prev_hash: None,
+            height: height,
+            size: 285,
+            merkle_root: Default::default(),
+            time: 1231006505,
+            bits: 0x1d00ffff,
+            nonce: 2083236893,
+            total_value: txs.iter().map(|tx| tx.total_value).sum(),
+            txs: txs,
+        }
+    }
+
+    #[test]
+    fn blocks_round_trip() {
+        let mut storage = MemoryStorage::new(Schema::new("talk_", vec![]));
+        let (first, second) = (hash(1), hash(2));
+        storage.insert_block(&block(second, 2, vec![tx(hash(20), vec![], vec![20])])).unwrap();
+        storage.insert_block(&block(first, 1, vec![tx(hash(10), vec![], vec![50, 10])])).unwrap();
+        assert_eq!(storage.block_hashes().unwrap(), vec![first, second]);
+        assert_eq!(storage.output_value(&hash(10), 1).unwrap(), Some(10));
+        assert_eq!(storage.output_value(&hash(10), 2).unwrap(), None);
+        assert!(storage.insert_block(&block(first, 1, vec![])).is_err());
+
+        storage.remove_block(&first).unwrap();
+        assert_eq!(storage.block_hashes().unwrap(), vec![second]);
+        assert_eq!(storage.output_value(&hash(10), 0).unwrap(), None);
+        assert_eq!(storage.output_value(&hash(20), 0).unwrap(), Some(20));
+        // removing a block that isn't stored is fine
+        storage.remove_block(&first).unwrap();
+    }
+
+    #[test]
+    fn address_history_links_spends() {
+        let mut storage = MemoryStorage::new(Schema::new("talk_", vec![]));
+        let (funding, spending) = (hash(10), hash(20));
+        storage.insert_block(&block(hash(1), 1, vec![tx(funding, vec![], vec![50])])).unwrap();
+        storage.insert_block(&block(hash(2), 2, vec![
+            tx(spending, vec![output_id(&funding, 0)], vec![20]),
+        ])).unwrap();
+
+        let history = storage.address_history(ADDRESS, 0, 10).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!((history[0].txid, history[0].height, history[0].value), (spending, 2, 20));
+        assert_eq!(history[0].spent_by, None);
+        assert_eq!((history[1].txid, history[1].height, history[1].value), (funding, 1, 50));
+        assert_eq!(history[1].block_hash, hash(1));
+        assert_eq!(history[1].spent_by, Some(spending));
+
+        let page = storage.address_history(ADDRESS, 1, 10).unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].txid, funding);
+        assert!(storage.address_history("1111111111111111111114oLvT2", 0, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn exports_rows_of_each_table() {
+        let mut storage = MemoryStorage::new(Schema::new("talk_", vec![]));
+        let funding = hash(10);
+        storage.insert_block(&block(hash(1), 1, vec![
+            tx(funding, vec![output_id(&hash(9), 0)], vec![50, 10]),
+        ])).unwrap();
+
+        let rows = storage.export_rows(&hash(1)).unwrap();
+        let tables = rows.iter().map(|&(ref table, _)| &table[..]).collect::<Vec<&str>>();
+        assert_eq!(tables, vec!["talk_txout", "talk_txout", "talk_txin", "talk_transaction",
+                                "talk_block"]);
+        assert!(rows[4].1.contains(&format!("\"block_hash\":\"{}\"", hash(1).be_hex_string())));
+        assert!(rows[3].1.contains(&format!("\"tx_hash\":\"{}\"", funding.be_hex_string())));
+        assert!(storage.export_rows(&hash(2)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn only_postgres_takes_schema_qualified_names() {
+        let dotted = Schema::new("talk.", vec![]);
+        for db_cnx in ["memory", "sqlite:/nonexistent/talk.db"].iter() {
+            match open(db_cnx, dotted.clone()) {
+                Err(e) => assert_eq!(e, "Bad table name \"talk.block\""),
+                Ok(_) => panic!("{} took a dotted table name", db_cnx),
+            }
+        }
+        let dependent = vec![DependentTable::new("talk_comment", "talk_block.block_hash")];
+        match open("memory", Schema::new("talk_", dependent)) {
+            Err(e) => assert_eq!(e, "Bad column name \"talk_block.block_hash\""),
+            Ok(_) => panic!("took a dotted column name"),
+        }
+        assert!(open("memory", Schema::new("talk_", vec![])).is_ok());
+    }
+}
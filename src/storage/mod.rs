@@ -0,0 +1,320 @@
+use std::fs;
+use std::path::Path;
+
+use bitcoin::network::serialize::{BitcoinHash, serialize};
+use bitcoin::util::hash::Sha256dHash;
+
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json::Json;
+
+use mempool::MempoolEntry;
+use store::write_atomically;
+use util::addr_from_output;
+use verify::InputStatus;
+use witness::WitnessBlock;
+
+mod memory;
+mod postgres;
+mod sqlite;
+
+pub use self::memory::MemoryStorage;
+pub use self::postgres::PostgresStorage;
+pub use self::sqlite::SqliteStorage;
+
+/// Where indexed data is kept: the blocks of the retention window with
+/// their transactions, inputs, outputs and addresses, the mempool mirror
+/// and the latest fee estimates
+pub trait Storage {
+    /// Create or upgrade the tables we write to
+    fn prepare(&mut self, mirror_mempool: bool) -> Result<(), String>;
+
+    /// Hashes of the stored blocks, lowest first
+    fn block_hashes(&mut self) -> Result<Vec<Sha256dHash>, String>;
+
+    fn insert_block(&mut self, block: &BlockRecord) -> Result<(), String>;
+
+    /// Remove a block along with its transactions and anything else that
+    /// refers to it
+    fn remove_block(&mut self, block_hash: &Sha256dHash) -> Result<(), String>;
+
+    /// A stored block's rows, and its rows in dependent tables, as (table,
+    /// JSON object) pairs
+    fn export_rows(&mut self, block_hash: &Sha256dHash) -> Result<Vec<(String, String)>, String>;
+
+    /// Value of a stored output, if we have it
+    fn output_value(&mut self, txid: &Sha256dHash, index: u32) -> Result<Option<u64>, String>;
+
+    /// Stored outputs paying to an address, most recent first
+    fn address_history(&mut self, address: &str, offset: usize, limit: usize)
+                       -> Result<Vec<AddressOutput>, String>;
+
+    fn insert_mempool_tx(&mut self, entry: &MempoolEntry) -> Result<(), String>;
+
+    fn remove_mempool_txs(&mut self, txids: &[Sha256dHash]) -> Result<(), String>;
+
+    /// Replace the fee estimates, per confirmation target
+    fn save_fee_estimates(&mut self, estimates: &[(u32, Option<f64>)], height: u32)
+                          -> Result<(), String>;
+
+    /// Send a payload to the database's listeners on `channel`
+    fn notify(&mut self, channel: &str, payload: &str) -> Result<(), String>;
+}
+
+/// Whether a connection string names a Postgres database
+pub fn is_postgres(db_cnx: &str) -> bool {
+    db_cnx.starts_with("postgres://") || db_cnx.starts_with("postgresql://")
+}
+
+/// Open the backend named by a connection string: a Postgres URL,
+/// `sqlite:<path>`, or `memory`
+pub fn open(db_cnx: &str, schema: Schema) -> Result<Box<Storage + Send>, String> {
+    try!(schema.check(is_postgres(db_cnx)));
+    if is_postgres(db_cnx) {
+        Ok(Box::new(try!(PostgresStorage::connect(db_cnx, schema))))
+    } else if db_cnx.starts_with("sqlite:") {
+        Ok(Box::new(try!(SqliteStorage::open(&db_cnx["sqlite:".len()..], schema))))
+    } else if db_cnx == "memory" {
+        if schema.archive {
+            return Err("Archive tables need a SQL backend; export to archive_dir instead"
+                       .to_string());
+        }
+        Ok(Box::new(MemoryStorage::new(schema)))
+    } else {
+        Err(format!("Unknown storage backend {}", db_cnx))
+    }
+}
+
+/// Write a stored block's rows to `<dir>/<block hash>.ndjson`, one
+/// `{"table": ..., "row": ...}` object per line, so they outlive pruning.
+/// Returns the number of rows written.
+pub fn export_block<S: Storage + ?Sized>(storage: &mut S, dir: &str, block_hash: &Sha256dHash)
+                                         -> Result<usize, String> {
+    let rows = try!(storage.export_rows(block_hash));
+    if let Err(e) = fs::create_dir_all(dir) {
+        return Err(format!("Creating archive directory {}: {:?}", dir, e));
+    }
+    let mut lines = String::new();
+    for &(ref table, ref row) in rows.iter() {
+        lines.push_str(&format!("{{\"table\":{},\"row\":{}}}\n",
+                                Json::String(table.clone()), row));
+    }
+    let path = Path::new(dir).join(format!("{}.ndjson", block_hash.be_hex_string()));
+    try!(write_atomically(&path.to_string_lossy(), lines.as_bytes()));
+    Ok(rows.len())
+}
+
+/// A table of another app with rows that refer to our blocks, and so must
+/// be deleted before a block is pruned
+#[derive(Clone)]
+pub struct DependentTable {
+    pub table: String,
+    /// Column holding the hash of the block a row refers to
+    pub block_column: String,
+}
+
+impl DependentTable {
+    pub fn new(table: &str, block_column: &str) -> DependentTable {
+        DependentTable {
+            table: table.to_string(),
+            block_column: block_column.to_string(),
+        }
+    }
+}
+
+/// Names of the tables the SQL backends write to
+#[derive(Clone)]
+pub struct Schema {
+    pub block: String,
+    pub transaction: String,
+    pub txin: String,
+    pub txout: String,
+    pub address: String,
+    pub mempool: String,
+    pub fee_estimate: String,
+    pub dependent_tables: Vec<DependentTable>,
+    /// Copy pruned rows into `<table>_archive` tables before deleting them
+    pub archive: bool,
+}
+
+impl Schema {
+    /// Tables named `<prefix>block`, `<prefix>transaction` and so on. With
+    /// Postgres the prefix may start with a schema name, as in `chain.`.
+    pub fn new(prefix: &str, dependent_tables: Vec<DependentTable>) -> Schema {
+        Schema {
+            block: format!("{}block", prefix),
+            transaction: format!("{}transaction", prefix),
+            txin: format!("{}txin", prefix),
+            txout: format!("{}txout", prefix),
+            address: format!("{}address", prefix),
+            mempool: format!("{}mempool", prefix),
+            fee_estimate: format!("{}fee_estimate", prefix),
+            dependent_tables: dependent_tables,
+            archive: false,
+        }
+    }
+
+    /// The rows that make up a stored block, as (table, condition) pairs
+    /// where `param` in the condition stands for the block's hash
+    pub fn block_rows(&self, param: &str) -> Vec<(String, String)> {
+        let mut rows = self.dependent_tables.iter()
+            .map(|dependent| (dependent.table.clone(),
+                              format!("{} = {}", dependent.block_column, param)))
+            .collect::<Vec<(String, String)>>();
+        let in_block = format!("tx_id IN (SELECT tx_hash FROM {} WHERE block_hash_id = {})",
+                               self.transaction, param);
+        rows.push((self.txout.clone(), in_block.clone()));
+        rows.push((self.txin.clone(), in_block));
+        rows.push((self.transaction.clone(), format!("block_hash_id = {}", param)));
+        rows.push((self.block.clone(), format!("block_hash = {}", param)));
+        rows
+    }
+
+    /// Names are pasted into SQL, so only allow plain identifiers. A table
+    /// name may only contain a `.` where it names a Postgres schema: SQLite
+    /// would read it as an attached database, and the index names built
+    /// from it would be invalid.
+    fn check(&self, allow_schema: bool) -> Result<(), String> {
+        let mut tables = vec![&self.block, &self.transaction, &self.txin, &self.txout,
+                              &self.address, &self.mempool, &self.fee_estimate];
+        let mut columns = vec![];
+        for dependent in self.dependent_tables.iter() {
+            tables.push(&dependent.table);
+            columns.push(&dependent.block_column);
+        }
+        let plain = |c: char| (c.is_alphanumeric() && (c as u32) < 128) || c == '_';
+        for name in tables {
+            if name.is_empty() || !name.chars().all(|c| plain(c) || (allow_schema && c == '.')) {
+                return Err(format!("Bad table name {:?}", name));
+            }
+        }
+        for name in columns {
+            if name.is_empty() || !name.chars().all(&plain) {
+                return Err(format!("Bad column name {:?}", name));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A block as it is stored, with the totals and addresses worked out
+#[derive(Clone)]
+pub struct BlockRecord {
+    pub hash: Sha256dHash,
+    /// Only set if the previous block is stored too
+    pub prev_hash: Option<Sha256dHash>,
+    pub height: u32,
+    pub size: usize,
+    pub merkle_root: Sha256dHash,
+    pub time: u32,
+    pub bits: u32,
+    pub nonce: u32,
+    /// Sum of the outputs we found addresses for
+    pub total_value: u64,
+    pub txs: Vec<TxRecord>,
+}
+
+#[derive(Clone)]
+pub struct TxRecord {
+    pub txid: Sha256dHash,
+    pub wtxid: Sha256dHash,
+    pub total_value: u64,
+    pub inputs: Vec<TxInRecord>,
+    pub outputs: Vec<TxOutRecord>,
+}
+
+#[derive(Clone)]
+pub struct TxInRecord {
+    /// Id of the output spent, which is only linked if it's stored
+    pub output_id: String,
+    /// Hex of the serialized witness stack, if there is one
+    pub witness: Option<String>,
+    pub script_status: InputStatus,
+}
+
+#[derive(Clone)]
+pub struct TxOutRecord {
+    pub output_id: String,
+    pub value: u64,
+    pub index: u32,
+    pub address: String,
+}
+
+/// An output paying to an address, and where it was spent if we know
+pub struct AddressOutput {
+    pub txid: Sha256dHash,
+    pub index: u32,
+    pub value: u64,
+    pub block_hash: Sha256dHash,
+    pub height: u32,
+    /// Transaction spending the output, if that's stored too
+    pub spent_by: Option<Sha256dHash>,
+}
+
+impl BlockRecord {
+    pub fn new(block: &WitnessBlock, height: u32, prev_hash: Option<Sha256dHash>,
+               statuses: &Vec<Vec<InputStatus>>) -> BlockRecord {
+        let txs = block.txdata.iter().enumerate()
+            .map(|(i, wtx)| {
+                let txid = wtx.txid();
+                let inputs = wtx.tx.input.iter().enumerate()
+                    .map(|(j, input)| TxInRecord {
+                        output_id: output_id(&input.prev_hash, input.prev_index),
+                        witness: match wtx.witness.get(j) {
+                            Some(stack) if !stack.is_empty() =>
+                                Some(serialize(stack).unwrap().to_hex()),
+                            _ => None,
+                        },
+                        script_status: statuses.get(i)
+                            .and_then(|tx_statuses| tx_statuses.get(j))
+                            .map_or(InputStatus::Unverified, |&status| status),
+                    })
+                    .collect::<Vec<TxInRecord>>();
+                // outputs we can't find an address for aren't stored
+                let outputs = wtx.tx.output.iter().enumerate()
+                    .filter_map(|(j, output)| addr_from_output(output).map(|address| {
+                        TxOutRecord {
+                            output_id: output_id(&txid, j as u32),
+                            value: output.value,
+                            index: j as u32,
+                            address: address,
+                        }
+                    }))
+                    .collect::<Vec<TxOutRecord>>();
+                TxRecord {
+                    txid: txid,
+                    wtxid: wtx.wtxid(),
+                    total_value: outputs.iter().map(|output| output.value).sum(),
+                    inputs: inputs,
+                    outputs: outputs,
+                }
+            })
+            .collect::<Vec<TxRecord>>();
+
+        BlockRecord {
+            hash: block.header.bitcoin_hash(),
+            prev_hash: prev_hash,
+            height: height,
+            size: block.size(),
+            merkle_root: block.header.merkle_root,
+            time: block.header.time,
+            bits: block.header.bits,
+            nonce: block.header.nonce,
+            total_value: txs.iter().map(|tx| tx.total_value).sum(),
+            txs: txs,
+        }
+    }
+}
+
+/// Parse a hash read back from a table
+pub fn parse_hash(hex: &str) -> Result<Sha256dHash, String> {
+    match Sha256dHash::from_hex(hex) {
+        Ok(hash) => Ok(hash),
+        Err(e) => Err(format!("Bad hash {} in database: {:?}", hex, e)),
+    }
+}
+
+/// The id the talk app gives an output: its txid in hex followed by its
+/// index in decimal
+pub fn output_id(txid: &Sha256dHash, index: u32) -> String {
+    txid.be_hex_string() + &index.to_string()
+}
@@ -0,0 +1,481 @@
+use postgres::{Connection, GenericConnection, SslMode};
+
+use bitcoin::util::hash::Sha256dHash;
+
+use mempool::MempoolEntry;
+use storage::{Storage, Schema, BlockRecord, TxRecord, AddressOutput, output_id, parse_hash};
+
+/// Tables in a Postgres database, shared with the web app that reads them
+pub struct PostgresStorage {
+    conn: Connection,
+    schema: Schema,
+}
+
+impl PostgresStorage {
+    pub fn connect(db_cnx: &str, schema: Schema) -> Result<PostgresStorage, String> {
+        match Connection::connect(db_cnx, SslMode::None) {
+            Ok(conn) => Ok(PostgresStorage { conn: conn, schema: schema }),
+            Err(e) => Err(format!("Connecting to database: {:?}", e)),
+        }
+    }
+
+    /// Run `f` in a transaction, which is rolled back if `f` fails
+    fn in_transaction<F>(&self, f: F) -> Result<(), String>
+        where F: FnOnce(&GenericConnection) -> Result<(), String> {
+        let trans = match self.conn.transaction() {
+            Ok(trans) => trans,
+            Err(e) => return Err(format!("Starting transaction: {:?}", e)),
+        };
+        // dropping the transaction without committing rolls it back
+        try!(f(&trans));
+        match trans.commit() {
+            Ok(()) => Ok(()),
+            Err(e) => Err(format!("Committing transaction: {:?}", e)),
+        }
+    }
+
+    fn insert_tx(&self, conn: &GenericConnection, block_hash_string: &String, tx: &TxRecord)
+                 -> Result<(), String> {
+        let tx_hash_string = tx.txid.be_hex_string();
+
+        // insert transaction. A failed statement would abort the enclosing
+        // database transaction, so a duplicate (a unique_violation, 23505)
+        // is skipped with ON CONFLICT, and any other error is returned.
+        match conn.execute(
+            &format!("INSERT INTO {} (tx_hash, wtx_hash, block_hash_id, \
+                      total_value) VALUES ($1, $2, $3, $4) ON CONFLICT DO NOTHING",
+                     self.schema.transaction),
+            &[&tx_hash_string, &tx.wtxid.be_hex_string(), block_hash_string,
+              &(tx.total_value as i64)]) {
+            Ok(0) => {
+                debug!("storage", txid = tx_hash_string; "Skipping duplicate transaction");
+                return Ok(());
+            },
+            Ok(_) => (),
+            Err(e) => return Err(format!("Writing transaction: {:?}", e)),
+        }
+
+        // insert inputs
+        for input in tx.inputs.iter() {
+            match conn.execute(
+                &format!("INSERT INTO {} (tx_id, output_id, witness, script_status) \
+                          VALUES ($1, (SELECT (CASE WHEN EXISTS (SELECT 1 FROM {} \
+                          WHERE output=$2) THEN $2 ELSE NULL END)), $3, $4)",
+                         self.schema.txin, self.schema.txout),
+                &[&tx_hash_string,
+                  &input.output_id,
+                  &input.witness,
+                  &input.script_status.as_str()]) {
+                Ok(_) => (),
+                Err(e) => return Err(format!("Error writing input to database: {:?}", e)),
+            }
+        }
+
+        // insert outputs
+        for output in tx.outputs.iter() {
+            // insert address
+            match conn.execute(
+                &format!("INSERT INTO {0} (address) (SELECT $1::VARCHAR \
+                          WHERE NOT EXISTS (SELECT $1::VARCHAR FROM {0} \
+                          WHERE address=$1))", self.schema.address),
+                &[&output.address]) {
+                Ok(_) => (),
+                Err(e) => return Err(format!("Writing address: {:?}", e)),
+            }
+
+            // actually insert output (after inserting address)
+            match conn.execute(
+                &format!("INSERT INTO {} (tx_id, value, output_index, \
+                          address_id, output) VALUES ($1, $2, $3, $4, $5)",
+                         self.schema.txout),
+                &[&tx_hash_string,
+                  &(output.value as i64),
+                  &(output.index as i32),
+                  &output.address,
+                  &output.output_id]) {
+                Ok(_) => (),
+                Err(e) => return Err(format!("Writing txout: {:?}", e)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy a block's rows, and those of dependent tables, into the
+    /// archive tables
+    fn archive_block(&self, conn: &GenericConnection, block_hash_string: &String)
+                     -> Result<(), String> {
+        for &(ref table, ref condition) in self.schema.block_rows("$1").iter() {
+            // name the columns, as the two tables only share those that
+            // existed when the archive was created or last prepared
+            let archived = try!(columns(conn, &format!("{}_archive", table)));
+            let shared = try!(columns(conn, table)).into_iter()
+                .filter(|&(ref name, _)| archived.iter().any(|&(ref other, _)| other == name))
+                .map(|(name, _)| quote_identifier(&name))
+                .collect::<Vec<String>>()
+                .join(", ");
+            match conn.execute(
+                &format!("INSERT INTO {0}_archive ({2}) SELECT {2} FROM {0} WHERE {1}",
+                         table, condition, shared),
+                &[block_hash_string]) {
+                Ok(n) => trace!("storage", block = block_hash_string, table = table, rows = n;
+                                "Archived rows"),
+                Err(e) => return Err(format!("Archiving {} data: {:?}", table, e)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete a block's rows, archiving them first if archiving is on
+    fn delete_block(&self, conn: &GenericConnection, old_block_hash_string: &String)
+                    -> Result<(), String> {
+        let schema = &self.schema;
+
+        if schema.archive {
+            try!(self.archive_block(conn, old_block_hash_string));
+        }
+
+        // remove rows of other apps that refer to the block
+        for dependent in schema.dependent_tables.iter() {
+            match conn.execute(
+                &format!("DELETE FROM {} WHERE {} = $1",
+                         dependent.table, dependent.block_column),
+                &[old_block_hash_string]) {
+                Ok(n) => trace!("storage", block = old_block_hash_string,
+                                table = dependent.table, rows = n; "Removed dependent rows"),
+                Err(e) => return Err(format!("Removing {} data: {:?}", dependent.table, e)),
+            }
+        }
+
+        // set 'prev_block_hash_id' to NULL for old block's successor
+        match conn.execute(
+            &format!("UPDATE {0} SET prev_block_hash_id = NULL WHERE block_hash IN \
+                      (SELECT block_hash FROM {0} WHERE prev_block_hash_id = $1)",
+                     schema.block),
+            &[old_block_hash_string]) {
+            Ok(n) => trace!("storage", block = old_block_hash_string, rows = n;
+                            "Unlinked block successor"),
+            Err(e) => return Err(format!("Setting prev_block_hash_id: {:?}", e)),
+        }
+
+        // set news txins referencing doomed txouts to NULL
+        match conn.execute(
+            &format!("UPDATE {} SET output_id = NULL WHERE output_id IN (SELECT \
+                      output FROM {} WHERE tx_id IN (SELECT tx_hash FROM \
+                      {} WHERE block_hash_id = $1))",
+                     schema.txin, schema.txout, schema.transaction),
+            &[old_block_hash_string]) {
+            Ok(n) => trace!("storage", block = old_block_hash_string, rows = n;
+                            "Nulled references to removed outputs"),
+            Err(e) => return Err(format!("Nulling doomed references: {:?}", e)),
+        }
+
+        // remove txout data
+        match conn.execute(
+            &format!("DELETE FROM {} WHERE tx_id IN (SELECT tx_hash FROM \
+                      {} WHERE block_hash_id = $1)", schema.txout, schema.transaction),
+            &[old_block_hash_string]) {
+            Ok(n) => trace!("storage", block = old_block_hash_string, rows = n;
+                            "Removed outputs"),
+            Err(e) => return Err(format!("Removing TxOut data: {:?}", e)),
+        }
+
+        // remove txin data
+        match conn.execute(
+            &format!("DELETE FROM {} WHERE tx_id IN (SELECT tx_hash FROM \
+                      {} WHERE block_hash_id = $1)", schema.txin, schema.transaction),
+            &[old_block_hash_string]) {
+            Ok(n) => trace!("storage", block = old_block_hash_string, rows = n;
+                            "Removed inputs"),
+            Err(e) => return Err(format!("Removing TxIn data: {:?}", e)),
+        }
+
+        // remove transactions
+        match conn.execute(
+            &format!("DELETE FROM {} WHERE block_hash_id = $1", schema.transaction),
+            &[old_block_hash_string]) {
+            Ok(n) => trace!("storage", block = old_block_hash_string, rows = n;
+                            "Removed transactions"),
+            Err(e) => return Err(format!("Removing Transaction data: {:?}", e)),
+        }
+
+        // delete the old block
+        match conn.execute(
+            &format!("DELETE FROM {} WHERE block_hash = $1", schema.block),
+            &[old_block_hash_string]) {
+            Ok(_) => (),
+            Err(e) => return Err(format!("Removing old block: {:?}", e)),
+        }
+
+        Ok(())
+    }
+}
+
+/// A table's columns, in order, with their types
+fn columns(conn: &GenericConnection, table: &str) -> Result<Vec<(String, String)>, String> {
+    match conn.query("SELECT attname::TEXT, format_type(atttypid, atttypmod) \
+                      FROM pg_attribute WHERE attrelid = $1::TEXT::regclass \
+                      AND attnum > 0 AND NOT attisdropped ORDER BY attnum",
+                     &[&table.to_string()]) {
+        Ok(rows) => Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect()),
+        Err(e) => Err(format!("Reading the columns of {}: {:?}", table, e)),
+    }
+}
+
+/// Quote a column name read from the catalog, which may need it
+fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace("\"", "\"\""))
+}
+
+impl Storage for PostgresStorage {
+    /// Add the columns our segwit support needs to the web app's tables,
+    /// and the mempool table if we're mirroring the mempool
+    fn prepare(&mut self, mirror_mempool: bool) -> Result<(), String> {
+        match self.conn.batch_execute(&format!(
+            "ALTER TABLE {0} ADD COLUMN IF NOT EXISTS wtx_hash VARCHAR(64); \
+             ALTER TABLE {1} ADD COLUMN IF NOT EXISTS witness TEXT; \
+             ALTER TABLE {1} ADD COLUMN IF NOT EXISTS script_status VARCHAR(10);",
+            self.schema.transaction, self.schema.txin)) {
+            Ok(()) => (),
+            Err(e) => return Err(format!("Preparing database: {:?}", e)),
+        }
+
+        match self.conn.batch_execute(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (\
+             target INTEGER PRIMARY KEY, \
+             fee_rate DOUBLE PRECISION, \
+             block_height INTEGER NOT NULL);", self.schema.fee_estimate)) {
+            Ok(()) => (),
+            Err(e) => return Err(format!("Preparing fee estimate table: {:?}", e)),
+        }
+
+        if mirror_mempool {
+            // the in-memory mempool starts out empty, so the mirror does too
+            match self.conn.batch_execute(&format!(
+                "CREATE TABLE IF NOT EXISTS {0} (\
+                 tx_hash VARCHAR(64) PRIMARY KEY, \
+                 wtx_hash VARCHAR(64) NOT NULL, \
+                 first_seen BIGINT NOT NULL, \
+                 fee BIGINT, \
+                 vsize INTEGER NOT NULL, \
+                 fee_rate DOUBLE PRECISION, \
+                 total_value BIGINT NOT NULL); \
+                 DELETE FROM {0};", self.schema.mempool)) {
+                Ok(()) => (),
+                Err(e) => return Err(format!("Preparing mempool table: {:?}", e)),
+            }
+        }
+
+        if self.schema.archive {
+            // LIKE copies the columns but not the keys, so archived rows
+            // never conflict with each other or refer to deleted ones
+            for (table, _) in self.schema.block_rows("$1") {
+                match self.conn.batch_execute(
+                    &format!("CREATE TABLE IF NOT EXISTS {0}_archive (LIKE {0});", table)) {
+                    Ok(()) => (),
+                    Err(e) => return Err(format!("Preparing archive of {}: {:?}", table, e)),
+                }
+                // columns added to the live table since the archive was made
+                let archive = format!("{}_archive", table);
+                let archived = try!(columns(&self.conn, &archive));
+                for (name, column_type) in try!(columns(&self.conn, &table)) {
+                    if archived.iter().any(|&(ref other, _)| *other == name) {
+                        continue;
+                    }
+                    match self.conn.batch_execute(
+                        &format!("ALTER TABLE {} ADD COLUMN {} {};",
+                                 archive, quote_identifier(&name), column_type)) {
+                        Ok(()) => info!("storage", table = archive, column = name;
+                                        "Added column to archive table"),
+                        Err(e) => return Err(format!("Adding {} to {}: {:?}", name, archive, e)),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn block_hashes(&mut self) -> Result<Vec<Sha256dHash>, String> {
+        let mut hashes: Vec<Sha256dHash> = vec![];
+        match self.conn.query(&format!("SELECT block_hash FROM {} ORDER BY block_height",
+                                       self.schema.block), &[]) {
+            Ok(rows) => {
+                for row in rows.iter() {
+                    let block_hash_string: String = row.get(0);
+                    match Sha256dHash::from_hex(&block_hash_string) {
+                        Ok(hash) => hashes.push(hash),
+                        Err(e) => return Err(format!("Bad block hash {} in database: {:?}",
+                                                     block_hash_string, e)),
+                    }
+                }
+            },
+            Err(e) => return Err(format!("Reading blocks from database: {:?}", e)),
+        }
+        Ok(hashes)
+    }
+
+    fn insert_block(&mut self, block: &BlockRecord) -> Result<(), String> {
+        let block_hash_string = block.hash.be_hex_string();
+        debug!("storage", block = block_hash_string, height = block.height,
+               txs = block.txs.len(); "Inserting block");
+
+        // a block is stored whole or not at all
+        self.in_transaction(|conn| {
+            match conn.execute(
+                &format!("INSERT INTO {} (block_hash, prev_block_hash_id, \
+                          block_size, block_height, merkleroot, time, median_time, bits, \
+                          nonce, total_value) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+                         self.schema.block),
+                &[&block_hash_string,
+                  &block.prev_hash.map(|hash| hash.be_hex_string()),
+                  &(block.size as i32),
+                  &(block.height as i32),
+                  &block.merkle_root.be_hex_string(),
+                  &(block.time as i32),
+                  &(1111 as i32),
+                  &(block.bits as i64),
+                  &(block.nonce as i64),
+                  &(block.total_value as i64)]) {
+                Ok(_) => (),
+                Err(e) => return Err(format!("Error writing header to database: {:?}", e)),
+            }
+            for tx in block.txs.iter() {
+                try!(self.insert_tx(conn, &block_hash_string, tx));
+            }
+            Ok(())
+        })
+    }
+
+    fn remove_block(&mut self, block_hash: &Sha256dHash) -> Result<(), String> {
+        let block_hash_string = block_hash.be_hex_string();
+        // archived rows are only kept if they are also deleted
+        try!(self.in_transaction(|conn| self.delete_block(conn, &block_hash_string)));
+        debug!("storage", block = block_hash_string; "Removed old block");
+        Ok(())
+    }
+
+
+    fn export_rows(&mut self, block_hash: &Sha256dHash) -> Result<Vec<(String, String)>, String> {
+        let block_hash_string = block_hash.be_hex_string();
+        let mut exported: Vec<(String, String)> = vec![];
+        for (table, condition) in self.schema.block_rows("$1") {
+            match self.conn.query(&format!("SELECT row_to_json(t)::TEXT FROM {} t WHERE {}",
+                                           table, condition),
+                                  &[&block_hash_string]) {
+                Ok(rows) => {
+                    for row in rows.iter() {
+                        exported.push((table.clone(), row.get(0)));
+                    }
+                },
+                Err(e) => return Err(format!("Exporting {} data: {:?}", table, e)),
+            }
+        }
+        Ok(exported)
+    }
+
+    fn output_value(&mut self, txid: &Sha256dHash, index: u32) -> Result<Option<u64>, String> {
+        match self.conn.query(&format!("SELECT value FROM {} WHERE output = $1",
+                                       self.schema.txout),
+                              &[&output_id(txid, index)]) {
+            Ok(ref rows) if !rows.is_empty() => {
+                let value: i64 = rows.get(0).get(0);
+                Ok(Some(value as u64))
+            },
+            Ok(_) => Ok(None),
+            Err(e) => Err(format!("Reading txout: {:?}", e)),
+        }
+    }
+
+    fn address_history(&mut self, address: &str, offset: usize, limit: usize)
+                       -> Result<Vec<AddressOutput>, String> {
+        let rows = match self.conn.query(
+            &format!("SELECT o.tx_id, o.output_index, o.value, t.block_hash_id, \
+                      b.block_height, i.tx_id FROM {} o \
+                      JOIN {} t ON t.tx_hash = o.tx_id \
+                      JOIN {} b ON b.block_hash = t.block_hash_id \
+                      LEFT JOIN {} i ON i.output_id = o.output \
+                      WHERE o.address_id = $1 \
+                      ORDER BY b.block_height DESC, o.tx_id, o.output_index \
+                      LIMIT $2 OFFSET $3",
+                     self.schema.txout, self.schema.transaction, self.schema.block,
+                     self.schema.txin),
+            &[&address, &(limit as i64), &(offset as i64)]) {
+            Ok(rows) => rows,
+            Err(e) => return Err(format!("Reading address history: {:?}", e)),
+        };
+
+        let mut outputs: Vec<AddressOutput> = vec![];
+        for row in rows.iter() {
+            let txid: String = row.get(0);
+            let index: i32 = row.get(1);
+            let value: i64 = row.get(2);
+            let block_hash: String = row.get(3);
+            let height: i32 = row.get(4);
+            let spent_by: Option<String> = row.get(5);
+            outputs.push(AddressOutput {
+                txid: try!(parse_hash(&txid)),
+                index: index as u32,
+                value: value as u64,
+                block_hash: try!(parse_hash(&block_hash)),
+                height: height as u32,
+                spent_by: match spent_by {
+                    Some(spent_by) => Some(try!(parse_hash(&spent_by))),
+                    None => None,
+                },
+            });
+        }
+        Ok(outputs)
+    }
+
+    fn insert_mempool_tx(&mut self, entry: &MempoolEntry) -> Result<(), String> {
+        let total_value: u64 = entry.tx.tx.output.iter().map(|output| output.value).sum();
+        match self.conn.execute(
+            &format!("INSERT INTO {} (tx_hash, wtx_hash, first_seen, fee, \
+                      vsize, fee_rate, total_value) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                     self.schema.mempool),
+            &[&entry.txid.be_hex_string(),
+              &entry.tx.wtxid().be_hex_string(),
+              &(entry.first_seen as i64),
+              &entry.fee.map(|fee| fee as i64),
+              &(entry.vsize as i32),
+              &entry.fee_rate(),
+              &(total_value as i64)]) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Writing mempool tx: {:?}", e)),
+        }
+    }
+
+    fn remove_mempool_txs(&mut self, txids: &[Sha256dHash]) -> Result<(), String> {
+        for txid in txids.iter() {
+            match self.conn.execute(&format!("DELETE FROM {} WHERE tx_hash = $1",
+                                             self.schema.mempool),
+                                    &[&txid.be_hex_string()]) {
+                Ok(_) => (),
+                Err(e) => return Err(format!("Removing mempool tx: {:?}", e)),
+            }
+        }
+        Ok(())
+    }
+
+    fn save_fee_estimates(&mut self, estimates: &[(u32, Option<f64>)], height: u32)
+                          -> Result<(), String> {
+        for &(target, fee_rate) in estimates.iter() {
+            match self.conn.execute(
+                &format!("INSERT INTO {} (target, fee_rate, block_height) \
+                          VALUES ($1, $2, $3) ON CONFLICT (target) DO UPDATE SET \
+                          fee_rate = EXCLUDED.fee_rate, block_height = EXCLUDED.block_height",
+                         self.schema.fee_estimate),
+                &[&(target as i32), &fee_rate, &(height as i32)]) {
+                Ok(_) => (),
+                Err(e) => return Err(format!("Writing fee estimate: {:?}", e)),
+            }
+        }
+        Ok(())
+    }
+
+    fn notify(&mut self, channel: &str, payload: &str) -> Result<(), String> {
+        match self.conn.execute("SELECT pg_notify($1, $2)", &[&channel, &payload]) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Notifying {}: {:?}", channel, e)),
+        }
+    }
+}
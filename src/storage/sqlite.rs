@@ -0,0 +1,429 @@
+use rusqlite::{Connection, Error};
+use rusqlite::types::ToSql;
+
+use bitcoin::util::hash::Sha256dHash;
+
+use mempool::MempoolEntry;
+use storage::{Storage, Schema, BlockRecord, TxRecord, AddressOutput, output_id, parse_hash};
+
+/// Tables in a SQLite file, for deployments without a database server
+pub struct SqliteStorage {
+    conn: Connection,
+    schema: Schema,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str, schema: Schema) -> Result<SqliteStorage, String> {
+        match Connection::open(path) {
+            Ok(conn) => Ok(SqliteStorage { conn: conn, schema: schema }),
+            Err(e) => Err(format!("Opening SQLite database {}: {:?}", path, e)),
+        }
+    }
+
+    fn execute_batch(&self, sql: &str) -> Result<(), String> {
+        match self.conn.execute_batch(sql) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(format!("Executing {}: {:?}", sql, e)),
+        }
+    }
+
+    fn insert_tx(&self, block_hash_string: &String, tx: &TxRecord) -> Result<(), String> {
+        let tx_hash_string = tx.txid.be_hex_string();
+
+        match self.conn.execute(
+            &format!("INSERT OR IGNORE INTO {} (tx_hash, wtx_hash, block_hash_id, \
+                      total_value) VALUES (?1, ?2, ?3, ?4)", self.schema.transaction),
+            &[&tx_hash_string, &tx.wtxid.be_hex_string(), block_hash_string,
+              &(tx.total_value as i64)]) {
+            Ok(0) => {
+                debug!("storage", txid = tx_hash_string; "Skipping duplicate transaction");
+                return Ok(());
+            },
+            Ok(_) => (),
+            Err(e) => return Err(format!("Writing transaction: {:?}", e)),
+        }
+
+        for input in tx.inputs.iter() {
+            match self.conn.execute(
+                &format!("INSERT INTO {} (tx_id, output_id, witness, script_status) \
+                          VALUES (?1, (SELECT output FROM {} WHERE output = ?2), ?3, ?4)",
+                         self.schema.txin, self.schema.txout),
+                &[&tx_hash_string,
+                  &input.output_id,
+                  &input.witness,
+                  &input.script_status.as_str()]) {
+                Ok(_) => (),
+                Err(e) => return Err(format!("Error writing input to database: {:?}", e)),
+            }
+        }
+
+        for output in tx.outputs.iter() {
+            match self.conn.execute(&format!("INSERT OR IGNORE INTO {} (address) VALUES (?1)",
+                                             self.schema.address),
+                                    &[&output.address]) {
+                Ok(_) => (),
+                Err(e) => return Err(format!("Writing address: {:?}", e)),
+            }
+            match self.conn.execute(
+                &format!("INSERT INTO {} (tx_id, value, output_index, \
+                          address_id, output) VALUES (?1, ?2, ?3, ?4, ?5)",
+                         self.schema.txout),
+                &[&tx_hash_string,
+                  &(output.value as i64),
+                  &(output.index as i64),
+                  &output.address,
+                  &output.output_id]) {
+                Ok(_) => (),
+                Err(e) => return Err(format!("Writing txout: {:?}", e)),
+            }
+        }
+        Ok(())
+    }
+
+    fn delete_block(&self, block_hash_string: &String) -> Result<(), String> {
+        let schema = &self.schema;
+        let mut statements: Vec<String> = vec![];
+        if schema.archive {
+            for (table, condition) in schema.block_rows("?1") {
+                statements.push(format!("INSERT INTO {0}_archive SELECT * FROM {0} WHERE {1}",
+                                        table, condition));
+            }
+        }
+        // rows of other apps that refer to the block go first
+        for dependent in schema.dependent_tables.iter() {
+            statements.push(format!("DELETE FROM {} WHERE {} = ?1",
+                                    dependent.table, dependent.block_column));
+        }
+        statements.push(format!(
+            "UPDATE {} SET prev_block_hash_id = NULL WHERE prev_block_hash_id = ?1",
+            schema.block));
+        statements.push(format!(
+            "UPDATE {} SET output_id = NULL WHERE output_id IN (SELECT \
+             output FROM {} WHERE tx_id IN (SELECT tx_hash FROM \
+             {} WHERE block_hash_id = ?1))", schema.txin, schema.txout, schema.transaction));
+        statements.push(format!(
+            "DELETE FROM {} WHERE tx_id IN (SELECT tx_hash FROM \
+             {} WHERE block_hash_id = ?1)", schema.txout, schema.transaction));
+        statements.push(format!(
+            "DELETE FROM {} WHERE tx_id IN (SELECT tx_hash FROM \
+             {} WHERE block_hash_id = ?1)", schema.txin, schema.transaction));
+        statements.push(format!("DELETE FROM {} WHERE block_hash_id = ?1", schema.transaction));
+        statements.push(format!("DELETE FROM {} WHERE block_hash = ?1", schema.block));
+
+        for sql in statements.iter() {
+            if let Err(e) = self.conn.execute(sql, &[block_hash_string]) {
+                return Err(format!("Removing block {}: {:?}", block_hash_string, e));
+            }
+        }
+        Ok(())
+    }
+
+    /// The text in `column` of each row a query returns
+    fn strings(&self, sql: &str, column: i32, params: &[&ToSql]) -> Result<Vec<String>, String> {
+        let mut stmt = match self.conn.prepare(sql) {
+            Ok(stmt) => stmt,
+            Err(e) => return Err(format!("Preparing {}: {:?}", sql, e)),
+        };
+        let rows = match stmt.query_map(params, |row| {
+            let text: String = row.get(column);
+            text
+        }) {
+            Ok(rows) => rows,
+            Err(e) => return Err(format!("Querying {}: {:?}", sql, e)),
+        };
+        let mut strings: Vec<String> = vec![];
+        for row in rows {
+            match row {
+                Ok(text) => strings.push(text),
+                Err(e) => return Err(format!("Reading {}: {:?}", sql, e)),
+            }
+        }
+        Ok(strings)
+    }
+
+    /// Run `f` in a transaction, rolling it back if `f` fails
+    fn in_transaction<F>(&self, f: F) -> Result<(), String>
+        where F: FnOnce(&SqliteStorage) -> Result<(), String> {
+        try!(self.execute_batch("BEGIN"));
+        match f(self) {
+            Ok(()) => self.execute_batch("COMMIT"),
+            Err(e) => {
+                if let Err(rollback_err) = self.execute_batch("ROLLBACK") {
+                    error!("storage", "Rollback failed: {}", rollback_err);
+                }
+                Err(e)
+            },
+        }
+    }
+}
+
+impl Storage for SqliteStorage {
+    /// Create the tables a web app's models would have for Postgres,
+    /// along with the ones we add
+    fn prepare(&mut self, mirror_mempool: bool) -> Result<(), String> {
+        let schema = self.schema.clone();
+        try!(self.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {block} (\
+             block_hash VARCHAR(64) PRIMARY KEY, \
+             prev_block_hash_id VARCHAR(64), \
+             block_size INTEGER NOT NULL, \
+             block_height INTEGER NOT NULL, \
+             merkleroot VARCHAR(64) NOT NULL, \
+             time INTEGER NOT NULL, \
+             median_time INTEGER NOT NULL, \
+             bits INTEGER NOT NULL, \
+             nonce INTEGER NOT NULL, \
+             total_value INTEGER); \
+             CREATE TABLE IF NOT EXISTS {transaction} (\
+             tx_hash VARCHAR(64) PRIMARY KEY, \
+             wtx_hash VARCHAR(64), \
+             block_hash_id VARCHAR(64) NOT NULL, \
+             total_value INTEGER); \
+             CREATE INDEX IF NOT EXISTS {transaction}_block \
+             ON {transaction} (block_hash_id); \
+             CREATE TABLE IF NOT EXISTS {address} (\
+             address VARCHAR(64) PRIMARY KEY); \
+             CREATE TABLE IF NOT EXISTS {txout} (\
+             output VARCHAR(80) PRIMARY KEY, \
+             tx_id VARCHAR(64) NOT NULL, \
+             value INTEGER NOT NULL, \
+             output_index INTEGER NOT NULL, \
+             address_id VARCHAR(64)); \
+             CREATE INDEX IF NOT EXISTS {txout}_tx ON {txout} (tx_id); \
+             CREATE TABLE IF NOT EXISTS {txin} (\
+             id INTEGER PRIMARY KEY AUTOINCREMENT, \
+             tx_id VARCHAR(64) NOT NULL, \
+             output_id VARCHAR(80), \
+             witness TEXT, \
+             script_status VARCHAR(10)); \
+             CREATE INDEX IF NOT EXISTS {txin}_tx ON {txin} (tx_id); \
+             CREATE INDEX IF NOT EXISTS {txin}_output ON {txin} (output_id); \
+             CREATE TABLE IF NOT EXISTS {fee_estimate} (\
+             target INTEGER PRIMARY KEY, \
+             fee_rate REAL, \
+             block_height INTEGER NOT NULL);",
+            block = schema.block, transaction = schema.transaction,
+            address = schema.address, txout = schema.txout, txin = schema.txin,
+            fee_estimate = schema.fee_estimate)));
+
+        if mirror_mempool {
+            try!(self.execute_batch(&format!(
+                "CREATE TABLE IF NOT EXISTS {0} (\
+                 tx_hash VARCHAR(64) PRIMARY KEY, \
+                 wtx_hash VARCHAR(64) NOT NULL, \
+                 first_seen INTEGER NOT NULL, \
+                 fee INTEGER, \
+                 vsize INTEGER NOT NULL, \
+                 fee_rate REAL, \
+                 total_value INTEGER NOT NULL); \
+                 DELETE FROM {0};", schema.mempool)));
+        }
+
+        if schema.archive {
+            // an empty copy of the table's columns, without its keys
+            for (table, _) in schema.block_rows("?1") {
+                try!(self.execute_batch(
+                    &format!("CREATE TABLE IF NOT EXISTS {0}_archive AS \
+                              SELECT * FROM {0} WHERE 0;", table)));
+            }
+        }
+        Ok(())
+    }
+
+    fn block_hashes(&mut self) -> Result<Vec<Sha256dHash>, String> {
+        let mut stmt = match self.conn.prepare(
+            &format!("SELECT block_hash FROM {} ORDER BY block_height", self.schema.block)) {
+            Ok(stmt) => stmt,
+            Err(e) => return Err(format!("Reading blocks from database: {:?}", e)),
+        };
+        let rows = match stmt.query_map(&[], |row| {
+            let block_hash_string: String = row.get(0);
+            block_hash_string
+        }) {
+            Ok(rows) => rows,
+            Err(e) => return Err(format!("Reading blocks from database: {:?}", e)),
+        };
+
+        let mut hashes: Vec<Sha256dHash> = vec![];
+        for row in rows {
+            let block_hash_string = match row {
+                Ok(block_hash_string) => block_hash_string,
+                Err(e) => return Err(format!("Reading blocks from database: {:?}", e)),
+            };
+            match Sha256dHash::from_hex(&block_hash_string) {
+                Ok(hash) => hashes.push(hash),
+                Err(e) => return Err(format!("Bad block hash {} in database: {:?}",
+                                             block_hash_string, e)),
+            }
+        }
+        Ok(hashes)
+    }
+
+    fn insert_block(&mut self, block: &BlockRecord) -> Result<(), String> {
+        let block_hash_string = block.hash.be_hex_string();
+        debug!("storage", block = block_hash_string, height = block.height,
+               txs = block.txs.len(); "Inserting block");
+
+        self.in_transaction(|storage| {
+            match storage.conn.execute(
+                &format!("INSERT INTO {} (block_hash, prev_block_hash_id, \
+                          block_size, block_height, merkleroot, time, median_time, bits, \
+                          nonce, total_value) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                         storage.schema.block),
+                &[&block_hash_string,
+                  &block.prev_hash.map(|hash| hash.be_hex_string()),
+                  &(block.size as i64),
+                  &(block.height as i64),
+                  &block.merkle_root.be_hex_string(),
+                  &(block.time as i64),
+                  &(1111 as i64),
+                  &(block.bits as i64),
+                  &(block.nonce as i64),
+                  &(block.total_value as i64)]) {
+                Ok(_) => (),
+                Err(e) => return Err(format!("Error writing header to database: {:?}", e)),
+            }
+            for tx in block.txs.iter() {
+                try!(storage.insert_tx(&block_hash_string, tx));
+            }
+            Ok(())
+        })
+    }
+
+    fn remove_block(&mut self, block_hash: &Sha256dHash) -> Result<(), String> {
+        let block_hash_string = block_hash.be_hex_string();
+        try!(self.in_transaction(|storage| storage.delete_block(&block_hash_string)));
+        debug!("storage", block = block_hash_string; "Removed old block");
+        Ok(())
+    }
+
+    fn export_rows(&mut self, block_hash: &Sha256dHash) -> Result<Vec<(String, String)>, String> {
+        let block_hash_string = block_hash.be_hex_string();
+        let mut exported: Vec<(String, String)> = vec![];
+        for (table, condition) in self.schema.block_rows("?1") {
+            // json_object() needs the columns spelled out
+            let columns = try!(self.strings(&format!("PRAGMA table_info({})", table), 1, &[]));
+            let fields = columns.iter()
+                .map(|column| format!("'{}', \"{}\"", column.replace("'", "''"),
+                                      column.replace("\"", "\"\"")))
+                .collect::<Vec<String>>();
+            let rows = try!(self.strings(
+                &format!("SELECT json_object({}) FROM {} WHERE {}",
+                         fields.join(", "), table, condition),
+                0, &[&block_hash_string]));
+            for row in rows {
+                exported.push((table.clone(), row));
+            }
+        }
+        Ok(exported)
+    }
+
+    fn output_value(&mut self, txid: &Sha256dHash, index: u32) -> Result<Option<u64>, String> {
+        match self.conn.query_row(&format!("SELECT value FROM {} WHERE output = ?1",
+                                           self.schema.txout),
+                                  &[&output_id(txid, index)],
+                                  |row| { let value: i64 = row.get(0); value }) {
+            Ok(value) => Ok(Some(value as u64)),
+            Err(Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(format!("Reading txout: {:?}", e)),
+        }
+    }
+
+    fn address_history(&mut self, address: &str, offset: usize, limit: usize)
+                       -> Result<Vec<AddressOutput>, String> {
+        let mut stmt = match self.conn.prepare(
+            &format!("SELECT o.tx_id, o.output_index, o.value, t.block_hash_id, \
+                      b.block_height, i.tx_id FROM {} o \
+                      JOIN {} t ON t.tx_hash = o.tx_id \
+                      JOIN {} b ON b.block_hash = t.block_hash_id \
+                      LEFT JOIN {} i ON i.output_id = o.output \
+                      WHERE o.address_id = ?1 \
+                      ORDER BY b.block_height DESC, o.tx_id, o.output_index \
+                      LIMIT ?2 OFFSET ?3",
+                     self.schema.txout, self.schema.transaction, self.schema.block,
+                     self.schema.txin)) {
+            Ok(stmt) => stmt,
+            Err(e) => return Err(format!("Reading address history: {:?}", e)),
+        };
+        let rows = match stmt.query_map(&[&address, &(limit as i64), &(offset as i64)], |row| {
+            let txid: String = row.get(0);
+            let index: i64 = row.get(1);
+            let value: i64 = row.get(2);
+            let block_hash: String = row.get(3);
+            let height: i64 = row.get(4);
+            let spent_by: Option<String> = row.get(5);
+            (txid, index, value, block_hash, height, spent_by)
+        }) {
+            Ok(rows) => rows,
+            Err(e) => return Err(format!("Reading address history: {:?}", e)),
+        };
+
+        let mut outputs: Vec<AddressOutput> = vec![];
+        for row in rows {
+            let (txid, index, value, block_hash, height, spent_by) = match row {
+                Ok(row) => row,
+                Err(e) => return Err(format!("Reading address history: {:?}", e)),
+            };
+            outputs.push(AddressOutput {
+                txid: try!(parse_hash(&txid)),
+                index: index as u32,
+                value: value as u64,
+                block_hash: try!(parse_hash(&block_hash)),
+                height: height as u32,
+                spent_by: match spent_by {
+                    Some(spent_by) => Some(try!(parse_hash(&spent_by))),
+                    None => None,
+                },
+            });
+        }
+        Ok(outputs)
+    }
+
+    fn insert_mempool_tx(&mut self, entry: &MempoolEntry) -> Result<(), String> {
+        let total_value: u64 = entry.tx.tx.output.iter().map(|output| output.value).sum();
+        match self.conn.execute(
+            &format!("INSERT OR REPLACE INTO {} (tx_hash, wtx_hash, first_seen, fee, \
+                      vsize, fee_rate, total_value) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                     self.schema.mempool),
+            &[&entry.txid.be_hex_string(),
+              &entry.tx.wtxid().be_hex_string(),
+              &(entry.first_seen as i64),
+              &entry.fee.map(|fee| fee as i64),
+              &(entry.vsize as i64),
+              &entry.fee_rate(),
+              &(total_value as i64)]) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Writing mempool tx: {:?}", e)),
+        }
+    }
+
+    fn remove_mempool_txs(&mut self, txids: &[Sha256dHash]) -> Result<(), String> {
+        for txid in txids.iter() {
+            match self.conn.execute(&format!("DELETE FROM {} WHERE tx_hash = ?1",
+                                             self.schema.mempool),
+                                    &[&txid.be_hex_string()]) {
+                Ok(_) => (),
+                Err(e) => return Err(format!("Removing mempool tx: {:?}", e)),
+            }
+        }
+        Ok(())
+    }
+
+    fn save_fee_estimates(&mut self, estimates: &[(u32, Option<f64>)], height: u32)
+                          -> Result<(), String> {
+        for &(target, fee_rate) in estimates.iter() {
+            match self.conn.execute(
+                &format!("INSERT OR REPLACE INTO {} (target, fee_rate, block_height) \
+                          VALUES (?1, ?2, ?3)", self.schema.fee_estimate),
+                &[&(target as i64), &fee_rate, &(height as i64)]) {
+                Ok(_) => (),
+                Err(e) => return Err(format!("Writing fee estimate: {:?}", e)),
+            }
+        }
+        Ok(())
+    }
+
+    fn notify(&mut self, channel: &str, _payload: &str) -> Result<(), String> {
+        Err(format!("Cannot notify {}: LISTEN/NOTIFY needs Postgres", channel))
+    }
+}
@@ -0,0 +1,227 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::blockchain::Blockchain;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::network::constants::Network;
+use bitcoin::network::encodable::ConsensusDecodable;
+use bitcoin::network::serialize::{RawDecoder, BitcoinHash, serialize, deserialize};
+use bitcoin::util::hash::Sha256dHash;
+use bitcoin::util::Error;
+
+use wire::checksum;
+use witness::WitnessBlock;
+
+const HEADER_SIZE: usize = 80;
+/// A serialized header followed by the first four bytes of its hash
+const RECORD_SIZE: usize = HEADER_SIZE + 4;
+
+/// Append-only log of every header added to the chain, in the order they
+/// were added. Each append is fsynced before returning, and a torn record
+/// left by a crash is truncated away when the log is next opened.
+pub struct HeaderStore {
+    file: File,
+    path: String,
+}
+
+impl HeaderStore {
+    /// Open the log at `path`, replaying its headers into a new blockchain
+    pub fn open(path: &str, network: Network) -> Result<(HeaderStore, Blockchain), String> {
+        let mut file = match OpenOptions::new().read(true).append(true).create(true).open(path) {
+            Ok(file) => file,
+            Err(e) => return Err(format!("Could not open header store {}: {:?}", path, e)),
+        };
+
+        let mut data = vec![];
+        if let Err(e) = file.read_to_end(&mut data) {
+            return Err(format!("Could not read header store {}: {:?}", path, e));
+        }
+
+        let mut blockchain = Blockchain::new(network);
+        let mut good_len = 0;
+        for record in data.chunks(RECORD_SIZE) {
+            if record.len() < RECORD_SIZE ||
+                checksum(&record[..HEADER_SIZE]) != record[HEADER_SIZE..] {
+                break;
+            }
+            let header: BlockHeader = match deserialize(&record[..HEADER_SIZE]) {
+                Ok(header) => header,
+                Err(_) => break,
+            };
+            match blockchain.add_header(header) {
+                Ok(()) | Err(Error::DuplicateHash) => good_len += RECORD_SIZE,
+                Err(_) => break,
+            }
+        }
+
+        if good_len < data.len() {
+            warn!("db", path = path, bytes = data.len() - good_len;
+                  "Truncating damaged data from header store");
+            if let Err(e) = file.set_len(good_len as u64).and_then(|_| file.sync_all()) {
+                return Err(format!("Could not truncate header store {}: {:?}", path, e));
+            }
+        }
+        info!("db", path = path, headers = good_len / RECORD_SIZE; "Loaded headers");
+
+        Ok((HeaderStore { file: file, path: path.to_string() }, blockchain))
+    }
+
+    /// Durably append headers that were just added to the chain
+    pub fn append(&mut self, headers: &[BlockHeader]) -> Result<(), String> {
+        if headers.is_empty() {
+            return Ok(());
+        }
+        let mut data = Vec::with_capacity(headers.len() * RECORD_SIZE);
+        for header in headers.iter() {
+            let bytes = serialize(header).unwrap();
+            data.extend_from_slice(&bytes[..]);
+            data.extend_from_slice(&checksum(&bytes[..])[..]);
+        }
+        match self.file.write_all(&data[..]).and_then(|_| self.file.sync_data()) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(format!("Failed to append to header store {}: {:?}", self.path, e)),
+        }
+    }
+}
+
+/// The full blocks of the retention window, one file per block
+pub struct BlockStore {
+    dir: String,
+}
+
+impl BlockStore {
+    pub fn open(dir: &str) -> Result<BlockStore, String> {
+        match fs::create_dir_all(dir) {
+            Ok(()) => Ok(BlockStore { dir: dir.to_string() }),
+            Err(e) => Err(format!("Could not create block store {}: {:?}", dir, e)),
+        }
+    }
+
+    pub fn put(&self, block: &WitnessBlock) -> Result<(), String> {
+        let data = serialize(block).unwrap();
+        write_atomically(&self.path_of(&block.bitcoin_hash()), &data[..])
+    }
+
+    pub fn get(&self, hash: &Sha256dHash) -> Option<WitnessBlock> {
+        let mut data = vec![];
+        match File::open(self.path_of(hash)).map(|mut file| file.read_to_end(&mut data)) {
+            Ok(Ok(_)) => deserialize(&data[..]).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn remove(&self, hash: &Sha256dHash) -> Result<(), String> {
+        match fs::remove_file(self.path_of(hash)) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(format!("Removing block {}: {:?}", hash.be_hex_string(), e)),
+        }
+    }
+
+    fn path_of(&self, hash: &Sha256dHash) -> String {
+        format!("{}/{}.blk", self.dir, hash.be_hex_string())
+    }
+}
+
+/// Replace the file at `path` so that a crash leaves either the old
+/// contents or the new, never a mix: write to a temporary file, fsync it,
+/// rename it over the original, then fsync the directory so that the
+/// rename itself survives a crash
+pub fn write_atomically(path: &str, data: &[u8]) -> Result<(), String> {
+    let tmp_path = format!("{}.tmp", path);
+    let dir = match Path::new(path).parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    let written = File::create(&tmp_path)
+        .and_then(|mut file| file.write_all(data).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .and_then(|_| File::open(dir))
+        .and_then(|dir| dir.sync_all());
+    match written {
+        Ok(()) => Ok(()),
+        Err(e) => Err(format!("Failed to write {}: {:?}", path, e)),
+    }
+}
+
+/// Read a blockchain saved whole by earlier versions of the daemon, so that
+/// it can be moved into a header store
+pub fn load_legacy_blockchain(path: &str) -> Option<Blockchain> {
+    match File::open(path) {
+        Err(_) => None,
+        Ok(file) => {
+            let mut decoder = RawDecoder::new(BufReader::new(file));
+            match ConsensusDecodable::consensus_decode(&mut decoder) {
+                Ok(blockchain) => Some(blockchain),
+                Err(e) => {
+                    error!("db", path = path; "Could not load legacy blockchain: {:?}", e);
+                    None
+                },
+            }
+        },
+    }
+}
+
+/// The headers of a blockchain's best chain, from just after genesis
+pub fn best_chain_headers(blockchain: &Blockchain, network: Network) -> Vec<BlockHeader> {
+    blockchain.iter(genesis_block(network).bitcoin_hash())
+        .skip(1)
+        .map(|block_node_ref| block_node_ref.block.header)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::process;
+
+    use rustc_serialize::hex::FromHex;
+
+    use bitcoin::blockdata::block::BlockHeader;
+    use bitcoin::network::constants::Network;
+    use bitcoin::network::serialize::{BitcoinHash, deserialize};
+
+    use super::{HeaderStore, RECORD_SIZE};
+
+    /// Mainnet headers 1 to 3
+    const HEADERS: [&'static str; 3] = [
+        "010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000982051fd1e4ba7\
+         44bbbe680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e61bc6649ffff001d01e36299",
+        "010000004860eb18bf1b1620e37e9490fc8a427514416fd75159ab86688e9a8300000000d5fdcc541e25de\
+         1c7a5addedf24858b8bb665c9f36ef744ee42c316022c90f9bb0bc6649ffff001d08d2bd61",
+        "01000000bddd99ccfda39da1b108ce1a5d70038d0a967bacb68b6b63065f626a0000000044f672226090d8\
+         5db9a9f2fbfe5f0f9609b387af7be5b7fbb7a1767c831c9e995dbe6649ffff001d05e0ed6d",
+    ];
+
+    #[test]
+    fn header_store_drops_torn_tail() {
+        let dir = env::temp_dir().join(format!("header_store_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("headers.dat").to_string_lossy().into_owned();
+        let headers = HEADERS.iter()
+            .map(|hex| deserialize(&hex.from_hex().unwrap()).unwrap())
+            .collect::<Vec<BlockHeader>>();
+
+        let (mut store, _) = HeaderStore::open(&path, Network::Bitcoin).unwrap();
+        store.append(&headers[..]).unwrap();
+        drop(store);
+        // a crash halfway through writing the third record
+        let torn_len = 2 * RECORD_SIZE + RECORD_SIZE / 2;
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(torn_len as u64).unwrap();
+
+        let (mut store, blockchain) = HeaderStore::open(&path, Network::Bitcoin).unwrap();
+        assert_eq!(blockchain.best_tip_hash(), headers[1].bitcoin_hash());
+        assert!(blockchain.get_block(headers[2].bitcoin_hash()).is_none());
+        assert_eq!(fs::metadata(&path).unwrap().len(), 2 * RECORD_SIZE as u64);
+
+        // appends carry on from the last whole record
+        store.append(&headers[2..]).unwrap();
+        drop(store);
+        let (_, blockchain) = HeaderStore::open(&path, Network::Bitcoin).unwrap();
+        assert_eq!(blockchain.best_tip_hash(), headers[2].bitcoin_hash());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
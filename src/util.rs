@@ -1,20 +1,81 @@
 use std::sync::mpsc::{Sender};
 use std::net::Ipv4Addr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use bitcoin::network::address::Address;
-use bitcoin::blockdata::block::{LoneBlockHeader, Block};
-use bitcoin::blockdata::transaction::{Transaction, TxOut};
-use bitcoin::network::message_blockdata::Inventory;
+use bitcoin::blockdata::block::LoneBlockHeader;
+use bitcoin::blockdata::transaction::TxOut;
 use bitcoin::util::hash::{Sha256dHash};
-use bitcoin::util::base58::ToBase58;
+use bitcoin::util::base58::{FromBase58, ToBase58};
+
+use crypto::digest::Digest;
+use crypto::ripemd160::Ripemd160;
+use crypto::sha2::Sha256;
+
+use bech32;
+use compact::{SendCmpct, CompactBlock, BlockTxn};
+use filter::{FilterRequest, CheckptRequest, CFilter, CFHeaders};
+use peerd::PeerInfo;
+use rest::RestCall;
+use rpc::RpcCall;
+use wire::InvVector;
+use witness::{WitnessBlock, WitnessTx};
 
 pub enum ThreadResponse {
     Addresses(Vec<(u32, Address)>),
     Headers(String, Vec<LoneBlockHeader>),
-    Inv(String, Vec<Inventory>),
-    Block(Block),
-    Tx(Transaction),
+    Inv(String, Vec<InvVector>),
+    GetData(String, Vec<InvVector>),
+    Block(String, WitnessBlock),
+    Tx(WitnessTx),
+    /// A peer told us which compact block version it speaks
+    SendCmpct(String, SendCmpct),
+    CmpctBlock(String, CompactBlock),
+    BlockTxn(String, BlockTxn),
+    GetCFilters(String, FilterRequest),
+    GetCFHeaders(String, FilterRequest),
+    GetCFCheckpt(String, CheckptRequest),
+    CFilter(String, CFilter),
+    CFHeaders(String, CFHeaders),
+    /// A peer broke the protocol in a way its thread noticed, e.g. with an
+    /// oversized bloom filter
+    Misbehaved(String, String),
     CloseThread((String, Sender<()>)),
+    /// A peer accepted us as a witness-serving node
+    Connected(String, PeerInfo),
+    Rpc(RpcCall),
+    Rest(RestCall),
+}
+
+impl ThreadResponse {
+    /// The variant, for logging
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ThreadResponse::Addresses(..) => "addresses",
+            ThreadResponse::Headers(..) => "headers",
+            ThreadResponse::Inv(..) => "inv",
+            ThreadResponse::GetData(..) => "getdata",
+            ThreadResponse::Block(..) => "block",
+            ThreadResponse::Tx(..) => "tx",
+            ThreadResponse::SendCmpct(..) => "sendcmpct",
+            ThreadResponse::CmpctBlock(..) => "cmpctblock",
+            ThreadResponse::BlockTxn(..) => "blocktxn",
+            ThreadResponse::GetCFilters(..) => "getcfilters",
+            ThreadResponse::GetCFHeaders(..) => "getcfheaders",
+            ThreadResponse::GetCFCheckpt(..) => "getcfcheckpt",
+            ThreadResponse::CFilter(..) => "cfilter",
+            ThreadResponse::CFHeaders(..) => "cfheaders",
+            ThreadResponse::Misbehaved(..) => "misbehaved",
+            ThreadResponse::CloseThread(..) => "closethread",
+            ThreadResponse::Connected(..) => "connected",
+            ThreadResponse::Rpc(..) => "rpc",
+            ThreadResponse::Rest(..) => "rest",
+        }
+    }
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
 }
 
 pub fn ipv4_to_ipv4addr(ip: &str) -> Ipv4Addr {
@@ -49,3 +110,154 @@ pub fn addr_from_output(output: &TxOut) -> Option<String> {
     } else { None }
 }
 
+/// RIPEMD160 of SHA256, as used for public key and script hashes
+pub fn hash160(data: &[u8]) -> [u8; 20] {
+    let mut sha256 = Sha256::new();
+    sha256.input(data);
+    let mut digest = [0u8; 32];
+    sha256.result(&mut digest);
+    let mut ripemd160 = Ripemd160::new();
+    ripemd160.input(&digest);
+    let mut hash = [0u8; 20];
+    ripemd160.result(&mut hash);
+    hash
+}
+
+pub fn p2pkh_script(pubkey_hash: &[u8]) -> Vec<u8> {
+    // OP_DUP OP_HASH160 <hash> OP_EQUALVERIFY OP_CHECKSIG
+    let mut script = vec![0x76, 0xa9, 0x14];
+    script.extend_from_slice(pubkey_hash);
+    script.extend_from_slice(&[0x88, 0xac]);
+    script
+}
+
+pub fn p2sh_script(script_hash: &[u8]) -> Vec<u8> {
+    // OP_HASH160 <hash> OP_EQUAL
+    let mut script = vec![0xa9, 0x14];
+    script.extend_from_slice(script_hash);
+    script.push(0x87);
+    script
+}
+
+/// Output script paying to a witness program
+pub fn witness_script(version: u8, program: &[u8]) -> Vec<u8> {
+    // OP_0, or OP_1 to OP_16, then a push of the program
+    let mut script = vec![if version == 0 { 0 } else { 0x50 + version }, program.len() as u8];
+    script.extend_from_slice(program);
+    script
+}
+
+/// The script an address pays to
+pub fn script_from_address(address: &str) -> Result<Vec<u8>, String> {
+    let lowercase = address.to_lowercase();
+    for hrp in ["bc", "tb"].iter() {
+        if lowercase.starts_with(&format!("{}1", hrp)) {
+            let (version, program) = try!(bech32::decode(hrp, address));
+            return Ok(witness_script(version, &program[..]));
+        }
+    }
+    let data: Vec<u8> = match FromBase58::from_base58check(address) {
+        Ok(data) => data,
+        Err(e) => return Err(format!("Invalid address {}: {:?}", address, e)),
+    };
+    if data.len() != 21 {
+        return Err(format!("Invalid address {}: wrong length", address));
+    }
+    match data[0] {
+        0x00 | 0x6f => Ok(p2pkh_script(&data[1..])),
+        0x05 | 0xc4 => Ok(p2sh_script(&data[1..])),
+        version => Err(format!("Invalid address {}: unknown version {}", address, version)),
+    }
+}
+
+/// SipHash-2-4, as used for compact block short IDs and filter items
+pub fn siphash(k0: u64, k1: u64, data: &[u8]) -> u64 {
+    let mut v = [k0 ^ 0x736f6d6570736575, k1 ^ 0x646f72616e646f6d,
+                 k0 ^ 0x6c7967656e657261, k1 ^ 0x7465646279746573];
+    let blocks = data.len() / 8;
+    for i in 0..blocks {
+        let m = u64_from_le(&data[i * 8..i * 8 + 8]);
+        v[3] ^= m;
+        sip_round(&mut v);
+        sip_round(&mut v);
+        v[0] ^= m;
+    }
+    // the remaining bytes, topped with the length
+    let mut last = (data.len() as u64 & 0xff) << 56;
+    for (i, &byte) in data[blocks * 8..].iter().enumerate() {
+        last |= (byte as u64) << (8 * i);
+    }
+    v[3] ^= last;
+    sip_round(&mut v);
+    sip_round(&mut v);
+    v[0] ^= last;
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        sip_round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+fn sip_round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13);
+    v[1] ^= v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16);
+    v[3] ^= v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21);
+    v[3] ^= v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17);
+    v[1] ^= v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+pub fn u64_from_le(data: &[u8]) -> u64 {
+    data.iter().take(8).enumerate()
+        .fold(0, |n, (i, &byte)| n | (byte as u64) << (8 * i))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{siphash, u64_from_le};
+
+    /// The reference SipHash-2-4 outputs for the key 00 01 .. 0f, where
+    /// message `i` is the bytes 00 01 .. up to length `i`
+    const SIPHASH_VECTORS: [u64; 64] = [
+        0x726fdb47dd0e0e31, 0x74f839c593dc67fd, 0x0d6c8009d9a94f5a,
+        0x85676696d7fb7e2d, 0xcf2794e0277187b7, 0x18765564cd99a68d,
+        0xcbc9466e58fee3ce, 0xab0200f58b01d137, 0x93f5f5799a932462,
+        0x9e0082df0ba9e4b0, 0x7a5dbbc594ddb9f3, 0xf4b32f46226bada7,
+        0x751e8fbc860ee5fb, 0x14ea5627c0843d90, 0xf723ca908e7af2ee,
+        0xa129ca6149be45e5, 0x3f2acc7f57c29bdb, 0x699ae9f52cbe4794,
+        0x4bc1b3f0968dd39c, 0xbb6dc91da77961bd, 0xbed65cf21aa2ee98,
+        0xd0f2cbb02e3b67c7, 0x93536795e3a33e88, 0xa80c038ccd5ccec8,
+        0xb8ad50c6f649af94, 0xbce192de8a85b8ea, 0x17d835b85bbb15f3,
+        0x2f2e6163076bcfad, 0xde4daaaca71dc9a5, 0xa6a2506687956571,
+        0xad87a3535c49ef28, 0x32d892fad841c342, 0x7127512f72f27cce,
+        0xa7f32346f95978e3, 0x12e0b01abb051238, 0x15e034d40fa197ae,
+        0x314dffbe0815a3b4, 0x027990f029623981, 0xcadcd4e59ef40c4d,
+        0x9abfd8766a33735c, 0x0e3ea96b5304a7d0, 0xad0c42d6fc585992,
+        0x187306c89bc215a9, 0xd4a60abcf3792b95, 0xf935451de4f21df2,
+        0xa9538f0419755787, 0xdb9acddff56ca510, 0xd06c98cd5c0975eb,
+        0xe612a3cb9ecba951, 0xc766e62cfcadaf96, 0xee64435a9752fe72,
+        0xa192d576b245165a, 0x0a8787bf8ecb74b2, 0x81b3e73d20b49b6f,
+        0x7fa8220ba3b2ecea, 0x245731c13ca42499, 0xb78dbfaf3a8d83bd,
+        0xea1ad565322a1a0b, 0x60e61c23a3795013, 0x6606d7e446282b93,
+        0x6ca4ecb15c5f91e1, 0x9f626da15c9625f3, 0xe51b38608ef25f57,
+        0x958a324ceb064572,
+    ];
+
+    #[test]
+    fn siphash_vectors() {
+        let key = (0..16).collect::<Vec<u8>>();
+        let (k0, k1) = (u64_from_le(&key[0..8]), u64_from_le(&key[8..16]));
+        let message = (0..64).collect::<Vec<u8>>();
+        for (length, &expected) in SIPHASH_VECTORS.iter().enumerate() {
+            assert_eq!(siphash(k0, k1, &message[..length]), expected, "length {}", length);
+        }
+    }
+}
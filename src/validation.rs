@@ -0,0 +1,354 @@
+use std::collections::HashSet;
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::blockdata::blockchain::Blockchain;
+use bitcoin::blockdata::constants::{max_target, DIFFCHANGE_INTERVAL, DIFFCHANGE_TIMESPAN};
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::network::constants::Network;
+use bitcoin::network::serialize::BitcoinHash;
+use bitcoin::util::hash::Sha256dHash;
+use bitcoin::util::uint::Uint256;
+
+use merkle::merkle_root;
+use witness::WitnessBlock;
+
+pub const MAX_BLOCK_WEIGHT: usize = 4000000;
+/// A transaction weighs at least 60, which bounds the number of
+/// transactions a block can have
+pub const MAX_BLOCK_TXS: usize = MAX_BLOCK_WEIGHT / 60;
+pub const MAX_MONEY: u64 = 21000000 * 100000000;
+/// How far ahead of our clock a header's timestamp may be
+pub const MAX_FUTURE_BLOCK_TIME: u32 = 2 * 60 * 60;
+/// Number of previous blocks whose median time a header must exceed
+const MEDIAN_TIME_SPAN: usize = 11;
+/// Seconds the difficulty aims for between blocks
+const TARGET_SPACING: u32 = 10 * 60;
+
+/// First block required to carry its height in the coinbase (BIP34)
+fn bip34_height(network: Network) -> u32 {
+    match network {
+        Network::Bitcoin => 227931,
+        Network::Testnet => 21111,
+    }
+}
+
+/// Known-good blocks, as (height, block hash), from Bitcoin Core
+fn checkpoints(network: Network) -> Vec<(u32, &'static str)> {
+    match network {
+        Network::Bitcoin => vec![
+            (11111, "0000000069e244f73d78e8fd29ba2fd2ed618bd6fa2ee92559f542fdb26e7c1d"),
+            (33333, "000000002dd5588a74784eaa7ab0507a18ad16a236e7b1ce69f00d7ddfb5d0a6"),
+            (74000, "0000000000573993a3c9e41ce34471c079dcf5f52a0e824a81e7f953b8661a20"),
+            (105000, "00000000000291ce28027faea320c8d2b054b2e0fe44a773f3eefb151d6bdc97"),
+            (134444, "00000000000005b12ffd4cd315cd34ffd4a594f430ac814c91184a0d42d2b0fe"),
+            (168000, "000000000000099e61ea72015e79632f216fe6cb33d7899acb35b75c8303b763"),
+            (193000, "000000000000059f452a5f7340de6682a977387c17010ff6e6c3bd83ca8b1317"),
+            (210000, "000000000000048b95347e83192f69cf0366076336c639f9b7228e9ba171342e"),
+            (216116, "00000000000001b4f4b433e81ee46494af945cf96014816a4e2370f11b23df4e"),
+            (225430, "00000000000001c108384350f74090433e7fcf79a606b8e797f065b130575932"),
+            (250000, "000000000000003887df1f29024b06fc2200b55f8af8f35453d7be294df2d214"),
+            (279000, "0000000000000001ae8c72a0b0c301f67e3afca10e819efa9041e458e9bd7e40"),
+            (295000, "00000000000000004d9b4ef50f0f9d686fd69db2e03af35a100370c64632a983"),
+        ],
+        Network::Testnet => vec![
+            (546, "000000002a936ca763904c3c35fce2f3556c559c0214345d31b1bcebf76acb70"),
+        ],
+    }
+}
+
+/// Check a header against the chain it extends: proof of work, difficulty
+/// retargeting, timestamp rules and checkpoints. Headers whose parent we
+/// don't know are left for the blockchain to reject.
+pub fn check_header(blockchain: &Blockchain, network: Network, header: &BlockHeader,
+                    now: u32) -> Result<(), String> {
+    let hash = header.bitcoin_hash();
+    let target = header.target();
+    if target.bits() == 0 || target > max_target(network) {
+        return Err(format!("Header {:x} has a bad target", hash));
+    }
+    if hash.into_le() > target {
+        return Err(format!("Header {:x} does not meet its target", hash));
+    }
+
+    let (height, parent) = match blockchain.get_block(header.prev_blockhash) {
+        Some(block_node_ref) => (block_node_ref.height + 1, block_node_ref.block.header),
+        None => return Ok(()),
+    };
+
+    // only retargets, and testnet's minimum difficulty rule, need to look
+    // further back than the parent
+    let expected_bits = if height % DIFFCHANGE_INTERVAL != 0 {
+        match network {
+            Network::Bitcoin => parent.bits,
+            Network::Testnet => testnet_bits(blockchain, header, &parent),
+        }
+    } else {
+        let first = match blockchain.rev_iter(header.prev_blockhash)
+            .nth(DIFFCHANGE_INTERVAL as usize - 1) {
+                Some(block_node_ref) => block_node_ref.block.header,
+                None => return Err(format!("Missing headers before retarget at {}", height)),
+            };
+        retarget(network, &parent, &first)
+    };
+    if header.bits != expected_bits {
+        return Err(format!("Header {:x} has bits {:x}, expected {:x}",
+                           hash, header.bits, expected_bits));
+    }
+
+    let mut times = blockchain.rev_iter(header.prev_blockhash)
+        .take(MEDIAN_TIME_SPAN)
+        .map(|block_node_ref| block_node_ref.block.header.time)
+        .collect::<Vec<u32>>();
+    times.sort();
+    let median_time_past = times[times.len() / 2];
+    if header.time <= median_time_past {
+        return Err(format!("Header {:x} is not after median time past", hash));
+    }
+    if header.time > now + MAX_FUTURE_BLOCK_TIME {
+        return Err(format!("Header {:x} is too far in the future", hash));
+    }
+
+    // a header at or below a checkpoint we have must be on its chain. Those
+    // above the last one descend from it, since their ancestor at its
+    // height was checked against it.
+    for (checkpoint_height, checkpoint_hash) in checkpoints(network) {
+        if height > checkpoint_height {
+            continue;
+        }
+        if height == checkpoint_height && hash.be_hex_string() != checkpoint_hash {
+            return Err(format!("Header {:x} conflicts with checkpoint at {}",
+                               hash, height));
+        }
+        let checkpoint = Sha256dHash::from_hex(checkpoint_hash).unwrap();
+        if blockchain.get_block(checkpoint).is_none() {
+            // not synced this far yet, and so neither past later ones
+            break;
+        }
+        let ancestor = blockchain.rev_iter(checkpoint)
+            .nth((checkpoint_height - height) as usize)
+            .map(|block_node_ref| block_node_ref.block.bitcoin_hash());
+        if ancestor != Some(hash) {
+            return Err(format!("Header {:x} forks from the chain before the checkpoint at {}",
+                               hash, checkpoint_height));
+        }
+        break;
+    }
+    Ok(())
+}
+
+/// Bits expected of a testnet header off a retarget height. Testnet allows
+/// a block at the minimum difficulty once none has been found for twice
+/// the target spacing, and then expects the bits of the last block that
+/// wasn't, or of the first of the period.
+fn testnet_bits(blockchain: &Blockchain, header: &BlockHeader, parent: &BlockHeader) -> u32 {
+    let pow_limit = compact_target(&max_target(Network::Testnet));
+    if header.time > parent.time + 2 * TARGET_SPACING {
+        return pow_limit;
+    }
+    blockchain.rev_iter(header.prev_blockhash)
+        .find(|block_node_ref| {
+            block_node_ref.height % DIFFCHANGE_INTERVAL == 0 ||
+                block_node_ref.block.header.bits != pow_limit
+        })
+        .map_or(pow_limit, |block_node_ref| block_node_ref.block.header.bits)
+}
+
+/// Compact target for the block following `last`, where `first` is the
+/// first block of the period ending with `last`
+fn retarget(network: Network, last: &BlockHeader, first: &BlockHeader) -> u32 {
+    let mut timespan = last.time.saturating_sub(first.time);
+    if timespan < DIFFCHANGE_TIMESPAN / 4 {
+        timespan = DIFFCHANGE_TIMESPAN / 4;
+    }
+    if timespan > DIFFCHANGE_TIMESPAN * 4 {
+        timespan = DIFFCHANGE_TIMESPAN * 4;
+    }
+    let mut target = last.target().mul_u32(timespan) /
+        Uint256::from_u64(DIFFCHANGE_TIMESPAN as u64).unwrap();
+    if target > max_target(network) {
+        target = max_target(network);
+    }
+    compact_target(&target)
+}
+
+/// Encode a target in the compact form used by a header's `bits`
+fn compact_target(target: &Uint256) -> u32 {
+    let mut size = (target.bits() + 7) / 8;
+    let mut compact = if size <= 3 {
+        target.low_u64() << (8 * (3 - size))
+    } else {
+        (*target >> (8 * (size - 3))).low_u64()
+    };
+    // the mantissa is signed, so keep its top bit clear
+    if compact & 0x00800000 != 0 {
+        compact >>= 8;
+        size += 1;
+    }
+    (compact as u32) | (size as u32) << 24
+}
+
+/// Context-free checks of a block's structure, plus the coinbase height
+/// check when the block's height is known. Returns why the block is
+/// invalid on failure.
+pub fn check_block(block: &WitnessBlock, network: Network, height: Option<u32>)
+                   -> Result<(), String> {
+    if block.txdata.is_empty() {
+        return Err("Block has no transactions".to_string());
+    }
+
+    // merkle root, and duplicate txids which could collide with it
+    let txids = block.txdata.iter()
+        .map(|wtx| wtx.txid())
+        .collect::<Vec<Sha256dHash>>();
+    if merkle_root(&txids[..]) != block.header.merkle_root {
+        return Err("Merkle root does not match header".to_string());
+    }
+    let mut unique_txids: HashSet<Sha256dHash> = HashSet::new();
+    for txid in txids.iter() {
+        if !unique_txids.insert(*txid) {
+            return Err(format!("Duplicate transaction {}", txid.be_hex_string()));
+        }
+    }
+
+    // the first transaction, and only the first, must be a coinbase
+    if !is_coinbase(&block.txdata[0].tx) {
+        return Err("First transaction is not a coinbase".to_string());
+    }
+    if block.txdata[1..].iter().any(|wtx| is_coinbase(&wtx.tx)) {
+        return Err("More than one coinbase".to_string());
+    }
+    let coinbase_script = block.txdata[0].tx.input[0].script_sig.clone().into_vec();
+    if coinbase_script.len() < 2 || coinbase_script.len() > 100 {
+        return Err(format!("Bad coinbase script length {}", coinbase_script.len()));
+    }
+    if let Some(height) = height {
+        if height >= bip34_height(network) && block.header.version >= 2 {
+            let expected = height_push(height);
+            if !coinbase_script.starts_with(&expected[..]) {
+                return Err(format!("Coinbase does not commit to height {}", height));
+            }
+        }
+    }
+
+    for wtx in block.txdata.iter() {
+        try!(check_transaction(&wtx.tx));
+    }
+
+    let weight = block.weight();
+    if weight > MAX_BLOCK_WEIGHT {
+        return Err(format!("Block weight {} exceeds limit", weight));
+    }
+
+    block.check_witness_commitment()
+}
+
+/// Checks a transaction's structure independently of its inputs' values
+pub fn check_transaction(tx: &Transaction) -> Result<(), String> {
+    let txid = tx.bitcoin_hash().be_hex_string();
+    if tx.input.is_empty() {
+        return Err(format!("Transaction {} has no inputs", txid));
+    }
+    if tx.output.is_empty() {
+        return Err(format!("Transaction {} has no outputs", txid));
+    }
+
+    let mut total: u64 = 0;
+    for output in tx.output.iter() {
+        total += output.value;
+        if output.value > MAX_MONEY || total > MAX_MONEY {
+            return Err(format!("Transaction {} output value out of range", txid));
+        }
+    }
+
+    if !is_coinbase(tx) {
+        let mut prevouts: HashSet<(Sha256dHash, u32)> = HashSet::new();
+        for input in tx.input.iter() {
+            if input.prev_hash == Default::default() && input.prev_index == 0xffffffff {
+                return Err(format!("Transaction {} spends a null prevout", txid));
+            }
+            if !prevouts.insert((input.prev_hash, input.prev_index)) {
+                return Err(format!("Transaction {} spends an output twice", txid));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn is_coinbase(tx: &Transaction) -> bool {
+    tx.input.len() == 1 &&
+        tx.input[0].prev_hash == Default::default() &&
+        tx.input[0].prev_index == 0xffffffff
+}
+
+/// The script push of a block height required by BIP34
+fn height_push(height: u32) -> Vec<u8> {
+    let mut num = vec![];
+    let mut n = height;
+    while n > 0 {
+        num.push((n & 0xff) as u8);
+        n >>= 8;
+    }
+    // keep the number positive
+    if num.last().map_or(false, |&byte| byte & 0x80 != 0) {
+        num.push(0);
+    }
+    let mut push = vec![num.len() as u8];
+    push.extend(num);
+    push
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::block::BlockHeader;
+    use bitcoin::blockdata::constants::{genesis_block, max_target};
+    use bitcoin::network::constants::Network;
+
+    use super::{compact_target, height_push, retarget};
+
+    fn header(time: u32, bits: u32) -> BlockHeader {
+        let mut header = genesis_block(Network::Bitcoin).header;
+        header.time = time;
+        header.bits = bits;
+        header
+    }
+
+    /// Bitcoin Core's retarget cases as (time of the first block of the
+    /// period, time and bits of its last block, expected bits)
+    const RETARGETS: [(u32, u32, u32, u32); 4] = [
+        // blocks 30240 to 32255
+        (1261130161, 1262152739, 0x1d00ffff, 0x1d00d86a),
+        // blocks 0 to 2015, held at the proof of work limit
+        (1231006505, 1233061996, 0x1d00ffff, 0x1d00ffff),
+        // blocks 66528 to 68543, clamped to a quarter of the timespan
+        (1279008237, 1279297671, 0x1c05a3f4, 0x1c0168fd),
+        // blocks 46368 to 48383, clamped to four times the timespan
+        (1263163443, 1269211443, 0x1c387f6f, 0x1d00e1fd),
+    ];
+
+    #[test]
+    fn retargets() {
+        for &(first_time, last_time, last_bits, expected) in RETARGETS.iter() {
+            let first = header(first_time, last_bits);
+            let last = header(last_time, last_bits);
+            assert_eq!(retarget(Network::Bitcoin, &last, &first), expected,
+                       "{:x} over {}s", last_bits, last_time - first_time);
+        }
+    }
+
+    #[test]
+    fn compact_target_round_trip() {
+        for &bits in [0x1d00ffff, 0x1c05a3f4, 0x1b0404cb, 0x17034267, 0x01120000, 0x02008000,
+                      0x05009234, 0x20123456].iter() {
+            assert_eq!(compact_target(&header(0, bits).target()), bits, "{:x}", bits);
+        }
+        assert_eq!(compact_target(&max_target(Network::Bitcoin)), 0x1d00ffff);
+    }
+
+    #[test]
+    fn height_pushes() {
+        assert_eq!(height_push(1), vec![0x01, 0x01]);
+        assert_eq!(height_push(0x80), vec![0x02, 0x80, 0x00]);
+        // mainnet's first BIP34 block
+        assert_eq!(height_push(227931), vec![0x03, 0x5b, 0x7a, 0x03]);
+    }
+}
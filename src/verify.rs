@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+
+use bitcoin::blockdata::block::Block;
+use bitcoin::blockdata::blockchain::Blockchain;
+use bitcoin::blockdata::transaction::TxOut;
+use bitcoin::network::serialize::{BitcoinHash, serialize};
+use bitcoin::util::hash::Sha256dHash;
+
+use mempool::Mempool;
+use validation::is_coinbase;
+use witness::{WitnessBlock, WitnessTx};
+
+/// Outcome of verifying one input's script
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InputStatus {
+    Verified,
+    /// Verification is off, unsupported, or the prevout couldn't be found
+    Unverified,
+    Failed,
+}
+
+impl InputStatus {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            InputStatus::Verified => "verified",
+            InputStatus::Unverified => "unverified",
+            InputStatus::Failed => "failed",
+        }
+    }
+}
+
+/// Anything able to look up the output an input spends
+pub trait UtxoSource {
+    fn prevout(&self, txid: &Sha256dHash, index: u32) -> Option<TxOut>;
+}
+
+/// Locates transactions within the blocks whose txdata the blockchain
+/// still holds, i.e. the retention window
+pub struct TxIndex {
+    txs: HashMap<Sha256dHash, (Sha256dHash, usize)>,
+}
+
+impl TxIndex {
+    pub fn new() -> TxIndex {
+        TxIndex {
+            txs: HashMap::new(),
+        }
+    }
+
+    pub fn add_block(&mut self, block: &Block) {
+        let block_hash = block.bitcoin_hash();
+        for (i, tx) in block.txdata.iter().enumerate() {
+            self.txs.insert(tx.bitcoin_hash(), (block_hash, i));
+        }
+    }
+
+    pub fn remove_block(&mut self, block: &Block) {
+        for tx in block.txdata.iter() {
+            self.txs.remove(&tx.bitcoin_hash());
+        }
+    }
+
+    /// Block hash and position of a retained transaction
+    pub fn get(&self, txid: &Sha256dHash) -> Option<(Sha256dHash, usize)> {
+        self.txs.get(txid).map(|&location| location)
+    }
+}
+
+/// Prevouts from retained blocks, then unconfirmed transactions, then an
+/// optional external source
+pub struct RetainedOutputs<'a> {
+    pub index: &'a TxIndex,
+    pub blockchain: &'a Blockchain,
+    pub mempool: &'a Mempool,
+    pub fallback: Option<&'a Box<UtxoSource + Send>>,
+}
+
+impl<'a> UtxoSource for RetainedOutputs<'a> {
+    fn prevout(&self, txid: &Sha256dHash, index: u32) -> Option<TxOut> {
+        if let Some((block_hash, position)) = self.index.get(txid) {
+            if let Some(block_node_ref) = self.blockchain.get_block(block_hash) {
+                return block_node_ref.block.txdata.get(position)
+                    .and_then(|tx| tx.output.get(index as usize))
+                    .map(|output| output.clone());
+            }
+        }
+        if let Some(entry) = self.mempool.get(txid) {
+            return entry.tx.tx.output.get(index as usize).map(|output| output.clone());
+        }
+        match self.fallback {
+            Some(source) => source.prevout(txid, index),
+            None => None,
+        }
+    }
+}
+
+/// Verify the scripts of every input in a block, returning statuses per
+/// transaction, per input
+pub fn verify_block(block: &WitnessBlock, source: &UtxoSource) -> Vec<Vec<InputStatus>> {
+    block.txdata.iter()
+        .map(|wtx| verify_tx(wtx, source))
+        .collect()
+}
+
+pub fn verify_tx(wtx: &WitnessTx, source: &UtxoSource) -> Vec<InputStatus> {
+    if is_coinbase(&wtx.tx) {
+        return vec![InputStatus::Unverified];
+    }
+    let tx_bytes = serialize(wtx).unwrap();
+    wtx.tx.input.iter().enumerate()
+        .map(|(i, input)| {
+            match source.prevout(&input.prev_hash, input.prev_index) {
+                // without every spent output libbitcoinconsensus can't check
+                // taproot, and would pass witness v1+ spends unchecked
+                Some(ref prevout)
+                    if is_future_witness_program(&prevout.script_pubkey.clone().into_vec()) =>
+                    InputStatus::Unverified,
+                Some(prevout) => verify_input(&prevout, &tx_bytes[..], i),
+                None => InputStatus::Unverified,
+            }
+        })
+        .collect()
+}
+
+/// Whether a script is a witness program of version 1 (taproot) or later,
+/// i.e. OP_1 to OP_16 followed by a single push of 2 to 40 bytes
+fn is_future_witness_program(script: &[u8]) -> bool {
+    script.len() >= 4 && script.len() <= 42 &&
+        script[0] >= 0x51 && script[0] <= 0x60 &&
+        script[1] as usize == script.len() - 2
+}
+
+#[cfg(feature = "libbitcoinconsensus")]
+mod ffi {
+    pub const VERIFY_P2SH: u32 = 1 << 0;
+    pub const VERIFY_DERSIG: u32 = 1 << 2;
+    pub const VERIFY_NULLDUMMY: u32 = 1 << 4;
+    pub const VERIFY_CHECKLOCKTIMEVERIFY: u32 = 1 << 9;
+    pub const VERIFY_CHECKSEQUENCEVERIFY: u32 = 1 << 10;
+    pub const VERIFY_WITNESS: u32 = 1 << 11;
+
+    #[link(name = "bitcoinconsensus")]
+    extern "C" {
+        pub fn bitcoinconsensus_verify_script_with_amount(
+            script_pubkey: *const u8, script_pubkey_len: u32, amount: i64,
+            tx_to: *const u8, tx_to_len: u32, n_in: u32, flags: u32,
+            err: *mut i32) -> i32;
+    }
+}
+
+#[cfg(feature = "libbitcoinconsensus")]
+fn verify_input(prevout: &TxOut, tx_bytes: &[u8], n_in: usize) -> InputStatus {
+    let script_pubkey = prevout.script_pubkey.clone().into_vec();
+    let flags = ffi::VERIFY_P2SH | ffi::VERIFY_DERSIG | ffi::VERIFY_NULLDUMMY |
+        ffi::VERIFY_CHECKLOCKTIMEVERIFY | ffi::VERIFY_CHECKSEQUENCEVERIFY |
+        ffi::VERIFY_WITNESS;
+    let mut err: i32 = 0;
+    let ok = unsafe {
+        ffi::bitcoinconsensus_verify_script_with_amount(
+            script_pubkey.as_ptr(), script_pubkey.len() as u32, prevout.value as i64,
+            tx_bytes.as_ptr(), tx_bytes.len() as u32, n_in as u32, flags, &mut err)
+    };
+    if ok == 1 {
+        InputStatus::Verified
+    } else if err != 0 {
+        // the library couldn't parse what we gave it, which says nothing
+        // about the script itself
+        InputStatus::Unverified
+    } else {
+        InputStatus::Failed
+    }
+}
+
+#[cfg(not(feature = "libbitcoinconsensus"))]
+fn verify_input(_prevout: &TxOut, _tx_bytes: &[u8], _n_in: usize) -> InputStatus {
+    InputStatus::Unverified
+}
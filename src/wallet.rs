@@ -0,0 +1,705 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+
+use rustc_serialize::hex::{FromHex, ToHex};
+use rustc_serialize::json::{self, Json};
+use secp256k1::Secp256k1;
+use secp256k1::key::PublicKey;
+
+use bitcoin::blockdata::blockchain::Blockchain;
+use bitcoin::network::constants::Network;
+use bitcoin::util::base58::FromBase58;
+use bitcoin::util::bip32::{ChildNumber, ExtendedPubKey};
+use bitcoin::util::hash::Sha256dHash;
+
+use address::{AddressType, address_from_script};
+use mempool::Mempool;
+use rpc::{object, confirmations};
+use store::{BlockStore, write_atomically};
+use util::script_from_address;
+use validation::is_coinbase;
+use witness::{WitnessBlock, WitnessTx};
+
+/// Unused addresses kept derived past the last used one of a ranged
+/// descriptor
+const GAP_LIMIT: u32 = 20;
+/// Confirmations before a coinbase output can be spent
+pub const COINBASE_MATURITY: i64 = 100;
+/// Characters a descriptor may have, in the order that gives their
+/// checksum symbols (BIP380)
+const DESCRIPTOR_CHARSET: &'static str = "0123456789()[],'/*abcdefgh@:$%{}\
+                                          IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~\
+                                          ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const CHECKSUM_CHARSET: &'static [u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+/// What a descriptor pays to
+enum Target {
+    Key(AddressType, KeySource),
+    Script(Vec<u8>),
+}
+
+enum KeySource {
+    Single(PublicKey),
+    /// Keys derived from an xpub: one branch per alternative of a `<a;b>`
+    /// step, each either a single key or, when the path ends in `*`, a
+    /// range of children
+    Xpub { branches: Vec<ExtendedPubKey>, ranged: bool },
+}
+
+struct WalletDescriptor {
+    /// As given, without the checksum
+    text: String,
+    target: Target,
+    /// Number of children derived per branch of a ranged descriptor
+    derived: Vec<u32>,
+}
+
+#[derive(Clone)]
+pub struct Utxo {
+    pub txid: Sha256dHash,
+    pub vout: u32,
+    pub value: u64,
+    pub script: Vec<u8>,
+    /// Hash and height of the confirming block, or None if unconfirmed
+    pub block: Option<(Sha256dHash, u32)>,
+    pub coinbase: bool,
+    /// The descriptor whose script the output pays
+    pub descriptor: String,
+}
+
+/// Balances in satoshis
+pub struct Balances {
+    /// Confirmed, mature and not spent in the mempool
+    pub confirmed: u64,
+    /// Paid by mempool transactions and not spent by others
+    pub unconfirmed: u64,
+    /// Coinbase outputs with fewer than COINBASE_MATURITY confirmations
+    pub immature: u64,
+}
+
+/// A watch-only wallet over the retention window: it knows the outputs
+/// paying its descriptors that were created in retained blocks or are in
+/// the mempool, and which of them are still unspent
+pub struct Wallet {
+    descriptors: Vec<WalletDescriptor>,
+    /// Every derived script, with its descriptor, branch and child index
+    index: HashMap<Vec<u8>, (usize, usize, u32)>,
+    /// Unspent outputs created in retained blocks
+    utxos: HashMap<(Sha256dHash, u32), Utxo>,
+    path: Option<String>,
+    secp: Secp256k1,
+}
+
+impl Wallet {
+    pub fn new() -> Wallet {
+        Wallet {
+            descriptors: vec![],
+            index: HashMap::new(),
+            utxos: HashMap::new(),
+            path: None,
+            secp: Secp256k1::new(),
+        }
+    }
+
+    /// Load the descriptors kept at `path`, which is created on the first
+    /// import if it doesn't exist
+    pub fn load(path: &str) -> Result<Wallet, String> {
+        let mut wallet = Wallet::new();
+        wallet.path = Some(path.to_string());
+        let mut data = String::new();
+        match File::open(path).map(|mut file| file.read_to_string(&mut data)) {
+            Ok(Ok(_)) => (),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(wallet),
+            Ok(Err(e)) | Err(e) => return Err(format!("Reading {}: {:?}", path, e)),
+        }
+        let descriptors = match Json::from_str(&data).ok()
+            .and_then(|json| json.find("descriptors").and_then(|d| d.as_array()).cloned()) {
+                Some(descriptors) => descriptors,
+                None => return Err(format!("{} must have a descriptors array", path)),
+            };
+        for descriptor in descriptors.iter() {
+            match descriptor.as_string() {
+                Some(descriptor) => { try!(wallet.add_descriptor(descriptor)); },
+                None => return Err(format!("Descriptors in {} must be strings", path)),
+            }
+        }
+        Ok(wallet)
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        match self.path {
+            Some(ref path) => {
+                let data = json::encode(&object(vec![("descriptors", self.descriptors_json())]));
+                write_atomically(path, data.unwrap().as_bytes())
+            },
+            None => Ok(()),
+        }
+    }
+
+    pub fn descriptors(&self) -> Vec<&str> {
+        self.descriptors.iter().map(|descriptor| descriptor.text.as_str()).collect()
+    }
+
+    pub fn descriptors_json(&self) -> Json {
+        Json::Array(self.descriptors().iter()
+                    .map(|text| Json::String(text.to_string()))
+                    .collect())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.descriptors.is_empty()
+    }
+
+    /// Watch an output descriptor: `pkh`, `sh(wpkh)`, `wpkh` or `tr` of a
+    /// hex public key or of an xpub with a derivation path, `addr` or
+    /// `raw`. A bare xpub stands for `pkh(<xpub>/<0;1>/*)`. A checksum after
+    /// `#` is optional, but must match. Returns false if it was already
+    /// watched.
+    pub fn add_descriptor(&mut self, descriptor: &str) -> Result<bool, String> {
+        let mut parts = descriptor.splitn(2, '#');
+        let text = parts.next().unwrap().trim().to_string();
+        if let Some(checksum) = parts.next() {
+            match descriptor_checksum(&text) {
+                Some(ref expected) if expected == checksum.trim() => (),
+                Some(expected) => return Err(format!("Checksum of {} is {}, not {}",
+                                                     text, expected, checksum.trim())),
+                None => return Err(format!("Invalid characters in descriptor {}", text)),
+            }
+        }
+        if self.descriptors.iter().any(|watched| watched.text == text) {
+            return Ok(false);
+        }
+        let target = if text.starts_with("xpub") || text.starts_with("tpub") {
+            Target::Key(AddressType::P2pkh,
+                        try!(parse_key(&self.secp, &format!("{}/<0;1>/*", text))))
+        } else {
+            try!(parse_descriptor(&self.secp, &text))
+        };
+        let branches = match target {
+            Target::Key(_, KeySource::Xpub { ref branches, .. }) => branches.len(),
+            _ => 1,
+        };
+        self.descriptors.push(WalletDescriptor {
+            text: text,
+            target: target,
+            derived: vec![0; branches],
+        });
+        let i = self.descriptors.len() - 1;
+        for branch in 0..branches {
+            if let Err(e) = self.derive(i, branch, GAP_LIMIT) {
+                self.descriptors.pop();
+                self.index.retain(|_, &mut (descriptor, _, _)| descriptor != i);
+                return Err(e);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Derive and index a descriptor's scripts on a branch up to `count`
+    fn derive(&mut self, i: usize, branch: usize, count: u32) -> Result<(), String> {
+        let descriptor = &mut self.descriptors[i];
+        let from = descriptor.derived[branch];
+        if from >= count {
+            return Ok(());
+        }
+        match descriptor.target {
+            Target::Script(ref script) => {
+                self.index.insert(script.clone(), (i, 0, 0));
+            },
+            Target::Key(address_type, KeySource::Single(ref key)) => {
+                self.index.insert(address_type.script_pubkey(&self.secp, key), (i, 0, 0));
+            },
+            Target::Key(address_type, KeySource::Xpub { ref branches, ranged }) => {
+                if !ranged {
+                    let key = &branches[branch].public_key;
+                    self.index.insert(address_type.script_pubkey(&self.secp, key), (i, branch, 0));
+                } else {
+                    for index in from..count {
+                        let child = ChildNumber::Normal(index);
+                        let key = match branches[branch].ckd_pub(&self.secp, child) {
+                            Ok(key) => key,
+                            Err(e) => return Err(format!("Deriving {}: {:?}",
+                                                         descriptor.text, e)),
+                        };
+                        let script = address_type.script_pubkey(&self.secp, &key.public_key);
+                        self.index.insert(script, (i, branch, index));
+                    }
+                }
+            },
+        }
+        descriptor.derived[branch] = count;
+        Ok(())
+    }
+
+    /// Keep GAP_LIMIT scripts derived past the first `used` children on a
+    /// branch of a descriptor, as when addresses are handed out from it
+    pub fn extend(&mut self, descriptor: &str, branch: usize, used: u32) -> Result<(), String> {
+        match self.descriptors.iter().position(|watched| watched.text == descriptor) {
+            Some(i) if branch < self.descriptors[i].derived.len() => {
+                self.derive(i, branch, used + GAP_LIMIT)
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// The descriptor paid by a script, deriving further ahead if the
+    /// script is near the end of what's derived
+    fn match_script(&mut self, script: &[u8]) -> Option<String> {
+        let (i, branch, index) = match self.index.get(script) {
+            Some(&location) => location,
+            None => return None,
+        };
+        if let Err(e) = self.derive(i, branch, index + 1 + GAP_LIMIT) {
+            error!("wallet", descriptor = self.descriptors[i].text; "Could not derive: {}", e);
+        }
+        Some(self.descriptors[i].text.clone())
+    }
+
+    /// Forget the outputs found so far and find them again in the retained
+    /// blocks still on the best chain
+    pub fn rescan(&mut self, blockchain: &Blockchain, block_store: &BlockStore,
+                  retained: &VecDeque<Sha256dHash>) {
+        self.utxos.clear();
+        if self.is_empty() {
+            return;
+        }
+        for hash in retained.iter() {
+            if confirmations(blockchain, hash) < 0 {
+                continue;
+            }
+            let height = blockchain.get_block(*hash)
+                .map_or(0, |block_node_ref| block_node_ref.height);
+            if let Some(block) = block_store.get(hash) {
+                self.add_block(&block, *hash, height);
+            }
+        }
+    }
+
+    /// Take in a block newly on the best chain
+    pub fn add_block(&mut self, block: &WitnessBlock, hash: Sha256dHash, height: u32) {
+        if self.is_empty() {
+            return;
+        }
+        for wtx in block.txdata.iter() {
+            for input in wtx.tx.input.iter() {
+                self.utxos.remove(&(input.prev_hash, input.prev_index));
+            }
+            for utxo in self.outputs_of(wtx, Some((hash, height))) {
+                self.utxos.insert((utxo.txid, utxo.vout), utxo);
+            }
+        }
+    }
+
+    /// Forget outputs of blocks that left the retention window
+    pub fn prune(&mut self, first_retained_height: u32) {
+        self.utxos.retain(|_, utxo| match utxo.block {
+            Some((_, height)) => height >= first_retained_height,
+            None => true,
+        });
+    }
+
+    fn outputs_of(&mut self, wtx: &WitnessTx, block: Option<(Sha256dHash, u32)>) -> Vec<Utxo> {
+        let txid = wtx.txid();
+        let coinbase = is_coinbase(&wtx.tx);
+        let mut utxos = vec![];
+        for (vout, output) in wtx.tx.output.iter().enumerate() {
+            let script = output.script_pubkey.clone().into_vec();
+            if let Some(descriptor) = self.match_script(&script[..]) {
+                utxos.push(Utxo {
+                    txid: txid,
+                    vout: vout as u32,
+                    value: output.value,
+                    script: script,
+                    block: block,
+                    coinbase: coinbase,
+                    descriptor: descriptor,
+                });
+            }
+        }
+        utxos
+    }
+
+    /// Unspent outputs, confirmed ones first, taking the mempool's
+    /// transactions into account
+    pub fn unspent(&mut self, mempool: &Mempool) -> Vec<Utxo> {
+        let mut spent: HashSet<(Sha256dHash, u32)> = HashSet::new();
+        let mut unconfirmed = vec![];
+        for entry in mempool.entries() {
+            for input in entry.tx.tx.input.iter() {
+                spent.insert((input.prev_hash, input.prev_index));
+            }
+            if !self.is_empty() {
+                unconfirmed.extend(self.outputs_of(&entry.tx, None));
+            }
+        }
+        let mut confirmed = self.utxos.values()
+            .filter(|utxo| !spent.contains(&(utxo.txid, utxo.vout)))
+            .cloned()
+            .collect::<Vec<Utxo>>();
+        confirmed.sort_by(|a, b| {
+            let height = |utxo: &Utxo| utxo.block.map_or(0, |(_, height)| height);
+            height(a).cmp(&height(b))
+                .then_with(|| a.txid.be_hex_string().cmp(&b.txid.be_hex_string()))
+                .then_with(|| a.vout.cmp(&b.vout))
+        });
+        unconfirmed.retain(|utxo| !spent.contains(&(utxo.txid, utxo.vout)));
+        unconfirmed.sort_by(|a, b| {
+            a.txid.be_hex_string().cmp(&b.txid.be_hex_string()).then_with(|| a.vout.cmp(&b.vout))
+        });
+        confirmed.extend(unconfirmed);
+        confirmed
+    }
+}
+
+pub fn balances(utxos: &[Utxo], blockchain: &Blockchain) -> Balances {
+    let mut balances = Balances { confirmed: 0, unconfirmed: 0, immature: 0 };
+    for utxo in utxos.iter() {
+        match utxo.block {
+            Some((ref hash, _)) if utxo.coinbase &&
+                confirmations(blockchain, hash) < COINBASE_MATURITY => {
+                balances.immature += utxo.value;
+            },
+            Some(_) => balances.confirmed += utxo.value,
+            None => balances.unconfirmed += utxo.value,
+        }
+    }
+    balances
+}
+
+/// The checksum of a descriptor (BIP380), or None if it has characters
+/// descriptors can't
+fn descriptor_checksum(text: &str) -> Option<String> {
+    fn polymod(checksum: u64, value: u64) -> u64 {
+        const GENERATOR: [u64; 5] = [0xf5dee51989, 0xa9fdca3312, 0x1bab10e32d, 0x3706b1677a,
+                                     0x644d626ffd];
+        let top = checksum >> 35;
+        let mut checksum = (checksum & 0x7ffffffff) << 5 ^ value;
+        for (i, &generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+        checksum
+    }
+
+    // each character gives its position's low five bits, and every three
+    // characters one more symbol from their high bits
+    let mut checksum = 1;
+    let mut groups = vec![];
+    for c in text.chars() {
+        let position = match DESCRIPTOR_CHARSET.find(c) {
+            Some(position) => position as u64,
+            None => return None,
+        };
+        checksum = polymod(checksum, position & 31);
+        groups.push(position >> 5);
+        if groups.len() == 3 {
+            checksum = polymod(checksum, groups[0] * 9 + groups[1] * 3 + groups[2]);
+            groups.clear();
+        }
+    }
+    match groups.len() {
+        1 => checksum = polymod(checksum, groups[0]),
+        2 => checksum = polymod(checksum, groups[0] * 3 + groups[1]),
+        _ => (),
+    }
+    for _ in 0..8 {
+        checksum = polymod(checksum, 0);
+    }
+    checksum ^= 1;
+    Some((0..8)
+         .map(|i| CHECKSUM_CHARSET[(checksum >> (5 * (7 - i)) & 31) as usize] as char)
+         .collect())
+}
+
+fn parse_descriptor(secp: &Secp256k1, text: &str) -> Result<Target, String> {
+    let (function, argument) = match (text.find('('), text.ends_with(')')) {
+        (Some(i), true) => (&text[..i], &text[i + 1..text.len() - 1]),
+        _ => return Err(format!("Invalid descriptor {}", text)),
+    };
+    match function {
+        "pkh" => Ok(Target::Key(AddressType::P2pkh, try!(parse_key(secp, argument)))),
+        "wpkh" => Ok(Target::Key(AddressType::P2wpkh, try!(parse_key(secp, argument)))),
+        "tr" => Ok(Target::Key(AddressType::P2tr, try!(parse_key(secp, argument)))),
+        "sh" if argument.starts_with("wpkh(") && argument.ends_with(')') => {
+            let key = &argument["wpkh(".len()..argument.len() - 1];
+            Ok(Target::Key(AddressType::P2shP2wpkh, try!(parse_key(secp, key))))
+        },
+        "addr" => Ok(Target::Script(try!(script_from_address(argument)))),
+        "raw" => match argument.from_hex() {
+            Ok(script) => Ok(Target::Script(script)),
+            Err(_) => Err(format!("Script in {} is not hexadecimal", text)),
+        },
+        _ => Err(format!("Unsupported descriptor {}", text)),
+    }
+}
+
+/// A hex public key, or an xpub followed by unhardened steps, one of which
+/// may be `<a;b>`, and optionally a final `*`. Key origins in brackets are
+/// skipped.
+fn parse_key(secp: &Secp256k1, text: &str) -> Result<KeySource, String> {
+    let text = match text.find(']') {
+        Some(i) if text.starts_with('[') => &text[i + 1..],
+        _ => text,
+    };
+    let mut steps = text.split('/');
+    let key = steps.next().unwrap();
+    if let Ok(bytes) = key.from_hex() {
+        // x-only keys, as tr() takes, are the even-y point with that x
+        let bytes = if bytes.len() == 32 {
+            let mut compressed = vec![2];
+            compressed.extend_from_slice(&bytes[..]);
+            compressed
+        } else {
+            bytes
+        };
+        return match PublicKey::from_slice(secp, &bytes[..]) {
+            Ok(key) if !text.contains('/') => Ok(KeySource::Single(key)),
+            _ => Err(format!("Invalid public key {}", text)),
+        };
+    }
+
+    let xpub: ExtendedPubKey = match FromBase58::from_base58check(key) {
+        Ok(xpub) => xpub,
+        Err(e) => return Err(format!("Invalid key {}: {:?}", key, e)),
+    };
+    let mut branches = vec![xpub];
+    let mut ranged = false;
+    for step in steps {
+        if ranged {
+            return Err(format!("* must be the last step in {}", text));
+        }
+        if step == "*" {
+            ranged = true;
+            continue;
+        }
+        let alternatives = if step.starts_with('<') && step.ends_with('>') {
+            if branches.len() > 1 {
+                return Err(format!("Only one <a;b> step is allowed in {}", text));
+            }
+            step[1..step.len() - 1].split(';').collect::<Vec<&str>>()
+        } else {
+            vec![step]
+        };
+        let mut indexes = vec![];
+        for alternative in alternatives.iter() {
+            match alternative.parse::<u32>() {
+                Ok(index) if index < 1 << 31 => indexes.push(index),
+                _ => return Err(format!("Invalid step {} in {}; xpubs can't derive \
+                                         hardened children", step, text)),
+            }
+        }
+        let mut derived = vec![];
+        for branch in branches.iter() {
+            for &index in indexes.iter() {
+                match branch.ckd_pub(secp, ChildNumber::Normal(index)) {
+                    Ok(key) => derived.push(key),
+                    Err(e) => return Err(format!("Deriving {}: {:?}", text, e)),
+                }
+            }
+        }
+        branches = derived;
+    }
+    Ok(KeySource::Xpub { branches: branches, ranged: ranged })
+}
+
+pub fn utxo_json(utxo: &Utxo, blockchain: &Blockchain, network: Network) -> Json {
+    object(vec![
+        ("txid", Json::String(utxo.txid.be_hex_string())),
+        ("vout", Json::U64(utxo.vout as u64)),
+        ("address", address_from_script(network, &utxo.script[..])
+         .map_or(Json::Null, |address| Json::String(address))),
+        ("script_pubkey", Json::String(utxo.script.to_hex())),
+        ("value", Json::U64(utxo.value)),
+        ("block_hash", utxo.block.map_or(Json::Null, |(hash, _)| {
+            Json::String(hash.be_hex_string())
+        })),
+        ("block_height", utxo.block.map_or(Json::Null, |(_, height)| Json::U64(height as u64))),
+        ("confirmations", Json::I64(utxo.block.map_or(0, |(hash, _)| {
+            confirmations(blockchain, &hash).max(0)
+        }))),
+        ("coinbase", Json::Boolean(utxo.coinbase)),
+        ("descriptor", Json::String(utxo.descriptor.clone())),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
+    use bitcoin::network::constants::Network;
+    use bitcoin::util::hash::Sha256dHash;
+
+    use mempool::Mempool;
+    use util::script_from_address;
+    use witness::{WitnessBlock, WitnessTx};
+
+    use super::{Wallet, GAP_LIMIT, descriptor_checksum};
+
+    /// Account 0 of the BIP44, BIP49, BIP84 and BIP86 wallets of the
+    /// mnemonic "abandon abandon ... about", with their first receiving
+    /// address, first change address and second receiving address
+    const ACCOUNTS: [(&'static str, &'static str, &'static str, &'static str); 4] = [
+        ("pkh(xpub6BosfCnifzxcFwrSzQiqu2DBVTshkCXacvNsWGYJVVhhawA7d4R5\
+          WSWGFNbi8Aw6ZRc1brxMyWMzG3DSSSSoekkudhUd9yLb6qx39T9nMdj/<0;1>/*)#34zak0jj",
+         "1LqBGSKuX5yYUonjxT5qGfpUsXKYYWeabA",
+         "1J3J6EvPrv8q6AC3VCjWV45Uf3nssNMRtH",
+         "1Ak8PffB2meyfYnbXZR9EGfLfFZVpzJvQP"),
+        ("sh(wpkh(xpub6C6nQwHaWbSrzs5tZ1q7m5R9cPK9eYpNMFesiXsYrgc1P8bvLLAe\
+          t9JfHjYXKjToD8cBRswJXXbbFpXgwsswVPAZzKMa1jUp2kVkGVUaJa7/<0;1>/*))#j62klm6f",
+         "37VucYSaXLCAsxYyAPfbSi9eh4iEcbShgf",
+         "34K56kSjgUCUSD8GTtuF7c9Zzwokbs6uZ7",
+         "3LtMnn87fqUeHBUG414p9CWwnoV6E2pNKS"),
+        ("wpkh(xpub6CatWdiZiodmUeTDp8LT5or8nmbKNcuyvz7WyksVFkKB4RHwCD3X\
+          yuvPEbvqAQY3rAPshWcMLoP2fMFMKHPJ4ZeZXYVUhLv1VMrjPC7PW6V/<0;1>/*)#3r0wrtd9",
+         "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu",
+         "bc1q8c6fshw2dlwun7ekn9qwf37cu2rn755upcp6el",
+         "bc1qnjg0jd8228aq7egyzacy8cys3knf9xvrerkf9g"),
+        ("tr(xpub6BgBgsespWvERF3LHQu6CnqdvfEvtMcQjYrcRzx53QJjSxarj2af\
+          YWcLteoGVky7D3UKDP9QyrLprQ3VCECoY49yfdDEHGCtMMj92pReUsQ/<0;1>/*)#fxqmx45h",
+         "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr",
+         "bc1p3qkhfews2uk44qtvauqyr2ttdsw7svhkl9nkm9s9c3x4ax5h60wqwruhk7",
+         "bc1p4qhjn9zdvkux4e44uhx8tc55attvtyu358kutcqkudyccelu0was9fqzwh"),
+    ];
+
+    /// A transaction paying `value` to `address`, told apart from others
+    /// by the output it spends
+    fn payment(n: u32, address: &str, value: u64) -> WitnessTx {
+        WitnessTx::from_tx(Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                prev_hash: Sha256dHash::from_data(&[1]),
+                prev_index: n,
+                script_sig: Script::new(),
+                sequence: 0xffffffff,
+            }],
+            output: vec![TxOut {
+                value: value,
+                script_pubkey: Script::from(script_from_address(address).unwrap()),
+            }],
+        })
+    }
+
+    fn block(txs: Vec<WitnessTx>) -> WitnessBlock {
+        WitnessBlock {
+            header: genesis_block(Network::Bitcoin).header,
+            txdata: txs,
+        }
+    }
+
+    #[test]
+    fn first_addresses() {
+        for &(descriptor, receive, change, second) in ACCOUNTS.iter() {
+            let mut wallet = Wallet::new();
+            assert!(wallet.add_descriptor(descriptor).unwrap());
+            for &(address, location) in [(receive, (0, 0, 0)), (change, (0, 1, 0)),
+                                         (second, (0, 0, 1))].iter() {
+                let script = script_from_address(address).unwrap();
+                assert_eq!(wallet.index.get(&script), Some(&location), "{}", address);
+            }
+            // the checksum is left out of the descriptor kept
+            assert_eq!(wallet.descriptors(), vec![descriptor.split('#').next().unwrap()]);
+            assert!(!wallet.add_descriptor(descriptor).unwrap());
+        }
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(descriptor_checksum("raw(deadbeef)"), Some("89f8spxm".to_string()));
+        assert_eq!(descriptor_checksum("raw(deadbeef)\u{e9}"), None);
+
+        let mut wallet = Wallet::new();
+        assert!(wallet.add_descriptor("raw(deadbeef)#89f8spxm").is_ok());
+        // a wrong checksum, a checksum of another descriptor, and an empty one
+        for descriptor in ["raw(deadbeef)#89f8spxn", "raw(deadbeee)#89f8spxm",
+                           "raw(deadbeef)#"].iter() {
+            assert!(Wallet::new().add_descriptor(descriptor).is_err(), "{}", descriptor);
+        }
+        let (descriptor, _, _, _) = ACCOUNTS[2];
+        let tampered = descriptor.replace("<0;1>", "<1;0>");
+        assert!(Wallet::new().add_descriptor(&tampered).is_err());
+    }
+
+    #[test]
+    fn multipath_steps() {
+        let (descriptor, receive, change, _) = ACCOUNTS[2];
+        let text = descriptor.split('#').next().unwrap();
+        let xpub = &text["wpkh(".len()..text.find('/').unwrap()];
+
+        let mut wallet = Wallet::new();
+        wallet.add_descriptor(text).unwrap();
+        assert_eq!(wallet.descriptors[0].derived, vec![GAP_LIMIT, GAP_LIMIT]);
+        assert_eq!(wallet.index.len(), 2 * GAP_LIMIT as usize);
+
+        // the same keys come from listing each branch on its own
+        for &(branch, address) in [(0, receive), (1, change)].iter() {
+            let mut single = Wallet::new();
+            single.add_descriptor(&format!("wpkh({}/{}/*)", xpub, branch)).unwrap();
+            assert_eq!(single.descriptors[0].derived, vec![GAP_LIMIT]);
+            let script = script_from_address(address).unwrap();
+            assert_eq!(single.index.get(&script), Some(&(0, 0, 0)));
+        }
+
+        // without a range, each branch is a single key
+        let mut unranged = Wallet::new();
+        unranged.add_descriptor(&format!("wpkh({}/<0;1>/0)", xpub)).unwrap();
+        assert_eq!(unranged.index.len(), 2);
+        assert_eq!(unranged.index.get(&script_from_address(receive).unwrap()),
+                   Some(&(0, 0, 0)));
+        assert_eq!(unranged.index.get(&script_from_address(change).unwrap()),
+                   Some(&(0, 1, 0)));
+
+        // a bare xpub watches both branches as P2PKH
+        let (descriptor, receive, change, _) = ACCOUNTS[0];
+        let text = descriptor.split('#').next().unwrap();
+        let mut bare = Wallet::new();
+        bare.add_descriptor(&text["pkh(".len()..text.find('/').unwrap()]).unwrap();
+        assert_eq!(bare.index.get(&script_from_address(receive).unwrap()), Some(&(0, 0, 0)));
+        assert_eq!(bare.index.get(&script_from_address(change).unwrap()), Some(&(0, 1, 0)));
+
+        for bad in ["<0;1>/<0;1>/*", "<0;1>/*/0", "<0;2147483648>/*", "<0;x>/*"].iter() {
+            let descriptor = format!("wpkh({}/{})", xpub, bad);
+            assert!(Wallet::new().add_descriptor(&descriptor).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn derives_ahead_of_used_addresses() {
+        let (descriptor, _, _, _) = ACCOUNTS[2];
+        let mut wallet = Wallet::new();
+        wallet.add_descriptor(descriptor).unwrap();
+        let used = wallet.index.iter()
+            .find(|&(_, &location)| location == (0, 1, 5))
+            .map(|(script, _)| script.clone())
+            .unwrap();
+        assert!(wallet.match_script(&used[..]).is_some());
+        assert_eq!(wallet.descriptors[0].derived, vec![GAP_LIMIT, 5 + 1 + GAP_LIMIT]);
+    }
+
+    #[test]
+    fn prune_forgets_outputs_of_pruned_blocks() {
+        let (descriptor, receive, change, _) = ACCOUNTS[2];
+        let mut wallet = Wallet::new();
+        wallet.add_descriptor(descriptor).unwrap();
+        let (old, new) = (payment(0, receive, 1000), payment(1, change, 2000));
+        wallet.add_block(&block(vec![old.clone()]), Sha256dHash::from_data(&[10]), 10);
+        wallet.add_block(&block(vec![new.clone()]), Sha256dHash::from_data(&[20]), 20);
+        let mut mempool = Mempool::new(10);
+        mempool.insert(payment(2, receive, 3000), None, 0).unwrap();
+
+        let values = |wallet: &mut Wallet, mempool: &Mempool| {
+            wallet.unspent(mempool).iter().map(|utxo| utxo.value).collect::<Vec<u64>>()
+        };
+        assert_eq!(values(&mut wallet, &mempool), vec![1000, 2000, 3000]);
+        // the first retained height is kept
+        wallet.prune(10);
+        assert_eq!(values(&mut wallet, &mempool), vec![1000, 2000, 3000]);
+        wallet.prune(15);
+        assert_eq!(values(&mut wallet, &mempool), vec![2000, 3000]);
+        wallet.prune(21);
+        assert_eq!(values(&mut wallet, &mempool), vec![3000]);
+    }
+}
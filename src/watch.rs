@@ -0,0 +1,623 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::mem;
+use std::sync::mpsc::{Sender, RecvTimeoutError, channel};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rustc_serialize::hex::{FromHex, ToHex};
+use rustc_serialize::json::{self, Json};
+use secp256k1::Secp256k1;
+
+use bitcoin::util::base58::FromBase58;
+use bitcoin::util::bip32::{ChildNumber, ExtendedPubKey};
+use bitcoin::util::hash::Sha256dHash;
+
+use address::AddressType;
+use http;
+use rpc::object;
+use store::write_atomically;
+use util::script_from_address;
+use validation::is_coinbase;
+use verify::UtxoSource;
+use witness::WitnessTx;
+
+/// Unused addresses kept derived past the last used one on each xpub chain
+const XPUB_GAP: u32 = 20;
+/// Deliveries are given up after this many attempts
+const WEBHOOK_ATTEMPTS: u32 = 6;
+/// Delay before the first retry, doubling with each attempt after it
+const RETRY_DELAY: u64 = 5;
+/// Extended public key versions (SLIP-132), with the addresses each stands
+/// for and the xpub or tpub version to parse it as
+const XPUB_VERSIONS: [([u8; 4], AddressType, [u8; 4]); 6] = [
+    ([0x04, 0x88, 0xb2, 0x1e], AddressType::P2pkh, [0x04, 0x88, 0xb2, 0x1e]),
+    ([0x04, 0x9d, 0x7c, 0xb2], AddressType::P2shP2wpkh, [0x04, 0x88, 0xb2, 0x1e]),
+    ([0x04, 0xb2, 0x47, 0x46], AddressType::P2wpkh, [0x04, 0x88, 0xb2, 0x1e]),
+    ([0x04, 0x35, 0x87, 0xcf], AddressType::P2pkh, [0x04, 0x35, 0x87, 0xcf]),
+    ([0x04, 0x4a, 0x52, 0x62], AddressType::P2shP2wpkh, [0x04, 0x35, 0x87, 0xcf]),
+    ([0x04, 0x5f, 0x1c, 0xf6], AddressType::P2wpkh, [0x04, 0x35, 0x87, 0xcf]),
+];
+
+/// Where a watched script came from
+struct Origin {
+    /// The address, script or xpub as it was given
+    watched: String,
+    /// Chain and index of scripts derived from an xpub
+    path: Option<(u32, u32)>,
+}
+
+struct WatchedXpub {
+    /// As given, which may be a ypub or zpub
+    text: String,
+    key: ExtendedPubKey,
+    address_type: AddressType,
+    /// Number of addresses derived on the receive and change chains
+    derived: [u32; 2],
+}
+
+/// Addresses, scripts and xpubs whose payments we alert on, optionally
+/// kept in a JSON file
+pub struct WatchList {
+    addresses: Vec<String>,
+    /// Hex scripts
+    scripts: Vec<String>,
+    xpubs: Vec<WatchedXpub>,
+    /// Every watched output script
+    index: HashMap<Vec<u8>, Origin>,
+    path: Option<String>,
+    secp: Secp256k1,
+}
+
+impl WatchList {
+    pub fn new() -> WatchList {
+        WatchList {
+            addresses: vec![],
+            scripts: vec![],
+            xpubs: vec![],
+            index: HashMap::new(),
+            path: None,
+            secp: Secp256k1::new(),
+        }
+    }
+
+    /// Load the list kept at `path`, which is created on the first change if
+    /// it doesn't exist
+    pub fn load(path: &str) -> Result<WatchList, String> {
+        let mut watch_list = WatchList::new();
+        watch_list.path = Some(path.to_string());
+        let mut data = String::new();
+        match File::open(path).map(|mut file| file.read_to_string(&mut data)) {
+            Ok(Ok(_)) => (),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(watch_list),
+            Ok(Err(e)) | Err(e) => return Err(format!("Reading {}: {:?}", path, e)),
+        }
+        let json = match Json::from_str(&data) {
+            Ok(json) => json,
+            Err(e) => return Err(format!("Parsing {}: {:?}", path, e)),
+        };
+        for address in try!(strings(&json, "addresses", path)) {
+            try!(watch_list.add_address(&address));
+        }
+        for script in try!(strings(&json, "scripts", path)) {
+            try!(watch_list.add_script(&script));
+        }
+        for xpub in try!(strings(&json, "xpubs", path)) {
+            try!(watch_list.add_xpub(&xpub));
+        }
+        info!("wallet", addresses = watch_list.addresses.len(),
+              scripts = watch_list.scripts.len(), xpubs = watch_list.xpubs.len();
+              "Loaded watch list");
+        Ok(watch_list)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Watch an address. Returns false if it was already watched.
+    pub fn add_address(&mut self, address: &str) -> Result<bool, String> {
+        let script = try!(script_from_address(address));
+        if self.addresses.iter().any(|watched| watched == address) {
+            return Ok(false);
+        }
+        self.addresses.push(address.to_string());
+        self.index.insert(script, Origin { watched: address.to_string(), path: None });
+        Ok(true)
+    }
+
+    /// Watch a hex output script. Returns false if it was already watched.
+    pub fn add_script(&mut self, hex: &str) -> Result<bool, String> {
+        let script = match hex.from_hex() {
+            Ok(script) => script,
+            Err(_) => return Err(format!("Script {} is not hexadecimal", hex)),
+        };
+        let hex = script.to_hex();
+        if self.scripts.contains(&hex) {
+            return Ok(false);
+        }
+        self.index.insert(script, Origin { watched: hex.clone(), path: None });
+        self.scripts.push(hex);
+        Ok(true)
+    }
+
+    /// Watch the addresses of an xpub's receive and change chains: P2PKH
+    /// for an xpub or tpub, P2SH-P2WPKH for a ypub or upub, and P2WPKH for a
+    /// zpub or vpub. Returns false if it was already watched.
+    pub fn add_xpub(&mut self, xpub: &str) -> Result<bool, String> {
+        let (key, address_type) = try!(parse_xpub(xpub));
+        if self.xpubs.iter().any(|watched| watched.key == key &&
+                                 watched.address_type == address_type) {
+            return Ok(false);
+        }
+        self.xpubs.push(WatchedXpub {
+            text: xpub.to_string(),
+            key: key,
+            address_type: address_type,
+            derived: [0, 0],
+        });
+        let i = self.xpubs.len() - 1;
+        for chain in 0..2 {
+            try!(self.derive(i, chain, XPUB_GAP));
+        }
+        Ok(true)
+    }
+
+    /// Stop watching an address, script or xpub. Returns false if it wasn't
+    /// watched.
+    pub fn remove(&mut self, watched: &str) -> bool {
+        let count = self.addresses.len() + self.scripts.len() + self.xpubs.len();
+        self.addresses.retain(|address| address != watched);
+        self.scripts.retain(|script| script != watched);
+        self.xpubs.retain(|xpub| xpub.text != watched);
+        self.index.retain(|_, origin| origin.watched != watched);
+        count != self.addresses.len() + self.scripts.len() + self.xpubs.len()
+    }
+
+    /// Write the list back to its file, if it has one
+    pub fn save(&self) -> Result<(), String> {
+        match self.path {
+            Some(ref path) => {
+                let data = json::encode(&self.to_json()).unwrap();
+                write_atomically(path, data.as_bytes())
+            },
+            None => Ok(()),
+        }
+    }
+
+    pub fn to_json(&self) -> Json {
+        let array = |values: Vec<String>| Json::Array(values.into_iter().map(Json::String).collect());
+        object(vec![
+            ("addresses", array(self.addresses.clone())),
+            ("scripts", array(self.scripts.clone())),
+            ("xpubs", array(self.xpubs.iter().map(|xpub| xpub.text.clone()).collect())),
+        ])
+    }
+
+    /// Watched outputs a transaction pays or spends, `block` being the hash
+    /// and height of the block it's in, if any
+    pub fn scan_tx(&mut self, wtx: &WitnessTx, block: Option<(Sha256dHash, u32)>,
+                   source: &UtxoSource) -> Vec<Alert> {
+        let txid = wtx.txid();
+        let mut alerts = vec![];
+        let mut used: Vec<(String, u32, u32)> = vec![];
+        for (i, output) in wtx.tx.output.iter().enumerate() {
+            let script = output.script_pubkey.clone().into_vec();
+            if let Some(origin) = self.index.get(&script) {
+                alerts.push(Alert {
+                    kind: AlertKind::Received,
+                    watched: origin.watched.clone(),
+                    script: script.clone(),
+                    txid: txid,
+                    index: i as u32,
+                    value: output.value,
+                    block: block,
+                });
+                if let Some((chain, index)) = origin.path {
+                    used.push((origin.watched.clone(), chain, index));
+                }
+            }
+        }
+        if !is_coinbase(&wtx.tx) {
+            for (i, input) in wtx.tx.input.iter().enumerate() {
+                let prevout = match source.prevout(&input.prev_hash, input.prev_index) {
+                    Some(prevout) => prevout,
+                    None => continue,
+                };
+                let script = prevout.script_pubkey.clone().into_vec();
+                if let Some(origin) = self.index.get(&script) {
+                    alerts.push(Alert {
+                        kind: AlertKind::Spent,
+                        watched: origin.watched.clone(),
+                        script: script.clone(),
+                        txid: txid,
+                        index: i as u32,
+                        value: prevout.value,
+                        block: block,
+                    });
+                }
+            }
+        }
+
+        // keep XPUB_GAP unused addresses ahead of the last one used
+        for (watched, chain, index) in used {
+            if let Some(i) = self.xpubs.iter().position(|xpub| xpub.text == watched) {
+                if let Err(e) = self.derive(i, chain, index + 1 + XPUB_GAP) {
+                    error!("wallet", xpub = watched; "Could not derive addresses: {}", e);
+                }
+            }
+        }
+        alerts
+    }
+
+    /// Derive and index an xpub's addresses on `chain` up to `count`
+    fn derive(&mut self, i: usize, chain: u32, count: u32) -> Result<(), String> {
+        let xpub = &mut self.xpubs[i];
+        let from = xpub.derived[chain as usize];
+        if from >= count {
+            return Ok(());
+        }
+        let chain_key = match xpub.key.ckd_pub(&self.secp, ChildNumber::Normal(chain)) {
+            Ok(key) => key,
+            Err(e) => return Err(format!("{:?}", e)),
+        };
+        for index in from..count {
+            let key = match chain_key.ckd_pub(&self.secp, ChildNumber::Normal(index)) {
+                Ok(key) => key,
+                Err(e) => return Err(format!("{:?}", e)),
+            };
+            self.index.insert(xpub.address_type.script_pubkey(&self.secp, &key.public_key),
+                              Origin { watched: xpub.text.clone(), path: Some((chain, index)) });
+        }
+        xpub.derived[chain as usize] = count;
+        Ok(())
+    }
+}
+
+/// An extended public key, with the type of address its version stands for
+fn parse_xpub(xpub: &str) -> Result<(ExtendedPubKey, AddressType), String> {
+    let mut data: Vec<u8> = match FromBase58::from_base58check(xpub) {
+        Ok(data) => data,
+        Err(e) => return Err(format!("Invalid xpub {}: {:?}", xpub, e)),
+    };
+    let (address_type, version) = match XPUB_VERSIONS.iter()
+        .find(|&&(version, _, _)| data.len() > 4 && data[..4] == version[..]) {
+            Some(&(_, address_type, version)) => (address_type, version),
+            None => return Err(format!("Unknown extended key version in {}", xpub)),
+        };
+    data[..4].copy_from_slice(&version[..]);
+    match ExtendedPubKey::from_base58_layout(data) {
+        Ok(key) => Ok((key, address_type)),
+        Err(e) => Err(format!("Invalid xpub {}: {:?}", xpub, e)),
+    }
+}
+
+/// An array of strings in the watch list file, which may be left out
+fn strings(json: &Json, key: &str, path: &str) -> Result<Vec<String>, String> {
+    let values = match json.find(key) {
+        Some(&Json::Array(ref values)) => values,
+        Some(_) => return Err(format!("{} in {} must be an array", key, path)),
+        None => return Ok(vec![]),
+    };
+    values.iter()
+        .map(|value| match value.as_string() {
+            Some(value) => Ok(value.to_string()),
+            None => Err(format!("{} in {} must be strings", key, path)),
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum AlertKind {
+    Received,
+    Spent,
+}
+
+/// A watched script being paid or spent
+#[derive(Clone)]
+pub struct Alert {
+    pub kind: AlertKind,
+    /// The address, script or xpub that matched
+    pub watched: String,
+    pub script: Vec<u8>,
+    pub txid: Sha256dHash,
+    /// Index of the output paying the script, or of the input spending it
+    pub index: u32,
+    pub value: u64,
+    /// Hash and height of the block confirming the transaction
+    pub block: Option<(Sha256dHash, u32)>,
+}
+
+impl Alert {
+    pub fn to_json(&self, confirmations: i64) -> Json {
+        let (index_key, kind) = match self.kind {
+            AlertKind::Received => ("vout", "received"),
+            AlertKind::Spent => ("vin", "spent"),
+        };
+        object(vec![
+            ("type", Json::String(kind.to_string())),
+            ("watched", Json::String(self.watched.clone())),
+            ("script", Json::String(self.script.to_hex())),
+            ("txid", Json::String(self.txid.be_hex_string())),
+            (index_key, Json::U64(self.index as u64)),
+            ("value", Json::U64(self.value)),
+            ("block_hash", self.block.map_or(Json::Null, |(hash, _)| {
+                Json::String(hash.be_hex_string())
+            })),
+            ("block_height", self.block.map_or(Json::Null, |(_, height)| {
+                Json::U64(height as u64)
+            })),
+            ("confirmations", Json::I64(confirmations)),
+        ])
+    }
+}
+
+/// Confirmed alerts still due confirmation count updates, with the count
+/// last sent
+pub struct Confirming {
+    /// Confirmation count up to which alerts are updated
+    target: i64,
+    alerts: Vec<(Alert, i64)>,
+}
+
+impl Confirming {
+    pub fn new(target: u32) -> Confirming {
+        Confirming {
+            target: target as i64,
+            alerts: vec![],
+        }
+    }
+
+    /// Follow an alert just sent with `sent` confirmations, if it's due
+    /// more updates
+    pub fn track(&mut self, alert: Alert, sent: i64) {
+        if sent > 0 && sent < self.target {
+            self.alerts.push((alert, sent));
+        }
+    }
+
+    /// The alerts whose confirmation count changed, with the new count.
+    /// `confirmations` gives a block's count, or -1 if it's off the best
+    /// chain. Alerts are no longer followed once they reach the target or
+    /// their block leaves the best chain.
+    pub fn update<F>(&mut self, confirmations: F) -> Vec<(Alert, i64)>
+        where F: Fn(&Sha256dHash) -> i64 {
+        let mut changed = vec![];
+        for (alert, sent) in mem::replace(&mut self.alerts, vec![]) {
+            let block_hash = match alert.block {
+                Some((hash, _)) => hash,
+                None => continue,
+            };
+            let count = confirmations(&block_hash).max(0);
+            if count != sent {
+                changed.push((alert.clone(), count));
+            }
+            self.track(alert, count);
+        }
+        changed
+    }
+}
+
+/// POSTs payloads to an HTTP endpoint from a thread of its own, retrying
+/// failed deliveries with a doubling delay
+pub struct Webhook {
+    payloads: Sender<String>,
+}
+
+impl Webhook {
+    pub fn start(url: String) -> Webhook {
+        Webhook::with_retry_delay(url, Duration::from_secs(RETRY_DELAY))
+    }
+
+    fn with_retry_delay(url: String, retry_delay: Duration) -> Webhook {
+        let (sender, receiver) = channel();
+        thread::spawn(move || {
+            // when each payload is next due, attempts made and the payload
+            let mut pending: Vec<(Instant, u32, String)> = vec![];
+            loop {
+                let now = Instant::now();
+                let received = match pending.iter().map(|&(due, _, _)| due).min() {
+                    Some(due) if due > now => receiver.recv_timeout(due - now),
+                    Some(_) => Err(RecvTimeoutError::Timeout),
+                    None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match received {
+                    Ok(payload) => pending.push((Instant::now(), 0, payload)),
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+
+                let now = Instant::now();
+                let (due, mut waiting): (Vec<_>, Vec<_>) = pending.into_iter()
+                    .partition(|&(due, _, _)| due <= now);
+                for (_, attempts, payload) in due {
+                    let error = match http::post_json(&url, &payload) {
+                        Ok(status) if status >= 200 && status < 300 => continue,
+                        Ok(status) => format!("status {}", status),
+                        Err(e) => e,
+                    };
+                    let attempts = attempts + 1;
+                    if attempts >= WEBHOOK_ATTEMPTS {
+                        error!("api", attempts = attempts;
+                               "Giving up on webhook delivery: {}", error);
+                    } else {
+                        let delay = retry_delay * (1 << (attempts - 1));
+                        warn!("api", attempts = attempts, retry_in = delay.as_secs();
+                              "Webhook delivery failed: {}", error);
+                        waiting.push((now + delay, attempts, payload));
+                    }
+                }
+                pending = waiting;
+            }
+        });
+        Webhook { payloads: sender }
+    }
+
+    pub fn send(&self, payload: String) {
+        if self.payloads.send(payload).is_err() {
+            error!("api", "Webhook thread is gone, dropping alert");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use rustc_serialize::json::{self, Json};
+
+    use bitcoin::util::hash::Sha256dHash;
+
+    use http::{self, Response};
+    use util::script_from_address;
+
+    use super::{Alert, AlertKind, Confirming, Webhook, WatchList};
+
+    /// The BIP44, BIP49 and BIP84 account 0 keys of the mnemonic "abandon
+    /// abandon ... about", as an xpub, ypub and zpub, with their first
+    /// receiving and change addresses
+    const ACCOUNT_KEYS: [(&'static str, &'static str, &'static str); 3] = [
+        ("xpub6BosfCnifzxcFwrSzQiqu2DBVTshkCXacvNsWGYJVVhhawA7d4R5\
+          WSWGFNbi8Aw6ZRc1brxMyWMzG3DSSSSoekkudhUd9yLb6qx39T9nMdj",
+         "1LqBGSKuX5yYUonjxT5qGfpUsXKYYWeabA", "1J3J6EvPrv8q6AC3VCjWV45Uf3nssNMRtH"),
+        ("ypub6Ww3ibxVfGzLrAH1PNcjyAWenMTbbAosGNB6VvmSEgytSER9azLD\
+          WCxoJwW7Ke7icmizBMXrzBx9979FfaHxHcrArf3zbeJJJUZPf663zsP",
+         "37VucYSaXLCAsxYyAPfbSi9eh4iEcbShgf", "34K56kSjgUCUSD8GTtuF7c9Zzwokbs6uZ7"),
+        ("zpub6rFR7y4Q2AijBEqTUquhVz398htDFrtymD9xYYfG1m4wAcvPhXNf\
+          E3EfH1r1ADqtfSdVCToUG868RvUUkgDKf31mGDtKsAYz2oz2AGutZYs",
+         "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu",
+         "bc1q8c6fshw2dlwun7ekn9qwf37cu2rn755upcp6el"),
+    ];
+
+    /// Bodies posted to a stub endpoint, with when they arrived
+    type Received = Arc<Mutex<Vec<(Instant, String)>>>;
+
+    /// Serve a stub webhook endpoint on a free port that fails the first
+    /// `failures` deliveries, returning its URL
+    fn stub(failures: usize) -> (String, Received) {
+        let received: Received = Arc::new(Mutex::new(vec![]));
+        let log = received.clone();
+        let address = http::serve("127.0.0.1:0", move |request| {
+            let mut log = log.lock().unwrap();
+            log.push((Instant::now(), String::from_utf8(request.body).unwrap()));
+            if log.len() <= failures {
+                Response::text(500, "try again")
+            } else {
+                Response::text(200, "ok")
+            }
+        }).unwrap();
+        (format!("http://{}/alerts", address), received)
+    }
+
+    fn wait_for(received: &Received, count: usize) {
+        let start = Instant::now();
+        while received.lock().unwrap().len() < count && start.elapsed() < Duration::from_secs(10) {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn alert(n: u8) -> Alert {
+        Alert {
+            kind: AlertKind::Received,
+            watched: "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2".to_string(),
+            script: vec![0x51],
+            txid: Sha256dHash::from_data(&[n]),
+            index: 0,
+            value: 1000,
+            block: Some((Sha256dHash::from_data(&[n, n]), 100)),
+        }
+    }
+
+    #[test]
+    fn xpub_versions_pick_the_address_type() {
+        let mut watch_list = WatchList::new();
+        for &(xpub, receive, change) in ACCOUNT_KEYS.iter() {
+            assert!(watch_list.add_xpub(xpub).unwrap());
+            for &(address, path) in [(receive, (0, 0)), (change, (1, 0))].iter() {
+                let origin = &watch_list.index[&script_from_address(address).unwrap()];
+                assert_eq!(origin.watched, xpub);
+                assert_eq!(origin.path, Some(path));
+            }
+        }
+        // the same key as a zpub and an xpub pays to different scripts
+        assert_eq!(watch_list.index.len(), 3 * 2 * 20);
+        assert!(!watch_list.add_xpub(ACCOUNT_KEYS[2].0).unwrap());
+
+        let xpubs = watch_list.to_json().find("xpubs").unwrap().as_array().unwrap()
+            .iter().map(|xpub| xpub.as_string().unwrap().to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(xpubs, ACCOUNT_KEYS.iter().map(|&(xpub, _, _)| xpub.to_string())
+                   .collect::<Vec<String>>());
+        assert!(watch_list.remove(ACCOUNT_KEYS[1].0));
+        assert_eq!(watch_list.index.len(), 2 * 2 * 20);
+        assert!(!watch_list.index.contains_key(&script_from_address(ACCOUNT_KEYS[1].1).unwrap()));
+
+        // an extended private key isn't taken for a public one
+        assert!(watch_list.add_xpub("xprv9s21ZrQH143K3QTDL4LXw2F7HEK3wJUD2nW2nRk4stbPy6cq3jPP\
+                                     qjiChkVvvNKmPGJxWUtg6LnF5kejMRNNU3TGtRBeJgk33yuGBxrMPHi")
+                .is_err());
+    }
+
+    #[test]
+    fn webhook_retries_with_backoff() {
+        let (url, received) = stub(2);
+        let webhook = Webhook::with_retry_delay(url, Duration::from_millis(100));
+        webhook.send("{\"n\":1}".to_string());
+        wait_for(&received, 3);
+        // nothing is sent once a delivery succeeds
+        thread::sleep(Duration::from_millis(500));
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 3);
+        assert!(received.iter().all(|&(_, ref body)| body == "{\"n\":1}"));
+        let first_wait = received[1].0.duration_since(received[0].0);
+        let second_wait = received[2].0.duration_since(received[1].0);
+        assert!(first_wait >= Duration::from_millis(90));
+        assert!(second_wait >= Duration::from_millis(190));
+    }
+
+    #[test]
+    fn webhook_sends_confirmation_updates() {
+        let (url, received) = stub(0);
+        let webhook = Webhook::start(url);
+        let send = |alert: &Alert, count: i64| {
+            webhook.send(json::encode(&alert.to_json(count)).unwrap());
+        };
+        let mut confirming = Confirming::new(3);
+
+        let (confirmed, reorged) = (alert(1), alert(2));
+        send(&confirmed, 1);
+        confirming.track(confirmed, 1);
+        for (alert, count) in confirming.update(|_| 2) {
+            send(&alert, count);
+        }
+        assert!(confirming.update(|_| 2).is_empty());
+        for (alert, count) in confirming.update(|_| 3) {
+            send(&alert, count);
+        }
+        // the target was reached, so there are no more updates
+        assert!(confirming.update(|_| 4).is_empty());
+
+        send(&reorged, 1);
+        confirming.track(reorged, 1);
+        for (alert, count) in confirming.update(|_| -1) {
+            send(&alert, count);
+        }
+        assert!(confirming.update(|_| 2).is_empty());
+
+        wait_for(&received, 5);
+        let sent = received.lock().unwrap().iter()
+            .map(|&(_, ref body)| {
+                let json = Json::from_str(body).unwrap();
+                (json.find("txid").unwrap().as_string().unwrap().to_string(),
+                 json.find("confirmations").unwrap().as_i64().unwrap())
+            })
+            .collect::<Vec<(String, i64)>>();
+        let (first, second) = (alert(1).txid.be_hex_string(), alert(2).txid.be_hex_string());
+        assert_eq!(sent, vec![(first.clone(), 1), (first.clone(), 2), (first, 3),
+                              (second.clone(), 1), (second, 0)]);
+    }
+}
@@ -0,0 +1,255 @@
+use std::io::Cursor;
+
+use bitcoin::network::encodable::{ConsensusEncodable, ConsensusDecodable};
+use bitcoin::network::serialize::{SimpleEncoder, SimpleDecoder, RawDecoder, serialize,
+                                  deserialize};
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::util::hash::Sha256dHash;
+
+use bloom::BloomFilter;
+use compact::{SendCmpct, CompactBlock, BlockTxnRequest, BlockTxn};
+use filter::{FilterRequest, CheckptRequest, CFilter, CFHeaders, CFCheckpt};
+use merkle::MerkleBlock;
+use witness::{WitnessBlock, WitnessTx};
+
+pub const PROTOCOL_VERSION: u32 = 70015;
+pub const USER_AGENT: &'static str = "/rotwatsb:0.1.0/";
+
+pub const NODE_NETWORK: u64 = 1 << 0;
+/// Serves filtered blocks to peers that load BIP37 bloom filters
+pub const NODE_BLOOM: u64 = 1 << 2;
+pub const NODE_WITNESS: u64 = 1 << 3;
+/// Serves BIP157 compact block filters
+pub const NODE_COMPACT_FILTERS: u64 = 1 << 6;
+
+pub const MSG_TX: u32 = 1;
+pub const MSG_BLOCK: u32 = 2;
+/// Only valid in getdata, asking for a block as a `merkleblock` matched
+/// against the peer's bloom filter
+pub const MSG_FILTERED_BLOCK: u32 = 3;
+/// Only valid in getdata, asking for a block as a `cmpctblock`
+pub const MSG_CMPCT_BLOCK: u32 = 4;
+pub const MSG_WITNESS_FLAG: u32 = 1 << 30;
+pub const MSG_WITNESS_TX: u32 = MSG_TX | MSG_WITNESS_FLAG;
+pub const MSG_WITNESS_BLOCK: u32 = MSG_BLOCK | MSG_WITNESS_FLAG;
+
+pub const HEADER_SIZE: usize = 24;
+pub const MAX_PAYLOAD_SIZE: usize = 32 * 1024 * 1024;
+
+/// An inventory vector. rust-bitcoin's `Inventory` can only represent the
+/// three original inventory types, so inventory is handled here instead.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct InvVector {
+    pub inv_type: u32,
+    pub hash: Sha256dHash,
+}
+
+impl InvVector {
+    pub fn new(inv_type: u32, hash: Sha256dHash) -> InvVector {
+        InvVector {
+            inv_type: inv_type,
+            hash: hash,
+        }
+    }
+}
+
+impl<S: SimpleEncoder> ConsensusEncodable<S> for InvVector {
+    fn consensus_encode(&self, s: &mut S) -> Result<(), S::Error> {
+        try!(self.inv_type.consensus_encode(s));
+        self.hash.consensus_encode(s)
+    }
+}
+
+impl<D: SimpleDecoder> ConsensusDecodable<D> for InvVector {
+    fn consensus_decode(d: &mut D) -> Result<InvVector, D::Error> {
+        Ok(InvVector {
+            inv_type: try!(ConsensusDecodable::consensus_decode(d)),
+            hash: try!(ConsensusDecodable::consensus_decode(d)),
+        })
+    }
+}
+
+/// Everything we send to or receive from a peer. Messages rust-bitcoin
+/// handles correctly are passed through as `Network`; the rest are encoded
+/// and decoded here.
+#[derive(Clone, Debug)]
+pub enum PeerMessage {
+    Network(NetworkMessage),
+    Inv(Vec<InvVector>),
+    GetData(Vec<InvVector>),
+    NotFound(Vec<InvVector>),
+    Block(WitnessBlock),
+    Tx(WitnessTx),
+    SendCmpct(SendCmpct),
+    CmpctBlock(CompactBlock),
+    GetBlockTxn(BlockTxnRequest),
+    BlockTxn(BlockTxn),
+    GetCFilters(FilterRequest),
+    CFilter(CFilter),
+    GetCFHeaders(FilterRequest),
+    CFHeaders(CFHeaders),
+    GetCFCheckpt(CheckptRequest),
+    CFCheckpt(CFCheckpt),
+    FilterLoad(BloomFilter),
+    FilterAdd(Vec<u8>),
+    FilterClear,
+    MerkleBlock(MerkleBlock),
+    /// Never sent as is: a block the daemon hands a peer's thread to match
+    /// against the peer's bloom filter and send as a `merkleblock`
+    FilteredBlock(WitnessBlock),
+}
+
+impl PeerMessage {
+    pub fn command(&self) -> String {
+        match *self {
+            PeerMessage::Network(ref msg) => RawNetworkMessage {
+                magic: 0,
+                payload: msg.clone(),
+            }.command(),
+            PeerMessage::Inv(_) => "inv".to_string(),
+            PeerMessage::GetData(_) => "getdata".to_string(),
+            PeerMessage::NotFound(_) => "notfound".to_string(),
+            PeerMessage::Block(_) => "block".to_string(),
+            PeerMessage::Tx(_) => "tx".to_string(),
+            PeerMessage::SendCmpct(_) => "sendcmpct".to_string(),
+            PeerMessage::CmpctBlock(_) => "cmpctblock".to_string(),
+            PeerMessage::GetBlockTxn(_) => "getblocktxn".to_string(),
+            PeerMessage::BlockTxn(_) => "blocktxn".to_string(),
+            PeerMessage::GetCFilters(_) => "getcfilters".to_string(),
+            PeerMessage::CFilter(_) => "cfilter".to_string(),
+            PeerMessage::GetCFHeaders(_) => "getcfheaders".to_string(),
+            PeerMessage::CFHeaders(_) => "cfheaders".to_string(),
+            PeerMessage::GetCFCheckpt(_) => "getcfcheckpt".to_string(),
+            PeerMessage::CFCheckpt(_) => "cfcheckpt".to_string(),
+            PeerMessage::FilterLoad(_) => "filterload".to_string(),
+            PeerMessage::FilterAdd(_) => "filteradd".to_string(),
+            PeerMessage::FilterClear => "filterclear".to_string(),
+            PeerMessage::MerkleBlock(_) |
+            PeerMessage::FilteredBlock(_) => "merkleblock".to_string(),
+        }
+    }
+}
+
+/// Serialize a message, header included, for sending over the wire
+pub fn encode_message(magic: u32, msg: &PeerMessage) -> Result<Vec<u8>, String> {
+    let payload = match *msg {
+        PeerMessage::Network(ref msg) => {
+            let raw = RawNetworkMessage { magic: magic, payload: msg.clone() };
+            return serialize(&raw)
+                .map_err(|e| format!("Encoding {}: {:?}", raw.command(), e));
+        },
+        PeerMessage::Inv(ref inv) |
+        PeerMessage::GetData(ref inv) |
+        PeerMessage::NotFound(ref inv) => serialize(inv),
+        PeerMessage::Block(ref block) => serialize(block),
+        PeerMessage::Tx(ref tx) => serialize(tx),
+        PeerMessage::SendCmpct(ref send_cmpct) => serialize(send_cmpct),
+        PeerMessage::CmpctBlock(ref block) => serialize(block),
+        PeerMessage::GetBlockTxn(ref request) => serialize(request),
+        PeerMessage::BlockTxn(ref txn) => serialize(txn),
+        PeerMessage::GetCFilters(ref request) |
+        PeerMessage::GetCFHeaders(ref request) => serialize(request),
+        PeerMessage::CFilter(ref cfilter) => serialize(cfilter),
+        PeerMessage::CFHeaders(ref cfheaders) => serialize(cfheaders),
+        PeerMessage::GetCFCheckpt(ref request) => serialize(request),
+        PeerMessage::CFCheckpt(ref cfcheckpt) => serialize(cfcheckpt),
+        PeerMessage::FilterLoad(ref filter) => serialize(filter),
+        PeerMessage::FilterAdd(ref data) => serialize(data),
+        PeerMessage::FilterClear => Ok(vec![]),
+        PeerMessage::MerkleBlock(ref merkle_block) => serialize(merkle_block),
+        PeerMessage::FilteredBlock(_) => {
+            return Err("Filtered blocks must be matched against a filter first".to_string());
+        },
+    };
+    match payload {
+        Ok(payload) => Ok(frame(magic, &msg.command(), &payload[..])),
+        Err(e) => Err(format!("Encoding {}: {:?}", msg.command(), e)),
+    }
+}
+
+/// Decode a message from its full serialization, header included. Returns
+/// `Ok(None)` for commands we don't understand, which are simply ignored.
+pub fn decode_message(command: &str, message: &[u8]) -> Result<Option<PeerMessage>, String> {
+    let payload = &message[HEADER_SIZE..];
+    let msg = match command {
+        "inv" => PeerMessage::Inv(try!(decode(command, payload))),
+        "getdata" => PeerMessage::GetData(try!(decode(command, payload))),
+        "notfound" => PeerMessage::NotFound(try!(decode(command, payload))),
+        "block" => PeerMessage::Block(try!(decode(command, payload))),
+        "tx" => PeerMessage::Tx(try!(decode(command, payload))),
+        "sendcmpct" => PeerMessage::SendCmpct(try!(decode(command, payload))),
+        "cmpctblock" => PeerMessage::CmpctBlock(try!(decode(command, payload))),
+        "getblocktxn" => PeerMessage::GetBlockTxn(try!(decode(command, payload))),
+        "blocktxn" => PeerMessage::BlockTxn(try!(decode(command, payload))),
+        "getcfilters" => PeerMessage::GetCFilters(try!(decode(command, payload))),
+        "cfilter" => PeerMessage::CFilter(try!(decode(command, payload))),
+        "getcfheaders" => PeerMessage::GetCFHeaders(try!(decode(command, payload))),
+        "cfheaders" => PeerMessage::CFHeaders(try!(decode(command, payload))),
+        "getcfcheckpt" => PeerMessage::GetCFCheckpt(try!(decode(command, payload))),
+        "cfcheckpt" => PeerMessage::CFCheckpt(try!(decode(command, payload))),
+        "filterload" => PeerMessage::FilterLoad(try!(decode(command, payload))),
+        "filteradd" => PeerMessage::FilterAdd(try!(decode(command, payload))),
+        "filterclear" => PeerMessage::FilterClear,
+        "version" | "verack" | "addr" | "ping" | "pong" | "headers" |
+        "getheaders" | "getblocks" | "mempool" | "getaddr" => {
+            let raw: RawNetworkMessage = try!(decode(command, message));
+            PeerMessage::Network(raw.payload)
+        },
+        _ => return Ok(None),
+    };
+    Ok(Some(msg))
+}
+
+fn decode<'a, T>(command: &str, data: &'a [u8]) -> Result<T, String>
+    where T: ConsensusDecodable<RawDecoder<Cursor<&'a [u8]>>> {
+    deserialize(data).map_err(|e| format!("Decoding {}: {:?}", command, e))
+}
+
+/// Prefix a payload with the message header
+pub fn frame(magic: u32, command: &str, payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(HEADER_SIZE + payload.len());
+    data.extend_from_slice(&u32_to_le(magic)[..]);
+    let mut command_bytes = [0u8; 12];
+    for (i, byte) in command.bytes().take(12).enumerate() {
+        command_bytes[i] = byte;
+    }
+    data.extend_from_slice(&command_bytes[..]);
+    data.extend_from_slice(&u32_to_le(payload.len() as u32)[..]);
+    data.extend_from_slice(&checksum(payload)[..]);
+    data.extend_from_slice(payload);
+    data
+}
+
+pub struct MessageHeader {
+    pub magic: u32,
+    pub command: String,
+    pub length: usize,
+    pub checksum: [u8; 4],
+}
+
+pub fn parse_header(header: &[u8]) -> MessageHeader {
+    let command = header[4..16].iter()
+        .take_while(|&&byte| byte != 0)
+        .map(|&byte| byte as char)
+        .collect::<String>();
+    MessageHeader {
+        magic: u32_from_le(&header[0..4]),
+        command: command,
+        length: u32_from_le(&header[16..20]) as usize,
+        checksum: [header[20], header[21], header[22], header[23]],
+    }
+}
+
+pub fn checksum(payload: &[u8]) -> [u8; 4] {
+    let hash = Sha256dHash::from_data(payload);
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+pub fn u32_to_le(n: u32) -> [u8; 4] {
+    [n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]
+}
+
+pub fn u32_from_le(data: &[u8]) -> u32 {
+    (data[0] as u32) | (data[1] as u32) << 8 |
+    (data[2] as u32) << 16 | (data[3] as u32) << 24
+}
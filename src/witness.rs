@@ -0,0 +1,231 @@
+use bitcoin::blockdata::block::{Block, BlockHeader};
+use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
+use bitcoin::network::encodable::{ConsensusEncodable, ConsensusDecodable, VarInt};
+use bitcoin::network::serialize::{SimpleEncoder, SimpleDecoder, BitcoinHash, serialize};
+use bitcoin::util::hash::Sha256dHash;
+
+use merkle::merkle_root;
+
+/// OP_RETURN, push of 36 bytes, then the BIP141 commitment tag
+const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+/// A transaction along with the witness stack of each of its inputs.
+/// rust-bitcoin knows nothing of segwit, so the stripped transaction is kept
+/// as a regular `Transaction` and the witness data is carried beside it.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct WitnessTx {
+    pub tx: Transaction,
+    /// One stack per input, or empty if the transaction has no witness
+    pub witness: Vec<Vec<Vec<u8>>>,
+}
+
+impl WitnessTx {
+    pub fn from_tx(tx: Transaction) -> WitnessTx {
+        WitnessTx {
+            tx: tx,
+            witness: vec![],
+        }
+    }
+
+    pub fn has_witness(&self) -> bool {
+        self.witness.iter().any(|stack| !stack.is_empty())
+    }
+
+    pub fn txid(&self) -> Sha256dHash {
+        self.tx.bitcoin_hash()
+    }
+
+    pub fn wtxid(&self) -> Sha256dHash {
+        if self.has_witness() {
+            Sha256dHash::from_data(&serialize(self).unwrap()[..])
+        } else {
+            self.txid()
+        }
+    }
+
+    /// Size of the transaction serialized without witness data
+    pub fn base_size(&self) -> usize {
+        serialize(&self.tx).unwrap().len()
+    }
+
+    /// Size of the transaction serialized with witness data
+    pub fn total_size(&self) -> usize {
+        serialize(self).unwrap().len()
+    }
+
+    pub fn weight(&self) -> usize {
+        self.base_size() * 3 + self.total_size()
+    }
+}
+
+impl<S: SimpleEncoder> ConsensusEncodable<S> for WitnessTx {
+    fn consensus_encode(&self, s: &mut S) -> Result<(), S::Error> {
+        let segwit = self.has_witness();
+        try!(self.tx.version.consensus_encode(s));
+        if segwit {
+            // marker and flag
+            try!(s.emit_u8(0));
+            try!(s.emit_u8(1));
+        }
+        try!(self.tx.input.consensus_encode(s));
+        try!(self.tx.output.consensus_encode(s));
+        if segwit {
+            for i in 0..self.tx.input.len() {
+                match self.witness.get(i) {
+                    Some(stack) => try!(stack.consensus_encode(s)),
+                    None => try!(VarInt(0).consensus_encode(s)),
+                }
+            }
+        }
+        self.tx.lock_time.consensus_encode(s)
+    }
+}
+
+impl<D: SimpleDecoder> ConsensusDecodable<D> for WitnessTx {
+    fn consensus_decode(d: &mut D) -> Result<WitnessTx, D::Error> {
+        let version: u32 = try!(ConsensusDecodable::consensus_decode(d));
+        let VarInt(n_inputs) = try!(ConsensusDecodable::consensus_decode(d));
+
+        // an input count of zero is the segwit marker
+        let segwit = n_inputs == 0;
+        let input: Vec<TxIn> = if segwit {
+            let flag = try!(d.read_u8());
+            if flag != 1 {
+                return Err(d.error(format!("Unknown segwit flag {}", flag)));
+            }
+            try!(ConsensusDecodable::consensus_decode(d))
+        } else {
+            let mut input = Vec::new();
+            for _ in 0..n_inputs {
+                input.push(try!(ConsensusDecodable::consensus_decode(d)));
+            }
+            input
+        };
+        let output: Vec<TxOut> = try!(ConsensusDecodable::consensus_decode(d));
+
+        let mut witness: Vec<Vec<Vec<u8>>> = vec![];
+        if segwit {
+            for _ in 0..input.len() {
+                witness.push(try!(ConsensusDecodable::consensus_decode(d)));
+            }
+        }
+        let lock_time: u32 = try!(ConsensusDecodable::consensus_decode(d));
+
+        Ok(WitnessTx {
+            tx: Transaction {
+                version: version,
+                lock_time: lock_time,
+                input: input,
+                output: output,
+            },
+            witness: witness,
+        })
+    }
+}
+
+/// A block as delivered in response to `MSG_WITNESS_BLOCK`
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct WitnessBlock {
+    pub header: BlockHeader,
+    pub txdata: Vec<WitnessTx>,
+}
+
+impl WitnessBlock {
+    pub fn from_block(block: Block) -> WitnessBlock {
+        WitnessBlock {
+            header: block.header,
+            txdata: block.txdata.into_iter().map(WitnessTx::from_tx).collect(),
+        }
+    }
+
+    /// The block stripped of its witness data, as rust-bitcoin expects it
+    pub fn block(&self) -> Block {
+        Block {
+            header: self.header,
+            txdata: self.txdata.iter().map(|wtx| wtx.tx.clone()).collect(),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        serialize(self).unwrap().len()
+    }
+
+    pub fn weight(&self) -> usize {
+        let base_size = serialize(&self.block()).unwrap().len();
+        base_size * 3 + self.size()
+    }
+
+    /// Merkle root of the wtxids, with the coinbase counted as zero
+    pub fn witness_root(&self) -> Sha256dHash {
+        let wtxids = self.txdata.iter().enumerate()
+            .map(|(i, wtx)| if i == 0 { Default::default() } else { wtx.wtxid() })
+            .collect::<Vec<Sha256dHash>>();
+        merkle_root(&wtxids[..])
+    }
+
+    /// The commitment found in the last matching coinbase output, if any
+    pub fn witness_commitment(&self) -> Option<Sha256dHash> {
+        let coinbase = match self.txdata.first() {
+            Some(wtx) => wtx,
+            None => return None,
+        };
+        coinbase.tx.output.iter().rev()
+            .map(|output| output.script_pubkey.clone().into_vec())
+            .find(|script| script.len() >= 38 &&
+                  script[..6] == WITNESS_COMMITMENT_HEADER[..])
+            .map(|script| Sha256dHash::from(&script[6..38]))
+    }
+
+    /// Check the BIP141 commitment in the coinbase against the witness data
+    /// actually carried by the block's transactions
+    pub fn check_witness_commitment(&self) -> Result<(), String> {
+        match self.witness_commitment() {
+            Some(commitment) => {
+                let reserved_value = match self.txdata[0].witness.first() {
+                    Some(stack) if stack.len() == 1 && stack[0].len() == 32 =>
+                        stack[0].clone(),
+                    _ => return Err(format!("Bad witness reserved value in block {:x}",
+                                            self.bitcoin_hash())),
+                };
+                let mut data = self.witness_root()[..].to_vec();
+                data.extend_from_slice(&reserved_value[..]);
+                if Sha256dHash::from_data(&data[..]) == commitment {
+                    Ok(())
+                } else {
+                    Err(format!("Witness commitment mismatch in block {:x}",
+                                self.bitcoin_hash()))
+                }
+            },
+            None => {
+                if self.txdata.iter().any(|wtx| wtx.has_witness()) {
+                    Err(format!("Unexpected witness data in block {:x}",
+                                self.bitcoin_hash()))
+                } else {
+                    Ok(())
+                }
+            },
+        }
+    }
+}
+
+impl BitcoinHash for WitnessBlock {
+    fn bitcoin_hash(&self) -> Sha256dHash {
+        self.header.bitcoin_hash()
+    }
+}
+
+impl<S: SimpleEncoder> ConsensusEncodable<S> for WitnessBlock {
+    fn consensus_encode(&self, s: &mut S) -> Result<(), S::Error> {
+        try!(self.header.consensus_encode(s));
+        self.txdata.consensus_encode(s)
+    }
+}
+
+impl<D: SimpleDecoder> ConsensusDecodable<D> for WitnessBlock {
+    fn consensus_decode(d: &mut D) -> Result<WitnessBlock, D::Error> {
+        Ok(WitnessBlock {
+            header: try!(ConsensusDecodable::consensus_decode(d)),
+            txdata: try!(ConsensusDecodable::consensus_decode(d)),
+        })
+    }
+}